@@ -0,0 +1,313 @@
+use crate::{codec::CodecId, packet::DecodeError};
+
+/// What the sound card runs at. Everything we negotiate is converted to and
+/// from this on the way to and from the network.
+pub const DEVICE_SAMPLE_RATE: u32 = 48000;
+pub const DEVICE_CHANNELS: usize = 2;
+
+/// The audio formats this unit can send and receive, most preferred first.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Capabilities {
+    pub codecs: Vec<CodecId>,
+    /// Only divisors of [`DEVICE_SAMPLE_RATE`] are supported.
+    pub sample_rates: Vec<u32>,
+    pub channels: Vec<u8>,
+    /// Audio per packet, in milliseconds.
+    pub frame_sizes: Vec<u8>,
+}
+
+impl Capabilities {
+    pub fn local() -> Self {
+        Self {
+            codecs: vec![
+                CodecId::ImaAdpcm,
+                CodecId::Pcmu,
+                CodecId::Pcma,
+                CodecId::Pcm16,
+            ],
+            sample_rates: vec![48000, 24000, 16000, 8000],
+            channels: vec![2, 1],
+            frame_sizes: vec![10, 20],
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.push(self.codecs.len() as u8);
+        data.extend(self.codecs.iter().map(|&codec| codec as u8));
+        data.push(self.sample_rates.len() as u8);
+        for rate in &self.sample_rates {
+            data.extend_from_slice(&rate.to_le_bytes());
+        }
+        data.push(self.channels.len() as u8);
+        data.extend_from_slice(&self.channels);
+        data.push(self.frame_sizes.len() as u8);
+        data.extend_from_slice(&self.frame_sizes);
+        data
+    }
+
+    /// Codecs we don't know are skipped, so newer units can offer more.
+    pub fn deserialize(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { data, position: 0 };
+        let count = reader.u8()? as usize;
+        let codecs = reader
+            .bytes(count)?
+            .iter()
+            .filter_map(|&codec| CodecId::try_from(codec).ok())
+            .collect();
+        let count = reader.u8()? as usize;
+        let sample_rates = reader
+            .bytes(count * 4)?
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let count = reader.u8()? as usize;
+        let channels = reader.bytes(count)?.to_vec();
+        let count = reader.u8()? as usize;
+        let frame_sizes = reader.bytes(count)?.to_vec();
+        Ok(Self {
+            codecs,
+            sample_rates,
+            channels,
+            frame_sizes,
+        })
+    }
+
+    /// Picks the format for a call from the caller's `offer`, following the
+    /// caller's preferences. `None` if we have nothing in common.
+    pub fn negotiate(&self, offer: &Capabilities) -> Option<AudioFormat> {
+        fn first_common<T: PartialEq + Copy>(offer: &[T], local: &[T]) -> Option<T> {
+            offer.iter().find(|value| local.contains(value)).copied()
+        }
+        Some(AudioFormat {
+            codec: first_common(&offer.codecs, &self.codecs)?,
+            sample_rate: first_common(&offer.sample_rates, &self.sample_rates)?,
+            channels: first_common(&offer.channels, &self.channels)?,
+            frame_size: first_common(&offer.frame_sizes, &self.frame_sizes)?,
+        })
+    }
+
+    pub fn supports(&self, format: &AudioFormat) -> bool {
+        self.codecs.contains(&format.codec)
+            && self.sample_rates.contains(&format.sample_rate)
+            && self.channels.contains(&format.channels)
+            && self.frame_sizes.contains(&format.frame_size)
+    }
+}
+
+/// The format audio is sent in during a call, picked by the callee.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct AudioFormat {
+    pub codec: CodecId,
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// Audio per packet, in milliseconds.
+    pub frame_size: u8,
+}
+
+impl Default for AudioFormat {
+    /// What every unit used before formats were negotiated.
+    fn default() -> Self {
+        Self {
+            codec: CodecId::Pcm16,
+            sample_rate: DEVICE_SAMPLE_RATE,
+            channels: DEVICE_CHANNELS as u8,
+            frame_size: 10,
+        }
+    }
+}
+
+impl AudioFormat {
+    pub const SERIALIZED_SIZE: usize = 7;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::SERIALIZED_SIZE);
+        data.push(self.codec as u8);
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.push(self.channels);
+        data.push(self.frame_size);
+        data
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < Self::SERIALIZED_SIZE {
+            return Err(DecodeError::PayloadTooShort {
+                expected: Self::SERIALIZED_SIZE,
+                actual: data.len(),
+            });
+        }
+        Ok(Self {
+            codec: CodecId::try_from(data[0])?,
+            sample_rate: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+            channels: data[5],
+            frame_size: data[6],
+        })
+    }
+
+    /// Device frames that make up one packet.
+    pub fn device_frames_per_packet(&self) -> usize {
+        DEVICE_SAMPLE_RATE as usize * self.frame_size as usize / 1000
+    }
+
+    /// How many device frames make up one frame at our sample rate.
+    fn decimation(&self) -> usize {
+        (DEVICE_SAMPLE_RATE / self.sample_rate) as usize
+    }
+
+    /// Converts a timestamp from our sample clock to the device's.
+    pub fn device_timestamp(&self, timestamp: u32) -> u32 {
+        timestamp.wrapping_mul(self.decimation() as u32)
+    }
+
+    /// Converts interleaved device audio to this format by averaging
+    /// channels and neighbouring frames.
+    pub fn convert_from_device(&self, samples: &[i16]) -> Vec<i16> {
+        let decimation = self.decimation();
+        let channels = self.channels as usize;
+        let mut converted = Vec::with_capacity(samples.len() / decimation);
+        for group in samples.chunks(decimation * DEVICE_CHANNELS) {
+            let frames = group.len() / DEVICE_CHANNELS;
+            for channel in 0..channels {
+                let sum: i32 = group
+                    .chunks_exact(DEVICE_CHANNELS)
+                    .map(|frame| {
+                        if channels == DEVICE_CHANNELS {
+                            frame[channel] as i32
+                        } else {
+                            frame.iter().map(|&s| s as i32).sum::<i32>() / DEVICE_CHANNELS as i32
+                        }
+                    })
+                    .sum();
+                converted.push((sum / frames.max(1) as i32) as i16);
+            }
+        }
+        converted
+    }
+
+    /// Converts audio in this format back to interleaved device audio,
+    /// interpolating between frames and copying mono to every channel.
+    pub fn convert_to_device(&self, samples: &[i16]) -> Vec<i16> {
+        let decimation = self.decimation();
+        let channels = self.channels as usize;
+        let frames = samples.len() / channels;
+        let mut converted = Vec::with_capacity(frames * decimation * DEVICE_CHANNELS);
+        for frame in 0..frames {
+            let next = (frame + 1).min(frames - 1);
+            for step in 0..decimation {
+                for device_channel in 0..DEVICE_CHANNELS {
+                    let channel = device_channel.min(channels - 1);
+                    let current = samples[frame * channels + channel] as i32;
+                    let following = samples[next * channels + channel] as i32;
+                    let value = current + (following - current) * step as i32 / decimation as i32;
+                    converted.push(value as i16);
+                }
+            }
+        }
+        converted
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.position + count;
+        if end > self.data.len() {
+            return Err(DecodeError::PayloadTooShort {
+                expected: end,
+                actual: self.data.len(),
+            });
+        }
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(codecs: &[CodecId], sample_rates: &[u32], channels: &[u8]) -> Capabilities {
+        Capabilities {
+            codecs: codecs.to_vec(),
+            sample_rates: sample_rates.to_vec(),
+            channels: channels.to_vec(),
+            frame_sizes: vec![20, 10],
+        }
+    }
+
+    #[test]
+    fn negotiation_follows_the_callers_preferences() {
+        let offer = offer(
+            &[CodecId::Pcma, CodecId::ImaAdpcm],
+            &[11025, 16000, 48000],
+            &[1, 2],
+        );
+        let format = Capabilities::local().negotiate(&offer).unwrap();
+        assert_eq!(
+            format,
+            AudioFormat {
+                codec: CodecId::Pcma,
+                sample_rate: 16000,
+                channels: 1,
+                frame_size: 20,
+            }
+        );
+        assert!(Capabilities::local().supports(&format));
+    }
+
+    #[test]
+    fn negotiation_fails_without_anything_in_common() {
+        let local = Capabilities::local();
+        let no_rate = offer(&[CodecId::Pcm16], &[11025, 44100], &[2]);
+        assert_eq!(local.negotiate(&no_rate), None);
+        let no_codec = offer(&[], &[48000], &[2]);
+        assert_eq!(local.negotiate(&no_codec), None);
+    }
+
+    #[test]
+    fn unknown_codecs_in_an_offer_are_skipped() {
+        let local = Capabilities::local();
+        let mut data = local.serialize();
+        // A codec from newer firmware in front of ours
+        data[0] += 1;
+        data.insert(1, 200);
+        assert_eq!(Capabilities::deserialize(&data), Ok(local.clone()));
+        assert!(Capabilities::deserialize(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn device_audio_is_averaged_down_and_interpolated_back_up() {
+        let format = AudioFormat {
+            codec: CodecId::Pcm16,
+            sample_rate: 24000,
+            channels: 1,
+            frame_size: 10,
+        };
+        // Two device frames per frame, and both channels make up the one
+        let device = [100, 300, 200, 400, -100, -300, -200, -400];
+        let converted = format.convert_from_device(&device);
+        assert_eq!(converted, [250, -250]);
+
+        let back = format.convert_to_device(&converted);
+        assert_eq!(back, [250, 250, 0, 0, -250, -250, -250, -250]);
+    }
+
+    #[test]
+    fn device_format_converts_unchanged() {
+        let format = AudioFormat::default();
+        let device = [1, -2, 3, -4, 5, -6];
+        assert_eq!(format.convert_from_device(&device), device);
+        assert_eq!(format.convert_to_device(&device), device);
+        assert_eq!(format.device_timestamp(480), 480);
+        assert_eq!(format.device_frames_per_packet(), 480);
+    }
+}
@@ -0,0 +1,55 @@
+//! Runs the echo canceller over a recording, to tune it and check it without
+//! a call. The reference is what the speaker played and the mic what the
+//! microphone picked up at the same time, both 48 kHz 16-bit WAV files.
+
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+
+use phone::{audio_format, echo_canceller, wav};
+
+/// Cancels the echo of a reference recording in a microphone recording
+#[derive(Parser, Debug)]
+struct Cli {
+    /// What the speaker played
+    #[arg(long)]
+    reference: PathBuf,
+    /// What the microphone picked up
+    #[arg(long)]
+    mic: PathBuf,
+    /// Where to write the microphone audio without the echo
+    #[arg(long)]
+    output: PathBuf,
+    /// Longest echo cancelled, in milliseconds
+    #[arg(long, default_value_t = 128)]
+    tail: u64,
+}
+
+fn energy(samples: &[i16]) -> f64 {
+    samples
+        .iter()
+        .map(|&sample| sample as f64 * sample as f64)
+        .sum()
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let reference = wav::read(&cli.reference)?;
+    let mic = wav::read(&cli.mic)?;
+    if reference.len() != mic.len() {
+        eprintln!(
+            "The recordings differ in length, only the first {:.1} s are used",
+            reference.len().min(mic.len()) as f64
+                / (audio_format::DEVICE_SAMPLE_RATE as usize * audio_format::DEVICE_CHANNELS)
+                    as f64
+        );
+    }
+    let output =
+        echo_canceller::cancel_recording(&reference, &mic, Duration::from_millis(cli.tail));
+    wav::Writer::create(&cli.output)?.write(&output)?;
+
+    // Echo return loss enhancement: how much quieter the echo got
+    let enhancement = 10.0 * (energy(&mic[..output.len()]) / energy(&output).max(1.0)).log10();
+    eprintln!("Echo reduced by {:.1} dB", enhancement);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Relays UDP between two units while impairing it, to hear how calls hold
+//! up on a bad network. Call the address it listens on instead of the unit
+//! it relays to.
+
+use std::{net::SocketAddr, time::Duration};
+
+use clap::Parser;
+
+use phone::impairment::{ImpairmentProxy, Impairments};
+
+/// UDP relay adding loss, delay, jitter, duplication, reordering and a
+/// bandwidth limit, the same both ways
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:33500")]
+    listen: SocketAddr,
+    /// Unit to relay to
+    #[arg(long, default_value = "127.0.0.1:33445")]
+    target: SocketAddr,
+    /// Percentage of datagrams dropped
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+    /// Milliseconds added to every datagram
+    #[arg(long, default_value_t = 0)]
+    delay: u64,
+    /// Milliseconds each datagram may come early or late
+    #[arg(long, default_value_t = 0)]
+    jitter: u64,
+    /// Percentage of datagrams delivered twice
+    #[arg(long, default_value_t = 0.0)]
+    duplicate: f64,
+    /// Percentage of datagrams held back so later ones overtake them
+    #[arg(long, default_value_t = 0.0)]
+    reorder: f64,
+    /// Milliseconds reordered datagrams are held back
+    #[arg(long, default_value_t = 30)]
+    reorder_delay: u64,
+    /// Kilobits per second the link carries, unlimited if unset
+    #[arg(long)]
+    bandwidth: Option<u64>,
+    /// Seed of the random choices, to repeat a run
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let impairments = Impairments {
+        loss: cli.loss / 100.0,
+        delay: Duration::from_millis(cli.delay),
+        jitter: Duration::from_millis(cli.jitter),
+        duplicate: cli.duplicate / 100.0,
+        reorder: cli.reorder / 100.0,
+        reorder_delay: Duration::from_millis(cli.reorder_delay),
+        bandwidth: cli.bandwidth.map(|kbps| kbps * 1000),
+    };
+    let seed = cli.seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(1)
+    });
+    let proxy = ImpairmentProxy::start(cli.listen, cli.target, impairments, impairments, seed)?;
+    eprintln!(
+        "Relaying {} to {} with seed {}, press Enter to stop",
+        proxy.address, cli.target, seed
+    );
+
+    std::io::stdin().read_line(&mut String::new())?;
+    let (to_target, from_target) = proxy.stats();
+    eprintln!("To {}: {}", cli.target, to_target);
+    eprintln!("From {}: {}", cli.target, from_target);
+    Ok(())
+}
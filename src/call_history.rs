@@ -0,0 +1,454 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{call_stats::CallQuality, terminal_task::CallEndReason};
+
+/// Oldest calls are forgotten past this many.
+const MAX_RECORDS: usize = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// How a call ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallEnd {
+    /// We hung up, or cancelled the call before it was answered.
+    LocalHangup,
+    /// The peer hung up, or the caller gave up before we answered.
+    RemoteHangup,
+    /// We declined the incoming call.
+    Declined,
+    Failed(CallEndReason),
+}
+
+/// Every [`CallEndReason`], for reading them back from the log.
+const FAILURES: [CallEndReason; 13] = [
+    CallEndReason::IncompatibleFormat,
+    CallEndReason::NoAnswer,
+    CallEndReason::MissedCall,
+    CallEndReason::PeerUnreachable,
+    CallEndReason::ConnectionLost,
+    CallEndReason::LineBusy,
+    CallEndReason::Declined,
+    CallEndReason::EncryptionRequired,
+    CallEndReason::AuthenticationFailed,
+    CallEndReason::NotPaired,
+    CallEndReason::PairingRefused,
+    CallEndReason::PairingTimedOut,
+    CallEndReason::PairingNotSaved,
+];
+
+fn failure_token(reason: CallEndReason) -> &'static str {
+    match reason {
+        CallEndReason::IncompatibleFormat => "incompatible-format",
+        CallEndReason::NoAnswer => "no-answer",
+        CallEndReason::MissedCall => "missed",
+        CallEndReason::PeerUnreachable => "unreachable",
+        CallEndReason::ConnectionLost => "connection-lost",
+        CallEndReason::LineBusy => "busy",
+        CallEndReason::Declined => "peer-declined",
+        CallEndReason::EncryptionRequired => "encryption-required",
+        CallEndReason::AuthenticationFailed => "authentication-failed",
+        CallEndReason::NotPaired => "not-paired",
+        CallEndReason::PairingRefused => "pairing-refused",
+        CallEndReason::PairingTimedOut => "pairing-timed-out",
+        CallEndReason::PairingNotSaved => "pairing-not-saved",
+    }
+}
+
+impl CallEnd {
+    fn token(&self) -> &'static str {
+        match self {
+            CallEnd::LocalHangup => "local-hangup",
+            CallEnd::RemoteHangup => "remote-hangup",
+            CallEnd::Declined => "declined",
+            CallEnd::Failed(reason) => failure_token(*reason),
+        }
+    }
+
+    fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "local-hangup" => Some(CallEnd::LocalHangup),
+            "remote-hangup" => Some(CallEnd::RemoteHangup),
+            "declined" => Some(CallEnd::Declined),
+            _ => FAILURES
+                .iter()
+                .find(|&&reason| failure_token(reason) == token)
+                .map(|&reason| CallEnd::Failed(reason)),
+        }
+    }
+}
+
+impl fmt::Display for CallEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallEnd::LocalHangup | CallEnd::RemoteHangup => write!(f, "Hung up"),
+            CallEnd::Declined => write!(f, "Declined"),
+            CallEnd::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// How an answered call went, kept with its record.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CallSummary {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub jitter: Duration,
+    pub rtt: Option<Duration>,
+    pub underruns: u64,
+}
+
+impl From<CallQuality> for CallSummary {
+    fn from(quality: CallQuality) -> Self {
+        Self {
+            packets_sent: quality.packets_sent,
+            packets_received: quality.packets_received,
+            packets_lost: quality.packets_lost,
+            jitter: Duration::from_micros((quality.jitter_ms * 1000.0) as u64),
+            rtt: quality.rtt,
+            underruns: quality.underruns,
+        }
+    }
+}
+
+impl CallSummary {
+    /// Share of the peer's packets that never arrived.
+    pub fn loss_fraction(&self) -> f32 {
+        let expected = self.packets_received + self.packets_lost;
+        if expected == 0 {
+            0.0
+        } else {
+            self.packets_lost as f32 / expected as f32
+        }
+    }
+
+    /// `key=value` fields appended to the record's line.
+    fn serialize(&self) -> String {
+        let mut fields = format!(
+            "sent={} received={} lost={} jitter={} underruns={}",
+            self.packets_sent,
+            self.packets_received,
+            self.packets_lost,
+            self.jitter.as_micros(),
+            self.underruns
+        );
+        if let Some(rtt) = self.rtt {
+            fields.push_str(&format!(" rtt={}", rtt.as_millis()));
+        }
+        fields
+    }
+
+    fn deserialize(fields: &[&str]) -> Option<Self> {
+        let mut summary = Self {
+            packets_sent: 0,
+            packets_received: 0,
+            packets_lost: 0,
+            jitter: Duration::ZERO,
+            rtt: None,
+            underruns: 0,
+        };
+        for field in fields {
+            let (key, value) = field.split_once('=')?;
+            let value = value.parse::<u64>().ok()?;
+            match key {
+                "sent" => summary.packets_sent = value,
+                "received" => summary.packets_received = value,
+                "lost" => summary.packets_lost = value,
+                "jitter" => summary.jitter = Duration::from_micros(value),
+                "rtt" => summary.rtt = Some(Duration::from_millis(value)),
+                "underruns" => summary.underruns = value,
+                // Written by newer firmware
+                _ => {}
+            }
+        }
+        Some(summary)
+    }
+}
+
+impl fmt::Display for CallSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% lost, jitter {} ms",
+            self.loss_fraction() * 100.0,
+            self.jitter.as_millis()
+        )?;
+        if let Some(rtt) = self.rtt {
+            write!(f, ", round trip {} ms", rtt.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CallRecord {
+    pub direction: CallDirection,
+    pub peer: IpAddr,
+    /// When the call started ringing.
+    pub started: SystemTime,
+    /// How long we talked, zero if the call was never answered.
+    pub duration: Duration,
+    pub end: CallEnd,
+    pub answered: bool,
+    /// The user has seen the call in the history, only matters for missed
+    /// calls.
+    pub seen: bool,
+    /// How the call went, for answered calls.
+    pub summary: Option<CallSummary>,
+}
+
+impl CallRecord {
+    /// An incoming call nobody picked up, as opposed to one we declined.
+    pub fn is_missed(&self) -> bool {
+        self.direction == CallDirection::Incoming && !self.answered && self.end != CallEnd::Declined
+    }
+
+    fn serialize(&self) -> String {
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let summary = self
+            .summary
+            .map(|summary| format!(" {}", summary.serialize()))
+            .unwrap_or_default();
+        format!(
+            "{} {} {} {} {} {} {}{}\n",
+            match self.direction {
+                CallDirection::Incoming => "in",
+                CallDirection::Outgoing => "out",
+            },
+            self.peer,
+            started,
+            self.duration.as_millis(),
+            self.answered as u8,
+            self.seen as u8,
+            self.end.token(),
+            summary,
+        )
+    }
+
+    fn deserialize(line: &str) -> Option<Self> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let [direction, peer, started, duration, answered, seen, end, ref summary @ ..] =
+            fields[..]
+        else {
+            return None;
+        };
+        Some(Self {
+            direction: match direction {
+                "in" => CallDirection::Incoming,
+                "out" => CallDirection::Outgoing,
+                _ => return None,
+            },
+            peer: peer.parse().ok()?,
+            started: UNIX_EPOCH + Duration::from_secs(started.parse().ok()?),
+            duration: Duration::from_millis(duration.parse().ok()?),
+            answered: answered == "1",
+            seen: seen == "1",
+            end: CallEnd::from_token(end)?,
+            // Calls logged before summaries were kept have none
+            summary: match summary {
+                [] => None,
+                fields => Some(CallSummary::deserialize(fields)?),
+            },
+        })
+    }
+}
+
+/// Every call placed or received, oldest first, stored one per line.
+pub struct CallHistory {
+    path: PathBuf,
+    records: Vec<CallRecord>,
+    /// Lines of the file we couldn't read.
+    skipped: usize,
+}
+
+impl CallHistory {
+    /// A missing file just means no calls yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (records, skipped) = crate::storage::read_lines(path, CallRecord::deserialize)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            records,
+            skipped,
+        })
+    }
+
+    /// How many lines of the file [`CallHistory::load`] couldn't read.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn records(&self) -> &[CallRecord] {
+        &self.records
+    }
+
+    pub fn add(&mut self, record: CallRecord) -> io::Result<()> {
+        self.records.push(record);
+        if self.records.len() > MAX_RECORDS {
+            self.records.drain(..self.records.len() - MAX_RECORDS);
+        }
+        self.save()
+    }
+
+    /// Missed calls the user hasn't looked at yet.
+    pub fn unseen_missed(&self) -> usize {
+        self.records
+            .iter()
+            .filter(|record| record.is_missed() && !record.seen)
+            .count()
+    }
+
+    pub fn mark_seen(&mut self) -> io::Result<()> {
+        if self.unseen_missed() == 0 {
+            return Ok(());
+        }
+        for record in &mut self.records {
+            record.seen = true;
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents = self
+            .records
+            .iter()
+            .map(CallRecord::serialize)
+            .collect::<String>();
+        crate::storage::write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_time(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(direction: CallDirection, answered: bool, end: CallEnd) -> CallRecord {
+        CallRecord {
+            direction,
+            peer: "192.168.1.20".parse().unwrap(),
+            started: UNIX_EPOCH + Duration::from_secs(1_760_000_000),
+            duration: Duration::from_millis(if answered { 65_500 } else { 0 }),
+            end,
+            answered,
+            seen: false,
+            summary: answered.then_some(CallSummary {
+                packets_sent: 3270,
+                packets_received: 3251,
+                packets_lost: 24,
+                jitter: Duration::from_micros(4200),
+                rtt: Some(Duration::from_millis(38)),
+                underruns: 2,
+            }),
+        }
+    }
+
+    #[test]
+    fn history_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("call_history");
+        let records = vec![
+            record(CallDirection::Outgoing, true, CallEnd::LocalHangup),
+            record(CallDirection::Incoming, false, CallEnd::RemoteHangup),
+            record(CallDirection::Incoming, false, CallEnd::Declined),
+            record(
+                CallDirection::Incoming,
+                false,
+                CallEnd::Failed(CallEndReason::MissedCall),
+            ),
+            record(
+                CallDirection::Outgoing,
+                false,
+                CallEnd::Failed(CallEndReason::LineBusy),
+            ),
+        ];
+
+        let mut history = CallHistory::load(&path).unwrap();
+        for record in &records {
+            history.add(record.clone()).unwrap();
+        }
+        let mut history = CallHistory::load(&path).unwrap();
+        assert_eq!(history.records(), &records[..]);
+
+        // Declined calls aren't missed
+        assert_eq!(history.unseen_missed(), 2);
+        history.mark_seen().unwrap();
+        let history = CallHistory::load(&path).unwrap();
+        assert_eq!(history.unseen_missed(), 0);
+    }
+
+    #[test]
+    fn summaries_are_optional() {
+        let line = "out 10.0.0.2 1760000000 5000 1 1 local-hangup\n";
+        let record = CallRecord::deserialize(line).unwrap();
+        assert_eq!(record.summary, None);
+        assert_eq!(record.serialize(), line);
+
+        let line = "in 10.0.0.2 1760000000 5000 1 1 remote-hangup sent=10 received=9 lost=1 jitter=1500 underruns=0 mos=4\n";
+        let summary = CallRecord::deserialize(line).unwrap().summary.unwrap();
+        assert_eq!((summary.packets_lost, summary.rtt), (1, None));
+        assert_eq!(summary.to_string(), "10.0% lost, jitter 1 ms");
+        assert_eq!(
+            CallRecord::deserialize(&line.replace("lost=1", "lost")),
+            None
+        );
+    }
+
+    #[test]
+    fn every_failure_has_its_own_token() {
+        for reason in FAILURES {
+            let end = CallEnd::Failed(reason);
+            assert_eq!(CallEnd::from_token(end.token()), Some(end));
+        }
+    }
+
+    #[test]
+    fn times_are_formatted_as_utc() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00");
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        assert_eq!(format_time(time), "2024-02-29 23:59");
+    }
+}
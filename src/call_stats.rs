@@ -0,0 +1,474 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::packet::{ReceiverReport, RoundTripTiming};
+
+/// How many sequence numbers behind the newest one we remember, used to tell
+/// reordered packets apart from duplicates.
+const HISTORY_SIZE: u64 = 64;
+/// Where the extended sequence number of the first packet of a call starts,
+/// so packets from before it that arrive late don't take it below zero.
+const FIRST_CYCLE: u64 = 1 << 32;
+
+/// Tracks the sequence numbers and timestamps of the audio packets received
+/// during a call to count loss, reordering and duplicates and to estimate
+/// interarrival jitter.
+#[derive(Clone)]
+pub struct ReceiveStats {
+    /// Rate of the sender's sample clock, in frames per second.
+    clock_rate: u32,
+    /// Local reference for arrival times.
+    started: Instant,
+    /// Arrival time minus send timestamp of the previous packet, in seconds.
+    last_transit: Option<f64>,
+    /// Interarrival jitter estimate (RFC 3550 section 6.4.1), in seconds.
+    jitter: f64,
+    /// First sequence number seen in this call, extended past 32 bits like
+    /// `highest_sequence`.
+    base_sequence: Option<u64>,
+    /// Highest sequence number seen so far, extended past 32 bits so the
+    /// counts keep working when the sender's sequence number wraps.
+    highest_sequence: u64,
+    /// Bit `n` is set if `highest_sequence - n` has been received.
+    history: u64,
+    /// Unique packets received.
+    pub received: u64,
+    /// Packets that arrived after a packet with a higher sequence number.
+    pub reordered: u64,
+    /// Packets we had already received.
+    pub duplicates: u64,
+    /// Packets that arrived too late to tell whether they were duplicates.
+    pub too_old: u64,
+    /// `expected()` and `received` when the last report was made, to work
+    /// out the loss in between (RFC 3550 appendix A.3).
+    expected_prior: u64,
+    received_prior: u64,
+}
+
+impl ReceiveStats {
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            started: Instant::now(),
+            last_transit: None,
+            jitter: 0.0,
+            base_sequence: None,
+            highest_sequence: 0,
+            history: 0,
+            received: 0,
+            reordered: 0,
+            duplicates: 0,
+            too_old: 0,
+            expected_prior: 0,
+            received_prior: 0,
+        }
+    }
+
+    pub fn record(&mut self, sequence: u32, timestamp: u32, arrival: Instant) {
+        let transit = arrival.duration_since(self.started).as_secs_f64()
+            - timestamp as f64 / self.clock_rate as f64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        if self.base_sequence.is_none() {
+            let sequence = FIRST_CYCLE + sequence as u64;
+            self.base_sequence = Some(sequence);
+            self.highest_sequence = sequence;
+            self.history = 1;
+            self.received = 1;
+            return;
+        }
+
+        let sequence = self.extend(sequence);
+        if sequence > self.highest_sequence {
+            let advance = sequence - self.highest_sequence;
+            self.history = if advance >= HISTORY_SIZE {
+                0
+            } else {
+                self.history << advance
+            };
+            self.history |= 1;
+            self.highest_sequence = sequence;
+            self.received += 1;
+        } else {
+            let behind = self.highest_sequence - sequence;
+            if behind >= HISTORY_SIZE {
+                self.too_old += 1;
+            } else if self.history & (1 << behind) != 0 {
+                self.duplicates += 1;
+            } else {
+                self.history |= 1 << behind;
+                self.received += 1;
+                self.reordered += 1;
+                // The very first packet of the call may have been overtaken
+                if let Some(base) = self.base_sequence.as_mut() {
+                    *base = (*base).min(sequence);
+                }
+            }
+        }
+    }
+
+    /// Places a sequence number relative to the highest one so far: within
+    /// half the range ahead of it is newer, anything else older, whether or
+    /// not the counter wrapped in between.
+    fn extend(&self, sequence: u32) -> u64 {
+        let delta = sequence.wrapping_sub(self.highest_sequence as u32) as i32;
+        self.highest_sequence.wrapping_add_signed(delta as i64)
+    }
+
+    /// Packets the sender has sent so far, judging by the sequence numbers.
+    pub fn expected(&self) -> u64 {
+        match self.base_sequence {
+            Some(base) => self.highest_sequence - base + 1,
+            None => 0,
+        }
+    }
+
+    /// Packets that never arrived (or arrived too late to be counted).
+    pub fn lost(&self) -> u64 {
+        self.expected().saturating_sub(self.received)
+    }
+
+    /// Interarrival jitter in milliseconds.
+    pub fn jitter_ms(&self) -> f32 {
+        (self.jitter * 1000.0) as f32
+    }
+
+    pub fn loss_fraction(&self) -> f32 {
+        let expected = self.expected();
+        if expected == 0 {
+            0.0
+        } else {
+            self.lost() as f32 / expected as f32
+        }
+    }
+
+    /// Sums up the audio received for the peer. Loss is counted from the
+    /// previous report on.
+    pub fn report(&mut self, timing: RoundTripTiming) -> ReceiverReport {
+        let expected = self.expected();
+        let expected_interval = expected - self.expected_prior;
+        let received_interval = self.received - self.received_prior;
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = (lost_interval << 8)
+            .checked_div(expected_interval)
+            .map_or(0, |fraction| fraction.min(255) as u8);
+        ReceiverReport {
+            fraction_lost,
+            cumulative_lost: self.lost().min(u32::MAX as u64) as u32,
+            highest_sequence: self.highest_sequence as u32,
+            jitter_us: (self.jitter * 1_000_000.0) as u32,
+            timing,
+        }
+    }
+}
+
+/// Measures the round trip time from the timestamps heartbeats and receiver
+/// reports carry.
+pub struct RoundTrip {
+    /// Reference for the millisecond clock we put in heartbeats.
+    started: Instant,
+    /// Clock of the last timed packet from the peer and when it arrived.
+    last_heard: Option<(u32, Instant)>,
+    /// The latest measurement.
+    pub rtt: Option<Duration>,
+}
+
+impl Default for RoundTrip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RoundTrip {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_heard: None,
+            rtt: None,
+        }
+    }
+
+    fn clock(&self, now: Instant) -> u32 {
+        now.duration_since(self.started).as_millis() as u32
+    }
+
+    /// What to put in the next heartbeat or report we send.
+    pub fn timing(&self, now: Instant) -> RoundTripTiming {
+        RoundTripTiming {
+            sent: self.clock(now),
+            echo: self
+                .last_heard
+                .map(|(sent, arrival)| (sent, now.duration_since(arrival).as_millis() as u32)),
+        }
+    }
+
+    /// Takes the timing of a heartbeat or report from the peer. Returns the
+    /// round trip time if it echoes one of ours.
+    pub fn heard(&mut self, timing: &RoundTripTiming, now: Instant) -> Option<Duration> {
+        self.last_heard = Some((timing.sent, now));
+        let (sent, held) = timing.echo?;
+        // The clock wraps after 49 days, differences still work out
+        let rtt = self.clock(now).wrapping_sub(sent).checked_sub(held)?;
+        // An echo of a time we haven't reached yet isn't one of ours
+        if rtt > u32::MAX / 2 {
+            return None;
+        }
+        self.rtt = Some(Duration::from_millis(rtt as u64));
+        self.rtt
+    }
+}
+
+/// How the call in progress is going, as far as each task can tell. Every
+/// task fills in its own part and clears it when a call starts.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CallQuality {
+    /// Audio packets, filled in by the network task.
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub loss_fraction: f32,
+    /// Packets that arrived out of order, twice, or too late to tell.
+    pub packets_reordered: u64,
+    pub packets_duplicated: u64,
+    pub packets_too_old: u64,
+    /// Interarrival jitter in milliseconds.
+    pub jitter_ms: f32,
+    pub rtt: Option<Duration>,
+    /// Datagrams the network task threw away: not our protocol, left over
+    /// from an earlier call, or (ours) too large to send.
+    pub packets_malformed: u64,
+    pub packets_stale: u64,
+    pub packets_unsendable: u64,
+    /// Security events: packets for the call from hosts other than the peer,
+    /// and packets that failed decryption or authentication.
+    pub packets_foreign: u64,
+    pub packets_rejected: u64,
+    /// How our audio reaches the peer, from its latest receiver report.
+    pub far_end: Option<ReceiverReport>,
+    /// Frames waiting in the jitter buffer and how many it aims for, filled
+    /// in by the output audio task.
+    pub buffered_frames: usize,
+    pub target_frames: usize,
+    /// Times the jitter buffer ran dry.
+    pub underruns: u64,
+    pub concealed_frames: u64,
+    /// Times the playback device ran dry.
+    pub playback_xruns: u64,
+    /// Times the capture device overran. Filled in by the input audio
+    /// task.
+    pub capture_xruns: u64,
+}
+
+impl CallQuality {
+    pub fn clear_network(&mut self) {
+        *self = Self {
+            packets_sent: 0,
+            packets_received: 0,
+            packets_lost: 0,
+            loss_fraction: 0.0,
+            packets_reordered: 0,
+            packets_duplicated: 0,
+            packets_too_old: 0,
+            jitter_ms: 0.0,
+            rtt: None,
+            packets_malformed: 0,
+            packets_stale: 0,
+            packets_unsendable: 0,
+            packets_foreign: 0,
+            packets_rejected: 0,
+            far_end: None,
+            ..*self
+        };
+    }
+
+    pub fn clear_playout(&mut self) {
+        *self = Self {
+            buffered_frames: 0,
+            target_frames: 0,
+            underruns: 0,
+            concealed_frames: 0,
+            playback_xruns: 0,
+            ..*self
+        };
+    }
+
+    pub fn clear_capture(&mut self) {
+        self.capture_xruns = 0;
+    }
+
+    /// Takes what the receive side of the network task has counted.
+    pub fn set_received(&mut self, stats: &ReceiveStats) {
+        self.packets_received = stats.received;
+        self.packets_lost = stats.lost();
+        self.loss_fraction = stats.loss_fraction();
+        self.packets_reordered = stats.reordered;
+        self.packets_duplicated = stats.duplicates;
+        self.packets_too_old = stats.too_old;
+        self.jitter_ms = stats.jitter_ms();
+    }
+}
+
+impl fmt::Display for CallQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {}, received {} ({:.1}% lost), jitter {:.1} ms, ",
+            self.packets_sent,
+            self.packets_received,
+            self.loss_fraction * 100.0,
+            self.jitter_ms
+        )?;
+        match self.rtt {
+            Some(rtt) => write!(f, "round trip {} ms, ", rtt.as_millis())?,
+            None => write!(f, "round trip unknown, ")?,
+        }
+        if let Some(far_end) = self.far_end {
+            write!(
+                f,
+                "peer lost {} ({:.1}% lately), peer jitter {:.1} ms, ",
+                far_end.cumulative_lost,
+                far_end.loss_fraction() * 100.0,
+                far_end.jitter_ms()
+            )?;
+        }
+        write!(
+            f,
+            "{} underruns, {} concealed, {} playback xruns, {} capture xruns, {} security events",
+            self.underruns,
+            self.concealed_frames,
+            self.playback_xruns,
+            self.capture_xruns,
+            self.packets_foreign + self.packets_rejected
+        )
+    }
+}
+
+/// The [`CallQuality`] of the current call, shared by the tasks measuring
+/// it and the UI showing it.
+#[derive(Clone, Default)]
+pub struct QualityMonitor(Arc<Mutex<CallQuality>>);
+
+impl QualityMonitor {
+    pub fn update(&self, update: impl FnOnce(&mut CallQuality)) {
+        update(&mut self.0.lock().unwrap());
+    }
+
+    pub fn get(&self) -> CallQuality {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(stats: &mut ReceiveStats, sequences: &[u32]) {
+        let now = Instant::now();
+        for &sequence in sequences {
+            stats.record(sequence, sequence.wrapping_mul(160), now);
+        }
+    }
+
+    #[test]
+    fn counting_carries_on_across_the_sequence_wrap() {
+        let mut stats = ReceiveStats::new(8000);
+        // u32::MAX - 1 is lost, 0 comes before u32::MAX and 1 arrives twice
+        receive(
+            &mut stats,
+            &[u32::MAX - 3, u32::MAX - 2, 0, u32::MAX, 1, 1, 2],
+        );
+        assert_eq!(stats.expected(), 7);
+        assert_eq!(stats.received, 6);
+        assert_eq!(stats.lost(), 1);
+        assert_eq!((stats.reordered, stats.duplicates), (1, 1));
+    }
+
+    fn timing() -> RoundTripTiming {
+        RoundTripTiming {
+            sent: 0,
+            echo: None,
+        }
+    }
+
+    #[test]
+    fn reports_count_loss_since_the_previous_one() {
+        let mut stats = ReceiveStats::new(8000);
+        // Nothing received yet, nothing expected
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (0, 0));
+
+        // 2 of 10 lost, 51 in 256ths
+        receive(&mut stats, &[0, 1, 2, 4, 5, 6, 8, 9]);
+        let report = stats.report(timing());
+        assert_eq!(report.fraction_lost, 51);
+        assert_eq!(report.cumulative_lost, 2);
+        assert_eq!(report.highest_sequence, 9);
+
+        // None of the next 10 lost, the earlier ones still count
+        receive(&mut stats, &(10..20).collect::<Vec<_>>());
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (0, 2));
+
+        // Nothing new since the last report
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (0, 2));
+
+        // 4 of the next 5 lost
+        receive(&mut stats, &[24]);
+        let report = stats.report(timing());
+        assert_eq!(report.fraction_lost, 204);
+        assert_eq!(report.cumulative_lost, 6);
+
+        // Every packet lost is 255, not 256 wrapping to 0
+        receive(&mut stats, &[1000]);
+        assert_eq!(stats.report(timing()).fraction_lost, 255);
+    }
+
+    #[test]
+    fn late_packets_make_up_for_loss_already_reported() {
+        let mut stats = ReceiveStats::new(8000);
+        receive(&mut stats, &[0, 1, 3]);
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (64, 1));
+
+        // 2 turns up after all, with nothing new to expect
+        receive(&mut stats, &[2]);
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (0, 0));
+    }
+
+    #[test]
+    fn reports_carry_on_across_the_sequence_wrap() {
+        let mut stats = ReceiveStats::new(8000);
+        receive(
+            &mut stats,
+            &[u32::MAX - 4, u32::MAX - 3, u32::MAX - 1, u32::MAX],
+        );
+        let report = stats.report(timing());
+        assert_eq!((report.fraction_lost, report.cumulative_lost), (51, 1));
+        assert_eq!(report.highest_sequence, u32::MAX);
+
+        receive(&mut stats, &[0, 2]);
+        let report = stats.report(timing());
+        assert_eq!(report.fraction_lost, 85);
+        assert_eq!(report.cumulative_lost, 2);
+        assert_eq!(report.highest_sequence, 2);
+    }
+
+    #[test]
+    fn a_late_first_packet_counts_from_before_the_wrap() {
+        let mut stats = ReceiveStats::new(8000);
+        receive(&mut stats, &[0, 1, u32::MAX]);
+        assert_eq!((stats.expected(), stats.received, stats.lost()), (3, 3, 0));
+        assert_eq!(stats.reordered, 1);
+    }
+}
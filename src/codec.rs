@@ -0,0 +1,449 @@
+use crate::{packet::DecodeError, samples};
+
+/// Identifies the encoding of the samples in an audio packet.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CodecId {
+    /// Uncompressed signed 16 bit little endian.
+    Pcm16 = 0,
+    /// G.711 µ-law, 8 bits per sample.
+    Pcmu,
+    /// G.711 A-law, 8 bits per sample.
+    Pcma,
+    /// IMA ADPCM, 4 bits per sample.
+    ImaAdpcm,
+}
+
+impl TryFrom<u8> for CodecId {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CodecId::Pcm16),
+            1 => Ok(CodecId::Pcmu),
+            2 => Ok(CodecId::Pcma),
+            3 => Ok(CodecId::ImaAdpcm),
+            other => Err(DecodeError::UnknownCodec(other)),
+        }
+    }
+}
+
+/// Turns interleaved 16 bit samples into the bytes of an audio packet and
+/// back. Every packet can be decoded on its own, so a lost packet never
+/// corrupts the ones after it.
+pub trait AudioCodec: Send {
+    fn id(&self) -> CodecId;
+    fn encode(&mut self, samples: &[i16]) -> Vec<u8>;
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>, DecodeError>;
+}
+
+pub fn new_codec(id: CodecId, channels: usize) -> Box<dyn AudioCodec> {
+    match id {
+        CodecId::Pcm16 => Box::new(Pcm16),
+        CodecId::Pcmu => Box::new(G711::MuLaw),
+        CodecId::Pcma => Box::new(G711::ALaw),
+        CodecId::ImaAdpcm => Box::new(ImaAdpcm::new(channels)),
+    }
+}
+
+pub struct Pcm16;
+
+impl AudioCodec for Pcm16 {
+    fn id(&self) -> CodecId {
+        CodecId::Pcm16
+    }
+
+    fn encode(&mut self, samples: &[i16]) -> Vec<u8> {
+        samples::encode_le(samples)
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>, DecodeError> {
+        samples::decode_le(data)
+    }
+}
+
+/// G.711 companding, ported from the Sun reference implementation.
+pub enum G711 {
+    MuLaw,
+    ALaw,
+}
+
+const ULAW_SEGMENT_ENDS: [i32; 8] = [0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF, 0x1FFF];
+const ALAW_SEGMENT_ENDS: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+const ULAW_BIAS: i32 = 0x84;
+const ULAW_CLIP: i32 = 8159;
+
+fn segment(value: i32, ends: &[i32; 8]) -> usize {
+    ends.iter().position(|&end| value <= end).unwrap_or(8)
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut value = sample as i32 >> 2;
+    let mask: i32 = if value < 0 {
+        value = -value;
+        0x7F
+    } else {
+        0xFF
+    };
+    value = value.min(ULAW_CLIP) + (ULAW_BIAS >> 2);
+    let segment = segment(value, &ULAW_SEGMENT_ENDS);
+    if segment >= 8 {
+        return (0x7F ^ mask) as u8;
+    }
+    let ulaw = ((segment as i32) << 4) | ((value >> (segment + 1)) & 0x0F);
+    (ulaw ^ mask) as u8
+}
+
+fn ulaw_to_linear(ulaw: u8) -> i16 {
+    let ulaw = !ulaw as i32;
+    let mut value = ((ulaw & 0x0F) << 3) + ULAW_BIAS;
+    value <<= (ulaw & 0x70) >> 4;
+    if ulaw & 0x80 != 0 {
+        (ULAW_BIAS - value) as i16
+    } else {
+        (value - ULAW_BIAS) as i16
+    }
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let mut value = sample as i32 >> 3;
+    let mask: i32 = if value >= 0 {
+        0xD5
+    } else {
+        value = -value - 1;
+        0x55
+    };
+    let segment = segment(value, &ALAW_SEGMENT_ENDS);
+    if segment >= 8 {
+        return (0x7F ^ mask) as u8;
+    }
+    let mut alaw = (segment as i32) << 4;
+    if segment < 2 {
+        alaw |= (value >> 1) & 0x0F;
+    } else {
+        alaw |= (value >> segment) & 0x0F;
+    }
+    (alaw ^ mask) as u8
+}
+
+fn alaw_to_linear(alaw: u8) -> i16 {
+    let alaw = (alaw ^ 0x55) as i32;
+    let mut value = (alaw & 0x0F) << 4;
+    let segment = (alaw & 0x70) >> 4;
+    match segment {
+        0 => value += 8,
+        1 => value += 0x108,
+        _ => {
+            value += 0x108;
+            value <<= segment - 1;
+        }
+    }
+    if alaw & 0x80 != 0 {
+        value as i16
+    } else {
+        -value as i16
+    }
+}
+
+impl AudioCodec for G711 {
+    fn id(&self) -> CodecId {
+        match self {
+            G711::MuLaw => CodecId::Pcmu,
+            G711::ALaw => CodecId::Pcma,
+        }
+    }
+
+    fn encode(&mut self, samples: &[i16]) -> Vec<u8> {
+        match self {
+            G711::MuLaw => samples.iter().map(|&s| linear_to_ulaw(s)).collect(),
+            G711::ALaw => samples.iter().map(|&s| linear_to_alaw(s)).collect(),
+        }
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>, DecodeError> {
+        Ok(match self {
+            G711::MuLaw => data.iter().map(|&b| ulaw_to_linear(b)).collect(),
+            G711::ALaw => data.iter().map(|&b| alaw_to_linear(b)).collect(),
+        })
+    }
+}
+
+const IMA_INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+const IMA_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+/// predictor (2) + step index (1) + padding (1) for every channel. The
+/// padding byte of the first channel is 1 when the last nibble of the packet
+/// is padding rather than a sample, the others are reserved.
+const IMA_CHANNEL_HEADER_SIZE: usize = 4;
+
+#[derive(Default, Clone, Copy)]
+struct ImaState {
+    predictor: i32,
+    index: usize,
+}
+
+impl ImaState {
+    fn encode(&mut self, sample: i16) -> u8 {
+        let step = IMA_STEP_TABLE[self.index];
+        let mut diff = sample as i32 - self.predictor;
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        let mut bit_step = step;
+        for bit in [4, 2, 1] {
+            if diff >= bit_step {
+                nibble |= bit;
+                diff -= bit_step;
+            }
+            bit_step >>= 1;
+        }
+        // Update the predictor exactly like the decoder will
+        self.decode(nibble);
+        nibble
+    }
+
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = IMA_STEP_TABLE[self.index];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            self.predictor -= delta;
+        } else {
+            self.predictor += delta;
+        }
+        self.predictor = self.predictor.clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index = (self.index as i32 + IMA_INDEX_TABLE[nibble as usize]).clamp(0, 88) as usize;
+        self.predictor as i16
+    }
+}
+
+/// IMA ADPCM. Every packet starts with the predictor state of each channel,
+/// followed by one nibble per sample, low nibble first. An odd number of
+/// samples leaves a padding nibble in the high half of the last byte.
+pub struct ImaAdpcm {
+    channels: usize,
+    state: Vec<ImaState>,
+}
+
+impl ImaAdpcm {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            state: vec![ImaState::default(); channels],
+        }
+    }
+}
+
+impl AudioCodec for ImaAdpcm {
+    fn id(&self) -> CodecId {
+        CodecId::ImaAdpcm
+    }
+
+    fn encode(&mut self, samples: &[i16]) -> Vec<u8> {
+        let mut data =
+            Vec::with_capacity(self.channels * IMA_CHANNEL_HEADER_SIZE + samples.len().div_ceil(2));
+        for (channel, state) in self.state.iter().enumerate() {
+            data.extend_from_slice(&(state.predictor as i16).to_le_bytes());
+            data.push(state.index as u8);
+            data.push((channel == 0 && samples.len() % 2 == 1) as u8);
+        }
+        for pair in samples.chunks(2) {
+            let mut byte = 0;
+            for (i, &sample) in pair.iter().enumerate() {
+                // Both samples of a pair can belong to the same channel when
+                // there is only one
+                let position = (data.len() - self.channels * IMA_CHANNEL_HEADER_SIZE) * 2 + i;
+                let channel = position % self.channels;
+                byte |= self.state[channel].encode(sample) << (4 * i);
+            }
+            data.push(byte);
+        }
+        data
+    }
+
+    /// Decodes as many whole frames as the nibbles hold, leaving out the
+    /// padding nibble.
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>, DecodeError> {
+        let header_size = self.channels * IMA_CHANNEL_HEADER_SIZE;
+        if data.len() < header_size {
+            return Err(DecodeError::PayloadTooShort {
+                expected: header_size,
+                actual: data.len(),
+            });
+        }
+        let mut state = data[..header_size]
+            .chunks_exact(IMA_CHANNEL_HEADER_SIZE)
+            .map(|header| ImaState {
+                predictor: i16::from_le_bytes([header[0], header[1]]) as i32,
+                index: (header[2] as usize).min(88),
+            })
+            .collect::<Vec<_>>();
+
+        let padding = (data.len() > header_size && data[3] == 1) as usize;
+        let nibbles = (data.len() - header_size) * 2 - padding;
+        let count = nibbles - nibbles % self.channels;
+        let samples = (0..count)
+            .map(|position| {
+                let nibble = (data[header_size + position / 2] >> (4 * (position % 2))) & 0x0F;
+                state[position % self.channels].decode(nibble)
+            })
+            .collect();
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames: usize, channels: usize) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|i| {
+                let value = (i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 48000.0).sin();
+                vec![(value * 12000.0) as i16; channels]
+            })
+            .collect()
+    }
+
+    /// Signal to noise ratio of `decoded` against `original`, in dB.
+    fn snr(original: &[i16], decoded: &[i16]) -> f64 {
+        let signal: f64 = original.iter().map(|&s| (s as f64).powi(2)).sum();
+        let noise: f64 = original
+            .iter()
+            .zip(decoded)
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum();
+        10.0 * (signal / noise).log10()
+    }
+
+    #[test]
+    fn pcm16_round_trip_is_lossless() {
+        let samples = sine(400, 2);
+        let mut codec = new_codec(CodecId::Pcm16, 2);
+        let data = codec.encode(&samples);
+        assert_eq!(data.len(), samples.len() * 2);
+        assert_eq!(codec.decode(&data).unwrap(), samples);
+    }
+
+    #[test]
+    fn g711_matches_reference_values() {
+        assert_eq!(linear_to_ulaw(0), 0xFF);
+        assert_eq!(linear_to_ulaw(i16::MAX), 0x80);
+        assert_eq!(linear_to_ulaw(i16::MIN), 0x00);
+        assert_eq!(linear_to_alaw(0), 0xD5);
+        assert_eq!(linear_to_alaw(i16::MAX), 0xAA);
+        assert_eq!(linear_to_alaw(i16::MIN), 0x2A);
+        assert_eq!(ulaw_to_linear(0xFF), 0);
+        assert_eq!(alaw_to_linear(0xD5), 8);
+    }
+
+    #[test]
+    fn g711_codes_are_stable_after_one_round_trip() {
+        for code in 0..=255u8 {
+            // µ-law has a negative zero that comes back as positive zero
+            if code != 0x7F {
+                assert_eq!(
+                    linear_to_ulaw(ulaw_to_linear(code)),
+                    code,
+                    "µ-law {:#x}",
+                    code
+                );
+            }
+            assert_eq!(
+                linear_to_alaw(alaw_to_linear(code)),
+                code,
+                "A-law {:#x}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn g711_round_trip() {
+        let samples = sine(400, 2);
+        for id in [CodecId::Pcmu, CodecId::Pcma] {
+            let mut codec = new_codec(id, 2);
+            let data = codec.encode(&samples);
+            assert_eq!(data.len(), samples.len());
+            let decoded = codec.decode(&data).unwrap();
+            assert_eq!(decoded.len(), samples.len());
+            assert!(snr(&samples, &decoded) > 30.0, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn ima_adpcm_round_trip() {
+        for channels in [1, 2] {
+            let samples = sine(4000, channels);
+            let mut encoder = new_codec(CodecId::ImaAdpcm, channels);
+            let mut decoder = new_codec(CodecId::ImaAdpcm, channels);
+            let mut decoded = Vec::new();
+            for chunk in samples.chunks(400 * channels) {
+                let data = encoder.encode(chunk);
+                assert_eq!(data.len(), 4 * channels + chunk.len() / 2);
+                decoded.extend(decoder.decode(&data).unwrap());
+            }
+            assert_eq!(decoded.len(), samples.len());
+            // Skip the first packet while the step size adapts
+            assert!(snr(&samples[400..], &decoded[400..]) > 20.0);
+        }
+    }
+
+    #[test]
+    fn ima_adpcm_packets_decode_independently() {
+        let samples = sine(1200, 2);
+        let mut encoder = ImaAdpcm::new(2);
+        let packets = samples
+            .chunks(800)
+            .map(|chunk| encoder.encode(chunk))
+            .collect::<Vec<_>>();
+
+        // Decoding the last packet alone gives the same result as decoding
+        // it after the others
+        let mut in_order = ImaAdpcm::new(2);
+        let mut last = Vec::new();
+        for packet in &packets {
+            last = in_order.decode(packet).unwrap();
+        }
+        let alone = ImaAdpcm::new(2).decode(packets.last().unwrap()).unwrap();
+        assert_eq!(alone, last);
+    }
+
+    #[test]
+    fn ima_adpcm_leaves_out_the_padding_nibble() {
+        let samples = sine(401, 1);
+        let mut encoder = ImaAdpcm::new(1);
+        let data = encoder.encode(&samples);
+        assert_eq!(data.len(), 4 + 201);
+        let decoded = ImaAdpcm::new(1).decode(&data).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        assert_eq!(ImaAdpcm::new(1).decode(&encoder.encode(&[])).unwrap(), []);
+    }
+
+    #[test]
+    fn ima_adpcm_rejects_truncated_header() {
+        let mut codec = ImaAdpcm::new(2);
+        assert_eq!(
+            codec.decode(&[0; 5]),
+            Err(DecodeError::PayloadTooShort {
+                expected: 8,
+                actual: 5
+            })
+        );
+    }
+}
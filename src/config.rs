@@ -0,0 +1,665 @@
+use std::{
+    fmt, fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use clap::{builder::BoolishValueParser, Parser};
+use serde::Deserialize;
+
+use crate::{
+    discovery::MAX_NAME_LEN,
+    hardware::AudioBackend,
+    network_thread::DEFAULT_PORT,
+    voice_processing::{MAX_GAIN_RANGE_DB, SUPPRESSION_RANGE_DB, TARGET_RANGE_DBFS},
+};
+
+/// Command line options. Each one overrides the matching setting of the
+/// configuration file.
+#[derive(Parser, Debug, Default)]
+#[command(about = "Intercom phone for the i.MX board and Linux desktops")]
+pub struct Cli {
+    /// Configuration file, instead of ~/.config/phone/config.toml or
+    /// /etc/phone/config.toml
+    #[arg(long, env = "PHONE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Audio devices to use, null to run without a sound card
+    #[arg(long, value_enum)]
+    pub audio_backend: Option<AudioBackend>,
+    /// WAV file the wav backend records from
+    #[arg(long)]
+    pub capture_file: Option<PathBuf>,
+    /// WAV file the wav backend plays to
+    #[arg(long)]
+    pub playback_file: Option<PathBuf>,
+    /// Run without the terminal UI, following the commands in this file.
+    /// Without --data-dir, keeps its identity in script-<port> of the
+    /// usual data directory
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+    /// ALSA device to record from
+    #[arg(long)]
+    pub input_device: Option<String>,
+    /// ALSA device to play to
+    #[arg(long)]
+    pub output_device: Option<String>,
+    /// ALSA mixer the volume is set on
+    #[arg(long)]
+    pub mixer_device: Option<String>,
+    /// Element of the mixer the volume is set on
+    #[arg(long)]
+    pub mixer_element: Option<String>,
+    /// UDP port to take calls on
+    #[arg(long, env = "PHONE_PORT")]
+    pub port: Option<u16>,
+    /// evdev device with the hardware keys, empty to go without
+    #[arg(long, value_parser = parse_path)]
+    pub keys_device: Option<PathBuf>,
+    /// Brightness files of one RGB LED, repeat for each LED
+    #[arg(long, value_name = "RED,GREEN,BLUE", value_parser = parse_led)]
+    pub led: Vec<LedPaths>,
+    /// Run without LEDs
+    #[arg(long, conflicts_with = "led")]
+    pub no_leds: bool,
+    /// Name other units list us as
+    #[arg(long, env = "PHONE_NAME")]
+    pub name: Option<String>,
+    /// Address of the interface to announce ourselves on
+    #[arg(long, env = "PHONE_DISCOVERY_INTERFACE")]
+    pub discovery_interface: Option<Ipv4Addr>,
+    /// Where identity, contacts and call history are kept
+    #[arg(long, env = "PHONE_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Passphrase shared by all units
+    #[arg(long, env = "PHONE_PSK", hide_env_values = true)]
+    pub psk: Option<String>,
+    /// Take and place calls without encryption, =false to forbid it
+    #[arg(
+        long,
+        env = "PHONE_ALLOW_UNENCRYPTED",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    pub allow_unencrypted: Option<bool>,
+    /// Refuse calls to and from units we haven't paired with, =false to
+    /// take them
+    #[arg(
+        long,
+        env = "PHONE_REFUSE_UNPAIRED",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    pub refuse_unpaired: Option<bool>,
+    /// Send the microphone audio with the speaker's echo left in
+    #[arg(long)]
+    pub no_echo_cancellation: bool,
+    /// Send the microphone audio with the background noise left in
+    #[arg(long)]
+    pub no_noise_suppression: bool,
+    /// Send the microphone audio at the level it was picked up
+    #[arg(long)]
+    pub no_gain_control: bool,
+}
+
+/// Echo tails we accept. Rooms rarely echo for longer than the maximum, and
+/// the board couldn't afford cancelling it.
+const MIN_ECHO_TAIL_MS: u32 = 8;
+const MAX_ECHO_TAIL_MS: u32 = 500;
+
+/// Unlike clap's own, takes an empty path.
+fn parse_path(value: &str) -> Result<PathBuf, String> {
+    Ok(value.into())
+}
+
+fn parse_led(value: &str) -> Result<LedPaths, String> {
+    match value.split(',').collect::<Vec<_>>()[..] {
+        [red, green, blue] => Ok(LedPaths {
+            red: red.into(),
+            green: green.into(),
+            blue: blue.into(),
+        }),
+        _ => Err("expected three comma separated paths".to_string()),
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name other units list us as, the host name if unset.
+    pub name: Option<String>,
+    /// Where identity, contacts and call history are kept,
+    /// `~/.local/share/phone` if unset.
+    pub data_dir: Option<PathBuf>,
+    pub audio: AudioConfig,
+    pub processing: ProcessingConfig,
+    pub network: NetworkConfig,
+    pub security: SecurityConfig,
+    pub keys: KeysConfig,
+    /// The three RGB LEDs, or none.
+    pub leds: Vec<LedPaths>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let led = |index: usize| LedPaths {
+            red: format!("/sys/class/leds/pca995x:red{}/brightness", index).into(),
+            green: format!("/sys/class/leds/pca995x:green{}/brightness", index).into(),
+            blue: format!("/sys/class/leds/pca995x:blue{}/brightness", index).into(),
+        };
+        Self {
+            name: None,
+            data_dir: None,
+            audio: AudioConfig::default(),
+            processing: ProcessingConfig::default(),
+            network: NetworkConfig::default(),
+            security: SecurityConfig::default(),
+            keys: KeysConfig::default(),
+            leds: (0..3).map(led).collect(),
+        }
+    }
+}
+
+/// ALSA devices. The defaults are the i.MX board's, a desktop usually wants
+/// `default` for the devices and `Master` for the mixer element.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// The devices below are only opened with the ALSA backend.
+    pub backend: AudioBackend,
+    pub input_device: String,
+    pub output_device: String,
+    pub mixer_device: String,
+    pub mixer_element: String,
+    /// What the wav backend records, silence if unset.
+    pub capture_file: Option<PathBuf>,
+    /// Where the wav backend plays to, nowhere if unset.
+    pub playback_file: Option<PathBuf>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            backend: AudioBackend::Alsa,
+            input_device: "plughw:0".to_string(),
+            output_device: "voldevice".to_string(),
+            mixer_device: "hw:1".to_string(),
+            mixer_element: "Softmaster".to_string(),
+            capture_file: None,
+            playback_file: None,
+        }
+    }
+}
+
+/// What is done to the microphone audio before it is sent.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingConfig {
+    /// Take what the speaker played back out of the microphone audio.
+    pub echo_cancellation: bool,
+    /// Longest echo cancelled, in milliseconds. Longer ones cost more CPU.
+    pub echo_tail_ms: u32,
+    /// Turn down steady background noise, such as fans.
+    pub noise_suppression: bool,
+    /// Most the noise is turned down, in dB.
+    pub noise_suppression_db: u32,
+    /// Bring speech to a steady level.
+    pub gain_control: bool,
+    /// Level speech is brought to, in dB relative to full scale.
+    pub gain_target_dbfs: i32,
+    /// Most quiet speech is amplified, in dB.
+    pub max_gain_db: u32,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            echo_cancellation: true,
+            echo_tail_ms: 128,
+            noise_suppression: true,
+            noise_suppression_db: 15,
+            gain_control: true,
+            gain_target_dbfs: -18,
+            max_gain_db: 20,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// UDP port we take calls on, and call other units on unless they
+    /// announce another one.
+    pub port: u16,
+    /// Address of the interface discovery announces us on, unspecified to
+    /// let the routing table pick.
+    pub discovery_interface: Ipv4Addr,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            discovery_interface: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub psk: Option<String>,
+    pub allow_unencrypted: bool,
+    pub refuse_unpaired: bool,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// evdev device with the hardware keys, empty to go without.
+    pub device: PathBuf,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            device: "/dev/input/event0".into(),
+        }
+    }
+}
+
+/// Brightness files of one RGB LED.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LedPaths {
+    pub red: PathBuf,
+    pub green: PathBuf,
+    pub blue: PathBuf,
+}
+
+/// Why we couldn't start with the configuration we were given.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A setting has a value we can't use.
+    Invalid {
+        setting: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse(path, error) => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Invalid { setting, reason } => write!(f, "{}: {}", setting, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(setting: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        setting,
+        reason: reason.into(),
+    }
+}
+
+/// Where we look for the configuration when not told.
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".config/phone/config.toml"));
+    }
+    paths.push("/etc/phone/config.toml".into());
+    paths
+}
+
+impl Config {
+    /// Reads the configuration file, applies the command line on top and
+    /// checks the result.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let path = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None => default_paths().into_iter().find(|path| path.exists()),
+        };
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|error| ConfigError::Read(path.clone(), error))?;
+                Self::parse(&text).map_err(|error| ConfigError::Parse(path, error))?
+            }
+            None => Self::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn apply(&mut self, cli: &Cli) {
+        fn set<T: Clone>(setting: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *setting = value.clone();
+            }
+        }
+        set(&mut self.audio.backend, &cli.audio_backend);
+        set(&mut self.audio.input_device, &cli.input_device);
+        set(&mut self.audio.output_device, &cli.output_device);
+        set(&mut self.audio.mixer_device, &cli.mixer_device);
+        set(&mut self.audio.mixer_element, &cli.mixer_element);
+        if cli.capture_file.is_some() {
+            self.audio.capture_file = cli.capture_file.clone();
+        }
+        if cli.playback_file.is_some() {
+            self.audio.playback_file = cli.playback_file.clone();
+        }
+        self.processing.echo_cancellation &= !cli.no_echo_cancellation;
+        self.processing.noise_suppression &= !cli.no_noise_suppression;
+        self.processing.gain_control &= !cli.no_gain_control;
+        set(&mut self.network.port, &cli.port);
+        set(
+            &mut self.network.discovery_interface,
+            &cli.discovery_interface,
+        );
+        set(&mut self.keys.device, &cli.keys_device);
+        if cli.no_leds {
+            self.leds.clear();
+        } else if !cli.led.is_empty() {
+            self.leds = cli.led.clone();
+        }
+        if cli.name.is_some() {
+            self.name = cli.name.clone();
+        }
+        if cli.data_dir.is_some() {
+            self.data_dir = cli.data_dir.clone();
+        }
+        if cli.psk.is_some() {
+            self.security.psk = cli.psk.clone();
+        }
+        set(&mut self.security.allow_unencrypted, &cli.allow_unencrypted);
+        set(&mut self.security.refuse_unpaired, &cli.refuse_unpaired);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(name) = &self.name {
+            if name.trim().is_empty() {
+                return Err(invalid("name", "must not be empty"));
+            }
+            if name.len() > MAX_NAME_LEN {
+                return Err(invalid(
+                    "name",
+                    format!("longer than {} bytes", MAX_NAME_LEN),
+                ));
+            }
+        }
+        let devices = [
+            ("audio.input_device", &self.audio.input_device),
+            ("audio.output_device", &self.audio.output_device),
+            ("audio.mixer_device", &self.audio.mixer_device),
+            ("audio.mixer_element", &self.audio.mixer_element),
+        ];
+        for (setting, value) in devices {
+            if self.audio.backend == AudioBackend::Alsa && value.trim().is_empty() {
+                return Err(invalid(setting, "must not be empty"));
+            }
+        }
+        if let Some(path) = &self.audio.capture_file {
+            if self.audio.backend == AudioBackend::Wav && !path.exists() {
+                return Err(invalid(
+                    "audio.capture_file",
+                    format!("{} doesn't exist", path.display()),
+                ));
+            }
+        }
+        if !(MIN_ECHO_TAIL_MS..=MAX_ECHO_TAIL_MS).contains(&self.processing.echo_tail_ms) {
+            return Err(invalid(
+                "processing.echo_tail_ms",
+                format!(
+                    "must be between {} and {}",
+                    MIN_ECHO_TAIL_MS, MAX_ECHO_TAIL_MS
+                ),
+            ));
+        }
+        if !SUPPRESSION_RANGE_DB.contains(&self.processing.noise_suppression_db) {
+            return Err(invalid(
+                "processing.noise_suppression_db",
+                format!(
+                    "must be between {} and {}",
+                    SUPPRESSION_RANGE_DB.start(),
+                    SUPPRESSION_RANGE_DB.end()
+                ),
+            ));
+        }
+        if !TARGET_RANGE_DBFS.contains(&self.processing.gain_target_dbfs) {
+            return Err(invalid(
+                "processing.gain_target_dbfs",
+                format!(
+                    "must be between {} and {}",
+                    TARGET_RANGE_DBFS.start(),
+                    TARGET_RANGE_DBFS.end()
+                ),
+            ));
+        }
+        if !MAX_GAIN_RANGE_DB.contains(&self.processing.max_gain_db) {
+            return Err(invalid(
+                "processing.max_gain_db",
+                format!(
+                    "must be between {} and {}",
+                    MAX_GAIN_RANGE_DB.start(),
+                    MAX_GAIN_RANGE_DB.end()
+                ),
+            ));
+        }
+        if self.network.port == 0 {
+            return Err(invalid("network.port", "must not be 0"));
+        }
+        if self.security.psk.as_deref() == Some("") {
+            return Err(invalid(
+                "security.psk",
+                "must not be empty, leave it out instead",
+            ));
+        }
+        if !self.keys.device.as_os_str().is_empty() && !self.keys.device.exists() {
+            return Err(invalid(
+                "keys.device",
+                format!(
+                    "{} doesn't exist, set it to \"\" to run without hardware keys",
+                    self.keys.device.display()
+                ),
+            ));
+        }
+        if !matches!(self.leds.len(), 0 | 3) {
+            return Err(invalid(
+                "leds",
+                format!("expected 3 LEDs or none, got {}", self.leds.len()),
+            ));
+        }
+        for path in self
+            .leds
+            .iter()
+            .flat_map(|led| [&led.red, &led.green, &led.blue])
+        {
+            if !path.exists() {
+                return Err(invalid(
+                    "leds",
+                    format!(
+                        "{} doesn't exist, set leds = [] to run without LEDs",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Name to announce, the host name unless configured.
+    pub fn device_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| "phone".to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A desktop without the board's keys and LEDs.
+    fn desktop() -> Config {
+        Config::parse(
+            r#"
+            name = "Laptop"
+            leds = []
+
+            [audio]
+            input_device = "default"
+            output_device = "default"
+            mixer_device = "default"
+            mixer_element = "Master"
+
+            [network]
+            port = 40000
+
+            [keys]
+            device = ""
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_settings_take_the_board_defaults() {
+        let config = Config::parse("[network]\nport = 40000\n").unwrap();
+        assert_eq!(config.network.port, 40000);
+        assert_eq!(config.audio, AudioConfig::default());
+        assert_eq!(config.keys.device, Path::new("/dev/input/event0"));
+        assert_eq!(config.leds.len(), 3);
+        assert_eq!(
+            config.leds[2].blue,
+            Path::new("/sys/class/leds/pca995x:blue2/brightness")
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn example_file_lists_the_defaults() {
+        let example = Config::parse(include_str!("../phone.example.toml")).unwrap();
+        assert_eq!(example, Config::default());
+    }
+
+    #[test]
+    fn typos_are_reported() {
+        let error = Config::parse("[audio]\ninput_devise = \"default\"\n").unwrap_err();
+        assert!(error.to_string().contains("input_devise"), "{}", error);
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let mut config = desktop();
+        let cli = Cli::try_parse_from([
+            "phone",
+            "--port",
+            "40001",
+            "--input-device",
+            "hw:2",
+            "--led",
+            "/r,/g,/b",
+            "--allow-unencrypted",
+            "--audio-backend",
+            "null",
+            "--no-echo-cancellation",
+            "--no-gain-control",
+        ])
+        .unwrap();
+        config.apply(&cli);
+        assert_eq!(config.network.port, 40001);
+        assert_eq!(config.audio.input_device, "hw:2");
+        assert_eq!(config.audio.output_device, "default");
+        assert_eq!(config.audio.backend, AudioBackend::Null);
+        assert_eq!(config.leds[0].green, Path::new("/g"));
+        assert!(config.security.allow_unencrypted);
+        assert!(!config.processing.echo_cancellation);
+        assert!(config.processing.noise_suppression);
+        assert!(!config.processing.gain_control);
+        assert_eq!(config.name.as_deref(), Some("Laptop"));
+
+        assert!(Cli::try_parse_from(["phone", "--led", "/r,/g"]).is_err());
+
+        let cli = Cli::try_parse_from(["phone", "--keys-device", ""]).unwrap();
+        config.apply(&cli);
+        assert_eq!(config.keys.device, Path::new(""));
+        // Left alone unless given
+        assert!(config.security.allow_unencrypted);
+
+        // The file's choice can be taken back too
+        let cli = Cli::try_parse_from([
+            "phone",
+            "--allow-unencrypted=false",
+            "--refuse-unpaired=yes",
+        ])
+        .unwrap();
+        config.apply(&cli);
+        assert!(!config.security.allow_unencrypted);
+        assert!(config.security.refuse_unpaired);
+        assert!(Cli::try_parse_from(["phone", "--allow-unencrypted=maybe"]).is_err());
+    }
+
+    #[test]
+    fn bad_settings_are_rejected_by_name() {
+        assert!(desktop().validate().is_ok());
+
+        let mut config = desktop();
+        config.network.port = 0;
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(error, "network.port: must not be 0");
+
+        let mut config = desktop();
+        config.keys.device = "/nonexistent/event7".into();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.starts_with("keys.device: /nonexistent/event7"),
+            "{}",
+            error
+        );
+
+        let mut config = desktop();
+        config.leds = parse_led("/r,/g,/b").into_iter().collect();
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(error, "leds: expected 3 LEDs or none, got 1");
+
+        let mut config = desktop();
+        config.processing.echo_tail_ms = 2000;
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(error, "processing.echo_tail_ms: must be between 8 and 500");
+
+        let mut config = desktop();
+        config.processing.gain_target_dbfs = 0;
+        let error = config.validate().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "processing.gain_target_dbfs: must be between -40 and -3"
+        );
+
+        let mut config = desktop();
+        config.audio.mixer_element.clear();
+        assert!(config.validate().is_err());
+        config.audio.backend = AudioBackend::Null;
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Contact {
+    pub name: String,
+    pub address: IpAddr,
+}
+
+/// The address book, stored one contact per line as the address followed by
+/// the name.
+pub struct Contacts {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+    /// Lines of the file we couldn't read.
+    skipped: usize,
+}
+
+impl Contacts {
+    /// A missing file just means no contacts yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (contacts, skipped) = crate::storage::read_lines(path, |line| {
+            let (address, name) = line.split_once(' ')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            Some(Contact {
+                name: name.to_string(),
+                address: address.parse().ok()?,
+            })
+        })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            contacts,
+            skipped,
+        })
+    }
+
+    /// How many lines of the file [`Contacts::load`] couldn't read.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Contact> {
+        self.contacts.get(index)
+    }
+
+    /// Indices of the contacts whose name or address contains `query`,
+    /// ignoring case, sorted by name.
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        let query = query.to_lowercase();
+        let mut found = (0..self.contacts.len())
+            .filter(|&i| {
+                let contact = &self.contacts[i];
+                contact.name.to_lowercase().contains(&query)
+                    || contact.address.to_string().contains(&query)
+            })
+            .collect::<Vec<_>>();
+        found.sort_by_key(|&i| self.contacts[i].name.to_lowercase());
+        found
+    }
+
+    /// The name of the contact at `address`, for the call screen.
+    pub fn name_for(&self, address: IpAddr) -> Option<&str> {
+        self.contacts
+            .iter()
+            .find(|contact| contact.address == address)
+            .map(|contact| contact.name.as_str())
+    }
+
+    pub fn add(&mut self, contact: Contact) -> io::Result<()> {
+        self.contacts.push(contact);
+        self.save()
+    }
+
+    pub fn update(&mut self, index: usize, contact: Contact) -> io::Result<()> {
+        if let Some(existing) = self.contacts.get_mut(index) {
+            *existing = contact;
+        }
+        self.save()
+    }
+
+    pub fn remove(&mut self, index: usize) -> io::Result<()> {
+        if index < self.contacts.len() {
+            self.contacts.remove(index);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents = self
+            .contacts
+            .iter()
+            .map(|contact| format!("{} {}\n", contact.address, contact.name))
+            .collect::<String>();
+        crate::storage::write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(name: &str, address: &str) -> Contact {
+        Contact {
+            name: name.to_string(),
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn contacts_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("contacts");
+
+        let mut contacts = Contacts::load(&path).unwrap();
+        contacts.add(contact("Ana Lopez", "192.168.1.20")).unwrap();
+        contacts.add(contact("Bob", "192.168.1.21")).unwrap();
+        contacts.add(contact("Carla", "fe80::1")).unwrap();
+        contacts
+            .update(1, contact("Bob Ruiz", "192.168.1.22"))
+            .unwrap();
+        contacts.remove(0).unwrap();
+
+        let contacts = Contacts::load(&path).unwrap();
+        assert_eq!(
+            contacts.contacts,
+            vec![
+                contact("Bob Ruiz", "192.168.1.22"),
+                contact("Carla", "fe80::1")
+            ]
+        );
+        assert_eq!(
+            contacts.name_for("192.168.1.22".parse().unwrap()),
+            Some("Bob Ruiz")
+        );
+        assert_eq!(contacts.name_for("192.168.1.20".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn damaged_lines_are_skipped_and_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("contacts");
+        std::fs::write(
+            &path,
+            "10.0.0.1 Ana\nnot-an-address Bob\n\n10.0.0.3\n10.0.0.4 Zoe\n",
+        )
+        .unwrap();
+
+        let contacts = Contacts::load(&path).unwrap();
+        assert_eq!(
+            contacts.contacts,
+            vec![contact("Ana", "10.0.0.1"), contact("Zoe", "10.0.0.4")]
+        );
+        assert_eq!(contacts.skipped(), 2);
+        assert!(
+            crate::storage::skipped_lines_notice(&path, contacts.skipped())
+                .unwrap()
+                .starts_with("Skipped 2 unreadable lines")
+        );
+    }
+
+    #[test]
+    fn search_matches_names_and_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut contacts = Contacts::load(&dir.path().join("contacts")).unwrap();
+        contacts.add(contact("zoe", "10.0.0.3")).unwrap();
+        contacts.add(contact("Ana", "10.0.0.1")).unwrap();
+        contacts.add(contact("Bob", "192.168.1.2")).unwrap();
+
+        assert_eq!(contacts.search(""), vec![1, 2, 0]);
+        assert_eq!(contacts.search("ZO"), vec![0]);
+        assert_eq!(contacts.search("10.0"), vec![1, 0]);
+        assert!(contacts.search("nobody").is_empty());
+    }
+}
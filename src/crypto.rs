@@ -0,0 +1,499 @@
+use std::{fmt, fs, io, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::packet::{
+    KeyExchange, KeyShare, NetworkPacket, SessionId, CONFIRMATION_SIZE, FLAG_ENCRYPTED,
+    KEY_SHARE_SIZE,
+};
+
+/// Packet counter in front of the ciphertext of every encrypted packet.
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+/// How many counters behind the newest one we still accept, so packets
+/// reordered by the network aren't mistaken for replays.
+const REPLAY_WINDOW: u64 = 64;
+/// Nonce counter reserved for the key confirmation in the Accept packet.
+/// Packets never count this high.
+const CONFIRMATION_COUNTER: u64 = u64::MAX;
+
+/// Turns a pre-shared passphrase into key material for [`Handshake::finish`].
+pub fn psk_key(passphrase: &str) -> [u8; 32] {
+    Sha256::digest(passphrase.as_bytes()).into()
+}
+
+/// Why an encrypted call packet was refused.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CryptoError {
+    /// A plaintext packet in an encrypted call.
+    NotEncrypted,
+    /// Too short to hold the counter and tag.
+    Truncated,
+    /// Failed authentication, either tampered with or sealed with other keys.
+    Forged,
+    /// A counter we already accepted or that is too old to tell.
+    Replayed,
+    /// The peer's key share is not a usable curve point.
+    BadKeyShare,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::NotEncrypted => write!(f, "packet is not encrypted"),
+            CryptoError::Truncated => write!(f, "encrypted payload is truncated"),
+            CryptoError::Forged => write!(f, "packet failed authentication"),
+            CryptoError::Replayed => write!(f, "packet was replayed"),
+            CryptoError::BadKeyShare => write!(f, "peer sent an invalid key share"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+fn new_secret() -> io::Result<StaticSecret> {
+    let mut bytes = [0; 32];
+    crate::random::fill(&mut bytes)?;
+    Ok(StaticSecret::from(bytes))
+}
+
+/// The long term X25519 key a unit is known by. Pairing exchanges these, and
+/// every call key exchange proves both sides hold theirs.
+pub struct Identity {
+    secret: StaticSecret,
+    pub public: KeyShare,
+}
+
+impl Identity {
+    /// Loads our identity, creating one the first time we run.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        let secret = match fs::read(path) {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes.try_into().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is not an identity key", path.display()),
+                    )
+                })?;
+                StaticSecret::from(bytes)
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let secret = new_secret()?;
+                crate::storage::write_private(path, secret.as_bytes())?;
+                secret
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Self::from_secret(secret))
+    }
+
+    #[cfg(test)]
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self::from_secret(new_secret()?))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+}
+
+/// Our half of the X25519 key exchange for one call.
+pub struct Handshake {
+    secret: StaticSecret,
+    pub key_exchange: KeyExchange,
+}
+
+impl Handshake {
+    pub fn new(identity: &Identity) -> io::Result<Self> {
+        let secret = new_secret()?;
+        let key_exchange = KeyExchange {
+            key_share: PublicKey::from(&secret).to_bytes(),
+            identity: identity.public,
+        };
+        Ok(Self {
+            secret,
+            key_exchange,
+        })
+    }
+
+    /// Derives the call keys from the peer's half of the exchange. Both the
+    /// fresh key shares and the identities are mixed in, so only the holders
+    /// of the two identity keys end up with the same call keys. The
+    /// pre-shared key, if any, is mixed in as well.
+    pub fn finish(
+        self,
+        identity: &Identity,
+        peer: &KeyExchange,
+        psk: Option<&[u8; 32]>,
+        session: SessionId,
+        caller: bool,
+    ) -> Result<CallCipher, CryptoError> {
+        let ephemeral = self.secret.diffie_hellman(&PublicKey::from(peer.key_share));
+        let identities = identity
+            .secret
+            .diffie_hellman(&PublicKey::from(peer.identity));
+        if !ephemeral.was_contributory() || !identities.was_contributory() {
+            return Err(CryptoError::BadKeyShare);
+        }
+
+        let (caller_exchange, callee_exchange) = if caller {
+            (&self.key_exchange, peer)
+        } else {
+            (peer, &self.key_exchange)
+        };
+        let mut info = Vec::with_capacity(16 + 4 + 4 * KEY_SHARE_SIZE);
+        info.extend_from_slice(b"phone call keys ");
+        info.extend_from_slice(&session.to_le_bytes());
+        for exchange in [caller_exchange, callee_exchange] {
+            info.extend_from_slice(&exchange.key_share);
+            info.extend_from_slice(&exchange.identity);
+        }
+
+        let mut secrets = [0; 64];
+        secrets[..32].copy_from_slice(ephemeral.as_bytes());
+        secrets[32..].copy_from_slice(identities.as_bytes());
+        let hkdf = Hkdf::<Sha256>::new(psk.map(|psk| &psk[..]), &secrets);
+        let mut keys = [0; 64];
+        hkdf.expand(&info, &mut keys)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        let (caller_key, callee_key) = keys.split_at(32);
+        let (send_key, receive_key) = if caller {
+            (caller_key, callee_key)
+        } else {
+            (callee_key, caller_key)
+        };
+
+        Ok(CallCipher {
+            send: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            receive: ChaCha20Poly1305::new(Key::from_slice(receive_key)),
+            next_counter: 0,
+            highest_counter: None,
+            history: 0,
+        })
+    }
+}
+
+/// Authenticated encryption of the packets of one call, one key per
+/// direction. The nonce of every packet is its counter, which the receiver
+/// also uses to reject replays.
+pub struct CallCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    next_counter: u64,
+    highest_counter: Option<u64>,
+    /// Bit `n` is set if `highest_counter - n` has been accepted.
+    history: u64,
+}
+
+impl CallCipher {
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        *Nonce::from_slice(&nonce)
+    }
+
+    /// The header fields an attacker must not be able to change.
+    fn associated_data(packet: &NetworkPacket) -> [u8; 6] {
+        let session = packet.session.to_le_bytes();
+        [
+            packet.packet_type as u8,
+            packet.flags,
+            session[0],
+            session[1],
+            session[2],
+            session[3],
+        ]
+    }
+
+    /// Encrypts the payload of a packet we are about to send.
+    pub fn seal(&mut self, mut packet: NetworkPacket) -> NetworkPacket {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        packet.flags |= FLAG_ENCRYPTED;
+        let ciphertext = self
+            .send
+            .encrypt(
+                &Self::nonce(counter),
+                Payload {
+                    msg: &packet.data,
+                    aad: &Self::associated_data(&packet),
+                },
+            )
+            .expect("encrypting into a Vec can't fail");
+        let mut data = Vec::with_capacity(COUNTER_SIZE + ciphertext.len());
+        data.extend_from_slice(&counter.to_le_bytes());
+        data.extend_from_slice(&ciphertext);
+        packet.data = data;
+        packet
+    }
+
+    /// Checks and decrypts a packet from the peer.
+    pub fn open(&mut self, packet: &NetworkPacket) -> Result<NetworkPacket, CryptoError> {
+        if packet.flags & FLAG_ENCRYPTED == 0 {
+            return Err(CryptoError::NotEncrypted);
+        }
+        if packet.data.len() < COUNTER_SIZE + TAG_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        let (counter, ciphertext) = packet.data.split_at(COUNTER_SIZE);
+        let counter = u64::from_le_bytes(counter.try_into().unwrap());
+        if counter == CONFIRMATION_COUNTER || self.seen(counter) {
+            return Err(CryptoError::Replayed);
+        }
+        let plaintext = self
+            .receive
+            .decrypt(
+                &Self::nonce(counter),
+                Payload {
+                    msg: ciphertext,
+                    aad: &Self::associated_data(packet),
+                },
+            )
+            .map_err(|_| CryptoError::Forged)?;
+        // Only remember counters of genuine packets, or a forgery could
+        // block the real one
+        self.remember(counter);
+        Ok(NetworkPacket {
+            packet_type: packet.packet_type,
+            flags: packet.flags & !FLAG_ENCRYPTED,
+            session: packet.session,
+            data: plaintext,
+        })
+    }
+
+    fn seen(&self, counter: u64) -> bool {
+        match self.highest_counter {
+            None => false,
+            Some(highest) if counter > highest => false,
+            Some(highest) => {
+                let behind = highest - counter;
+                behind >= REPLAY_WINDOW || self.history & (1 << behind) != 0
+            }
+        }
+    }
+
+    fn remember(&mut self, counter: u64) {
+        match self.highest_counter {
+            Some(highest) if counter <= highest => {
+                self.history |= 1 << (highest - counter);
+            }
+            Some(highest) => {
+                let advance = counter - highest;
+                self.history = if advance >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.history << advance
+                };
+                self.history |= 1;
+                self.highest_counter = Some(counter);
+            }
+            None => {
+                self.history = 1;
+                self.highest_counter = Some(counter);
+            }
+        }
+    }
+
+    /// Proves to the caller that we derived the same keys, and binds the
+    /// audio format we picked to them. Sent by the callee in its Accept.
+    pub fn confirmation(&self, format: &[u8]) -> [u8; CONFIRMATION_SIZE] {
+        let tag = self
+            .send
+            .encrypt(
+                &Self::nonce(CONFIRMATION_COUNTER),
+                Payload {
+                    msg: &[],
+                    aad: format,
+                },
+            )
+            .expect("encrypting into a Vec can't fail");
+        tag.try_into().expect("the tag is all there is")
+    }
+
+    /// Checks the callee's [`CallCipher::confirmation`].
+    pub fn check_confirmation(
+        &self,
+        format: &[u8],
+        confirmation: &[u8; CONFIRMATION_SIZE],
+    ) -> Result<(), CryptoError> {
+        self.receive
+            .decrypt(
+                &Self::nonce(CONFIRMATION_COUNTER),
+                Payload {
+                    msg: confirmation,
+                    aad: format,
+                },
+            )
+            .map(|_| ())
+            .map_err(|_| CryptoError::Forged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::NetworkPacketType;
+
+    fn call_ciphers(caller_psk: &str, callee_psk: &str) -> (CallCipher, CallCipher) {
+        let caller_identity = Identity::generate().unwrap();
+        let callee_identity = Identity::generate().unwrap();
+        call_ciphers_between(&caller_identity, &callee_identity, caller_psk, callee_psk)
+    }
+
+    fn call_ciphers_between(
+        caller_identity: &Identity,
+        callee_identity: &Identity,
+        caller_psk: &str,
+        callee_psk: &str,
+    ) -> (CallCipher, CallCipher) {
+        let caller = Handshake::new(caller_identity).unwrap();
+        let callee = Handshake::new(callee_identity).unwrap();
+        let caller_exchange = caller.key_exchange;
+        let callee_exchange = callee.key_exchange;
+        (
+            caller
+                .finish(
+                    caller_identity,
+                    &callee_exchange,
+                    Some(&psk_key(caller_psk)),
+                    7,
+                    true,
+                )
+                .unwrap(),
+            callee
+                .finish(
+                    callee_identity,
+                    &caller_exchange,
+                    Some(&psk_key(callee_psk)),
+                    7,
+                    false,
+                )
+                .unwrap(),
+        )
+    }
+
+    fn heartbeat(data: &[u8]) -> NetworkPacket {
+        NetworkPacket {
+            packet_type: NetworkPacketType::Heartbeat,
+            flags: 0,
+            session: 7,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_in_both_directions() {
+        let (mut caller, mut callee) = call_ciphers("secret", "secret");
+        let packet = heartbeat(b"hello");
+
+        let sealed = caller.seal(packet.clone());
+        assert_ne!(sealed.data, packet.data);
+        assert_eq!(callee.open(&sealed).unwrap(), packet);
+
+        let sealed = callee.seal(packet.clone());
+        assert_eq!(caller.open(&sealed).unwrap(), packet);
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (mut caller, mut callee) = call_ciphers("secret", "secret");
+        let sealed = caller.seal(heartbeat(b"hello"));
+
+        let mut flipped = sealed.clone();
+        *flipped.data.last_mut().unwrap() ^= 1;
+        assert_eq!(callee.open(&flipped), Err(CryptoError::Forged));
+
+        let mut retyped = sealed.clone();
+        retyped.packet_type = NetworkPacketType::StopConnection;
+        assert_eq!(callee.open(&retyped), Err(CryptoError::Forged));
+
+        let mut moved = sealed.clone();
+        moved.session = 8;
+        assert_eq!(callee.open(&moved), Err(CryptoError::Forged));
+
+        let mut truncated = sealed.clone();
+        truncated.data.truncate(COUNTER_SIZE + TAG_SIZE - 1);
+        assert_eq!(callee.open(&truncated), Err(CryptoError::Truncated));
+
+        assert_eq!(
+            callee.open(&heartbeat(b"hello")),
+            Err(CryptoError::NotEncrypted)
+        );
+        // The genuine packet still gets through after all that
+        assert!(callee.open(&sealed).is_ok());
+    }
+
+    #[test]
+    fn replayed_packets_are_rejected() {
+        let (mut caller, mut callee) = call_ciphers("secret", "secret");
+        let sealed = (0..100)
+            .map(|i| caller.seal(heartbeat(&[i])))
+            .collect::<Vec<_>>();
+
+        assert!(callee.open(&sealed[1]).is_ok());
+        assert_eq!(callee.open(&sealed[1]), Err(CryptoError::Replayed));
+        // Reordering within the window is fine
+        assert!(callee.open(&sealed[0]).is_ok());
+        assert_eq!(callee.open(&sealed[0]), Err(CryptoError::Replayed));
+
+        assert!(callee.open(&sealed[99]).is_ok());
+        assert!(callee.open(&sealed[50]).is_ok());
+        // Too old to tell
+        assert_eq!(callee.open(&sealed[20]), Err(CryptoError::Replayed));
+    }
+
+    #[test]
+    fn different_pre_shared_keys_fail_the_confirmation() {
+        let format = [1, 2, 3];
+        let (caller, callee) = call_ciphers("secret", "secret");
+        assert!(caller
+            .check_confirmation(&format, &callee.confirmation(&format))
+            .is_ok());
+        assert_eq!(
+            caller.check_confirmation(&[1, 2, 4], &callee.confirmation(&format)),
+            Err(CryptoError::Forged)
+        );
+
+        let (mut caller, callee) = call_ciphers("secret", "guess");
+        assert_eq!(
+            caller.check_confirmation(&format, &callee.confirmation(&format)),
+            Err(CryptoError::Forged)
+        );
+        let mut callee = callee;
+        assert_eq!(
+            caller.open(&callee.seal(heartbeat(b"hello"))),
+            Err(CryptoError::Forged)
+        );
+    }
+
+    #[test]
+    fn claiming_someone_elses_identity_fails_the_confirmation() {
+        let format = [1, 2, 3];
+        let caller_identity = Identity::generate().unwrap();
+        let callee_identity = Identity::generate().unwrap();
+        let impostor = Identity::generate().unwrap();
+
+        // The impostor offers the caller's identity key without holding its
+        // secret
+        let handshake = Handshake::new(&impostor).unwrap();
+        let mut offer = handshake.key_exchange;
+        offer.identity = caller_identity.public;
+        let callee = Handshake::new(&callee_identity).unwrap();
+        let answer = callee.key_exchange;
+        let callee = callee
+            .finish(&callee_identity, &offer, None, 7, false)
+            .unwrap();
+        let impostor = handshake.finish(&impostor, &answer, None, 7, true).unwrap();
+        assert_eq!(
+            impostor.check_confirmation(&format, &callee.confirmation(&format)),
+            Err(CryptoError::Forged)
+        );
+    }
+}
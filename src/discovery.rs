@@ -0,0 +1,363 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    packet::{DecodeError, KeyShare, KEY_SHARE_SIZE},
+    terminal_task::CallScreenCommand,
+};
+
+/// Marks our announcements, so we can share the group with other software.
+pub const ANNOUNCEMENT_MAGIC: [u8; 2] = *b"ED";
+/// Bumped whenever the announcement format changes.
+pub const ANNOUNCEMENT_VERSION: u8 = 1;
+/// Site local multicast group the units announce themselves on.
+pub const DISCOVERY_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 51, 45), 33446);
+/// Longest name we announce, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+pub struct DiscoverySettings {
+    /// What other units list us as.
+    pub name: String,
+    pub identity: KeyShare,
+    /// Port we take calls on.
+    pub port: u16,
+    pub group: SocketAddrV4,
+    /// Local address of the interface to announce on, unspecified to let the
+    /// routing table pick. Set it to 127.0.0.1 to run several units on one
+    /// machine.
+    pub interface: Ipv4Addr,
+    pub announce_interval: Duration,
+    /// Drop a unit from the list if we haven't heard from it for this long.
+    pub expiry: Duration,
+}
+
+impl DiscoverySettings {
+    pub fn new(name: String, identity: KeyShare, port: u16) -> Self {
+        Self {
+            name,
+            identity,
+            port,
+            group: DISCOVERY_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            announce_interval: Duration::from_secs(2),
+            expiry: Duration::from_secs(7),
+        }
+    }
+}
+
+/// What a unit multicasts every [`DiscoverySettings::announce_interval`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+struct Announcement {
+    name: String,
+    identity: KeyShare,
+    port: u16,
+}
+
+impl Announcement {
+    const MIN_SIZE: usize = 3 + KEY_SHARE_SIZE + 3;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut name = self.name.as_bytes();
+        if name.len() > MAX_NAME_LEN {
+            // Cut at a character boundary so the name stays valid UTF-8
+            let end = (0..=MAX_NAME_LEN)
+                .rev()
+                .find(|&end| self.name.is_char_boundary(end))
+                .unwrap_or(0);
+            name = &name[..end];
+        }
+        let mut data = Vec::with_capacity(Self::MIN_SIZE + name.len());
+        data.extend_from_slice(&ANNOUNCEMENT_MAGIC);
+        data.push(ANNOUNCEMENT_VERSION);
+        data.extend_from_slice(&self.identity);
+        data.extend_from_slice(&self.port.to_le_bytes());
+        data.push(name.len() as u8);
+        data.extend_from_slice(name);
+        data
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < Self::MIN_SIZE {
+            return Err(DecodeError::Truncated { len: data.len() });
+        }
+        if data[0..2] != ANNOUNCEMENT_MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if data[2] != ANNOUNCEMENT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(data[2]));
+        }
+        let mut identity = [0; KEY_SHARE_SIZE];
+        identity.copy_from_slice(&data[3..3 + KEY_SHARE_SIZE]);
+        let rest = &data[3 + KEY_SHARE_SIZE..];
+        let port = u16::from_le_bytes([rest[0], rest[1]]);
+        let name_len = rest[2] as usize;
+        let name = rest
+            .get(3..3 + name_len)
+            .ok_or(DecodeError::PayloadTooShort {
+                expected: Self::MIN_SIZE + name_len,
+                actual: data.len(),
+            })?;
+        Ok(Self {
+            name: String::from_utf8_lossy(name).into_owned(),
+            identity,
+            port,
+        })
+    }
+}
+
+/// A unit we have heard announcing itself.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NearbyDevice {
+    pub name: String,
+    pub identity: KeyShare,
+    /// Where it takes calls.
+    pub address: SocketAddr,
+    pub last_seen: Instant,
+}
+
+/// The units we have heard from recently, in the order we first heard them.
+#[derive(Default)]
+struct NearbyDevices {
+    devices: Vec<NearbyDevice>,
+}
+
+impl NearbyDevices {
+    /// Records an announcement, returning whether the list changed.
+    fn heard(&mut self, announcement: Announcement, from: SocketAddr, now: Instant) -> bool {
+        let address = SocketAddr::new(from.ip(), announcement.port);
+        match self
+            .devices
+            .iter_mut()
+            .find(|device| device.identity == announcement.identity)
+        {
+            Some(device) => {
+                let changed = device.name != announcement.name || device.address != address;
+                device.name = announcement.name;
+                device.address = address;
+                device.last_seen = now;
+                changed
+            }
+            None => {
+                self.devices.push(NearbyDevice {
+                    name: announcement.name,
+                    identity: announcement.identity,
+                    address,
+                    last_seen: now,
+                });
+                true
+            }
+        }
+    }
+
+    /// Forgets units that went quiet, returning whether the list changed.
+    fn expire(&mut self, now: Instant, expiry: Duration) -> bool {
+        let before = self.devices.len();
+        self.devices
+            .retain(|device| now.duration_since(device.last_seen) <= expiry);
+        self.devices.len() != before
+    }
+}
+
+pub enum DiscoveryCommand {
+    Exit,
+}
+
+/// Joins the discovery group. Several units on the same machine can listen
+/// on the group at once.
+fn discovery_socket(settings: &DiscoverySettings) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, settings.group.port()).into())?;
+    socket.join_multicast_v4(settings.group.ip(), &settings.interface)?;
+    socket.set_multicast_if_v4(&settings.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    // Announcements stay on the local network
+    socket.set_multicast_ttl_v4(1)?;
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(socket)
+}
+
+fn discovery_task(
+    rx: Receiver<DiscoveryCommand>,
+    settings: DiscoverySettings,
+    main_task_queue: Sender<CallScreenCommand>,
+) -> anyhow::Result<()> {
+    let socket = discovery_socket(&settings)?;
+    let announcement = Announcement {
+        name: settings.name.clone(),
+        identity: settings.identity,
+        port: settings.port,
+    }
+    .serialize();
+    let mut nearby = NearbyDevices::default();
+    let mut last_announced: Option<Instant> = None;
+    let mut buffer = [0; 512];
+    // Whether the last announcement failed, so an outage is reported once
+    let mut announcing_failed = false;
+
+    loop {
+        if let Ok(DiscoveryCommand::Exit) = rx.try_recv() {
+            break;
+        }
+
+        let now = Instant::now();
+        if last_announced.is_none_or(|last| now - last >= settings.announce_interval) {
+            match socket.send_to(&announcement, settings.group) {
+                Ok(_) => announcing_failed = false,
+                // The network may not be up yet, keep trying
+                Err(_) if announcing_failed => {}
+                Err(error) => {
+                    announcing_failed = true;
+                    main_task_queue.send(CallScreenCommand::Notice(format!(
+                        "Nearby units can't see us: {}",
+                        error
+                    )))?;
+                }
+            }
+            last_announced = Some(now);
+        }
+
+        let mut changed = false;
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) => match Announcement::deserialize(&buffer[..len]) {
+                // We hear our own announcements too
+                Ok(heard) if heard.identity == settings.identity => {}
+                Ok(heard) => changed |= nearby.heard(heard, from, Instant::now()),
+                // Anything else sent to the group isn't for us
+                Err(_) => {}
+            },
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(error) => return Err(error.into()),
+        }
+        changed |= nearby.expire(Instant::now(), settings.expiry);
+        if changed {
+            main_task_queue.send(CallScreenCommand::NearbyDevices(nearby.devices.clone()))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn create_discovery_task(
+    settings: DiscoverySettings,
+    main_task_queue: Sender<CallScreenCommand>,
+) -> (Sender<DiscoveryCommand>, JoinHandle<()>) {
+    let (command_tx, command_rx) = unbounded();
+
+    let thread = spawn(move || {
+        if let Err(e) = discovery_task(command_rx, settings, main_task_queue) {
+            eprintln!("Error in discovery_task: {}", e);
+        }
+    });
+
+    (command_tx, thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(name: &str, identity: u8, port: u16) -> Announcement {
+        Announcement {
+            name: name.to_string(),
+            identity: [identity; KEY_SHARE_SIZE],
+            port,
+        }
+    }
+
+    #[test]
+    fn announcements_round_trip() {
+        let sent = announcement("Kitchen", 1, 33445);
+        assert_eq!(Announcement::deserialize(&sent.serialize()), Ok(sent));
+
+        let long = announcement(&"é".repeat(MAX_NAME_LEN), 1, 33445);
+        let received = Announcement::deserialize(&long.serialize()).unwrap();
+        assert_eq!(received.name, "é".repeat(MAX_NAME_LEN / 2));
+
+        assert_eq!(
+            Announcement::deserialize(&[0; Announcement::MIN_SIZE]),
+            Err(DecodeError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn devices_are_listed_until_they_go_quiet() {
+        let mut nearby = NearbyDevices::default();
+        let start = Instant::now();
+        let from = "192.168.1.20:33446".parse().unwrap();
+        let expiry = Duration::from_secs(7);
+
+        assert!(nearby.heard(announcement("Kitchen", 1, 33445), from, start));
+        assert!(!nearby.heard(announcement("Kitchen", 1, 33445), from, start));
+        assert_eq!(
+            nearby.devices[0].address,
+            "192.168.1.20:33445".parse().unwrap()
+        );
+
+        let later = start + Duration::from_secs(5);
+        assert!(nearby.heard(announcement("Garage", 2, 40000), from, later));
+        assert!(nearby.heard(
+            announcement("Kitchen 2", 1, 33445),
+            from,
+            later - Duration::from_secs(4)
+        ));
+        assert!(!nearby.expire(later, expiry));
+        assert!(nearby.expire(later + Duration::from_secs(4), expiry));
+        assert_eq!(nearby.devices.len(), 1);
+        assert_eq!(nearby.devices[0].name, "Garage");
+    }
+
+    /// Two units on loopback find each other and not themselves.
+    #[test]
+    fn units_on_loopback_discover_each_other() {
+        let group = SocketAddrV4::new(
+            Ipv4Addr::new(239, 255, 51, 46),
+            40000 + (std::process::id() % 20000) as u16,
+        );
+        let settings = |name: &str, identity: u8, port: u16| DiscoverySettings {
+            group,
+            interface: Ipv4Addr::LOCALHOST,
+            announce_interval: Duration::from_millis(100),
+            ..DiscoverySettings::new(name.to_string(), [identity; KEY_SHARE_SIZE], port)
+        };
+        let (kitchen_tx, kitchen_rx) = unbounded();
+        let (garage_tx, garage_rx) = unbounded();
+        let (kitchen, kitchen_thread) =
+            create_discovery_task(settings("Kitchen", 1, 40001), kitchen_tx);
+        let (garage, garage_thread) =
+            create_discovery_task(settings("Garage", 2, 40002), garage_tx);
+
+        let first_list =
+            |rx: &Receiver<CallScreenCommand>| match rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(CallScreenCommand::NearbyDevices(devices)) => devices,
+                _ => panic!("no nearby devices reported"),
+            };
+        let heard_by_kitchen = first_list(&kitchen_rx);
+        let heard_by_garage = first_list(&garage_rx);
+        assert_eq!(heard_by_kitchen.len(), 1);
+        assert_eq!(heard_by_kitchen[0].name, "Garage");
+        assert_eq!(
+            heard_by_kitchen[0].address,
+            "127.0.0.1:40002".parse().unwrap()
+        );
+        assert_eq!(heard_by_garage.len(), 1);
+        assert_eq!(heard_by_garage[0].name, "Kitchen");
+
+        kitchen.send(DiscoveryCommand::Exit).unwrap();
+        garage.send(DiscoveryCommand::Exit).unwrap();
+        kitchen_thread.join().unwrap();
+        garage_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio_format::{DEVICE_CHANNELS, DEVICE_SAMPLE_RATE},
+    fft::{Complex, Fft},
+    hardware::frames_to_duration,
+};
+
+/// Frames the canceller works on at a time, which is also the delay it adds
+/// (5.3 ms at 48 kHz).
+pub const BLOCK_FRAMES: usize = 256;
+const FFT_SIZE: usize = 2 * BLOCK_FRAMES;
+/// Bins of a real signal's spectrum we keep, the rest mirror them.
+const BINS: usize = BLOCK_FRAMES + 1;
+/// Share of the way to the best filter each block moves.
+const STEP_SIZE: f32 = 0.4;
+/// Keeps the adaptation from blowing up quiet bins, about -60 dBFS.
+const REGULARIZATION: f32 = 1e-6;
+/// Below this mean power the speaker is too quiet to learn the echo from,
+/// about -70 dBFS.
+const ADAPTATION_FLOOR: f32 = 1e-7;
+/// Weight of the previous blocks in the smoothed error energies.
+const ERROR_SMOOTHING: f32 = 0.8;
+/// The adapting filter takes over once its error is this much below the
+/// one in use (about 1.5 dB)...
+const TAKE_OVER_RATIO: f32 = 0.7;
+/// ...and is reset to the one in use once it is this much above it (9 dB),
+/// which happens when it adapts to the near end talking over the echo.
+const DIVERGED_RATIO: f32 = 8.0;
+/// Most far end audio we keep waiting for the microphone to catch up.
+const MAX_REFERENCE_FRAMES: usize = DEVICE_SAMPLE_RATE as usize;
+/// Gaps between played frames shorter than this are taken as jitter in our
+/// timing rather than silence.
+const GAP_TOLERANCE: Duration = Duration::from_millis(5);
+
+fn duration_to_frames(duration: Duration) -> usize {
+    (duration.as_secs_f64() * DEVICE_SAMPLE_RATE as f64).round() as usize
+}
+
+/// Averages interleaved device audio down to one channel.
+fn downmix(samples: &[i16]) -> impl Iterator<Item = i16> + '_ {
+    samples.chunks_exact(DEVICE_CHANNELS).map(|frame| {
+        (frame.iter().map(|&sample| sample as i32).sum::<i32>() / DEVICE_CHANNELS as i32) as i16
+    })
+}
+
+/// The audio the speaker played and when, shared by the output audio task,
+/// which fills it in, and the input audio task, which cancels its echo.
+#[derive(Clone, Default)]
+pub struct EchoReference(Arc<Mutex<ReferenceQueue>>);
+
+#[derive(Default)]
+struct ReferenceQueue {
+    /// Played audio, mono, not yet matched with the microphone.
+    samples: VecDeque<i16>,
+    /// When the first of `samples` comes out of the speaker.
+    starts: Option<Instant>,
+}
+
+impl EchoReference {
+    /// Notes that the device audio in `samples` starts playing at `at`.
+    pub fn played(&self, samples: &[i16], at: Instant) {
+        let mut queue = self.0.lock().unwrap();
+        match queue.starts {
+            Some(starts) if !queue.samples.is_empty() => {
+                let end = starts + frames_to_duration(queue.samples.len() as u64);
+                // The speaker went quiet in between
+                if at > end + GAP_TOLERANCE {
+                    let gap = duration_to_frames(at - end);
+                    queue.samples.extend(std::iter::repeat_n(0, gap));
+                }
+            }
+            _ => queue.starts = Some(at),
+        }
+        queue.samples.extend(downmix(samples));
+        let excess = queue.samples.len().saturating_sub(MAX_REFERENCE_FRAMES);
+        if excess > 0 {
+            queue.samples.drain(..excess);
+            queue.starts = queue
+                .starts
+                .map(|starts| starts + frames_to_duration(excess as u64));
+        }
+    }
+
+    /// Forgets audio that was queued but won't be played after all.
+    pub fn clear(&self) {
+        let mut queue = self.0.lock().unwrap();
+        queue.samples.clear();
+        queue.starts = None;
+    }
+
+    /// What the speaker played, mono, during the `frames` frames from `at`
+    /// on. Silence where it played nothing.
+    pub fn take(&self, at: Instant, frames: usize) -> Vec<i16> {
+        let mut queue = self.0.lock().unwrap();
+        let mut reference = Vec::with_capacity(frames);
+        let Some(starts) = queue.starts else {
+            return vec![0; frames];
+        };
+        if at >= starts {
+            // Played before the microphone audio, its echo is gone by now
+            let skip = duration_to_frames(at - starts).min(queue.samples.len());
+            queue.samples.drain(..skip);
+            queue.starts = Some(starts + frames_to_duration(skip as u64));
+        } else {
+            let silence = duration_to_frames(starts - at).min(frames);
+            reference.resize(silence, 0);
+        }
+        let played = (frames - reference.len()).min(queue.samples.len());
+        reference.extend(queue.samples.drain(..played));
+        queue.starts = queue
+            .starts
+            .map(|starts| starts + frames_to_duration(played as u64));
+        reference.resize(frames, 0);
+        reference
+    }
+}
+
+/// Echo path estimates of one microphone channel.
+struct ChannelFilters {
+    /// Adapts with every block, partition spectra newest first.
+    adapting: Vec<Vec<Complex>>,
+    /// A copy of `adapting` from when it did well, used for the output so
+    /// double talk can't throw the cancellation off.
+    stable: Vec<Vec<Complex>>,
+    adapting_error: f32,
+    stable_error: f32,
+}
+
+/// Removes the echo of the far end audio from the microphone audio, with a
+/// partitioned block frequency domain adaptive filter. Two copies of the
+/// filter are kept: one adapts all the time, the other is what we cancel
+/// with and only follows the first while it does better.
+pub struct EchoCanceller {
+    channels: usize,
+    fft: Fft,
+    /// Spectra of the last reference windows, newest first, one per
+    /// partition of the echo tail.
+    reference_spectra: VecDeque<Vec<Complex>>,
+    /// The previous reference block, the first half of the next window.
+    previous_reference: Vec<f32>,
+    filters: Vec<ChannelFilters>,
+    /// Partition of the adapting filters to trim to the block length next.
+    constrain_next: usize,
+    /// Audio waiting for a whole block, mono reference and interleaved
+    /// microphone.
+    pending_reference: Vec<i16>,
+    pending_mic: Vec<i16>,
+    /// Processed interleaved microphone audio.
+    processed: VecDeque<i16>,
+    /// Scratch space for the transforms.
+    scratch: Vec<Complex>,
+}
+
+impl EchoCanceller {
+    /// Cancels echoes up to `tail` after the sound that caused them, in
+    /// audio with `channels` channels.
+    pub fn new(channels: usize, tail: Duration) -> Self {
+        let partitions = duration_to_frames(tail).div_ceil(BLOCK_FRAMES).max(1);
+        let empty = vec![vec![Complex::ZERO; BINS]; partitions];
+        Self {
+            channels,
+            fft: Fft::new(FFT_SIZE),
+            reference_spectra: VecDeque::from(empty.clone()),
+            previous_reference: vec![0.0; BLOCK_FRAMES],
+            filters: (0..channels)
+                .map(|_| ChannelFilters {
+                    adapting: empty.clone(),
+                    stable: empty.clone(),
+                    adapting_error: 0.0,
+                    stable_error: 0.0,
+                })
+                .collect(),
+            constrain_next: 0,
+            pending_reference: Vec::new(),
+            pending_mic: Vec::new(),
+            processed: VecDeque::from(vec![0; BLOCK_FRAMES * channels]),
+            scratch: vec![Complex::ZERO; FFT_SIZE],
+        }
+    }
+
+    fn partitions(&self) -> usize {
+        self.reference_spectra.len()
+    }
+
+    /// Takes interleaved microphone audio and the mono reference played
+    /// during the same frames, returns the microphone audio without the
+    /// echo, [`BLOCK_FRAMES`] later. A reference shorter than the
+    /// microphone audio is made up with silence, a longer one is cut short.
+    pub fn process(&mut self, mic: &[i16], reference: &[i16]) -> Vec<i16> {
+        let frames = mic.len() / self.channels;
+        self.pending_mic.extend_from_slice(mic);
+        self.pending_reference.extend(
+            reference
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(0))
+                .take(frames),
+        );
+        while self.pending_reference.len() >= BLOCK_FRAMES {
+            let reference = self
+                .pending_reference
+                .drain(..BLOCK_FRAMES)
+                .map(|sample| sample as f32 / 32768.0)
+                .collect::<Vec<_>>();
+            let mic = self
+                .pending_mic
+                .drain(..BLOCK_FRAMES * self.channels)
+                .map(|sample| sample as f32 / 32768.0)
+                .collect::<Vec<_>>();
+            self.process_block(&reference, &mic);
+        }
+        self.processed.drain(..mic.len()).collect()
+    }
+
+    fn process_block(&mut self, reference: &[f32], mic: &[f32]) {
+        // Spectrum of the last two reference blocks
+        for (value, &sample) in self
+            .scratch
+            .iter_mut()
+            .zip(self.previous_reference.iter().chain(reference))
+        {
+            *value = Complex::new(sample, 0.0);
+        }
+        self.previous_reference.copy_from_slice(reference);
+        self.fft.forward(&mut self.scratch);
+        self.reference_spectra.pop_back();
+        self.reference_spectra
+            .push_front(self.scratch[..BINS].to_vec());
+
+        let partitions = self.partitions();
+        let mut power = vec![REGULARIZATION * (partitions * FFT_SIZE) as f32; BINS];
+        for spectrum in &self.reference_spectra {
+            for (power, value) in power.iter_mut().zip(spectrum) {
+                *power += value.norm_sqr();
+            }
+        }
+        let mean_power = power.iter().sum::<f32>() / (partitions * FFT_SIZE * BINS) as f32;
+        let adapt = mean_power > ADAPTATION_FLOOR + REGULARIZATION;
+
+        let mut output = vec![0.0; mic.len()];
+        let mut all_filters = std::mem::take(&mut self.filters);
+        for (channel, filters) in all_filters.iter_mut().enumerate() {
+            let near = mic
+                .iter()
+                .skip(channel)
+                .step_by(self.channels)
+                .copied()
+                .collect::<Vec<_>>();
+            let adapting_error = self.error(&filters.adapting, &near);
+            let stable_error = self.error(&filters.stable, &near);
+            let energy = |error: &[f32]| error.iter().map(|e| e * e).sum::<f32>();
+            filters.adapting_error = filters.adapting_error * ERROR_SMOOTHING
+                + energy(&adapting_error) * (1.0 - ERROR_SMOOTHING);
+            filters.stable_error = filters.stable_error * ERROR_SMOOTHING
+                + energy(&stable_error) * (1.0 - ERROR_SMOOTHING);
+
+            let mut error = stable_error;
+            if filters.adapting_error < filters.stable_error * TAKE_OVER_RATIO {
+                filters.stable.clone_from(&filters.adapting);
+                filters.stable_error = filters.adapting_error;
+                error.clone_from(&adapting_error);
+            } else if filters.adapting_error > filters.stable_error * DIVERGED_RATIO {
+                filters.adapting.clone_from(&filters.stable);
+                filters.adapting_error = filters.stable_error;
+            }
+            if adapt {
+                self.adapt(&mut filters.adapting, &adapting_error, &power);
+            }
+
+            for (frame, sample) in error.into_iter().enumerate() {
+                output[frame * self.channels + channel] = sample;
+            }
+        }
+        self.filters = all_filters;
+        self.constrain_next = (self.constrain_next + 1) % partitions;
+
+        self.processed.extend(
+            output
+                .into_iter()
+                .map(|sample| (sample * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16),
+        );
+    }
+
+    /// What is left of the microphone block `near` once the echo estimated
+    /// by `filter` is taken out.
+    fn error(&mut self, filter: &[Vec<Complex>], near: &[f32]) -> Vec<f32> {
+        let mut echo = [Complex::ZERO; BINS];
+        for (partition, spectrum) in filter.iter().zip(&self.reference_spectra) {
+            for ((echo, &weight), &value) in echo.iter_mut().zip(partition).zip(spectrum) {
+                *echo += weight * value;
+            }
+        }
+        self.mirror(&echo);
+        self.fft.inverse(&mut self.scratch);
+        // Overlap-save: only the second half is a valid convolution
+        near.iter()
+            .zip(&self.scratch[BLOCK_FRAMES..])
+            .map(|(&near, echo)| near - echo.re)
+            .collect()
+    }
+
+    /// Moves the filter towards cancelling what it missed, `error`, and
+    /// trims one of its partitions back to the block length.
+    fn adapt(&mut self, filter: &mut [Vec<Complex>], error: &[f32], power: &[f32]) {
+        for (i, value) in self.scratch.iter_mut().enumerate() {
+            let sample = if i < BLOCK_FRAMES {
+                0.0
+            } else {
+                error[i - BLOCK_FRAMES]
+            };
+            *value = Complex::new(sample, 0.0);
+        }
+        self.fft.forward(&mut self.scratch);
+        let gradient = self.scratch[..BINS]
+            .iter()
+            .zip(power)
+            .map(|(error, power)| error.scale(STEP_SIZE / power))
+            .collect::<Vec<_>>();
+        for (partition, spectrum) in filter.iter_mut().zip(&self.reference_spectra) {
+            for ((weight, &value), &gradient) in partition.iter_mut().zip(spectrum).zip(&gradient) {
+                *weight += gradient * value.conj();
+            }
+        }
+
+        // Leaving the partitions unconstrained is cheaper but lets them wrap
+        // around, so constrain one of them every block
+        let partition = &mut filter[self.constrain_next];
+        self.mirror(partition);
+        self.fft.inverse(&mut self.scratch);
+        for value in &mut self.scratch[BLOCK_FRAMES..] {
+            *value = Complex::ZERO;
+        }
+        for value in &mut self.scratch[..BLOCK_FRAMES] {
+            value.im = 0.0;
+        }
+        self.fft.forward(&mut self.scratch);
+        partition.copy_from_slice(&self.scratch[..BINS]);
+    }
+
+    /// Fills the scratch space with the whole spectrum of a real signal,
+    /// given its first half.
+    fn mirror(&mut self, half: &[Complex]) {
+        self.scratch[..BINS].copy_from_slice(half);
+        for (bin, value) in half.iter().enumerate().take(BLOCK_FRAMES).skip(1) {
+            self.scratch[FFT_SIZE - bin] = value.conj();
+        }
+    }
+}
+
+/// Cancels the echo in a whole recording of the microphone, `mic`, given
+/// what the speaker played at the same time, `reference`. Both are
+/// interleaved device audio, lined up sample for sample.
+pub fn cancel_recording(reference: &[i16], mic: &[i16], tail: Duration) -> Vec<i16> {
+    let frames = (reference.len().min(mic.len())) / DEVICE_CHANNELS;
+    let reference = downmix(&reference[..frames * DEVICE_CHANNELS])
+        .chain(std::iter::repeat_n(0, BLOCK_FRAMES))
+        .collect::<Vec<_>>();
+    let mut mic = mic[..frames * DEVICE_CHANNELS].to_vec();
+    mic.resize(reference.len() * DEVICE_CHANNELS, 0);
+    let mut canceller = EchoCanceller::new(DEVICE_CHANNELS, tail);
+    let mut output = canceller.process(&mic, &reference);
+    // Make up for the delay of the canceller
+    output.drain(..BLOCK_FRAMES * DEVICE_CHANNELS);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wav;
+
+    const TAIL: Duration = Duration::from_millis(64);
+
+    /// Noise shaped a little like speech, loud and quiet in turns.
+    fn far_end(frames: usize, seed: u32) -> Vec<i16> {
+        let mut state = seed;
+        let mut low_passed = 0.0;
+        (0..frames)
+            .map(|frame| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let noise = state as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                low_passed = low_passed * 0.6 + noise * 0.4;
+                // Syllables about 10 times a second
+                let envelope = 0.3 + 0.7 * (frame as f32 / 1500.0).sin().abs();
+                (low_passed * envelope * 12000.0) as i16
+            })
+            .collect()
+    }
+
+    /// What the microphone picks up of `reference` in a small room.
+    fn echo(reference: &[i16]) -> Vec<i16> {
+        let path = [(480, 0.5), (500, -0.3), (700, 0.2), (1500, 0.1)];
+        (0..reference.len())
+            .map(|frame| {
+                path.iter()
+                    .filter(|(delay, _)| frame >= *delay)
+                    .map(|(delay, gain)| reference[frame - delay] as f32 * gain)
+                    .sum::<f32>() as i16
+            })
+            .collect()
+    }
+
+    fn stereo(mono: &[i16]) -> Vec<i16> {
+        mono.iter().flat_map(|&sample| [sample; 2]).collect()
+    }
+
+    fn energy(samples: &[i16]) -> f64 {
+        samples.iter().map(|&s| s as f64 * s as f64).sum()
+    }
+
+    #[test]
+    fn echo_in_a_recording_is_cancelled() {
+        // The way the aec binary gets them
+        let dir = tempfile::tempdir().unwrap();
+        let reference = far_end(48000 * 3, 1);
+        for (name, samples) in [
+            ("reference", stereo(&reference)),
+            ("mic", stereo(&echo(&reference))),
+        ] {
+            wav::Writer::create(&dir.path().join(format!("{}.wav", name)))
+                .unwrap()
+                .write(&samples)
+                .unwrap();
+        }
+        let reference = wav::read(&dir.path().join("reference.wav")).unwrap();
+        let mic = wav::read(&dir.path().join("mic.wav")).unwrap();
+
+        let output = cancel_recording(&reference, &mic, TAIL);
+
+        assert_eq!(output.len(), mic.len());
+        // Judge the last second, once the filter has converged
+        let tail = mic.len() - 48000 * 2;
+        let enhancement = 10.0 * (energy(&mic[tail..]) / energy(&output[tail..])).log10();
+        assert!(enhancement > 30.0, "only {:.1} dB", enhancement);
+    }
+
+    #[test]
+    fn near_end_speech_gets_through() {
+        let reference = far_end(48000 * 4, 1);
+        let near = far_end(48000 * 4, 7)
+            .into_iter()
+            .enumerate()
+            // The near end only talks in the last two seconds
+            .map(|(frame, sample)| if frame < 48000 * 2 { 0 } else { sample / 2 })
+            .collect::<Vec<_>>();
+        let mic = echo(&reference)
+            .iter()
+            .zip(&near)
+            .map(|(&echo, &near)| echo.saturating_add(near))
+            .collect::<Vec<_>>();
+        let output = cancel_recording(&stereo(&reference), &stereo(&mic), TAIL);
+
+        let tail = 48000 * 3 * 2;
+        let residue = output[tail..]
+            .iter()
+            .zip(&stereo(&near)[tail..])
+            .map(|(&output, &near)| output as i32 - near as i32)
+            .map(|difference| difference as i16)
+            .collect::<Vec<_>>();
+        let echo_left = 10.0 * (energy(&stereo(&near)[tail..]) / energy(&residue)).log10();
+        assert!(echo_left > 20.0, "near end only {:.1} dB above", echo_left);
+    }
+
+    #[test]
+    fn reference_lines_up_with_the_microphone() {
+        let reference = EchoReference::default();
+        let start = Instant::now();
+        let played = (1..=480).flat_map(|i| [i; 2]).collect::<Vec<_>>();
+        reference.played(&played, start + frames_to_duration(100));
+
+        // The microphone started 100 frames before the speaker did
+        let taken = reference.take(start, 200);
+        assert!(taken[..100].iter().all(|&sample| sample == 0));
+        assert_eq!(taken[100], 1);
+        assert_eq!(taken[199], 100);
+
+        // A read that came late skips what it missed
+        let taken = reference.take(start + frames_to_duration(300), 100);
+        assert_eq!(taken[0], 201);
+        assert_eq!(reference.take(start + frames_to_duration(600), 10), [0; 10]);
+    }
+
+    #[test]
+    fn a_reference_of_the_wrong_length_is_made_to_fit() {
+        let mut canceller = EchoCanceller::new(DEVICE_CHANNELS, TAIL);
+        let mic = stereo(&far_end(1000, 3));
+
+        // Short of the microphone, then past it
+        let mut output = canceller.process(&mic[..1200], &far_end(100, 1));
+        output.extend(canceller.process(&mic[1200..], &far_end(2000, 1)));
+
+        assert_eq!(output.len(), mic.len());
+    }
+}
@@ -1,9 +1,11 @@
 use std::thread::{spawn, JoinHandle};
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
-use evdev::{Device, InputEventKind, Key};
 
-use crate::terminal_task::CallScreenCommand;
+use crate::{
+    hardware::{InputKeys, PhoneKey},
+    terminal_task::CallScreenCommand,
+};
 
 pub enum EventCommand {
     Exit,
@@ -11,11 +13,12 @@ pub enum EventCommand {
 
 pub fn create_event_task(
     main_task_queue: Sender<CallScreenCommand>,
+    keys: Box<dyn InputKeys>,
 ) -> (Sender<EventCommand>, JoinHandle<()>) {
     let (command_tx, command_rx): (Sender<EventCommand>, Receiver<EventCommand>) = unbounded();
 
     let thread = spawn(move || {
-        if let Err(e) = event_task(command_rx, main_task_queue) {
+        if let Err(e) = event_task(command_rx, main_task_queue, keys) {
             eprintln!("Error in event_task: {}", e);
         }
     });
@@ -26,10 +29,8 @@ pub fn create_event_task(
 fn event_task(
     command_receiver: Receiver<EventCommand>,
     main_task_queue: Sender<CallScreenCommand>,
+    mut keys: Box<dyn InputKeys>,
 ) -> anyhow::Result<()> {
-    // Read linux event0
-    let mut device = Device::open("/dev/input/event0")?;
-
     loop {
         if let Ok(ev) = command_receiver.try_recv() {
             match ev {
@@ -39,28 +40,16 @@ fn event_task(
             }
         }
 
-        let events = device.fetch_events()?;
-        for event in events {
-            if let InputEventKind::Key(k) = event.kind() {
-                match k {
-                    Key::KEY_UP => {
-                        main_task_queue.send(CallScreenCommand::IncreaseVolume)?;
-                    }
-                    Key::KEY_DOWN => {
-                        main_task_queue.send(CallScreenCommand::DecreaseVolume)?;
-                    }
-                    Key::KEY_MUTE => {
-                        main_task_queue.send(CallScreenCommand::ToggleMute)?;
-                    }
-                    Key::KEY_SELECT => {
-                        main_task_queue.send(CallScreenCommand::AcceptCall)?;
-                    }
-                    Key::KEY_OK => {
-                        main_task_queue.send(CallScreenCommand::StopCall)?;
-                    }
-                    _ => {}
-                }
-            }
+        for key in keys.poll()? {
+            let command = match key {
+                PhoneKey::VolumeUp => CallScreenCommand::IncreaseVolume,
+                PhoneKey::VolumeDown => CallScreenCommand::DecreaseVolume,
+                PhoneKey::Mute => CallScreenCommand::ToggleMute,
+                PhoneKey::Accept => CallScreenCommand::AcceptCall,
+                PhoneKey::HangUp => CallScreenCommand::StopCall,
+                PhoneKey::Pair => CallScreenCommand::StartPairing,
+            };
+            main_task_queue.send(command)?;
         }
     }
 
@@ -0,0 +1,176 @@
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const ZERO: Self = Self { re: 0.0, im: 0.0 };
+
+    pub fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+/// Radix-2 fast Fourier transform of a fixed power of two size.
+pub struct Fft {
+    size: usize,
+    /// `e^(-2πik/size)` for the first half of the circle.
+    twiddles: Vec<Complex>,
+    /// Where each input index ends up before the butterflies.
+    reversed: Vec<usize>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Self {
+        assert!(
+            size.is_power_of_two(),
+            "FFT size {} isn't a power of two",
+            size
+        );
+        let bits = size.trailing_zeros();
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let angle = -2.0 * std::f64::consts::PI * k as f64 / size as f64;
+                Complex::new(angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        let reversed = (0..size)
+            .map(|i| match bits {
+                0 => 0,
+                bits => i.reverse_bits() >> (usize::BITS - bits),
+            })
+            .collect();
+        Self {
+            size,
+            twiddles,
+            reversed,
+        }
+    }
+
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+
+    /// The inverse of [`Fft::forward`], including the division by the size.
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size as f32;
+        for value in data.iter_mut() {
+            *value = value.scale(scale);
+        }
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        assert_eq!(data.len(), self.size);
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= self.size {
+            let half = len / 2;
+            let step = self.size / len;
+            for start in (0..self.size).step_by(len) {
+                for k in 0..half {
+                    let twiddle = self.twiddles[k * step];
+                    let twiddle = if inverse { twiddle.conj() } else { twiddle };
+                    let even = data[start + k];
+                    let odd = data[start + k + half] * twiddle;
+                    data[start + k] = even + odd;
+                    data[start + k + half] = even - odd;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_undoes_forward() {
+        let fft = Fft::new(64);
+        let signal = (0..64)
+            .map(|i| Complex::new((i as f32 * 0.37).sin(), (i % 5) as f32))
+            .collect::<Vec<_>>();
+        let mut data = signal.clone();
+        fft.forward(&mut data);
+        fft.inverse(&mut data);
+        for (value, original) in data.iter().zip(&signal) {
+            assert!((*value - *original).norm_sqr() < 1e-8, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn sine_lands_in_its_bin() {
+        let fft = Fft::new(256);
+        let mut data = (0..256)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * 10.0 * i as f32 / 256.0;
+                Complex::new(phase.cos(), 0.0)
+            })
+            .collect::<Vec<_>>();
+        fft.forward(&mut data);
+        // Half the energy in bin 10, the other half in its mirror image
+        assert!((data[10].re - 128.0).abs() < 1e-2, "{:?}", data[10]);
+        assert!((data[246].re - 128.0).abs() < 1e-2, "{:?}", data[246]);
+        let elsewhere = data
+            .iter()
+            .enumerate()
+            .filter(|&(bin, _)| bin != 10 && bin != 246)
+            .map(|(_, value)| value.norm_sqr())
+            .fold(0.0, f32::max);
+        assert!(elsewhere < 1e-4, "{}", elsewhere);
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    fs::File,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use alsa::{
+    mixer::SelemId,
+    nix::errno::Errno,
+    pcm::{Access, Format, HwParams, State, PCM},
+    Direction, Mixer, ValueOr,
+};
+use anyhow::Context;
+use evdev::{Device, InputEventKind, Key};
+use serde::Deserialize;
+
+use crate::{
+    audio_format::{DEVICE_CHANNELS, DEVICE_SAMPLE_RATE},
+    config::{AudioConfig, LedPaths},
+    wav,
+};
+
+/// Which implementation the audio devices use.
+#[derive(Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    /// The ALSA devices of the configuration.
+    #[default]
+    Alsa,
+    /// No sound card, records silence and throws away what it plays, at the
+    /// speed a real device would.
+    Null,
+    /// Like null, but records from and plays to the configured WAV files.
+    Wav,
+}
+
+/// What came of a read from an [`AudioCapture`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaptureRead {
+    /// This many frames were read.
+    Frames(usize),
+    /// We didn't read in time and the device lost audio. It is restarted
+    /// and the next read carries on.
+    Overrun,
+}
+
+/// Records interleaved samples at [`DEVICE_SAMPLE_RATE`] and
+/// [`DEVICE_CHANNELS`].
+pub trait AudioCapture: Send {
+    /// Waits until `buffer` is full.
+    fn read(&mut self, buffer: &mut [i16]) -> anyhow::Result<CaptureRead>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PlaybackStatus {
+    /// Whether the device is playing, as opposed to stopped or run dry.
+    pub running: bool,
+    /// Frames the device can take right now.
+    pub available: usize,
+    /// Frames written but not played yet.
+    pub queued: usize,
+}
+
+/// Plays interleaved samples at [`DEVICE_SAMPLE_RATE`] and
+/// [`DEVICE_CHANNELS`].
+pub trait AudioPlayback: Send {
+    fn status(&mut self) -> anyhow::Result<PlaybackStatus>;
+    /// Queues `samples`, restarting the device first if it isn't running.
+    fn write(&mut self, samples: &[i16]) -> anyhow::Result<()>;
+    /// Throws away whatever is still queued.
+    fn stop(&mut self) -> anyhow::Result<()>;
+}
+
+pub trait VolumeControl: Send {
+    /// Sets the playback volume, from 0 to 100.
+    fn set_volume(&mut self, volume: i64) -> anyhow::Result<()>;
+}
+
+/// The hardware keys of the unit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PhoneKey {
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Accept,
+    HangUp,
+    Pair,
+}
+
+pub trait InputKeys: Send {
+    /// Waits for key presses. May return none, so the caller gets to check
+    /// for commands now and then.
+    fn poll(&mut self) -> anyhow::Result<Vec<PhoneKey>>;
+}
+
+/// Brightness of the red, green and blue parts of an LED.
+pub type LedColor = [u8; 3];
+
+pub trait Leds: Send {
+    /// Shows one color on each of the three LEDs.
+    fn show(&mut self, colors: [LedColor; 3]) -> anyhow::Result<()>;
+}
+
+fn alsa_pcm(device: &str, direction: Direction) -> anyhow::Result<PCM> {
+    let pcm = PCM::new(device, direction, false)
+        .with_context(|| format!("couldn't open ALSA device {}", device))?;
+    {
+        let hw_params = HwParams::any(&pcm)?;
+        hw_params.set_access(Access::RWInterleaved)?;
+        hw_params.set_format(Format::S16LE)?;
+        hw_params.set_channels(DEVICE_CHANNELS as u32)?;
+        hw_params.set_rate(DEVICE_SAMPLE_RATE, ValueOr::Nearest)?;
+        if direction == Direction::Playback {
+            hw_params.set_buffer_size_near(PLAYBACK_BUFFER_FRAMES as alsa::pcm::Frames)?;
+        }
+        pcm.hw_params(&hw_params)?;
+    }
+    Ok(pcm)
+}
+
+/// Keep the device buffer short so the jitter buffer decides the delay.
+pub const PLAYBACK_BUFFER_FRAMES: usize = 4800;
+
+pub struct AlsaCapture {
+    pcm: PCM,
+}
+
+impl AlsaCapture {
+    pub fn open(device: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            pcm: alsa_pcm(device, Direction::Capture)?,
+        })
+    }
+}
+
+impl AudioCapture for AlsaCapture {
+    fn read(&mut self, buffer: &mut [i16]) -> anyhow::Result<CaptureRead> {
+        match self.pcm.io_i16()?.readi(buffer) {
+            Ok(frames) => Ok(CaptureRead::Frames(frames)),
+            // The device stops once it overruns, until prepared again
+            Err(error) if error.errno() == Errno::EPIPE => {
+                self.pcm.prepare()?;
+                Ok(CaptureRead::Overrun)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+pub struct AlsaPlayback {
+    pcm: PCM,
+    buffer_frames: usize,
+}
+
+impl AlsaPlayback {
+    pub fn open(device: &str) -> anyhow::Result<Self> {
+        let pcm = alsa_pcm(device, Direction::Playback)?;
+        let buffer_frames = pcm.hw_params_current()?.get_buffer_size()? as usize;
+        Ok(Self { pcm, buffer_frames })
+    }
+}
+
+impl AudioPlayback for AlsaPlayback {
+    fn status(&mut self) -> anyhow::Result<PlaybackStatus> {
+        let status = self.pcm.status()?;
+        let available = (status.get_avail() as usize).min(self.buffer_frames);
+        Ok(PlaybackStatus {
+            running: status.get_state() == State::Running,
+            available,
+            queued: self.buffer_frames - available,
+        })
+    }
+
+    fn write(&mut self, samples: &[i16]) -> anyhow::Result<()> {
+        if self.pcm.state() != State::Running {
+            self.pcm.prepare()?;
+        }
+        self.pcm.io_i16()?.writei(samples)?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        Ok(self.pcm.drop()?)
+    }
+}
+
+pub struct AlsaVolume {
+    mixer: Mixer,
+    element: SelemId,
+}
+
+impl AlsaVolume {
+    pub fn open(device: &str, element: &str) -> anyhow::Result<Self> {
+        let mixer =
+            Mixer::new(device, false).with_context(|| format!("couldn't open mixer {}", device))?;
+        let element = SelemId::new(element, 0);
+        mixer
+            .find_selem(&element)
+            .with_context(|| {
+                format!(
+                    "mixer {} has no element {}",
+                    device,
+                    element.get_name().unwrap_or_default()
+                )
+            })?
+            .set_playback_volume_range(0, 100)?;
+        Ok(Self { mixer, element })
+    }
+}
+
+impl VolumeControl for AlsaVolume {
+    fn set_volume(&mut self, volume: i64) -> anyhow::Result<()> {
+        let selem = self
+            .mixer
+            .find_selem(&self.element)
+            .context("mixer element went away")?;
+        Ok(selem.set_playback_volume_all(volume)?)
+    }
+}
+
+pub struct AudioDevices {
+    pub capture: Box<dyn AudioCapture>,
+    pub playback: Box<dyn AudioPlayback>,
+    pub volume: Box<dyn VolumeControl>,
+}
+
+impl AudioDevices {
+    /// Opens the audio devices the configuration asks for.
+    pub fn open(config: &AudioConfig) -> anyhow::Result<Self> {
+        Ok(match config.backend {
+            AudioBackend::Alsa => Self {
+                capture: Box::new(AlsaCapture::open(&config.input_device)?),
+                playback: Box::new(AlsaPlayback::open(&config.output_device)?),
+                volume: Box::new(AlsaVolume::open(
+                    &config.mixer_device,
+                    &config.mixer_element,
+                )?),
+            },
+            AudioBackend::Null => Self {
+                capture: Box::new(MemoryCapture::new(Vec::new())),
+                playback: Box::new(SimulatedPlayback::memory().0),
+                volume: Box::new(NullVolume),
+            },
+            AudioBackend::Wav => Self {
+                capture: Box::new(match &config.capture_file {
+                    Some(path) => MemoryCapture::new(wav::read(path)?),
+                    None => MemoryCapture::new(Vec::new()),
+                }),
+                playback: Box::new(match &config.playback_file {
+                    Some(path) => SimulatedPlayback::wav(path)?,
+                    None => SimulatedPlayback::memory().0,
+                }),
+                volume: Box::new(NullVolume),
+            },
+        })
+    }
+}
+
+pub fn frames_to_duration(frames: u64) -> Duration {
+    Duration::from_micros(frames * 1_000_000 / DEVICE_SAMPLE_RATE as u64)
+}
+
+fn duration_to_frames(duration: Duration) -> u64 {
+    (duration.as_micros() * DEVICE_SAMPLE_RATE as u128 / 1_000_000) as u64
+}
+
+/// Records the samples it was given, then silence, as fast as a sound card
+/// would. With no samples it is the null capture device.
+pub struct MemoryCapture {
+    samples: Vec<i16>,
+    position: usize,
+    started: Option<Instant>,
+    frames_read: u64,
+}
+
+impl MemoryCapture {
+    pub fn new(samples: Vec<i16>) -> Self {
+        Self {
+            samples,
+            position: 0,
+            started: None,
+            frames_read: 0,
+        }
+    }
+}
+
+impl AudioCapture for MemoryCapture {
+    fn read(&mut self, buffer: &mut [i16]) -> anyhow::Result<CaptureRead> {
+        let frames = buffer.len() / DEVICE_CHANNELS;
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.frames_read += frames as u64;
+        let ready_at = started + frames_to_duration(self.frames_read);
+        std::thread::sleep(ready_at.saturating_duration_since(Instant::now()));
+
+        let remaining = &self.samples[self.position..];
+        let copied = remaining.len().min(buffer.len());
+        buffer[..copied].copy_from_slice(&remaining[..copied]);
+        buffer[copied..].fill(0);
+        self.position += copied;
+        Ok(CaptureRead::Frames(frames))
+    }
+}
+
+/// Where a playback device without a sound card puts what it played.
+enum PlaybackSink {
+    Memory(Arc<Mutex<Vec<i16>>>),
+    Wav(wav::Writer),
+}
+
+/// Plays into memory or a WAV file as fast as a sound card would.
+pub struct SimulatedPlayback {
+    sink: PlaybackSink,
+    /// When the device last started from empty.
+    started: Instant,
+    /// Frames written since then.
+    written: u64,
+}
+
+impl SimulatedPlayback {
+    fn new(sink: PlaybackSink) -> Self {
+        Self {
+            sink,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Keeps everything played for whoever holds the other end. Dropping
+    /// that end makes it the null playback device.
+    pub fn memory() -> (Self, Arc<Mutex<Vec<i16>>>) {
+        let played = Arc::new(Mutex::new(Vec::new()));
+        let playback = Self::new(PlaybackSink::Memory(played.clone()));
+        (playback, played)
+    }
+
+    pub fn wav(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(PlaybackSink::Wav(wav::Writer::create(path)?)))
+    }
+
+    fn queued(&self) -> usize {
+        let played = duration_to_frames(self.started.elapsed());
+        self.written.saturating_sub(played) as usize
+    }
+}
+
+impl AudioPlayback for SimulatedPlayback {
+    fn status(&mut self) -> anyhow::Result<PlaybackStatus> {
+        let queued = self.queued();
+        Ok(PlaybackStatus {
+            running: queued > 0,
+            available: PLAYBACK_BUFFER_FRAMES.saturating_sub(queued),
+            queued,
+        })
+    }
+
+    fn write(&mut self, samples: &[i16]) -> anyhow::Result<()> {
+        if self.queued() == 0 {
+            self.started = Instant::now();
+            self.written = 0;
+        }
+        self.written += (samples.len() / DEVICE_CHANNELS) as u64;
+        match &mut self.sink {
+            // Only someone listening cares what was played
+            PlaybackSink::Memory(played) if Arc::strong_count(played) > 1 => {
+                played.lock().unwrap().extend_from_slice(samples);
+            }
+            PlaybackSink::Memory(_) => {}
+            PlaybackSink::Wav(writer) => writer.write(samples)?,
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.written = 0;
+        Ok(())
+    }
+}
+
+pub struct NullVolume;
+
+impl VolumeControl for NullVolume {
+    fn set_volume(&mut self, _volume: i64) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct EvdevKeys {
+    device: Device,
+}
+
+impl EvdevKeys {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let device = Device::open(path)
+            .with_context(|| format!("couldn't open keys device {}", path.display()))?;
+        Ok(Self { device })
+    }
+}
+
+impl InputKeys for EvdevKeys {
+    fn poll(&mut self) -> anyhow::Result<Vec<PhoneKey>> {
+        let keys = self
+            .device
+            .fetch_events()?
+            .filter_map(|event| match event.kind() {
+                InputEventKind::Key(Key::KEY_UP) => Some(PhoneKey::VolumeUp),
+                InputEventKind::Key(Key::KEY_DOWN) => Some(PhoneKey::VolumeDown),
+                InputEventKind::Key(Key::KEY_MUTE) => Some(PhoneKey::Mute),
+                InputEventKind::Key(Key::KEY_SELECT) => Some(PhoneKey::Accept),
+                InputEventKind::Key(Key::KEY_OK) => Some(PhoneKey::HangUp),
+                InputEventKind::Key(Key::KEY_CONNECT) => Some(PhoneKey::Pair),
+                _ => None,
+            })
+            .collect();
+        Ok(keys)
+    }
+}
+
+/// Keys pressed by sending them down a channel.
+#[cfg(test)]
+pub struct ChannelKeys {
+    receiver: crossbeam::channel::Receiver<PhoneKey>,
+}
+
+#[cfg(test)]
+impl ChannelKeys {
+    pub fn new(receiver: crossbeam::channel::Receiver<PhoneKey>) -> Self {
+        Self { receiver }
+    }
+}
+
+#[cfg(test)]
+impl InputKeys for ChannelKeys {
+    fn poll(&mut self) -> anyhow::Result<Vec<PhoneKey>> {
+        let Ok(key) = self.receiver.recv_timeout(Duration::from_millis(100)) else {
+            return Ok(Vec::new());
+        };
+        Ok(std::iter::once(key)
+            .chain(self.receiver.try_iter())
+            .collect())
+    }
+}
+
+/// The LEDs' sysfs brightness files.
+pub struct SysfsLeds {
+    files: Vec<[File; 3]>,
+}
+
+impl SysfsLeds {
+    pub fn open(leds: &[LedPaths]) -> anyhow::Result<Self> {
+        let open = |path: &Path| {
+            File::options()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("couldn't open LED {}", path.display()))
+        };
+        let files = leds
+            .iter()
+            .map(|led| Ok([open(&led.red)?, open(&led.green)?, open(&led.blue)?]))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { files })
+    }
+}
+
+impl Leds for SysfsLeds {
+    fn show(&mut self, colors: [LedColor; 3]) -> anyhow::Result<()> {
+        for (files, color) in self.files.iter().zip(colors) {
+            for (file, brightness) in files.iter().zip(color) {
+                file.write_all_at(brightness.to_string().as_bytes(), 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// LEDs nobody sees, except whoever holds the other end.
+pub struct MemoryLeds {
+    colors: Arc<Mutex<[LedColor; 3]>>,
+}
+
+impl MemoryLeds {
+    pub fn new() -> (Self, Arc<Mutex<[LedColor; 3]>>) {
+        let colors = Arc::new(Mutex::new([[0; 3]; 3]));
+        (
+            Self {
+                colors: colors.clone(),
+            },
+            colors,
+        )
+    }
+}
+
+impl Leds for MemoryLeds {
+    fn show(&mut self, colors: [LedColor; 3]) -> anyhow::Result<()> {
+        *self.colors.lock().unwrap() = colors;
+        Ok(())
+    }
+}
+
+/// Opens the configured LEDs, or stand-ins when the unit has none.
+pub fn open_leds(leds: &[LedPaths]) -> anyhow::Result<Box<dyn Leds>> {
+    if leds.is_empty() {
+        Ok(Box::new(MemoryLeds::new().0))
+    } else {
+        // Catch a wrong path or missing permission now rather than on the
+        // first ring
+        let leds = SysfsLeds::open(leds)
+            .context("leds: can't drive the LEDs, fix the paths or run with --no-leds")?;
+        Ok(Box::new(leds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_capture_records_at_the_device_rate() {
+        let mut capture = MemoryCapture::new(vec![7; 3 * DEVICE_CHANNELS]);
+        // 10 ms of audio
+        let mut buffer = vec![1; DEVICE_SAMPLE_RATE as usize / 100 * DEVICE_CHANNELS];
+
+        let start = Instant::now();
+        assert_eq!(
+            capture.read(&mut buffer).unwrap(),
+            CaptureRead::Frames(buffer.len() / 2)
+        );
+        assert_eq!(buffer[..3 * DEVICE_CHANNELS], [7; 3 * DEVICE_CHANNELS]);
+        assert!(buffer[3 * DEVICE_CHANNELS..].iter().all(|&s| s == 0));
+        capture.read(&mut buffer).unwrap();
+        assert!(buffer.iter().all(|&s| s == 0));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn memory_playback_drains_like_a_device() {
+        let (mut playback, played) = SimulatedPlayback::memory();
+        assert!(!playback.status().unwrap().running);
+
+        // 20 ms of audio
+        let samples = vec![5; DEVICE_SAMPLE_RATE as usize / 50 * DEVICE_CHANNELS];
+        playback.write(&samples).unwrap();
+        let status = playback.status().unwrap();
+        assert!(status.running);
+        assert!(status.queued <= samples.len() / DEVICE_CHANNELS);
+        assert_eq!(status.available + status.queued, PLAYBACK_BUFFER_FRAMES);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(playback.status().unwrap().queued, 0);
+        assert_eq!(*played.lock().unwrap(), samples);
+
+        playback.write(&samples).unwrap();
+        playback.stop().unwrap();
+        assert!(!playback.status().unwrap().running);
+    }
+
+    #[test]
+    fn channel_keys_come_out_in_order() {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut keys = ChannelKeys::new(receiver);
+        assert!(keys.poll().unwrap().is_empty());
+        sender.send(PhoneKey::Accept).unwrap();
+        sender.send(PhoneKey::HangUp).unwrap();
+        assert_eq!(
+            keys.poll().unwrap(),
+            vec![PhoneKey::Accept, PhoneKey::HangUp]
+        );
+    }
+
+    #[test]
+    fn sysfs_leds_write_the_brightness_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = (0..3)
+            .map(|i| {
+                let path = |color: &str| {
+                    let path = dir.path().join(format!("{}{}", color, i));
+                    std::fs::write(&path, "").unwrap();
+                    path
+                };
+                LedPaths {
+                    red: path("red"),
+                    green: path("green"),
+                    blue: path("blue"),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut leds = SysfsLeds::open(&paths).unwrap();
+        leds.show([[128, 0, 0], [0, 128, 0], [0, 0, 128]]).unwrap();
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&paths[0].red), "128");
+        assert_eq!(read(&paths[0].green), "0");
+        assert_eq!(read(&paths[2].blue), "128");
+
+        std::fs::remove_file(&paths[1].green).unwrap();
+        let error = SysfsLeds::open(&paths).err().unwrap().to_string();
+        assert!(error.contains("green1"), "{}", error);
+        let error = format!("{:#}", open_leds(&paths).err().unwrap());
+        assert!(error.starts_with("leds: "), "{}", error);
+        assert!(error.contains("green1"), "{}", error);
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Once this much is waiting for a bandwidth limited link, further datagrams
+/// are dropped like a router's full queue would.
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+/// Big enough for any datagram the units send.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// What happens to the datagrams going one way through the link. The
+/// default passes them through untouched.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Impairments {
+    /// Fraction of datagrams dropped, from 0 to 1.
+    pub loss: f64,
+    /// Added to every datagram.
+    pub delay: Duration,
+    /// Each datagram is delayed by up to this much more or less than
+    /// `delay`, picked uniformly. Enough of it reorders datagrams.
+    pub jitter: Duration,
+    /// Fraction of datagrams delivered twice.
+    pub duplicate: f64,
+    /// Fraction of datagrams held back by `reorder_delay` on top of their
+    /// delay, so the ones sent after them overtake them.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// Bits per second the link carries, unlimited if `None`.
+    pub bandwidth: Option<u64>,
+}
+
+/// What the link did to the datagrams going one way.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ImpairmentStats {
+    pub received: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Dropped because the bandwidth limited queue was full.
+    pub overflowed: u64,
+}
+
+impl fmt::Display for ImpairmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in, {} out, {} lost, {} duplicated, {} reordered, {} overflowed",
+            self.received,
+            self.delivered,
+            self.lost,
+            self.duplicated,
+            self.reordered,
+            self.overflowed
+        )
+    }
+}
+
+/// Small deterministic generator, so a failing run can be repeated with the
+/// same seed.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Zero would only ever produce zero
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1).
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.fraction() < probability
+    }
+}
+
+/// Decides when, if ever, each datagram going one way comes out of the
+/// link. Knows nothing about sockets, so tests can drive it with their own
+/// clock.
+pub struct Impairer {
+    pub impairments: Impairments,
+    rng: XorShift,
+    /// Datagrams on their way, by when they come out and then in the order
+    /// they went in.
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    next_order: u64,
+    /// When the bandwidth limited link has finished sending what it has.
+    link_free_at: Option<Instant>,
+    pub stats: ImpairmentStats,
+}
+
+impl Impairer {
+    pub fn new(impairments: Impairments, seed: u64) -> Self {
+        Self {
+            impairments,
+            rng: XorShift::new(seed),
+            queue: BinaryHeap::new(),
+            next_order: 0,
+            link_free_at: None,
+            stats: ImpairmentStats::default(),
+        }
+    }
+
+    /// Takes a datagram sent at `now`.
+    pub fn push(&mut self, datagram: Vec<u8>, now: Instant) {
+        self.stats.received += 1;
+        if self.rng.chance(self.impairments.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let mut departure = now;
+        if let Some(bandwidth) = self.impairments.bandwidth {
+            let link_free_at = self.link_free_at.unwrap_or(now).max(now);
+            if link_free_at - now > MAX_QUEUE_DELAY {
+                self.stats.overflowed += 1;
+                return;
+            }
+            let bits = datagram.len() as u64 * 8;
+            let transmission = Duration::from_secs_f64(bits as f64 / bandwidth.max(1) as f64);
+            departure = link_free_at + transmission;
+            self.link_free_at = Some(departure);
+        }
+
+        let copies = if self.rng.chance(self.impairments.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let held_back = self.rng.chance(self.impairments.reorder);
+        if held_back {
+            self.stats.reordered += 1;
+        }
+        for _ in 0..copies {
+            let mut arrival = departure + self.latency();
+            if held_back {
+                arrival += self.impairments.reorder_delay;
+            }
+            self.queue
+                .push(Reverse((arrival, self.next_order, datagram.clone())));
+            self.next_order += 1;
+        }
+    }
+
+    /// Delay plus jitter for one datagram.
+    fn latency(&mut self) -> Duration {
+        let jitter = self.impairments.jitter.as_secs_f64();
+        let offset = (self.rng.fraction() * 2.0 - 1.0) * jitter;
+        Duration::from_secs_f64((self.impairments.delay.as_secs_f64() + offset).max(0.0))
+    }
+
+    /// The next datagram due to come out by `now`.
+    pub fn pop(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.next_due()? > now {
+            return None;
+        }
+        let Reverse((_, _, datagram)) = self.queue.pop()?;
+        self.stats.delivered += 1;
+        Some(datagram)
+    }
+
+    /// When the next datagram comes out.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((due, _, _))| *due)
+    }
+}
+
+/// Which way through the proxy datagrams are going.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// From whoever sends to the proxy to the target.
+    ToTarget,
+    /// From the target back.
+    FromTarget,
+}
+
+/// A UDP relay that impairs what it forwards. Whatever is sent to
+/// [`ImpairmentProxy::address`] goes to the target, and what the target
+/// sends back goes to the last unit that sent something, so a unit calling
+/// the proxy is in a call with the target.
+pub struct ImpairmentProxy {
+    pub address: SocketAddr,
+    /// Where the target's datagrams come in.
+    back_address: SocketAddr,
+    to_target: Arc<Mutex<Impairer>>,
+    from_target: Arc<Mutex<Impairer>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ImpairmentProxy {
+    /// Listens on `listen` and relays to `target`, with a generator seeded
+    /// by `seed` for each direction.
+    pub fn start(
+        listen: SocketAddr,
+        target: SocketAddr,
+        to_target: Impairments,
+        from_target: Impairments,
+        seed: u64,
+    ) -> io::Result<Self> {
+        let front = UdpSocket::bind(listen)?;
+        // The target sees us on another port, so it can tell us apart from
+        // whoever we relay for
+        let back = UdpSocket::bind(SocketAddr::new(listen.ip(), 0))?;
+        let address = front.local_addr()?;
+        let back_address = back.local_addr()?;
+        let to_target = Arc::new(Mutex::new(Impairer::new(to_target, seed)));
+        let from_target = Arc::new(Mutex::new(Impairer::new(
+            from_target,
+            seed.rotate_left(32) ^ 0x9E37_79B9_7F4A_7C15,
+        )));
+        let stop = Arc::new(AtomicBool::new(false));
+        // Who the target's datagrams go back to
+        let client = Arc::new(Mutex::new(None));
+
+        let relays = [
+            Relay {
+                input: front.try_clone()?,
+                output: back.try_clone()?,
+                link: to_target.clone(),
+                stop: stop.clone(),
+            }
+            .spawn(
+                {
+                    let client = client.clone();
+                    move |from| {
+                        *client.lock().unwrap() = Some(from);
+                        true
+                    }
+                },
+                move || Some(target),
+            ),
+            Relay {
+                input: back,
+                output: front,
+                link: from_target.clone(),
+                stop: stop.clone(),
+            }
+            .spawn(move |from| from == target, move || *client.lock().unwrap()),
+        ];
+        Ok(Self {
+            address,
+            back_address,
+            to_target,
+            from_target,
+            stop,
+            threads: relays.into(),
+        })
+    }
+
+    pub fn set_impairments(&self, direction: Direction, impairments: Impairments) {
+        let link = match direction {
+            Direction::ToTarget => &self.to_target,
+            Direction::FromTarget => &self.from_target,
+        };
+        link.lock().unwrap().impairments = impairments;
+    }
+
+    /// What happened to the datagrams going to and coming from the target.
+    pub fn stats(&self) -> (ImpairmentStats, ImpairmentStats) {
+        (
+            self.to_target.lock().unwrap().stats,
+            self.from_target.lock().unwrap().stats,
+        )
+    }
+}
+
+impl Drop for ImpairmentProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake the relays up from waiting for a datagram
+        if let Ok(waker) = UdpSocket::bind(SocketAddr::new(self.address.ip(), 0)) {
+            let _ = waker.send_to(&[], self.address);
+            let _ = waker.send_to(&[], self.back_address);
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Carries the datagrams going one way through the proxy, sleeping in the
+/// socket until one comes in or the next one is due to go out.
+struct Relay {
+    input: UdpSocket,
+    output: UdpSocket,
+    link: Arc<Mutex<Impairer>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Relay {
+    /// Relays what `accept` lets in to wherever `destination` says.
+    fn spawn(
+        self,
+        accept: impl FnMut(SocketAddr) -> bool + Send + 'static,
+        destination: impl Fn() -> Option<SocketAddr> + Send + 'static,
+    ) -> JoinHandle<()> {
+        spawn(move || {
+            if let Err(e) = self.run(accept, destination) {
+                eprintln!("Error in impairment proxy: {}", e);
+            }
+        })
+    }
+
+    fn run(
+        &self,
+        mut accept: impl FnMut(SocketAddr) -> bool,
+        destination: impl Fn() -> Option<SocketAddr>,
+    ) -> io::Result<()> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        while !self.stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            let next_due = {
+                let mut link = self.link.lock().unwrap();
+                while let Some(datagram) = link.pop(now) {
+                    if let Some(to) = destination() {
+                        self.output.send_to(&datagram, to)?;
+                    }
+                }
+                link.next_due()
+            };
+            // A zero timeout would mean none at all
+            let timeout = next_due.map(|due| {
+                due.saturating_duration_since(now)
+                    .max(Duration::from_micros(100))
+            });
+            self.input.set_read_timeout(timeout)?;
+            match self.input.recv_from(&mut buffer) {
+                Ok(_) if self.stop.load(Ordering::Relaxed) => break,
+                Ok((len, from)) if accept(from) => {
+                    let datagram = buffer[..len].to_vec();
+                    self.link.lock().unwrap().push(datagram, Instant::now());
+                }
+                Ok(_) => {}
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `count` datagrams 10 ms apart and returns the ones that came
+    /// out, with how long after the first was sent.
+    fn run(impairer: &mut Impairer, count: u32) -> Vec<(Duration, u32)> {
+        let start = Instant::now();
+        for i in 0..count {
+            let sent = start + Duration::from_millis(10) * i;
+            impairer.push(i.to_le_bytes().to_vec(), sent);
+        }
+        let mut delivered = Vec::new();
+        while let Some(due) = impairer.next_due() {
+            let datagram = impairer.pop(due).unwrap();
+            let number = u32::from_le_bytes(datagram.try_into().unwrap());
+            delivered.push((due - start, number));
+        }
+        delivered
+    }
+
+    #[test]
+    fn default_link_is_transparent() {
+        let mut impairer = Impairer::new(Impairments::default(), 1);
+        let delivered = run(&mut impairer, 100);
+        assert_eq!(delivered.len(), 100);
+        for (i, (when, number)) in delivered.into_iter().enumerate() {
+            assert_eq!(number, i as u32);
+            assert_eq!(when, Duration::from_millis(10) * i as u32);
+        }
+    }
+
+    #[test]
+    fn datagrams_are_held_until_due() {
+        let mut impairer = Impairer::new(
+            Impairments {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            },
+            1,
+        );
+        let now = Instant::now();
+        impairer.push(vec![1], now);
+        assert_eq!(impairer.pop(now + Duration::from_millis(49)), None);
+        assert_eq!(impairer.pop(now + Duration::from_millis(50)), Some(vec![1]));
+        assert_eq!(impairer.pop(now + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn loss_and_duplication_hit_about_the_configured_share() {
+        let mut impairer = Impairer::new(
+            Impairments {
+                loss: 0.2,
+                duplicate: 0.1,
+                ..Default::default()
+            },
+            42,
+        );
+        let delivered = run(&mut impairer, 10000);
+        let stats = impairer.stats;
+        assert!((1800..2200).contains(&stats.lost), "{}", stats);
+        assert!((700..900).contains(&stats.duplicated), "{}", stats);
+        assert_eq!(
+            delivered.len() as u64,
+            10000 - stats.lost + stats.duplicated
+        );
+        assert_eq!(stats.delivered, delivered.len() as u64);
+    }
+
+    #[test]
+    fn jitter_and_reordering_shuffle_but_stay_in_bounds() {
+        let mut impairer = Impairer::new(
+            Impairments {
+                delay: Duration::from_millis(40),
+                jitter: Duration::from_millis(15),
+                reorder: 0.05,
+                reorder_delay: Duration::from_millis(30),
+                ..Default::default()
+            },
+            7,
+        );
+        let delivered = run(&mut impairer, 1000);
+        assert_eq!(delivered.len(), 1000);
+        for &(when, number) in &delivered {
+            let latency = when - Duration::from_millis(10) * number;
+            assert!(latency >= Duration::from_millis(25), "{:?}", latency);
+            assert!(latency <= Duration::from_millis(85), "{:?}", latency);
+        }
+        let out_of_order = delivered
+            .windows(2)
+            .filter(|pair| pair[1].1 < pair[0].1)
+            .count();
+        assert!(out_of_order > 0);
+        assert!(impairer.stats.reordered > 0);
+    }
+
+    #[test]
+    fn bandwidth_limit_spaces_datagrams_and_drops_when_full() {
+        // 100 bytes every 10 ms is 80 kbit/s, over a 40 kbit/s link
+        let mut impairer = Impairer::new(
+            Impairments {
+                bandwidth: Some(40_000),
+                ..Default::default()
+            },
+            1,
+        );
+        let start = Instant::now();
+        for i in 0..400 {
+            impairer.push(vec![0; 100], start + Duration::from_millis(10) * i);
+        }
+        let mut times = Vec::new();
+        while let Some(due) = impairer.next_due() {
+            impairer.pop(due).unwrap();
+            times.push(due);
+        }
+        for pair in times.windows(2) {
+            assert_eq!(pair[1] - pair[0], Duration::from_millis(20));
+        }
+        assert!(impairer.stats.overflowed > 100, "{}", impairer.stats);
+        let last_sent = Duration::from_millis(10) * 399;
+        assert!(
+            *times.last().unwrap() - start
+                <= last_sent + MAX_QUEUE_DELAY + Duration::from_millis(20)
+        );
+    }
+
+    #[test]
+    fn proxy_relays_both_ways() {
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let proxy = ImpairmentProxy::start(
+            "127.0.0.1:0".parse().unwrap(),
+            target.local_addr().unwrap(),
+            Impairments::default(),
+            Impairments::default(),
+            1,
+        )
+        .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut buffer = [0; 16];
+        client.send_to(b"ping", proxy.address).unwrap();
+        let (len, relay) = target.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_ne!(relay, proxy.address);
+        target.send_to(b"pong", relay).unwrap();
+        let (len, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+        assert_eq!(from, proxy.address);
+
+        proxy.set_impairments(
+            Direction::ToTarget,
+            Impairments {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+        client.send_to(b"lost", proxy.address).unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(target.recv_from(&mut buffer).is_err());
+        let (to_target, from_target) = proxy.stats();
+        assert_eq!((to_target.received, to_target.lost), (2, 1));
+        assert_eq!(from_target.delivered, 1);
+    }
+}
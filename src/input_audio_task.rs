@@ -1,64 +1,106 @@
-use std::thread::JoinHandle;
-
-use alsa::{
-    pcm::{HwParams, PCM},
-    Direction,
+use std::{
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
+
 use crossbeam::channel::{unbounded, Receiver, Sender};
 
-use crate::network_thread::NetworkTaskCommand;
+use crate::{
+    audio_format::DEVICE_CHANNELS,
+    call_stats::QualityMonitor,
+    echo_canceller::{EchoCanceller, EchoReference},
+    hardware::{frames_to_duration, AudioCapture, CaptureRead},
+    network_thread::NetworkTaskCommand,
+    voice_processing::{VoiceProcessor, VoiceSettings},
+};
+
+/// Roughly how long captured audio waits in the device before we read it.
+/// Erring on the long side only costs a little of the echo tail.
+const CAPTURE_LATENCY: Duration = Duration::from_millis(10);
 
 pub enum InputAudioCommand {
     Start,
     Stop,
+    /// Changes noise suppression and gain control, in or out of a call.
+    SetVoice(VoiceSettings),
     Exit,
 }
 
-const INPUT_HARDWARE_NAME: &str = "plughw:0";
-// const INPUT_HARDWARE_NAME: &str = "default";
+/// What is done to the captured audio before it is sent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessingSettings {
+    /// Longest echo of the speaker to cancel, `None` to leave echoes in.
+    pub echo_tail: Option<Duration>,
+    pub voice: VoiceSettings,
+}
 
 fn input_audio_task(
     command_receiver: Receiver<InputAudioCommand>,
     output_audio_sender: Sender<NetworkTaskCommand>,
+    mut capture: Box<dyn AudioCapture>,
+    mut settings: ProcessingSettings,
+    echo_reference: EchoReference,
+    quality: QualityMonitor,
 ) -> anyhow::Result<()> {
-    let pcm = PCM::new(INPUT_HARDWARE_NAME, Direction::Capture, false)?;
-
-    let hw_params = HwParams::any(&pcm)?;
-    hw_params.set_access(alsa::pcm::Access::RWInterleaved)?;
-    hw_params.set_format(alsa::pcm::Format::S16LE)?;
-    hw_params.set_channels(2)?;
-    hw_params.set_rate(48000, alsa::ValueOr::Nearest)?;
-    pcm.hw_params(&hw_params)?;
-
-    let io = pcm.io_i16()?;
-    let mut buffer = vec![0; 400 * 2];
+    let mut buffer = vec![0; 400 * DEVICE_CHANNELS];
+    let mut echo_canceller: Option<EchoCanceller> = None;
+    let mut voice_processor: Option<VoiceProcessor> = None;
 
     let mut record = false;
 
     loop {
-        match io.readi(&mut buffer) {
-            Ok(read) => {
+        match capture.read(&mut buffer) {
+            Ok(CaptureRead::Frames(read)) => {
+                let now = Instant::now();
+                let captured_at = now
+                    .checked_sub(frames_to_duration(read as u64) + CAPTURE_LATENCY)
+                    .unwrap_or(now);
+                // Keep the reference moving even when not in a call
+                let reference = echo_reference.take(captured_at, buffer.len() / DEVICE_CHANNELS);
                 if record {
-                    output_audio_sender.send(NetworkTaskCommand::SendAudio(buffer.clone()))?;
+                    let audio = match &mut echo_canceller {
+                        Some(echo_canceller) => echo_canceller.process(&buffer, &reference),
+                        None => buffer.clone(),
+                    };
+                    let audio = match &mut voice_processor {
+                        Some(voice_processor) => voice_processor.process(audio),
+                        None => audio,
+                    };
+                    output_audio_sender.send(NetworkTaskCommand::SendAudio(audio))?;
                     // Set buffer to 0
-                    for i in 0..read {
-                        buffer[i] = 0;
-                    }
+                    buffer[..read].fill(0);
                 }
             }
-            Err(e) => {
-                // Try to recover
-                println!("Error reading audio: {}", e);
+            Ok(CaptureRead::Overrun) => {
+                if record {
+                    quality.update(|quality| quality.capture_xruns += 1);
+                }
+            }
+            Err(_) => {
+                // Give the device a moment rather than spin on it
+                std::thread::sleep(frames_to_duration((buffer.len() / DEVICE_CHANNELS) as u64));
             }
         }
         match command_receiver.try_recv() {
             Ok(InputAudioCommand::Start) => {
                 record = true;
+                // Every call starts learning the echo afresh
+                echo_canceller = settings
+                    .echo_tail
+                    .map(|tail| EchoCanceller::new(DEVICE_CHANNELS, tail));
+                voice_processor = Some(VoiceProcessor::new(DEVICE_CHANNELS, settings.voice));
+                quality.update(|quality| quality.clear_capture());
             }
             Ok(InputAudioCommand::Stop) => {
                 // Stop
                 record = false;
             }
+            Ok(InputAudioCommand::SetVoice(voice)) => {
+                settings.voice = voice;
+                if let Some(voice_processor) = &mut voice_processor {
+                    voice_processor.set_settings(voice);
+                }
+            }
             Ok(InputAudioCommand::Exit) => {
                 break;
             }
@@ -73,14 +115,88 @@ fn input_audio_task(
 
 pub fn create_input_audio_task(
     network_sender: Sender<NetworkTaskCommand>,
+    capture: Box<dyn AudioCapture>,
+    settings: ProcessingSettings,
+    echo_reference: EchoReference,
+    quality: QualityMonitor,
 ) -> anyhow::Result<(JoinHandle<()>, Sender<InputAudioCommand>)> {
     let (command_sender, command_receiver) = unbounded::<InputAudioCommand>();
 
     let handle = std::thread::spawn(move || {
-        if let Err(e) = input_audio_task(command_receiver, network_sender) {
+        if let Err(e) = input_audio_task(
+            command_receiver,
+            network_sender,
+            capture,
+            settings,
+            echo_reference,
+            quality,
+        ) {
             eprintln!("Error in input audio task: {}", e);
         }
     });
 
     Ok((handle, command_sender))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hardware::MemoryCapture;
+    use std::collections::VecDeque;
+
+    /// Silence, with the given outcomes in place of some reads once
+    /// recording has had time to start.
+    struct FlakyCapture {
+        silence: MemoryCapture,
+        reads: usize,
+        hiccups: VecDeque<anyhow::Result<CaptureRead>>,
+    }
+
+    impl AudioCapture for FlakyCapture {
+        fn read(&mut self, buffer: &mut [i16]) -> anyhow::Result<CaptureRead> {
+            let read = self.silence.read(buffer);
+            self.reads += 1;
+            match self.hiccups.pop_front() {
+                Some(hiccup) if self.reads > 5 => hiccup,
+                Some(hiccup) => {
+                    self.hiccups.push_front(hiccup);
+                    read
+                }
+                None => read,
+            }
+        }
+    }
+
+    #[test]
+    fn overruns_are_counted_once_and_read_errors_not_at_all() {
+        let capture = FlakyCapture {
+            silence: MemoryCapture::new(Vec::new()),
+            reads: 0,
+            hiccups: VecDeque::from(vec![
+                Ok(CaptureRead::Overrun),
+                Err(anyhow::anyhow!("device unplugged")),
+                Err(anyhow::anyhow!("device unplugged")),
+            ]),
+        };
+        let quality = QualityMonitor::default();
+        let (network_sender, network_rx) = unbounded();
+        let (thread, commands) = create_input_audio_task(
+            network_sender,
+            Box::new(capture),
+            ProcessingSettings::default(),
+            EchoReference::default(),
+            quality.clone(),
+        )
+        .unwrap();
+        commands.send(InputAudioCommand::Start).unwrap();
+
+        // 5 reads before the hiccups and 5 after
+        for _ in 0..10 {
+            network_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+        commands.send(InputAudioCommand::Exit).unwrap();
+        thread.join().unwrap();
+        assert_eq!(quality.get().capture_xruns, 1);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, time::Instant};
+
+/// Never hold back less than this many frames before starting playout.
+const MIN_TARGET_FRAMES: usize = 2;
+/// Never hold back more than this many frames, no matter how bad the jitter.
+const MAX_TARGET_FRAMES: usize = 12;
+/// How far above the target the buffer may grow before we start dropping
+/// the oldest frames to bring the delay back down.
+const OVERFLOW_MARGIN: usize = 3;
+
+/// Whether sequence number `a` comes before `b`. Sequence numbers wrap, so
+/// anything up to half the range behind counts as earlier.
+fn is_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// One received audio packet, interleaved samples.
+pub struct AudioFrame {
+    pub sequence: u32,
+    /// Sender's sample clock (in frames) at the first sample.
+    pub timestamp: u32,
+    pub samples: Vec<i16>,
+}
+
+/// What the jitter buffer hands to the playback device.
+pub enum Playout {
+    /// The next frame in sequence.
+    Frame(Vec<i16>),
+    /// There is a hole in playout, either because the next frame never
+    /// arrived or because the buffer ran dry and is filling up again. Holds
+    /// the number of samples that should be concealed.
+    Missing(usize),
+    /// Nothing has been played yet, we are still filling up to the target
+    /// delay.
+    Empty,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct JitterBufferStats {
+    pub frames_played: u64,
+    /// Frames skipped because they never arrived in time.
+    pub frames_missing: u64,
+    /// Times the buffer ran dry in the middle of playout.
+    pub underruns: u64,
+    /// Frames that arrived after their playout time had passed.
+    pub late_drops: u64,
+    /// Frames thrown away to shrink the delay after a burst.
+    pub overflow_drops: u64,
+    pub duplicates: u64,
+}
+
+/// Reorders received audio frames by sequence number and holds them back
+/// long enough to absorb the network jitter measured so far.
+pub struct JitterBuffer {
+    clock_rate: u32,
+    channels: usize,
+    /// Keyed by sequence number. Which frame is oldest is worked out with
+    /// [`is_before`], the order of the keys breaks down when they wrap.
+    frames: BTreeMap<u32, AudioFrame>,
+    /// Sequence number of the next frame to play, once playout has started.
+    next_sequence: Option<u32>,
+    /// False while we are (re)filling the buffer up to the target delay.
+    playing: bool,
+    started: Instant,
+    last_transit: Option<f64>,
+    /// Interarrival jitter estimate (RFC 3550 section 6.4.1), in seconds.
+    jitter: f64,
+    /// Duration of the last received frame, in seconds.
+    frame_duration: f64,
+    last_frame_len: usize,
+    pub stats: JitterBufferStats,
+}
+
+impl JitterBuffer {
+    pub fn new(clock_rate: u32, channels: usize) -> Self {
+        Self {
+            clock_rate,
+            channels,
+            frames: BTreeMap::new(),
+            next_sequence: None,
+            playing: false,
+            started: Instant::now(),
+            last_transit: None,
+            jitter: 0.0,
+            frame_duration: 0.0,
+            last_frame_len: 0,
+            stats: JitterBufferStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, frame: AudioFrame, arrival: Instant) {
+        let transit = arrival.duration_since(self.started).as_secs_f64()
+            - frame.timestamp as f64 / self.clock_rate as f64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+        self.frame_duration = (frame.samples.len() / self.channels) as f64 / self.clock_rate as f64;
+
+        if let Some(next_sequence) = self.next_sequence {
+            if is_before(frame.sequence, next_sequence) {
+                self.stats.late_drops += 1;
+                return;
+            }
+        }
+        if self.frames.contains_key(&frame.sequence) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        self.frames.insert(frame.sequence, frame);
+
+        // After a burst, drop the oldest frames so we don't keep the extra
+        // delay for the rest of the call
+        while self.frames.len() > self.target_frames() + OVERFLOW_MARGIN {
+            if let Some(sequence) = self.oldest() {
+                self.frames.remove(&sequence);
+                self.stats.overflow_drops += 1;
+                self.next_sequence = Some(sequence.wrapping_add(1));
+            }
+        }
+    }
+
+    /// How many frames we want buffered before playout, based on the
+    /// measured jitter.
+    pub fn target_frames(&self) -> usize {
+        if self.frame_duration <= 0.0 {
+            return MIN_TARGET_FRAMES;
+        }
+        let frames = ((self.frame_duration + 3.0 * self.jitter) / self.frame_duration).ceil();
+        (frames as usize).clamp(MIN_TARGET_FRAMES, MAX_TARGET_FRAMES)
+    }
+
+    /// Sequence number of the earliest frame waiting to be played.
+    fn oldest(&self) -> Option<u32> {
+        self.frames.keys().copied().reduce(|oldest, sequence| {
+            if is_before(sequence, oldest) {
+                sequence
+            } else {
+                oldest
+            }
+        })
+    }
+
+    /// Frames waiting to be played.
+    pub fn buffered_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.frames.len() < self.target_frames() {
+                return if self.last_frame_len == 0 {
+                    Playout::Empty
+                } else {
+                    Playout::Missing(self.last_frame_len)
+                };
+            }
+            self.playing = true;
+            if self.next_sequence.is_none() {
+                self.next_sequence = self.oldest();
+            }
+        }
+
+        let Some(next_sequence) = self.next_sequence else {
+            return Playout::Empty;
+        };
+
+        if let Some(frame) = self.frames.remove(&next_sequence) {
+            self.next_sequence = Some(next_sequence.wrapping_add(1));
+            self.last_frame_len = frame.samples.len();
+            self.stats.frames_played += 1;
+            Playout::Frame(frame.samples)
+        } else if self.frames.is_empty() {
+            // Ran dry, wait until we have the target delay again
+            self.playing = false;
+            self.stats.underruns += 1;
+            Playout::Missing(self.last_frame_len)
+        } else {
+            // Later frames are here, so this one is lost or hopelessly late
+            self.next_sequence = Some(next_sequence.wrapping_add(1));
+            self.stats.frames_missing += 1;
+            Playout::Missing(self.last_frame_len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// The `index`th frame of a call, 20 ms of mono audio at 8 kHz filled
+    /// with its index so playout order can be checked.
+    fn frame(sequence: u32, index: u32) -> AudioFrame {
+        AudioFrame {
+            sequence,
+            timestamp: index * 160,
+            samples: vec![index as i16; 160],
+        }
+    }
+
+    #[test]
+    fn playout_carries_on_across_the_sequence_wrap() {
+        let mut buffer = JitterBuffer::new(8000, 1);
+        let start = Instant::now();
+        // 0 overtakes u32::MAX on the way
+        let arrivals = [(u32::MAX - 1, 0), (1, 3), (0, 2), (u32::MAX, 1), (2, 4)];
+        for (sequence, index) in arrivals {
+            let arrival = start + Duration::from_millis(20 * index as u64);
+            buffer.push(frame(sequence, index), arrival);
+        }
+
+        let mut played = Vec::new();
+        while let Playout::Frame(samples) = buffer.pop() {
+            played.push(samples[0]);
+        }
+        assert_eq!(played, [0, 1, 2, 3, 4]);
+        assert_eq!(buffer.stats.frames_missing, 0);
+
+        // Frames from before the wrap are now late, the ones after it are not
+        buffer.push(frame(u32::MAX, 1), start);
+        assert_eq!(buffer.stats.late_drops, 1);
+        buffer.push(frame(3, 5), start + Duration::from_millis(100));
+        assert_eq!(buffer.buffered_frames(), 1);
+    }
+}
@@ -0,0 +1,36 @@
+//! The phone's tasks and the pieces they are built from, shared by the
+//! phone itself and the tools in `src/bin`.
+
+pub mod audio_format;
+pub mod call_history;
+pub mod call_stats;
+pub mod codec;
+pub mod config;
+pub mod contacts;
+pub mod crypto;
+pub mod discovery;
+pub mod echo_canceller;
+pub mod events;
+pub mod fft;
+pub mod hardware;
+// Relays for the impair binary, and impairs the links of the tests
+pub mod impairment;
+pub mod input_audio_task;
+pub mod jitter_buffer;
+pub mod loss_concealment;
+pub mod network_thread;
+pub mod output_audio_task;
+pub mod packet;
+pub mod pairing;
+pub mod random;
+pub mod samples;
+pub mod script;
+pub mod storage;
+pub mod terminal_task;
+// Units talking over loopback UDP, with channels standing in for the UI and
+// audio tasks
+#[cfg(test)]
+mod test_harness;
+pub mod utils;
+pub mod voice_processing;
+pub mod wav;
@@ -0,0 +1,331 @@
+use crate::jitter_buffer::Playout;
+
+/// Frames of audio history kept for pitch detection (25 ms at 48 kHz).
+const HISTORY_FRAMES: usize = 1200;
+/// Pitch search range, 80 Hz to 500 Hz at 48 kHz.
+const MIN_PITCH_FRAMES: usize = 96;
+const MAX_PITCH_FRAMES: usize = 600;
+/// Length of the window compared when searching for the pitch period.
+const PITCH_WINDOW_FRAMES: usize = 240;
+/// After this many consecutive missing frames the repetition has faded out
+/// completely and only comfort noise is left.
+const MAX_REPEAT_FRAMES: usize = 3;
+/// Length of the crossfade from concealment back into real audio.
+const CROSSFADE_FRAMES: usize = 48;
+/// Loudest comfort noise we generate (RMS), about -40 dBFS.
+const MAX_COMFORT_NOISE_RMS: f32 = 300.0;
+
+/// Fills in for audio frames that never arrived.
+///
+/// Short gaps are covered by repeating the last pitch period of the received
+/// signal with a fade-out, long gaps by comfort noise at the level of the
+/// background noise heard so far.
+pub struct LossConcealer {
+    channels: usize,
+    /// Last received frames, interleaved.
+    history: Vec<i16>,
+    /// Pitch period of `history`, in frames, once a gap has started.
+    pitch: Option<usize>,
+    /// Frames generated since the gap started.
+    position: usize,
+    /// Length of the missing frames, in frames.
+    frame_frames: usize,
+    /// Consecutive missing frames so far.
+    missing: usize,
+    /// Estimated RMS of the background noise.
+    noise_floor: f32,
+    noise_state: u32,
+    pub concealed_frames: u64,
+}
+
+impl LossConcealer {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            history: Vec::new(),
+            pitch: None,
+            position: 0,
+            frame_frames: 0,
+            missing: 0,
+            noise_floor: 0.0,
+            noise_state: 0x1234_5678,
+            concealed_frames: 0,
+        }
+    }
+
+    /// Turns what the jitter buffer gave us into samples for the device.
+    pub fn process(&mut self, playout: Playout) -> Option<Vec<i16>> {
+        match playout {
+            Playout::Frame(samples) => Some(self.received(samples)),
+            Playout::Missing(len) => Some(self.conceal(len)),
+            Playout::Empty => None,
+        }
+    }
+
+    fn received(&mut self, mut samples: Vec<i16>) -> Vec<i16> {
+        if self.missing > 0 {
+            // Blend out of the concealment so the real audio doesn't start
+            // with a click
+            let crossfade = CROSSFADE_FRAMES.min(samples.len() / self.channels);
+            let tail = self.generate(crossfade * self.channels);
+            for frame in 0..crossfade {
+                let weight = frame as f32 / crossfade as f32;
+                for channel in 0..self.channels {
+                    let i = frame * self.channels + channel;
+                    samples[i] =
+                        (samples[i] as f32 * weight + tail[i] as f32 * (1.0 - weight)) as i16;
+                }
+            }
+            self.missing = 0;
+            self.position = 0;
+            self.pitch = None;
+        }
+
+        let rms = rms(&samples);
+        if self.noise_floor == 0.0 || rms < self.noise_floor {
+            self.noise_floor = rms;
+        } else {
+            // Rise slowly so speech doesn't count as background noise
+            self.noise_floor += (rms - self.noise_floor) * 0.01;
+        }
+
+        self.history.extend_from_slice(&samples);
+        let max_len = HISTORY_FRAMES * self.channels;
+        if self.history.len() > max_len {
+            self.history.drain(..self.history.len() - max_len);
+        }
+        samples
+    }
+
+    fn conceal(&mut self, len: usize) -> Vec<i16> {
+        if self.missing == 0 {
+            self.pitch = self.find_pitch();
+        }
+        self.missing += 1;
+        self.concealed_frames += 1;
+        self.frame_frames = len / self.channels;
+        let samples = self.generate(len);
+        self.position += len / self.channels;
+        samples
+    }
+
+    /// Produces `len` samples of concealment starting at the current
+    /// position in the gap.
+    fn generate(&mut self, len: usize) -> Vec<i16> {
+        let frames = len / self.channels;
+        let fade_frames = (MAX_REPEAT_FRAMES * self.frame_frames).max(1) as f32;
+        let mut samples = Vec::with_capacity(len);
+        let noise_amplitude = self.noise_floor.min(MAX_COMFORT_NOISE_RMS) * 3f32.sqrt();
+        let history_frames = self.history.len() / self.channels;
+
+        for frame in 0..frames {
+            // Fade the repetition out over MAX_REPEAT_FRAMES frames
+            let gain = (1.0 - (self.position + frame) as f32 / fade_frames).max(0.0);
+            for channel in 0..self.channels {
+                let repeated = match self.pitch {
+                    Some(pitch) if gain > 0.0 => {
+                        let offset = (self.position + frame) % pitch;
+                        let index = (history_frames - pitch + offset) * self.channels + channel;
+                        self.history[index] as f32
+                    }
+                    _ => 0.0,
+                };
+                let noise = self.next_noise() * noise_amplitude;
+                samples.push((repeated * gain + noise * (1.0 - gain)) as i16);
+            }
+        }
+        samples
+    }
+
+    /// Finds the pitch period of the end of the history by autocorrelation
+    /// of the first channel.
+    fn find_pitch(&self) -> Option<usize> {
+        let frames = self.history.len() / self.channels;
+        if frames < MIN_PITCH_FRAMES + PITCH_WINDOW_FRAMES {
+            return None;
+        }
+        let sample = |frame: usize| self.history[frame * self.channels] as f32;
+        let window_start = frames - PITCH_WINDOW_FRAMES;
+        let max_pitch = MAX_PITCH_FRAMES.min(frames - PITCH_WINDOW_FRAMES);
+
+        let mut best = None;
+        let mut best_score = 0.0;
+        for pitch in MIN_PITCH_FRAMES..=max_pitch {
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            for frame in window_start..frames {
+                let lagged = sample(frame - pitch);
+                correlation += sample(frame) * lagged;
+                energy += lagged * lagged;
+            }
+            if energy > 0.0 {
+                let score = correlation / energy.sqrt();
+                if score > best_score {
+                    best_score = score;
+                    best = Some(pitch);
+                }
+            }
+        }
+        // Fall back to repeating the whole history if nothing correlates
+        best.or(Some(frames.min(MAX_PITCH_FRAMES)))
+    }
+
+    /// Uniform noise in [-1, 1].
+    fn next_noise(&mut self) -> f32 {
+        // xorshift32
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+        self.noise_state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+fn rms(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|&s| s as f32 * s as f32).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        codec::{AudioCodec, CodecId, Pcm16},
+        jitter_buffer::{AudioFrame, JitterBuffer},
+        packet::{AudioPayload, NetworkPacket, MAX_DATAGRAM_SIZE},
+    };
+
+    const FRAMES_PER_PACKET: usize = 400;
+    const PACKETS: usize = 100;
+
+    fn sine(frames: usize) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|i| {
+                let value = (i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 48000.0).sin();
+                let value = (value * 8000.0) as i16;
+                [value, value]
+            })
+            .collect()
+    }
+
+    /// Sends `signal` over a loopback UDP link, dropping the packets for which
+    /// `drop` returns true, and plays it out through a jitter buffer. Missing
+    /// frames are concealed by `conceal`.
+    fn play_over_lossy_link(
+        signal: &[i16],
+        drop: impl Fn(usize) -> bool,
+        mut conceal: impl FnMut(Playout) -> Option<Vec<i16>>,
+    ) -> Vec<i16> {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut jitter_buffer = JitterBuffer::new(48000, 2);
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        let mut output = Vec::new();
+
+        for (sequence, chunk) in signal.chunks(FRAMES_PER_PACKET * 2).enumerate() {
+            if !drop(sequence) {
+                let timestamp = (sequence * FRAMES_PER_PACKET) as u32;
+                let data = Pcm16.encode(chunk);
+                let packet =
+                    NetworkPacket::new_audio(sequence as u32, timestamp, CodecId::Pcm16, &data);
+                sender
+                    .send_to(&packet.serialize().unwrap(), receiver.local_addr().unwrap())
+                    .unwrap();
+
+                let (len, _) = receiver.recv_from(&mut buffer).unwrap();
+                let packet = NetworkPacket::deserialize(&buffer[..len]).unwrap();
+                let payload = AudioPayload::parse(&packet.data).unwrap();
+                let samples = Pcm16.decode(payload.samples).unwrap();
+                let frame = AudioFrame {
+                    sequence: payload.sequence,
+                    timestamp: payload.timestamp,
+                    samples,
+                };
+                jitter_buffer.push(frame, Instant::now());
+            }
+            output.extend(conceal(jitter_buffer.pop()).unwrap_or_default());
+        }
+        // Play out what is left in the buffer
+        while output.len() < signal.len() {
+            output.extend(conceal(jitter_buffer.pop()).unwrap());
+        }
+        output.truncate(signal.len());
+        output
+    }
+
+    fn largest_step(samples: &[i16]) -> i32 {
+        samples
+            .windows(2)
+            .map(|w| (w[1] as i32 - w[0] as i32).abs())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn short_gaps_are_concealed_without_clicks() {
+        let signal = sine(FRAMES_PER_PACKET * PACKETS);
+        let drop = |sequence: usize| sequence % 7 == 3;
+
+        let mut concealer = LossConcealer::new(2);
+        let concealed = play_over_lossy_link(&signal, drop, |p| concealer.process(p));
+        let silenced = play_over_lossy_link(&signal, drop, |p| match p {
+            Playout::Frame(samples) => Some(samples),
+            Playout::Missing(len) => Some(vec![0; len]),
+            Playout::Empty => None,
+        });
+
+        assert_eq!(concealed.len(), signal.len());
+        assert_eq!(silenced.len(), signal.len());
+        assert_eq!(concealer.concealed_frames, 14);
+
+        // Left channel only, so we compare neighbouring samples in time
+        let left = |s: &[i16]| s.iter().step_by(2).copied().collect::<Vec<_>>();
+        let clean_step = largest_step(&left(&signal));
+        let concealed_step = largest_step(&left(&concealed));
+        let silenced_step = largest_step(&left(&silenced));
+        assert!(
+            concealed_step < clean_step * 2,
+            "concealment clicks: step {} vs {} in the clean signal",
+            concealed_step,
+            clean_step
+        );
+        assert!(silenced_step > clean_step * 10);
+
+        let error = |s: &[i16]| -> f64 {
+            s.iter()
+                .zip(&signal)
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum()
+        };
+        assert!(error(&concealed) * 10.0 < error(&silenced));
+    }
+
+    #[test]
+    fn long_gaps_fall_back_to_comfort_noise() {
+        let signal = sine(FRAMES_PER_PACKET * PACKETS);
+        let gap = 40..50;
+        let mut concealer = LossConcealer::new(2);
+        let output = play_over_lossy_link(&signal, |s| gap.contains(&s), |p| concealer.process(p));
+
+        // The buffer runs dry during the gap. Playout lags one packet behind
+        // the link, so the last frame played before the gap ends holds the
+        // concealment for packet 48, which should be quiet noise, not
+        // silence and not a repetition of the tone
+        let start = (gap.end - 2) * FRAMES_PER_PACKET * 2;
+        let frame = &output[start..start + FRAMES_PER_PACKET * 2];
+        let level = rms(frame);
+        assert!(level > 0.0);
+        assert!(level < MAX_COMFORT_NOISE_RMS * 1.1);
+        assert!(concealer.concealed_frames >= gap.len() as u64);
+    }
+}
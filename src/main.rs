@@ -1,58 +1,176 @@
-use std::io::stdout;
+use std::{io::stdout, time::Duration};
 
+use clap::Parser;
 use crossterm::{
     terminal::{disable_raw_mode, LeaveAlternateScreen},
     ExecutableCommand,
 };
 
-mod events;
-mod input_audio_task;
-mod network_thread;
-mod output_audio_task;
-mod terminal_task;
-mod utils;
+use phone::{
+    call_stats, config, crypto, discovery, echo_canceller, events, hardware, input_audio_task,
+    network_thread, output_audio_task, pairing, script, storage, terminal_task, utils,
+    voice_processing,
+};
 
 const READY_TO_PAIR_SOUND: &[u8] = include_bytes!("assets/ready_to_pair.mp3");
+const INCOMING_CALL_SOUND: &[u8] = include_bytes!("assets/capitao_whatsapp.mp3");
 
 fn main() -> anyhow::Result<()> {
+    let cli = config::Cli::parse();
+    let config = config::Config::load(&cli)?;
+    // Without a script we are run by someone at the terminal
+    let script = cli
+        .script
+        .as_deref()
+        .map(script::Script::load)
+        .transpose()?;
+    match &config.data_dir {
+        Some(dir) => storage::set_data_dir(dir.clone()),
+        // Headless units are run side by side on one machine, and each
+        // needs an identity of its own
+        None if script.is_some() => storage::set_data_dir(
+            storage::data_dir().join(format!("script-{}", config.network.port)),
+        ),
+        None => {}
+    }
     std::panic::set_hook(Box::new(|panic_info| {
         eprintln!("Panic: {}", panic_info);
         let _ = disable_raw_mode();
         let _ = stdout().execute(LeaveAlternateScreen);
         std::process::exit(1);
     }));
-    let (output_audio_sender, output_audio_thread) = output_audio_task::create_output_audio_task();
-    let (network_thread, network_sender) = network_thread::create_network_task()?;
-    let (input_audio_thread, input_audio_sender) =
-        input_audio_task::create_input_audio_task(network_sender.clone())?;
-
-    let (terminal_thread, terminal_tx) = terminal_task::create_terminal_task(
-        output_audio_sender.clone(),
-        input_audio_sender.clone(),
-        network_sender.clone(),
+    // Open everything up front so a missing device stops us right away
+    let audio = hardware::AudioDevices::open(&config.audio)?;
+    let leds = match script {
+        Some(_) => Box::new(hardware::MemoryLeds::new().0),
+        None => hardware::open_leds(&config.leds)?,
+    };
+    // Without a keys device the unit is driven from the keyboard only
+    let keys = if script.is_some() || config.keys.device.as_os_str().is_empty() {
+        None
+    } else {
+        Some(hardware::EvdevKeys::open(&config.keys.device)?)
+    };
+    // Filled in by the audio and network tasks, shown on the call screen
+    let call_quality = call_stats::QualityMonitor::default();
+    // What the speaker plays, for the echo canceller to take back out
+    let echo_reference = echo_canceller::EchoReference::default();
+    // Where the call screen starts from when changing them
+    let voice = voice_processing::VoiceSettings {
+        noise_suppression: config.processing.noise_suppression,
+        suppression_db: config.processing.noise_suppression_db,
+        gain_control: config.processing.gain_control,
+        target_dbfs: config.processing.gain_target_dbfs,
+        max_gain_db: config.processing.max_gain_db,
+    };
+    let (output_audio_sender, output_audio_thread) = output_audio_task::create_output_audio_task(
+        audio.playback,
+        audio.volume,
+        echo_reference.clone(),
+        call_quality.clone(),
     );
-    let (event_sender, _) = events::create_event_task(terminal_tx.clone());
+    let security = network_thread::SecuritySettings {
+        psk: config.security.psk.clone(),
+        allow_unencrypted: config.security.allow_unencrypted,
+        unpaired_calls: if config.security.refuse_unpaired {
+            network_thread::UnpairedCallPolicy::Refuse
+        } else {
+            network_thread::UnpairedCallPolicy::Flag
+        },
+    };
+    let port = config.network.port;
+    let identity = crypto::Identity::load_or_create(&storage::data_dir().join("identity.key"))?;
+    let trusted_peers_path = storage::data_dir().join("trusted_peers");
+    let trusted_peers = pairing::TrustedPeers::load(&trusted_peers_path)?;
+    let skipped_peers = storage::skipped_lines_notice(&trusted_peers_path, trusted_peers.skipped());
+    let mut discovery_settings =
+        discovery::DiscoverySettings::new(config.device_name(), identity.public, port);
+    discovery_settings.interface = config.network.discovery_interface;
+    let (network_thread, network_sender) = network_thread::create_network_task(
+        port,
+        identity,
+        trusted_peers,
+        network_thread::NetworkTimeouts::default(),
+        security,
+        call_quality.clone(),
+    )?;
+    let (input_audio_thread, input_audio_sender) = input_audio_task::create_input_audio_task(
+        network_sender.clone(),
+        audio.capture,
+        input_audio_task::ProcessingSettings {
+            echo_tail: config
+                .processing
+                .echo_cancellation
+                .then(|| Duration::from_millis(config.processing.echo_tail_ms as u64)),
+            voice,
+        },
+        echo_reference,
+        call_quality.clone(),
+    )?;
 
-    std::thread::sleep(std::time::Duration::from_millis(100));
+    // The network task must know where to report before the UI can ask it
+    // for anything
+    let (terminal_tx, terminal_rx) = crossbeam::channel::unbounded();
     network_sender.send(network_thread::NetworkTaskCommand::MainTaskQueue(
-        terminal_tx,
+        terminal_tx.clone(),
     ))?;
     network_sender.send(network_thread::NetworkTaskCommand::OutputAudioQueue(
         output_audio_sender.clone(),
     ))?;
+    if let Some(notice) = skipped_peers {
+        terminal_tx.send(terminal_task::CallScreenCommand::Notice(notice))?;
+    }
+
+    let headless = script.is_some();
+    let terminal_thread = match script {
+        Some(script) => script::create_script_task(
+            script,
+            terminal_rx,
+            output_audio_sender.clone(),
+            input_audio_sender.clone(),
+            network_sender.clone(),
+            port,
+        ),
+        None => terminal_task::create_terminal_task(
+            terminal_rx,
+            output_audio_sender.clone(),
+            input_audio_sender.clone(),
+            network_sender.clone(),
+            leds,
+            terminal_task::TerminalSettings {
+                call_port: port,
+                voice,
+                ringtone: utils::decode_bytes(INCOMING_CALL_SOUND),
+            },
+            call_quality,
+        ),
+    };
+    let event_sender =
+        keys.map(|keys| events::create_event_task(terminal_tx.clone(), Box::new(keys)).0);
+    let (discovery_sender, discovery_thread) =
+        discovery::create_discovery_task(discovery_settings, terminal_tx);
 
-    let play_buffer = utils::decode_bytes(READY_TO_PAIR_SOUND);
+    // Keep what a headless unit plays down to the calls
+    if !headless {
+        let play_buffer = utils::decode_bytes(READY_TO_PAIR_SOUND);
 
-    output_audio_sender.send(output_audio_task::OutputAudioTaskCommand::Play(play_buffer))?;
+        output_audio_sender.send(output_audio_task::OutputAudioTaskCommand::Play(play_buffer))?;
+    }
 
-    let _ = terminal_thread.join();
+    let result = terminal_thread
+        .join()
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("the terminal task panicked")));
     output_audio_sender.send(output_audio_task::OutputAudioTaskCommand::Exit)?;
     input_audio_sender.send(input_audio_task::InputAudioCommand::Exit)?;
     network_sender.send(network_thread::NetworkTaskCommand::Exit)?;
-    event_sender.send(events::EventCommand::Exit)?;
+    if let Some(event_sender) = event_sender {
+        event_sender.send(events::EventCommand::Exit)?;
+    }
+    discovery_sender.send(discovery::DiscoveryCommand::Exit)?;
     let _ = output_audio_thread.join();
     let _ = input_audio_thread.join();
     let _ = network_thread.join();
+    let _ = discovery_thread.join();
 
-    Ok(())
+    result
 }
@@ -1,124 +1,444 @@
 use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
     thread::{spawn, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
 
-use crate::{output_audio_task::OutputAudioTaskCommand, terminal_task::CallScreenCommand};
+use crate::{
+    audio_format::{AudioFormat, Capabilities, DEVICE_CHANNELS, DEVICE_SAMPLE_RATE},
+    call_stats::{QualityMonitor, ReceiveStats, RoundTrip},
+    codec::{new_codec, AudioCodec},
+    crypto::{psk_key, CallCipher, Handshake, Identity},
+    jitter_buffer::AudioFrame,
+    output_audio_task::OutputAudioTaskCommand,
+    packet::{
+        new_session_id, Answer, AudioPayload, DecodeError, KeyExchange, NetworkPacket,
+        NetworkPacketType, Offer, PairingHello, ReceiverReport, RejectReason, RoundTripTiming,
+        SessionId, StopReason, MAX_DATAGRAM_SIZE,
+    },
+    pairing::{Pairing, TrustedPeer, TrustedPeers},
+    terminal_task::{CallEndReason, CallScreenCommand},
+};
+
+/// Port units take calls on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 33445;
 
 pub enum NetworkTaskCommand {
-    StartConnection(std::net::SocketAddr),
+    /// Calls the peer, tagging the call with the given session ID.
+    StartConnection(std::net::SocketAddr, SessionId),
     StopConnection,
     SendAccept,
+    /// Declines the incoming call we are ringing for.
+    SendReject,
+    /// Pairs with the unit at the given address, or waits for another unit
+    /// to start pairing with us.
+    StartPairing(Option<SocketAddr>),
+    /// Our user confirmed the pairing code matches the peer's.
+    ConfirmPairing,
+    CancelPairing,
     SendAudio(Vec<i16>),
     MainTaskQueue(Sender<CallScreenCommand>),
     OutputAudioQueue(Sender<OutputAudioTaskCommand>),
     Exit,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 enum NetworkState {
     PendingConnection(std::net::SocketAddr),
     InCall(std::net::SocketAddr),
+    /// Pairing with the given unit, or waiting for one to pair with us.
+    Pairing(Option<SocketAddr>),
     Stopped,
 }
 
-#[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
-enum NetworkPacketType {
-    StartConnection = 0,
-    StopConnection,
-    Audio,
-    Heartbeat,
-    Accept,
+/// Timeouts driving call setup and liveness detection.
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkTimeouts {
+    /// How often we send a heartbeat while setting up or in a call.
+    pub heartbeat_interval: Duration,
+    /// How often we tell the peer how its audio is arriving during a call.
+    pub report_interval: Duration,
+    /// Give up on an outgoing call if the peer hasn't answered our
+    /// StartConnection at all within this time.
+    pub unreachable_timeout: Duration,
+    /// Give up on a call nobody picks up within this time.
+    pub ring_timeout: Duration,
+    /// Hang up if we haven't heard from the peer for this long.
+    pub connection_lost_timeout: Duration,
+    /// Give up on pairing if the codes haven't been confirmed on both units
+    /// within this time.
+    pub pairing_timeout: Duration,
 }
 
-#[derive(PartialEq, Clone)]
-struct NetworkPacket {
-    packet_type: NetworkPacketType,
-    data: Vec<u8>,
+impl Default for NetworkTimeouts {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(1),
+            report_interval: Duration::from_secs(5),
+            unreachable_timeout: Duration::from_secs(5),
+            ring_timeout: Duration::from_secs(30),
+            connection_lost_timeout: Duration::from_secs(5),
+            pairing_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How calls are protected.
+#[derive(Clone, Debug, Default)]
+pub struct SecuritySettings {
+    /// Passphrase shared by the units allowed to call each other. Without one
+    /// calls are still encrypted, but the peer isn't authenticated.
+    pub psk: Option<String>,
+    /// Whether to take calls from and place calls to units that don't
+    /// encrypt.
+    pub allow_unencrypted: bool,
+    pub unpaired_calls: UnpairedCallPolicy,
 }
 
-impl NetworkPacket {
-    fn new_start_connection() -> Self {
+impl SecuritySettings {
+    fn accepts_unpaired(&self) -> bool {
+        self.unpaired_calls == UnpairedCallPolicy::Flag
+    }
+}
+
+/// What to do with calls to and from units we haven't paired with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum UnpairedCallPolicy {
+    /// Let them through but warn on the call screen.
+    #[default]
+    Flag,
+    /// Refuse them.
+    Refuse,
+}
+
+/// When we last heard from and talked to the peer of the current call.
+struct Liveness {
+    /// Whether we placed the call.
+    outgoing: bool,
+    started: Instant,
+    last_heard: Option<Instant>,
+    last_sent: Instant,
+}
+
+impl Liveness {
+    fn outgoing(now: Instant) -> Self {
         Self {
-            packet_type: NetworkPacketType::StartConnection,
-            data: Vec::new(),
+            outgoing: true,
+            started: now,
+            last_heard: None,
+            last_sent: now,
         }
     }
 
-    fn new_stop_connection() -> Self {
+    fn incoming(now: Instant) -> Self {
         Self {
-            packet_type: NetworkPacketType::StopConnection,
-            data: Vec::new(),
+            outgoing: false,
+            started: now,
+            last_heard: Some(now),
+            last_sent: now,
         }
     }
 
-    fn new_audio(audio: Vec<i16>) -> Self {
-        // Use unsafe to convert the i32 slice to a u8 slice
-        let data = unsafe {
-            Vec::from_raw_parts(
-                audio.as_ptr() as *mut u8,
-                audio.len() * 2,
-                audio.capacity() * 2,
-            )
-        };
+    /// Why the call should be given up on, if it should.
+    fn expired(
+        &self,
+        state: &NetworkState,
+        timeouts: &NetworkTimeouts,
+        now: Instant,
+    ) -> Option<CallEndReason> {
+        match self.last_heard {
+            None if now - self.started > timeouts.unreachable_timeout => {
+                Some(CallEndReason::PeerUnreachable)
+            }
+            Some(last_heard) if now - last_heard > timeouts.connection_lost_timeout => {
+                Some(CallEndReason::ConnectionLost)
+            }
+            _ if matches!(state, NetworkState::PendingConnection(_))
+                && now - self.started > timeouts.ring_timeout =>
+            {
+                Some(if self.outgoing {
+                    CallEndReason::NoAnswer
+                } else {
+                    CallEndReason::MissedCall
+                })
+            }
+            _ if matches!(state, NetworkState::Pairing(_))
+                && now - self.started > timeouts.pairing_timeout =>
+            {
+                Some(CallEndReason::PairingTimedOut)
+            }
+            _ => None,
+        }
+    }
+}
 
-        // Forget the audio so it doesn't get dropped
-        std::mem::forget(audio);
+/// Counts the datagrams we threw away instead of acting on, for the call
+/// quality panel.
+struct DropCounters(QualityMonitor);
 
-        Self {
-            packet_type: NetworkPacketType::Audio,
-            data,
+impl DropCounters {
+    fn malformed(&self) {
+        self.0.update(|quality| quality.packets_malformed += 1);
+    }
+
+    /// A packet from the peer left over from an earlier call.
+    fn stale(&self) {
+        self.0.update(|quality| quality.packets_stale += 1);
+    }
+
+    /// A packet claiming to belong to the current call that came from
+    /// somewhere other than its peer. This is a security event: someone may
+    /// be trying to inject audio into or hang up a call.
+    fn foreign(&self) {
+        self.0.update(|quality| quality.packets_foreign += 1);
+    }
+
+    /// A packet that failed decryption: tampered with, replayed, or sent in
+    /// the clear in an encrypted call. Also a security event.
+    fn rejected(&self) {
+        self.0.update(|quality| quality.packets_rejected += 1);
+    }
+
+    /// One of our packets too large to put on the wire.
+    fn unsendable(&self) {
+        self.0.update(|quality| quality.packets_unsendable += 1);
+    }
+}
+
+/// How many calls turned away as busy we remember.
+const MISSED_CALLS_REMEMBERED: usize = 16;
+
+/// Calls we turned away as busy and told the UI about, so a caller repeating
+/// its offer makes one missed call.
+#[derive(Default)]
+struct MissedCalls(VecDeque<(SocketAddr, SessionId)>);
+
+impl MissedCalls {
+    /// Whether this is the first we hear of the call.
+    fn is_new(&mut self, from: SocketAddr, session: SessionId) -> bool {
+        if self.0.contains(&(from, session)) {
+            return false;
         }
+        if self.0.len() == MISSED_CALLS_REMEMBERED {
+            self.0.pop_front();
+        }
+        self.0.push_back((from, session));
+        true
     }
+}
+
+/// Turns away a call or pairing that `from` started while we are busy with
+/// something else, telling the UI about the calls it missed.
+fn reply_busy(
+    udp_socket: &UdpSocket,
+    packet: &NetworkPacket,
+    from: SocketAddr,
+    counters: &DropCounters,
+    missed_calls: &mut MissedCalls,
+    main_thread_sender: &Sender<CallScreenCommand>,
+) -> anyhow::Result<()> {
+    let busy = NetworkPacket::new_busy(None).with_session(packet.session);
+    send_packet(udp_socket, &busy, from, counters)?;
+    if packet.packet_type == NetworkPacketType::StartConnection
+        && missed_calls.is_new(from, packet.session)
+    {
+        main_thread_sender.send(CallScreenCommand::MissedCall(from))?;
+    }
+    Ok(())
+}
 
-    fn new_accept() -> Self {
+/// Encoding and numbering of the audio packets we send during a call.
+struct AudioSendState {
+    format: AudioFormat,
+    encoder: Box<dyn AudioCodec>,
+    /// Captured device audio not yet making up a whole packet.
+    pending: Vec<i16>,
+    next_sequence: u32,
+    /// Sample clock in frames (one sample per channel) at the negotiated
+    /// sample rate.
+    next_timestamp: u32,
+}
+
+impl AudioSendState {
+    fn new(format: AudioFormat) -> Self {
         Self {
-            packet_type: NetworkPacketType::Accept,
-            data: Vec::new(),
+            format,
+            encoder: new_codec(format.codec, format.channels as usize),
+            pending: Vec::new(),
+            next_sequence: 0,
+            next_timestamp: 0,
         }
     }
 
-    fn new_heartbeat() -> Self {
-        Self {
-            packet_type: NetworkPacketType::Heartbeat,
-            data: Vec::new(),
+    /// Takes captured device audio and returns the packets that are now
+    /// complete.
+    fn packets(&mut self, audio: &[i16]) -> Vec<NetworkPacket> {
+        self.pending.extend_from_slice(audio);
+        let packet_len = self.format.device_frames_per_packet() * DEVICE_CHANNELS;
+        let mut packets = Vec::new();
+        while self.pending.len() >= packet_len {
+            let device_audio = self.pending.drain(..packet_len).collect::<Vec<_>>();
+            let samples = self.format.convert_from_device(&device_audio);
+            let frames = (samples.len() / self.format.channels as usize) as u32;
+            let data = self.encoder.encode(&samples);
+            packets.push(NetworkPacket::new_audio(
+                self.next_sequence,
+                self.next_timestamp,
+                self.encoder.id(),
+                &data,
+            ));
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+            self.next_timestamp = self.next_timestamp.wrapping_add(frames);
         }
+        packets
     }
+}
 
-    fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.push(self.packet_type as u8);
-        buffer.extend_from_slice(&self.data);
-        buffer
+/// Decoding of the audio packets we receive during a call.
+struct AudioReceiveState {
+    format: AudioFormat,
+    /// Decoder for the codec of the last packet.
+    decoder: Option<Box<dyn AudioCodec>>,
+}
+
+impl AudioReceiveState {
+    fn new(format: AudioFormat) -> Self {
+        Self {
+            format,
+            decoder: None,
+        }
     }
 
-    fn deserialize(data: Vec<u8>) -> Self {
-        let packet_type = match data[0] {
-            0 => NetworkPacketType::StartConnection,
-            1 => NetworkPacketType::StopConnection,
-            2 => NetworkPacketType::Audio,
-            3 => NetworkPacketType::Heartbeat,
-            4 => NetworkPacketType::Accept,
-            _ => panic!("Invalid packet type"),
+    /// Decodes an audio packet into a frame of device audio.
+    fn frame(&mut self, data: &[u8]) -> Result<AudioFrame, DecodeError> {
+        let payload = AudioPayload::parse(data)?;
+        let channels = self.format.channels as usize;
+        let decoder = match &mut self.decoder {
+            Some(decoder) if decoder.id() == payload.codec => decoder,
+            decoder => decoder.insert(new_codec(payload.codec, channels)),
         };
-        if data.len() == 1 {
-            Self {
-                packet_type,
-                data: Vec::new(),
-            }
-        } else {
-            let data = data[1..].to_vec();
-            Self { packet_type, data }
+        let samples = decoder.decode(payload.samples)?;
+        Ok(AudioFrame {
+            sequence: payload.sequence,
+            timestamp: self.format.device_timestamp(payload.timestamp),
+            samples: self.format.convert_to_device(&samples),
+        })
+    }
+}
+
+/// Reads one datagram from the socket and decodes it. Anything that isn't a
+/// valid packet for our protocol version is counted and dropped.
+fn receive_packet(
+    udp_socket: &UdpSocket,
+    buffer: &mut [u8],
+    counters: &DropCounters,
+) -> Option<(NetworkPacket, SocketAddr)> {
+    let (len, peer) = udp_socket.recv_from(buffer).ok()?;
+    match NetworkPacket::deserialize(&buffer[..len]) {
+        Ok(packet) => Some((packet, peer)),
+        Err(_) => {
+            counters.malformed();
+            None
+        }
+    }
+}
+
+/// Sends a packet to `to`. One that can't be encoded is counted and dropped
+/// rather than taking the task down.
+fn send_packet(
+    udp_socket: &UdpSocket,
+    packet: &NetworkPacket,
+    to: SocketAddr,
+    counters: &DropCounters,
+) -> std::io::Result<()> {
+    match packet.serialize() {
+        Ok(datagram) => udp_socket.send_to(&datagram, to).map(|_| ()),
+        Err(_) => {
+            counters.unsendable();
+            Ok(())
         }
     }
 }
 
-fn network_task(rx: Receiver<NetworkTaskCommand>) -> anyhow::Result<()> {
+/// What the call screen should show when the peer ends the call.
+fn stop_command(reason: StopReason) -> CallScreenCommand {
+    match reason {
+        StopReason::Hangup => CallScreenCommand::PeerHungUp,
+        StopReason::IncompatibleFormat => {
+            CallScreenCommand::CallFailed(CallEndReason::IncompatibleFormat)
+        }
+        StopReason::EncryptionRequired => {
+            CallScreenCommand::CallFailed(CallEndReason::EncryptionRequired)
+        }
+        StopReason::AuthenticationFailed => {
+            CallScreenCommand::CallFailed(CallEndReason::AuthenticationFailed)
+        }
+        StopReason::NotPaired => CallScreenCommand::CallFailed(CallEndReason::NotPaired),
+    }
+}
+
+/// What the call screen should show when the peer refuses a call.
+fn reject_reason(reason: Option<RejectReason>) -> CallEndReason {
+    match reason {
+        Some(RejectReason::IncompatibleFormat) => CallEndReason::IncompatibleFormat,
+        Some(RejectReason::EncryptionRequired) => CallEndReason::EncryptionRequired,
+        Some(RejectReason::NotPaired) => CallEndReason::NotPaired,
+        Some(RejectReason::PairingRefused) => CallEndReason::PairingRefused,
+        Some(RejectReason::KeyExchangeFailed) => CallEndReason::AuthenticationFailed,
+        Some(RejectReason::Declined) | None => CallEndReason::Declined,
+    }
+}
+
+/// Encrypts the packet if the call is encrypted.
+fn protect(packet: NetworkPacket, cipher: &mut Option<CallCipher>) -> NetworkPacket {
+    match cipher {
+        Some(cipher) => cipher.seal(packet),
+        None => packet,
+    }
+}
+
+fn network_task(
+    rx: Receiver<NetworkTaskCommand>,
+    udp_socket: UdpSocket,
+    identity: Identity,
+    mut trusted_peers: TrustedPeers,
+    timeouts: NetworkTimeouts,
+    security: SecuritySettings,
+    quality: QualityMonitor,
+) -> anyhow::Result<()> {
     let mut current_state = NetworkState::Stopped;
-    let udp_socket = std::net::UdpSocket::bind("0.0.0.0:33445")?;
+    // Session of the call we are setting up or in
+    let mut session: SessionId = 0;
+    let mut liveness = Liveness::incoming(Instant::now());
+    let drop_counters = DropCounters(quality.clone());
+    let capabilities = Capabilities::local();
+    let psk = security.psk.as_deref().map(psk_key);
+    // Our half of the key exchange of the call we are placing
+    let mut handshake: Option<Handshake> = None;
+    // Keys of the call we are in, `None` for an unencrypted call
+    let mut cipher: Option<CallCipher> = None;
+    // Format picked for the incoming call we are ringing for
+    let mut incoming_format = AudioFormat::default();
+    // Our half of the key exchange and the keys for the incoming call we are
+    // ringing for
+    let mut incoming_security: Option<(KeyExchange, CallCipher)> = None;
+    // The Accept we answered the call we are in with, in case the caller
+    // missed it
+    let mut accept: Option<NetworkPacket> = None;
+    // Progress of the pairing we are in
+    let mut pairing: Option<Pairing> = None;
+    // The last pairing we completed, in case the peer missed our confirmation
+    let mut last_pairing: Option<(SocketAddr, SessionId)> = None;
+    let mut missed_calls = MissedCalls::default();
+    let mut audio_send_state = AudioSendState::new(incoming_format);
+    let mut audio_receive_state = AudioReceiveState::new(incoming_format);
+    let mut receive_stats = ReceiveStats::new(DEVICE_SAMPLE_RATE);
+    let mut round_trip = RoundTrip::new();
+    let mut last_report = Instant::now();
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
     let main_thread_sender = {
         if let NetworkTaskCommand::MainTaskQueue(sender) = rx.recv()? {
             sender
@@ -133,108 +453,620 @@ fn network_task(rx: Receiver<NetworkTaskCommand>) -> anyhow::Result<()> {
             panic!("Invalid command");
         }
     };
-    // Set read timeout to 50ms
-    udp_socket.set_read_timeout(Some(Duration::from_millis(1)))?;
 
     loop {
         match current_state {
             NetworkState::PendingConnection(peer) => {
-                let mut buffer = [0; 1024];
-                if let Ok(data) = udp_socket.recv_from(&mut buffer) {
-                    if data.0 != 0 {
-                        let packet = NetworkPacket::deserialize(buffer[..data.0].to_vec());
-                        if packet.packet_type == NetworkPacketType::Accept {
-                            // We are now in a call
-                            current_state = NetworkState::InCall(peer);
-                            main_thread_sender.send(CallScreenCommand::StartCall(peer))?;
-                        } else if packet.packet_type == NetworkPacketType::StopConnection {
-                            current_state = NetworkState::Stopped;
-                            main_thread_sender.send(CallScreenCommand::StopCall)?;
+                if let Some((packet, from)) =
+                    receive_packet(&udp_socket, &mut buffer, &drop_counters)
+                {
+                    if from == peer && packet.session == session {
+                        liveness.last_heard = Some(Instant::now());
+                    }
+                    if packet.starts_something() && (from != peer || packet.session != session) {
+                        // Someone else is calling while we set up this call
+                        reply_busy(
+                            &udp_socket,
+                            &packet,
+                            from,
+                            &drop_counters,
+                            &mut missed_calls,
+                            &main_thread_sender,
+                        )?;
+                    } else if from != peer {
+                        drop_counters.foreign();
+                    } else if packet.session != session {
+                        drop_counters.stale();
+                    } else if packet.packet_type == NetworkPacketType::StartConnection {
+                        if !liveness.outgoing {
+                            // The caller didn't get our heartbeat yet and repeated its offer
+                            let packet = NetworkPacket::new_heartbeat().with_session(session);
+                            send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                        }
+                    } else if packet.packet_type == NetworkPacketType::Busy {
+                        current_state = NetworkState::Stopped;
+                        main_thread_sender
+                            .send(CallScreenCommand::CallFailed(CallEndReason::LineBusy))?;
+                    } else if packet.packet_type == NetworkPacketType::Reject {
+                        current_state = NetworkState::Stopped;
+                        let reason = reject_reason(packet.reject_reason());
+                        main_thread_sender.send(CallScreenCommand::CallFailed(reason))?;
+                    } else if packet.packet_type == NetworkPacketType::Accept {
+                        // Work out whether we can take the call as answered
+                        let outcome = match Answer::parse(&packet.data) {
+                            Ok(answer) if capabilities.supports(&answer.format) => {
+                                match (answer.security, handshake.take()) {
+                                    (Some((exchange, confirmation)), Some(handshake)) => {
+                                        let format = answer.format.serialize();
+                                        handshake
+                                            .finish(
+                                                &identity,
+                                                &exchange,
+                                                psk.as_ref(),
+                                                session,
+                                                true,
+                                            )
+                                            .and_then(|call_cipher| {
+                                                call_cipher
+                                                    .check_confirmation(&format, &confirmation)?;
+                                                Ok(call_cipher)
+                                            })
+                                            .map_err(|_| StopReason::AuthenticationFailed)
+                                            .and_then(|call_cipher| {
+                                                let paired =
+                                                    trusted_peers.is_trusted(&exchange.identity);
+                                                if paired || security.accepts_unpaired() {
+                                                    Ok((answer.format, Some(call_cipher), paired))
+                                                } else {
+                                                    Err(StopReason::NotPaired)
+                                                }
+                                            })
+                                    }
+                                    (None, _)
+                                        if security.allow_unencrypted
+                                            && security.accepts_unpaired() =>
+                                    {
+                                        Ok((answer.format, None, false))
+                                    }
+                                    (None, _) if security.allow_unencrypted => {
+                                        Err(StopReason::NotPaired)
+                                    }
+                                    _ => Err(StopReason::EncryptionRequired),
+                                }
+                            }
+                            // The callee picked something we never offered
+                            _ => Err(StopReason::IncompatibleFormat),
+                        };
+                        match outcome {
+                            Ok((format, call_cipher, paired)) => {
+                                // We are now in a call
+                                current_state = NetworkState::InCall(peer);
+                                cipher = call_cipher;
+                                audio_send_state = AudioSendState::new(format);
+                                audio_receive_state = AudioReceiveState::new(format);
+                                receive_stats = ReceiveStats::new(DEVICE_SAMPLE_RATE);
+                                round_trip = RoundTrip::new();
+                                last_report = Instant::now();
+                                quality.update(|quality| quality.clear_network());
+                                main_thread_sender
+                                    .send(CallScreenCommand::StartCall(peer, session, paired))?;
+                            }
+                            Err(reason) => {
+                                if reason == StopReason::AuthenticationFailed {
+                                    drop_counters.rejected();
+                                }
+                                let packet = NetworkPacket::new_stop_connection(reason)
+                                    .with_session(session);
+                                send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                                current_state = NetworkState::Stopped;
+                                main_thread_sender.send(stop_command(reason))?;
+                            }
                         }
+                    } else if packet.packet_type == NetworkPacketType::StopConnection {
+                        current_state = NetworkState::Stopped;
+                        main_thread_sender.send(stop_command(packet.stop_reason()))?;
                     }
                 }
             }
-            NetworkState::InCall(_) => {
-                let mut buffer = vec![0; 1024 * 32 * 2];
-                if let Ok(data) = udp_socket.recv_from(&mut buffer) {
-                    if data.0 != 0 {
-                        let packet = NetworkPacket::deserialize(buffer[..data.0].to_vec());
-                        if packet.packet_type == NetworkPacketType::Audio {
-                            // Send the audio to the main thread
-                            let audio = unsafe {
-                                Vec::from_raw_parts(
-                                    packet.data.as_ptr() as *mut i16,
-                                    packet.data.len() / 2,
-                                    packet.data.capacity() / 2,
-                                )
-                            };
-                            std::mem::forget(packet.data);
-                            output_audio_sender.send(OutputAudioTaskCommand::Play(audio))?;
-                        } else if packet.packet_type == NetworkPacketType::StopConnection {
-                            current_state = NetworkState::Stopped;
-                            main_thread_sender.send(CallScreenCommand::StopCall)?;
+            NetworkState::InCall(peer) => {
+                if let Some((packet, from)) =
+                    receive_packet(&udp_socket, &mut buffer, &drop_counters)
+                {
+                    if packet.starts_something() && (from != peer || packet.session != session) {
+                        reply_busy(
+                            &udp_socket,
+                            &packet,
+                            from,
+                            &drop_counters,
+                            &mut missed_calls,
+                            &main_thread_sender,
+                        )?;
+                    } else if from != peer {
+                        drop_counters.foreign();
+                    } else if packet.session != session {
+                        drop_counters.stale();
+                    } else if packet.packet_type == NetworkPacketType::StartConnection {
+                        // The caller missed our answer and repeated its offer
+                        if let Some(accept) = &accept {
+                            send_packet(&udp_socket, accept, peer, &drop_counters)?;
+                        }
+                    } else if packet.packet_type == NetworkPacketType::Accept {
+                        // A repeat of the answer this call started with
+                    } else {
+                        let packet = match &mut cipher {
+                            Some(cipher) => cipher.open(&packet),
+                            None => Ok(packet),
+                        };
+                        match packet {
+                            Err(_) => drop_counters.rejected(),
+                            Ok(packet) => {
+                                liveness.last_heard = Some(Instant::now());
+                                if packet.packet_type == NetworkPacketType::Audio {
+                                    if let Ok(frame) = audio_receive_state.frame(&packet.data) {
+                                        receive_stats.record(
+                                            frame.sequence,
+                                            frame.timestamp,
+                                            Instant::now(),
+                                        );
+                                        quality
+                                            .update(|quality| quality.set_received(&receive_stats));
+                                        // Send the audio to the main thread
+                                        output_audio_sender
+                                            .send(OutputAudioTaskCommand::QueueFrame(frame))?;
+                                    } else {
+                                        drop_counters.malformed();
+                                    }
+                                } else if packet.packet_type == NetworkPacketType::Heartbeat {
+                                    match RoundTripTiming::parse(&packet.data) {
+                                        Ok(Some(timing)) => {
+                                            let now = Instant::now();
+                                            if let Some(rtt) = round_trip.heard(&timing, now) {
+                                                quality.update(|quality| quality.rtt = Some(rtt));
+                                            }
+                                        }
+                                        Ok(None) => {}
+                                        Err(_) => drop_counters.malformed(),
+                                    }
+                                } else if packet.packet_type == NetworkPacketType::ReceiverReport {
+                                    match ReceiverReport::parse(&packet.data) {
+                                        Ok(report) => {
+                                            let rtt =
+                                                round_trip.heard(&report.timing, Instant::now());
+                                            quality.update(|quality| {
+                                                quality.far_end = Some(report);
+                                                quality.rtt = rtt.or(quality.rtt);
+                                            });
+                                        }
+                                        Err(_) => drop_counters.malformed(),
+                                    }
+                                } else if packet.packet_type == NetworkPacketType::StopConnection {
+                                    current_state = NetworkState::Stopped;
+                                    main_thread_sender.send(stop_command(packet.stop_reason()))?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            NetworkState::Pairing(None) => {
+                // Waiting for another unit to start pairing with us
+                if let Some((packet, from)) =
+                    receive_packet(&udp_socket, &mut buffer, &drop_counters)
+                {
+                    if packet.packet_type == NetworkPacketType::PairRequest {
+                        if let Ok(request) = PairingHello::parse(&packet.data) {
+                            let responder = Pairing::respond(&identity, &request)?;
+                            session = packet.session;
+                            let reply = responder.last_sent.clone().with_session(session);
+                            send_packet(&udp_socket, &reply, from, &drop_counters)?;
+                            pairing = Some(responder);
+                            liveness = Liveness::incoming(Instant::now());
+                            current_state = NetworkState::Pairing(Some(from));
+                        } else {
+                            drop_counters.malformed();
+                        }
+                    } else if packet.packet_type == NetworkPacketType::StartConnection {
+                        reply_busy(
+                            &udp_socket,
+                            &packet,
+                            from,
+                            &drop_counters,
+                            &mut missed_calls,
+                            &main_thread_sender,
+                        )?;
+                    }
+                }
+            }
+            NetworkState::Pairing(Some(peer)) => {
+                if let Some((packet, from)) =
+                    receive_packet(&udp_socket, &mut buffer, &drop_counters)
+                {
+                    if from == peer && packet.session == session {
+                        liveness.last_heard = Some(Instant::now());
+                    }
+                    if packet.starts_something() && (from != peer || packet.session != session) {
+                        reply_busy(
+                            &udp_socket,
+                            &packet,
+                            from,
+                            &drop_counters,
+                            &mut missed_calls,
+                            &main_thread_sender,
+                        )?;
+                    } else if from != peer {
+                        drop_counters.foreign();
+                    } else if packet.session != session {
+                        drop_counters.stale();
+                    } else if let Some(progress) = pairing.as_mut() {
+                        match packet.packet_type {
+                            NetworkPacketType::PairResponse
+                                if progress.is_initiator() && progress.code.is_none() =>
+                            {
+                                if let Ok(response) = PairingHello::parse(&packet.data) {
+                                    let code = progress.handle_response(&response);
+                                    let reply = progress.last_sent.clone().with_session(session);
+                                    send_packet(&udp_socket, &reply, peer, &drop_counters)?;
+                                    main_thread_sender
+                                        .send(CallScreenCommand::PairingCode(code))?;
+                                } else {
+                                    drop_counters.malformed();
+                                }
+                            }
+                            NetworkPacketType::PairReveal
+                                if !progress.is_initiator() && progress.code.is_none() =>
+                            {
+                                match progress.handle_reveal(&packet.data) {
+                                    Ok(code) => {
+                                        main_thread_sender
+                                            .send(CallScreenCommand::PairingCode(code))?;
+                                    }
+                                    Err(_) => {
+                                        // The peer's nonce doesn't match its
+                                        // commitment: a security event
+                                        drop_counters.rejected();
+                                        let packet = NetworkPacket::new_reject(Some(
+                                            RejectReason::PairingRefused,
+                                        ))
+                                        .with_session(session);
+                                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                                        pairing = None;
+                                        current_state = NetworkState::Stopped;
+                                        main_thread_sender.send(
+                                            CallScreenCommand::PairingFailed(
+                                                CallEndReason::AuthenticationFailed,
+                                            ),
+                                        )?;
+                                    }
+                                }
+                            }
+                            NetworkPacketType::PairConfirm if progress.code.is_some() => {
+                                progress.peer_confirmed = true;
+                            }
+                            NetworkPacketType::Reject | NetworkPacketType::Busy => {
+                                let reason = if packet.packet_type == NetworkPacketType::Busy {
+                                    CallEndReason::LineBusy
+                                } else {
+                                    reject_reason(packet.reject_reason())
+                                };
+                                pairing = None;
+                                current_state = NetworkState::Stopped;
+                                main_thread_sender
+                                    .send(CallScreenCommand::PairingFailed(reason))?;
+                            }
+                            _ => {}
                         }
                     }
                 }
             }
             NetworkState::Stopped => {
                 // We are in a valid state to receive a call
-                let mut buffer = [0; 1024];
-                if let Ok((data, peer)) = udp_socket.recv_from(&mut buffer) {
-                    if data != 0 {
-                        let packet = NetworkPacket::deserialize(buffer[..data].to_vec());
-                        if packet.packet_type == NetworkPacketType::StartConnection {
-                            current_state = NetworkState::PendingConnection(peer);
-
-                            // Send a heartbeat
-                            let packet = NetworkPacket::new_heartbeat();
-                            let packet = packet.serialize();
-                            udp_socket.send_to(&packet, peer)?;
-
-                            // Send a command to the main thread to start the call screen
-                            main_thread_sender.send(CallScreenCommand::IncomingCall(peer))?;
+                if let Some((packet, peer)) =
+                    receive_packet(&udp_socket, &mut buffer, &drop_counters)
+                {
+                    if packet.packet_type == NetworkPacketType::StartConnection {
+                        let offer = Offer::parse(&packet.data);
+                        let bad_key_share = matches!(offer, Err(DecodeError::BadKeyShare { .. }));
+                        let offer = offer.ok();
+                        let format = offer
+                            .as_ref()
+                            .and_then(|offer| capabilities.negotiate(&offer.capabilities));
+                        let exchange = offer.and_then(|offer| offer.key_exchange);
+                        let paired = exchange
+                            .as_ref()
+                            .is_some_and(|exchange| trusted_peers.is_trusted(&exchange.identity));
+                        // Refuse without ringing if we couldn't talk anyway
+                        let refusal = if bad_key_share {
+                            Some(RejectReason::KeyExchangeFailed)
+                        } else if format.is_none() {
+                            Some(RejectReason::IncompatibleFormat)
+                        } else if exchange.is_none() && !security.allow_unencrypted {
+                            Some(RejectReason::EncryptionRequired)
+                        } else if !paired && !security.accepts_unpaired() {
+                            Some(RejectReason::NotPaired)
+                        } else {
+                            None
+                        };
+                        // Derive the keys now so we are ready when the call is picked up
+                        let call_security = match exchange {
+                            Some(exchange) => {
+                                let handshake = Handshake::new(&identity)?;
+                                let our_exchange = handshake.key_exchange;
+                                handshake
+                                    .finish(
+                                        &identity,
+                                        &exchange,
+                                        psk.as_ref(),
+                                        packet.session,
+                                        false,
+                                    )
+                                    .map(|call_cipher| Some((our_exchange, call_cipher)))
+                            }
+                            None => Ok(None),
+                        };
+
+                        match (format, refusal, call_security) {
+                            (Some(format), None, Ok(call_security)) => {
+                                current_state = NetworkState::PendingConnection(peer);
+                                session = packet.session;
+                                liveness = Liveness::incoming(Instant::now());
+                                incoming_format = format;
+                                incoming_security = call_security;
+                                cipher = None;
+
+                                // Send a heartbeat
+                                let packet = NetworkPacket::new_heartbeat().with_session(session);
+                                send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+
+                                // Send a command to the main thread to start the call screen
+                                main_thread_sender
+                                    .send(CallScreenCommand::IncomingCall(peer, session, paired))?;
+                            }
+                            (_, refusal, call_security) => {
+                                // Without a refusal it's the key exchange that failed
+                                if call_security.is_err() {
+                                    drop_counters.rejected();
+                                }
+                                let refusal = refusal.unwrap_or(RejectReason::KeyExchangeFailed);
+                                let packet = NetworkPacket::new_reject(Some(refusal))
+                                    .with_session(packet.session);
+                                send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                            }
                         }
+                    } else if packet.packet_type == NetworkPacketType::PairRequest {
+                        // We only pair when someone asked us to
+                        let packet = NetworkPacket::new_reject(Some(RejectReason::PairingRefused))
+                            .with_session(packet.session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    } else if packet.packet_type == NetworkPacketType::PairConfirm
+                        && last_pairing == Some((peer, packet.session))
+                    {
+                        // The peer missed our confirmation and is still waiting for it
+                        let packet = NetworkPacket::new_pair_confirm().with_session(packet.session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
                     }
                 }
             }
         }
 
+        if let (NetworkState::Pairing(Some(peer)), Some(progress)) = (current_state, &pairing) {
+            if progress.is_complete() {
+                let saved = match progress.peer_identity() {
+                    Some(peer_identity) => trusted_peers.add(TrustedPeer {
+                        identity: peer_identity,
+                        address: peer.ip(),
+                    }),
+                    None => Ok(()),
+                };
+                pairing = None;
+                current_state = NetworkState::Stopped;
+                if let Err(error) = saved {
+                    // Calls still work, only without the pairing. Stop the
+                    // peer waiting on us if it still is.
+                    main_thread_sender.send(CallScreenCommand::Notice(format!(
+                        "Couldn't save the pairing to {}: {}",
+                        trusted_peers.path().display(),
+                        error
+                    )))?;
+                    let packet = NetworkPacket::new_reject(Some(RejectReason::PairingRefused))
+                        .with_session(session);
+                    send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    main_thread_sender.send(CallScreenCommand::PairingFailed(
+                        CallEndReason::PairingNotSaved,
+                    ))?;
+                } else {
+                    let packet = NetworkPacket::new_pair_confirm().with_session(session);
+                    send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    last_pairing = Some((peer, session));
+                    main_thread_sender.send(CallScreenCommand::PairingComplete)?;
+                }
+            }
+        }
+
+        if let NetworkState::PendingConnection(peer)
+        | NetworkState::InCall(peer)
+        | NetworkState::Pairing(Some(peer)) = current_state
+        {
+            let now = Instant::now();
+            if let Some(reason) = liveness.expired(&current_state, &timeouts, now) {
+                // Let the peer know in case it can still hear us
+                if let NetworkState::Pairing(_) = current_state {
+                    let packet = NetworkPacket::new_reject(Some(RejectReason::PairingRefused))
+                        .with_session(session);
+                    send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    pairing = None;
+                    main_thread_sender.send(CallScreenCommand::PairingFailed(reason))?;
+                } else {
+                    let packet = NetworkPacket::new_stop_connection(StopReason::Hangup)
+                        .with_session(session);
+                    send_packet(
+                        &udp_socket,
+                        &protect(packet, &mut cipher),
+                        peer,
+                        &drop_counters,
+                    )?;
+                    main_thread_sender.send(CallScreenCommand::CallFailed(reason))?;
+                }
+                current_state = NetworkState::Stopped;
+            } else if now - liveness.last_sent >= timeouts.heartbeat_interval {
+                let packet = if let Some(progress) = &pairing {
+                    // Repeat where we are in pairing in case it got lost
+                    progress.last_sent.clone()
+                } else if liveness.outgoing
+                    && matches!(current_state, NetworkState::PendingConnection(_))
+                {
+                    // Until the callee picks up, repeat the offer in case it
+                    // or the answer got lost
+                    NetworkPacket::new_start_connection(
+                        &capabilities,
+                        handshake.as_ref().map(|handshake| &handshake.key_exchange),
+                    )
+                } else if let NetworkState::InCall(_) = current_state {
+                    NetworkPacket::new_timed_heartbeat(&round_trip.timing(now))
+                } else {
+                    NetworkPacket::new_heartbeat()
+                };
+                let packet = protect(packet.with_session(session), &mut cipher);
+                send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                liveness.last_sent = now;
+            }
+        }
+
+        if let NetworkState::InCall(peer) = current_state {
+            let now = Instant::now();
+            if now - last_report >= timeouts.report_interval {
+                let report = receive_stats.report(round_trip.timing(now));
+                let packet = NetworkPacket::new_receiver_report(&report).with_session(session);
+                send_packet(
+                    &udp_socket,
+                    &protect(packet, &mut cipher),
+                    peer,
+                    &drop_counters,
+                )?;
+                last_report = now;
+            }
+        }
+
         //We need to check if we are in a valid state for receiving a call (base state, not in call, not made a call)
-        if let NetworkState::Stopped = current_state {}
         match rx.try_recv() {
-            Ok(NetworkTaskCommand::StartConnection(message)) => {
+            Ok(NetworkTaskCommand::StartConnection(message, new_session)) => {
                 // Start the network connection
-                let packet = NetworkPacket::new_start_connection();
-                let serialized_packet = packet.serialize();
-                udp_socket.send_to(&serialized_packet, message)?;
+                session = new_session;
+                cipher = None;
+                pairing = None;
+                accept = None;
+                let call_handshake = Handshake::new(&identity)?;
+                let packet = NetworkPacket::new_start_connection(
+                    &capabilities,
+                    Some(&call_handshake.key_exchange),
+                )
+                .with_session(session);
+                handshake = Some(call_handshake);
+                send_packet(&udp_socket, &packet, message, &drop_counters)?;
 
                 // Wait for a response, it should be a heartbeat
                 current_state = NetworkState::PendingConnection(message);
+                liveness = Liveness::outgoing(Instant::now());
             }
             Ok(NetworkTaskCommand::SendAccept) => {
                 if let NetworkState::PendingConnection(peer) = current_state {
                     // Send an accept packet
-                    let packet = NetworkPacket::new_accept();
-                    let serialized_packet = packet.serialize();
-                    udp_socket.send_to(&serialized_packet, peer)?;
+                    let format = incoming_format.serialize();
+                    let packet = match incoming_security.take() {
+                        Some((exchange, call_cipher)) => {
+                            let confirmation = call_cipher.confirmation(&format);
+                            cipher = Some(call_cipher);
+                            NetworkPacket::new_accept(
+                                &incoming_format,
+                                Some((&exchange, &confirmation)),
+                            )
+                        }
+                        None => {
+                            cipher = None;
+                            NetworkPacket::new_accept(&incoming_format, None)
+                        }
+                    };
+                    let packet = packet.with_session(session);
+                    send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    accept = Some(packet);
                     current_state = NetworkState::InCall(peer);
+                    audio_send_state = AudioSendState::new(incoming_format);
+                    audio_receive_state = AudioReceiveState::new(incoming_format);
+                    receive_stats = ReceiveStats::new(DEVICE_SAMPLE_RATE);
+                    round_trip = RoundTrip::new();
+                    last_report = Instant::now();
+                    quality.update(|quality| quality.clear_network());
+                }
+            }
+            Ok(NetworkTaskCommand::SendReject) => {
+                if let NetworkState::PendingConnection(peer) = current_state {
+                    if !liveness.outgoing {
+                        let packet = NetworkPacket::new_reject(Some(RejectReason::Declined))
+                            .with_session(session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                        current_state = NetworkState::Stopped;
+                    }
                 }
             }
             Ok(NetworkTaskCommand::StopConnection) => {
-                if let NetworkState::InCall(peer) = current_state {
+                if let NetworkState::PendingConnection(peer) | NetworkState::InCall(peer) =
+                    current_state
+                {
                     // Send a stop connection packet
-                    let packet = NetworkPacket::new_stop_connection();
-                    let serialized_packet = packet.serialize();
-                    udp_socket.send_to(&serialized_packet, peer)?;
+                    let packet = NetworkPacket::new_stop_connection(StopReason::Hangup)
+                        .with_session(session);
+                    let packet = protect(packet, &mut cipher);
+                    send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                }
+                if let NetworkState::PendingConnection(_) | NetworkState::InCall(_) = current_state
+                {
+                    current_state = NetworkState::Stopped;
+                }
+            }
+            Ok(NetworkTaskCommand::StartPairing(peer)) => {
+                if current_state == NetworkState::Stopped {
+                    cipher = None;
+                    pairing = None;
+                    if let Some(peer) = peer {
+                        session = new_session_id();
+                        let initiator = Pairing::initiate(&identity)?;
+                        let packet = initiator.last_sent.clone().with_session(session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                        pairing = Some(initiator);
+                        liveness = Liveness::outgoing(Instant::now());
+                    }
+                    current_state = NetworkState::Pairing(peer);
+                }
+            }
+            Ok(NetworkTaskCommand::ConfirmPairing) => {
+                if let (NetworkState::Pairing(Some(peer)), Some(progress)) =
+                    (current_state, pairing.as_mut())
+                {
+                    if progress.code.is_some() {
+                        progress.confirm();
+                        let packet = progress.last_sent.clone().with_session(session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    }
+                }
+            }
+            Ok(NetworkTaskCommand::CancelPairing) => {
+                if let NetworkState::Pairing(peer) = current_state {
+                    if let Some(peer) = peer {
+                        let packet = NetworkPacket::new_reject(Some(RejectReason::PairingRefused))
+                            .with_session(session);
+                        send_packet(&udp_socket, &packet, peer, &drop_counters)?;
+                    }
+                    pairing = None;
+                    current_state = NetworkState::Stopped;
                 }
-                current_state = NetworkState::Stopped;
             }
             Ok(NetworkTaskCommand::SendAudio(audio)) => {
                 if let NetworkState::InCall(remote_peer) = current_state {
                     // Send audio
-                    let packet = NetworkPacket::new_audio(audio);
-                    let serialized_packet = packet.serialize();
-                    udp_socket.send_to(&serialized_packet, remote_peer)?;
+                    for packet in audio_send_state.packets(&audio) {
+                        let packet = protect(packet.with_session(session), &mut cipher);
+                        quality.update(|quality| quality.packets_sent += 1);
+                        send_packet(&udp_socket, &packet, remote_peer, &drop_counters)?;
+                    }
                 }
             }
             Ok(NetworkTaskCommand::Exit) => {
@@ -250,14 +1082,128 @@ fn network_task(rx: Receiver<NetworkTaskCommand>) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn create_network_task() -> anyhow::Result<(JoinHandle<()>, Sender<NetworkTaskCommand>)> {
+pub fn create_network_task(
+    port: u16,
+    identity: Identity,
+    trusted_peers: TrustedPeers,
+    timeouts: NetworkTimeouts,
+    security: SecuritySettings,
+    quality: QualityMonitor,
+) -> anyhow::Result<(JoinHandle<()>, Sender<NetworkTaskCommand>)> {
     let (sender, receiver) = unbounded::<NetworkTaskCommand>();
+    // Bound before we return, so calls to the port can't get lost
+    let udp_socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    udp_socket.set_read_timeout(Some(Duration::from_millis(1)))?;
 
     let join = spawn(move || {
-        if let Err(e) = network_task(receiver) {
+        if let Err(e) = network_task(
+            receiver,
+            udp_socket,
+            identity,
+            trusted_peers,
+            timeouts,
+            security,
+            quality,
+        ) {
             eprintln!("Error in network_task: {}", e);
         }
     });
 
     Ok((join, sender))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+    #[test]
+    fn refusals_are_shown_with_their_reason() {
+        assert_eq!(reject_reason(None), CallEndReason::Declined);
+        assert_eq!(
+            reject_reason(Some(RejectReason::Declined)),
+            CallEndReason::Declined
+        );
+        assert_eq!(
+            reject_reason(Some(RejectReason::NotPaired)),
+            CallEndReason::NotPaired
+        );
+        assert_eq!(
+            reject_reason(Some(RejectReason::IncompatibleFormat)),
+            CallEndReason::IncompatibleFormat
+        );
+    }
+
+    #[test]
+    fn caller_gives_up_on_a_call_nobody_answers() {
+        let timeouts = NetworkTimeouts::default();
+        let start = Instant::now();
+        let mut liveness = Liveness::outgoing(start);
+        let ringing = NetworkState::PendingConnection(PEER);
+
+        let later = start + timeouts.unreachable_timeout + Duration::from_millis(1);
+        assert_eq!(
+            liveness.expired(&ringing, &timeouts, later),
+            Some(CallEndReason::PeerUnreachable)
+        );
+
+        // The callee's heartbeats keep the call going until the ring timeout
+        let later = start + timeouts.ring_timeout + Duration::from_millis(1);
+        liveness.last_heard = Some(later - Duration::from_millis(10));
+        assert_eq!(
+            liveness.expired(&ringing, &timeouts, later - Duration::from_millis(2)),
+            None
+        );
+        assert_eq!(
+            liveness.expired(&ringing, &timeouts, later),
+            Some(CallEndReason::NoAnswer)
+        );
+        // An answered call doesn't ring out
+        assert_eq!(
+            liveness.expired(&NetworkState::InCall(PEER), &timeouts, later),
+            None
+        );
+    }
+
+    #[test]
+    fn pairing_gives_up_when_the_codes_arent_confirmed() {
+        let timeouts = NetworkTimeouts::default();
+        let start = Instant::now();
+        let mut liveness = Liveness::outgoing(start);
+        let pairing = NetworkState::Pairing(Some(PEER));
+
+        let later = start + timeouts.pairing_timeout + Duration::from_millis(1);
+        liveness.last_heard = Some(later - Duration::from_millis(10));
+        assert_eq!(
+            liveness.expired(&pairing, &timeouts, later - Duration::from_millis(2)),
+            None
+        );
+        assert_eq!(
+            liveness.expired(&pairing, &timeouts, later),
+            Some(CallEndReason::PairingTimedOut)
+        );
+    }
+
+    #[test]
+    fn callee_misses_a_call_it_doesnt_pick_up() {
+        let timeouts = NetworkTimeouts::default();
+        let start = Instant::now();
+        let mut liveness = Liveness::incoming(start);
+        let ringing = NetworkState::PendingConnection(PEER);
+
+        let later = start + timeouts.ring_timeout + Duration::from_millis(1);
+        liveness.last_heard = Some(later - Duration::from_millis(10));
+        assert_eq!(
+            liveness.expired(&ringing, &timeouts, later),
+            Some(CallEndReason::MissedCall)
+        );
+
+        // A caller that went quiet is gone, however long we rang
+        liveness.last_heard = Some(start);
+        assert_eq!(
+            liveness.expired(&ringing, &timeouts, later),
+            Some(CallEndReason::ConnectionLost)
+        );
+    }
+}
@@ -1,86 +1,113 @@
-use alsa::{mixer::SelemId, pcm::HwParams};
 use crossbeam::channel::{unbounded, Receiver, Sender};
-use std::thread::{spawn, JoinHandle};
+use std::{
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio_format::{DEVICE_CHANNELS, DEVICE_SAMPLE_RATE},
+    call_stats::{CallQuality, QualityMonitor},
+    echo_canceller::EchoReference,
+    hardware::{frames_to_duration, AudioPlayback, VolumeControl},
+    jitter_buffer::{AudioFrame, JitterBuffer},
+    loss_concealment::LossConcealer,
+};
 
 pub enum OutputAudioTaskCommand {
+    /// Play a local sound (ringtones, prompts) as fast as the device takes it.
     Play(Vec<i16>),
+    /// Audio received during a call, played out through the jitter buffer.
+    QueueFrame(AudioFrame),
     Stop,
     SetVolume(i64),
     SetMute(bool),
     Exit,
 }
 
-const OUTPUT_HARDWARE_NAME: &str = "voldevice";
-const MIXER_HARDWARE_NAME: &str = "hw:1";
-const MIXER_SELEM_NAME: &str = "Softmaster";
-// const OUTPUT_HARDWARE_NAME: &str = "default";
-// const MIXER_HARDWARE_NAME: &str = "default";
-// const MIXER_SELEM_NAME: &str = "Master";
+/// Only pull the next call frame once the device has less than this many
+/// frames left to play.
+const DEVICE_LOW_WATER_FRAMES: usize = 1200;
 
-fn output_audio(receiver: Receiver<OutputAudioTaskCommand>) -> anyhow::Result<()> {
-    let output_pcm = alsa::PCM::new(OUTPUT_HARDWARE_NAME, alsa::Direction::Playback, false)?;
-
-    let hw_params = HwParams::any(&output_pcm)?;
-    hw_params.set_access(alsa::pcm::Access::RWInterleaved)?;
-    hw_params.set_format(alsa::pcm::Format::S16LE)?;
-    hw_params.set_channels(2)?;
-    hw_params.set_rate(48000, alsa::ValueOr::Nearest)?;
-
-    output_pcm.hw_params(&hw_params)?;
-
-    let mixer = alsa::Mixer::new(MIXER_HARDWARE_NAME, false)?;
-
-    let selem_id = SelemId::new(MIXER_SELEM_NAME, 0);
-
-    let selem = mixer.find_selem(&selem_id).unwrap();
-
-    selem.set_playback_volume_range(0, 100)?;
+/// Takes the playout side of the call's quality.
+fn set_playout(quality: &mut CallQuality, jitter_buffer: &JitterBuffer, concealer: &LossConcealer) {
+    quality.buffered_frames = jitter_buffer.buffered_frames();
+    quality.target_frames = jitter_buffer.target_frames();
+    quality.underruns = jitter_buffer.stats.underruns;
+    quality.concealed_frames = concealer.concealed_frames;
+}
 
-    let io = output_pcm.io_i16()?;
+/// When audio written now comes out of the speaker, with `queued` frames
+/// ahead of it.
+fn playing_at(queued: usize) -> Instant {
+    Instant::now() + frames_to_duration(queued as u64)
+}
 
+fn output_audio(
+    receiver: Receiver<OutputAudioTaskCommand>,
+    mut playback: Box<dyn AudioPlayback>,
+    mut volume: Box<dyn VolumeControl>,
+    echo_reference: EchoReference,
+    quality: QualityMonitor,
+) -> anyhow::Result<()> {
     let mut play_buffer = Vec::<i16>::new();
+    // Whether the device has started playing the call, after which it
+    // should never stop until the call does
+    let mut playing_call = false;
+    let mut jitter_buffer = JitterBuffer::new(DEVICE_SAMPLE_RATE, DEVICE_CHANNELS);
+    let mut concealer = LossConcealer::new(DEVICE_CHANNELS);
 
     loop {
         // Check if we have any audio to play AND if we are not currently playing
-        let pcm_status = output_pcm.status()?;
-        if play_buffer.len() > 0 && pcm_status.get_state() != alsa::pcm::State::Running {
+        let status = playback.status()?;
+        if !play_buffer.is_empty() && !status.running {
             // Write ONLY the amount of audio that we can fit in the buffer
-            let buffer_size = pcm_status.get_avail() as usize;
-
-            let buffer_size = play_buffer.len().min(buffer_size);
+            let buffer_size = play_buffer.len().min(status.available * DEVICE_CHANNELS);
 
             let buffer = play_buffer.drain(0..buffer_size).collect::<Vec<i16>>();
 
-            output_pcm.prepare()?;
-            io.writei(&buffer)?;
+            playback.write(&buffer)?;
+            echo_reference.played(&buffer, playing_at(status.queued));
+        } else if play_buffer.is_empty() && status.queued < DEVICE_LOW_WATER_FRAMES {
+            // The device is about to run dry, feed it the next call frame
+            if let Some(frame) = concealer.process(jitter_buffer.pop()) {
+                let xrun = playing_call && !status.running;
+                playback.write(&frame)?;
+                echo_reference.played(&frame, playing_at(status.queued));
+                playing_call = true;
+                quality.update(|quality| {
+                    set_playout(quality, &jitter_buffer, &concealer);
+                    quality.playback_xruns += xrun as u64;
+                });
+            }
         }
         // Receive a command from the main thread
-        if let Ok(cmd) = receiver.try_recv() {
+        if let Ok(cmd) = receiver.recv_timeout(Duration::from_millis(1)) {
             match cmd {
                 OutputAudioTaskCommand::Play(buffer) => {
                     // Play doesn't actually play, it just buffers the audio
-                    // If play_buffer.len() > 4000, clear the first buffer.len() elements
-                    if play_buffer.len() > 4000 {
-                        play_buffer.drain(0..buffer.len());
-                    }
                     play_buffer.extend_from_slice(&buffer);
                 }
+                OutputAudioTaskCommand::QueueFrame(frame) => {
+                    jitter_buffer.push(frame, Instant::now());
+                    quality.update(|quality| set_playout(quality, &jitter_buffer, &concealer));
+                }
                 OutputAudioTaskCommand::Stop => {
                     // Stop playing
-                    output_pcm.drop()?;
+                    playback.stop()?;
+                    echo_reference.clear();
                     play_buffer.clear();
+                    jitter_buffer = JitterBuffer::new(DEVICE_SAMPLE_RATE, DEVICE_CHANNELS);
+                    concealer = LossConcealer::new(DEVICE_CHANNELS);
+                    playing_call = false;
+                    quality.update(|quality| quality.clear_playout());
                 }
                 OutputAudioTaskCommand::SetMute(mute) => {
                     // Set mute
-                    if mute {
-                        selem.set_playback_volume_all(0)?;
-                    } else {
-                        selem.set_playback_volume_all(100)?;
-                    }
+                    volume.set_volume(if mute { 0 } else { 100 })?;
                 }
-                OutputAudioTaskCommand::SetVolume(volume) => {
+                OutputAudioTaskCommand::SetVolume(level) => {
                     // Set volume
-                    selem.set_playback_volume_all(volume)?;
+                    volume.set_volume(level)?;
                 }
                 OutputAudioTaskCommand::Exit => {
                     // Exit the thread
@@ -92,14 +119,19 @@ fn output_audio(receiver: Receiver<OutputAudioTaskCommand>) -> anyhow::Result<()
     Ok(())
 }
 
-pub fn create_output_audio_task() -> (Sender<OutputAudioTaskCommand>, JoinHandle<()>) {
+pub fn create_output_audio_task(
+    playback: Box<dyn AudioPlayback>,
+    volume: Box<dyn VolumeControl>,
+    echo_reference: EchoReference,
+    quality: QualityMonitor,
+) -> (Sender<OutputAudioTaskCommand>, JoinHandle<()>) {
     let (command_tx, command_rx): (
         Sender<OutputAudioTaskCommand>,
         Receiver<OutputAudioTaskCommand>,
     ) = unbounded();
 
     let thread = spawn(move || {
-        if let Err(e) = output_audio(command_rx) {
+        if let Err(e) = output_audio(command_rx, playback, volume, echo_reference, quality) {
             eprintln!("Error in output_audio: {}", e);
         }
     });
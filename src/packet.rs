@@ -0,0 +1,855 @@
+use std::fmt;
+
+use crate::{
+    audio_format::{AudioFormat, Capabilities},
+    codec::CodecId,
+};
+
+/// Two bytes at the start of every datagram so we can tell our packets apart
+/// from anything else that happens to hit the port.
+pub const PACKET_MAGIC: [u8; 2] = *b"EP";
+/// Bump this whenever the wire format changes in an incompatible way.
+pub const PROTOCOL_VERSION: u8 = 7;
+/// magic (2) + version (1) + type (1) + flags (1) + session (4) + payload
+/// length (2)
+pub const HEADER_SIZE: usize = 11;
+/// Largest payload the 16 bit length field can describe.
+pub const MAX_PAYLOAD_SIZE: usize = u16::MAX as usize;
+/// Big enough for any packet we can produce.
+pub const MAX_DATAGRAM_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE;
+/// sequence (4) + timestamp (4) + codec (1) in front of the samples of an
+/// audio packet
+pub const AUDIO_HEADER_SIZE: usize = 9;
+/// Set on packets whose payload is encrypted with the keys of the call.
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+/// An X25519 public key offered for the call key exchange.
+pub const KEY_SHARE_SIZE: usize = 32;
+/// Proof in the Accept packet that the callee derived the same call keys.
+pub const CONFIRMATION_SIZE: usize = 16;
+
+/// Random value exchanged while pairing, see [`crate::pairing`].
+pub const PAIRING_NONCE_SIZE: usize = 32;
+
+pub type KeyShare = [u8; KEY_SHARE_SIZE];
+pub type PairingNonce = [u8; PAIRING_NONCE_SIZE];
+
+/// One side's contribution to the key exchange of an encrypted call.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct KeyExchange {
+    /// Fresh for every call.
+    pub key_share: KeyShare,
+    /// The unit's long term identity key, which pairing vouches for.
+    pub identity: KeyShare,
+}
+
+impl KeyExchange {
+    pub const SERIALIZED_SIZE: usize = 2 * KEY_SHARE_SIZE;
+
+    fn serialize(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.key_share);
+        data.extend_from_slice(&self.identity);
+    }
+
+    fn deserialize(data: &[u8]) -> Self {
+        Self {
+            key_share: data[..KEY_SHARE_SIZE].try_into().unwrap(),
+            identity: data[KEY_SHARE_SIZE..Self::SERIALIZED_SIZE]
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+
+/// Identifies one call. Picked at random by the caller and carried in every
+/// packet of the call, so packets left over from an earlier call with the same
+/// peer can be told apart.
+pub type SessionId = u32;
+
+/// Picks the session ID for a new call. Never 0, so 0 can mean "no call".
+pub fn new_session_id() -> SessionId {
+    loop {
+        let session = crate::random::u32();
+        if session != 0 {
+            return session;
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NetworkPacketType {
+    StartConnection = 0,
+    StopConnection,
+    Audio,
+    Heartbeat,
+    Accept,
+    /// The callee is already in a call.
+    Busy,
+    /// The callee refused the call.
+    Reject,
+    /// Starts pairing: the initiator's identity and a commitment to its nonce.
+    PairRequest,
+    /// The responder's identity and nonce.
+    PairResponse,
+    /// The initiator's nonce, now that it can't be changed to fit the
+    /// responder's.
+    PairReveal,
+    /// Someone confirmed that the short codes on both units match.
+    PairConfirm,
+    /// Sent periodically during a call to tell the peer how its audio is
+    /// arriving. Units that don't know it drop it like any unknown packet.
+    ReceiverReport,
+}
+
+impl TryFrom<u8> for NetworkPacketType {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(NetworkPacketType::StartConnection),
+            1 => Ok(NetworkPacketType::StopConnection),
+            2 => Ok(NetworkPacketType::Audio),
+            3 => Ok(NetworkPacketType::Heartbeat),
+            4 => Ok(NetworkPacketType::Accept),
+            5 => Ok(NetworkPacketType::Busy),
+            6 => Ok(NetworkPacketType::Reject),
+            7 => Ok(NetworkPacketType::PairRequest),
+            8 => Ok(NetworkPacketType::PairResponse),
+            9 => Ok(NetworkPacketType::PairReveal),
+            10 => Ok(NetworkPacketType::PairConfirm),
+            11 => Ok(NetworkPacketType::ReceiverReport),
+            other => Err(DecodeError::UnknownPacketType(other)),
+        }
+    }
+}
+
+/// Why a call was ended or refused, carried by a StopConnection packet.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum StopReason {
+    Hangup = 0,
+    /// The callee supports none of the audio formats the caller offered.
+    IncompatibleFormat,
+    /// The callee answered without encryption and we don't allow that.
+    EncryptionRequired,
+    /// The callee's Accept failed the key confirmation.
+    AuthenticationFailed,
+    /// The callee has not been paired with us and we only call paired units.
+    NotPaired,
+}
+
+impl From<u8> for StopReason {
+    /// Reasons added by newer firmware are treated as a plain hangup.
+    fn from(value: u8) -> Self {
+        match value {
+            1 => StopReason::IncompatibleFormat,
+            2 => StopReason::EncryptionRequired,
+            3 => StopReason::AuthenticationFailed,
+            4 => StopReason::NotPaired,
+            _ => StopReason::Hangup,
+        }
+    }
+}
+
+/// Why a call was refused, optionally carried by Busy and Reject packets.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum RejectReason {
+    /// Someone pressed decline on the callee.
+    Declined = 0,
+    /// The callee supports none of the audio formats the caller offered.
+    IncompatibleFormat,
+    /// The caller offered no key exchange and the callee doesn't take
+    /// unencrypted calls.
+    EncryptionRequired,
+    /// The callee only takes calls from units it has been paired with.
+    NotPaired,
+    /// Pairing was cancelled, or the unit isn't in pairing mode.
+    PairingRefused,
+    /// The caller's half of the key exchange is malformed or unusable.
+    KeyExchangeFailed,
+}
+
+impl From<u8> for RejectReason {
+    /// Reasons added by newer firmware are treated as a plain decline.
+    fn from(value: u8) -> Self {
+        match value {
+            1 => RejectReason::IncompatibleFormat,
+            2 => RejectReason::EncryptionRequired,
+            3 => RejectReason::NotPaired,
+            4 => RejectReason::PairingRefused,
+            5 => RejectReason::KeyExchangeFailed,
+            _ => RejectReason::Declined,
+        }
+    }
+}
+
+/// Why a datagram could not be turned into a [`NetworkPacket`].
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DecodeError {
+    /// Shorter than the fixed header.
+    Truncated {
+        len: usize,
+    },
+    /// Not one of our packets.
+    BadMagic,
+    /// Sent by a unit speaking a different protocol version.
+    UnsupportedVersion(u8),
+    UnknownPacketType(u8),
+    UnknownCodec(u8),
+    /// The header announces a payload length that doesn't match the datagram.
+    LengthMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    /// 16 bit audio with an odd number of bytes.
+    PartialSample {
+        len: usize,
+    },
+    /// The payload is too short for the fields its packet type requires.
+    PayloadTooShort {
+        expected: usize,
+        actual: usize,
+    },
+    /// A key exchange of a length we don't know.
+    BadKeyShare {
+        len: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { len } => {
+                write!(f, "datagram of {} bytes is shorter than the header", len)
+            }
+            DecodeError::BadMagic => write!(f, "bad magic bytes"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            DecodeError::UnknownPacketType(packet_type) => {
+                write!(f, "unknown packet type {}", packet_type)
+            }
+            DecodeError::UnknownCodec(codec) => write!(f, "unknown codec {}", codec),
+            DecodeError::LengthMismatch { declared, actual } => write!(
+                f,
+                "header declares {} payload bytes but {} were received",
+                declared, actual
+            ),
+            DecodeError::PartialSample { len } => {
+                write!(
+                    f,
+                    "{} bytes of 16 bit audio is not a whole number of samples",
+                    len
+                )
+            }
+            DecodeError::PayloadTooShort { expected, actual } => write!(
+                f,
+                "payload needs at least {} bytes but only has {}",
+                expected, actual
+            ),
+            DecodeError::BadKeyShare { len } => write!(f, "key exchange of {} bytes", len),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Why a [`NetworkPacket`] could not be turned into a datagram.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EncodeError {
+    /// The payload is longer than the 16 bit length field can describe.
+    PayloadTooLarge { len: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::PayloadTooLarge { len } => {
+                write!(f, "payload of {} bytes doesn't fit in a packet", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct NetworkPacket {
+    pub packet_type: NetworkPacketType,
+    pub flags: u8,
+    pub session: SessionId,
+    pub data: Vec<u8>,
+}
+
+impl NetworkPacket {
+    fn new(packet_type: NetworkPacketType, data: Vec<u8>) -> Self {
+        Self {
+            packet_type,
+            flags: 0,
+            session: 0,
+            data,
+        }
+    }
+
+    /// Tags the packet with the call it belongs to.
+    pub fn with_session(mut self, session: SessionId) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Offers the audio formats the caller can handle and, for an encrypted
+    /// call, the caller's half of the key exchange.
+    pub fn new_start_connection(
+        capabilities: &Capabilities,
+        key_exchange: Option<&KeyExchange>,
+    ) -> Self {
+        let mut data = Vec::new();
+        match key_exchange {
+            Some(key_exchange) => {
+                data.push(KeyExchange::SERIALIZED_SIZE as u8);
+                key_exchange.serialize(&mut data);
+            }
+            None => data.push(0),
+        }
+        data.extend_from_slice(&capabilities.serialize());
+        Self::new(NetworkPacketType::StartConnection, data)
+    }
+
+    pub fn new_stop_connection(reason: StopReason) -> Self {
+        Self::new(NetworkPacketType::StopConnection, vec![reason as u8])
+    }
+
+    /// `sequence` counts audio packets within a call and `timestamp` is the
+    /// sample clock (in frames) of the first sample in `data`, which holds
+    /// samples encoded with `codec`.
+    pub fn new_audio(sequence: u32, timestamp: u32, codec: CodecId, samples: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(AUDIO_HEADER_SIZE + samples.len());
+        data.extend_from_slice(&sequence.to_le_bytes());
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.push(codec as u8);
+        data.extend_from_slice(samples);
+
+        Self::new(NetworkPacketType::Audio, data)
+    }
+
+    /// Accepts a call with the audio format picked by the callee. An
+    /// encrypted call also carries the callee's half of the key exchange and
+    /// the key confirmation.
+    pub fn new_accept(
+        format: &AudioFormat,
+        security: Option<(&KeyExchange, &[u8; CONFIRMATION_SIZE])>,
+    ) -> Self {
+        let mut data = format.serialize();
+        if let Some((key_exchange, confirmation)) = security {
+            key_exchange.serialize(&mut data);
+            data.extend_from_slice(confirmation);
+        }
+        Self::new(NetworkPacketType::Accept, data)
+    }
+
+    /// Tells a caller we can't take its call because we are in another one.
+    pub fn new_busy(reason: Option<RejectReason>) -> Self {
+        Self::new(
+            NetworkPacketType::Busy,
+            reason.map(|reason| reason as u8).into_iter().collect(),
+        )
+    }
+
+    /// Refuses an incoming call.
+    pub fn new_reject(reason: Option<RejectReason>) -> Self {
+        Self::new(
+            NetworkPacketType::Reject,
+            reason.map(|reason| reason as u8).into_iter().collect(),
+        )
+    }
+
+    /// Asks the peer to pair with us. `commitment` binds us to a nonce we
+    /// only reveal after hearing the peer's.
+    pub fn new_pair_request(identity: &KeyShare, commitment: &[u8; 32]) -> Self {
+        let mut data = identity.to_vec();
+        data.extend_from_slice(commitment);
+        Self::new(NetworkPacketType::PairRequest, data)
+    }
+
+    pub fn new_pair_response(identity: &KeyShare, nonce: &PairingNonce) -> Self {
+        let mut data = identity.to_vec();
+        data.extend_from_slice(nonce);
+        Self::new(NetworkPacketType::PairResponse, data)
+    }
+
+    pub fn new_pair_reveal(nonce: &PairingNonce) -> Self {
+        Self::new(NetworkPacketType::PairReveal, nonce.to_vec())
+    }
+
+    pub fn new_pair_confirm() -> Self {
+        Self::new(NetworkPacketType::PairConfirm, Vec::new())
+    }
+
+    pub fn new_heartbeat() -> Self {
+        Self::new(NetworkPacketType::Heartbeat, Vec::new())
+    }
+
+    /// A heartbeat carrying what the peer needs to measure the round trip
+    /// time.
+    pub fn new_timed_heartbeat(timing: &RoundTripTiming) -> Self {
+        let mut data = Vec::new();
+        timing.serialize(&mut data);
+        Self::new(NetworkPacketType::Heartbeat, data)
+    }
+
+    pub fn new_receiver_report(report: &ReceiverReport) -> Self {
+        let mut data = Vec::with_capacity(ReceiverReport::FIXED_SIZE + 12);
+        data.push(report.fraction_lost);
+        data.extend_from_slice(&report.cumulative_lost.to_le_bytes());
+        data.extend_from_slice(&report.highest_sequence.to_le_bytes());
+        data.extend_from_slice(&report.jitter_us.to_le_bytes());
+        report.timing.serialize(&mut data);
+        Self::new(NetworkPacketType::ReceiverReport, data)
+    }
+
+    /// Whether the packet tries to start a call or pairing, as opposed to
+    /// being part of one.
+    pub fn starts_something(&self) -> bool {
+        matches!(
+            self.packet_type,
+            NetworkPacketType::StartConnection | NetworkPacketType::PairRequest
+        )
+    }
+
+    pub fn stop_reason(&self) -> StopReason {
+        self.data
+            .first()
+            .copied()
+            .map(StopReason::from)
+            .unwrap_or(StopReason::Hangup)
+    }
+
+    /// The reason of a Busy or Reject packet, if the sender gave one.
+    pub fn reject_reason(&self) -> Option<RejectReason> {
+        self.data.first().copied().map(RejectReason::from)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        if self.data.len() > MAX_PAYLOAD_SIZE {
+            return Err(EncodeError::PayloadTooLarge {
+                len: self.data.len(),
+            });
+        }
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        buffer.extend_from_slice(&PACKET_MAGIC);
+        buffer.push(PROTOCOL_VERSION);
+        buffer.push(self.packet_type as u8);
+        buffer.push(self.flags);
+        buffer.extend_from_slice(&self.session.to_le_bytes());
+        buffer.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(&self.data);
+        Ok(buffer)
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(DecodeError::Truncated { len: data.len() });
+        }
+        if data[0..2] != PACKET_MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        // Check the version before anything else so a unit running a newer
+        // firmware gets a clear rejection instead of a confusing type error
+        if data[2] != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(data[2]));
+        }
+        let packet_type = NetworkPacketType::try_from(data[3])?;
+        let flags = data[4];
+        let session = u32::from_le_bytes([data[5], data[6], data[7], data[8]]);
+        let declared = u16::from_le_bytes([data[9], data[10]]) as usize;
+        let payload = &data[HEADER_SIZE..];
+        if payload.len() != declared {
+            return Err(DecodeError::LengthMismatch {
+                declared,
+                actual: payload.len(),
+            });
+        }
+
+        Ok(Self {
+            packet_type,
+            flags,
+            session,
+            data: payload.to_vec(),
+        })
+    }
+}
+
+/// The payload of an [`NetworkPacketType::Audio`] packet.
+pub struct AudioPayload<'a> {
+    pub sequence: u32,
+    pub timestamp: u32,
+    pub codec: CodecId,
+    pub samples: &'a [u8],
+}
+
+impl AudioPayload<'_> {
+    pub fn parse(data: &[u8]) -> Result<AudioPayload<'_>, DecodeError> {
+        if data.len() < AUDIO_HEADER_SIZE {
+            return Err(DecodeError::PayloadTooShort {
+                expected: AUDIO_HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+        Ok(AudioPayload {
+            sequence: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            timestamp: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            codec: CodecId::try_from(data[8])?,
+            samples: &data[AUDIO_HEADER_SIZE..],
+        })
+    }
+}
+
+/// Lets the peer measure the round trip time. The optional payload of a
+/// [`NetworkPacketType::Heartbeat`] packet and the tail of a
+/// [`NetworkPacketType::ReceiverReport`].
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RoundTripTiming {
+    /// The sender's millisecond clock when it sent the packet.
+    pub sent: u32,
+    /// `sent` of the last timed packet the sender got from us, and how many
+    /// milliseconds it held on to it before sending this one.
+    pub echo: Option<(u32, u32)>,
+}
+
+impl RoundTripTiming {
+    fn serialize(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.sent.to_le_bytes());
+        if let Some((sent, held)) = self.echo {
+            data.extend_from_slice(&sent.to_le_bytes());
+            data.extend_from_slice(&held.to_le_bytes());
+        }
+    }
+
+    /// `None` for the bare heartbeats sent during call setup.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, DecodeError> {
+        let field = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        match data.len() {
+            0 => Ok(None),
+            4 => Ok(Some(Self {
+                sent: field(0),
+                echo: None,
+            })),
+            len if len >= 12 => Ok(Some(Self {
+                sent: field(0),
+                echo: Some((field(4), field(8))),
+            })),
+            len => Err(DecodeError::PayloadTooShort {
+                expected: 12,
+                actual: len,
+            }),
+        }
+    }
+}
+
+/// The payload of a [`NetworkPacketType::ReceiverReport`] packet, modelled
+/// on the report blocks of RTCP (RFC 3550 section 6.4).
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ReceiverReport {
+    /// Share of the audio packets lost since the previous report, in 256ths.
+    pub fraction_lost: u8,
+    /// Audio packets lost since the call started.
+    pub cumulative_lost: u32,
+    /// Highest audio sequence number received.
+    pub highest_sequence: u32,
+    /// Interarrival jitter in microseconds.
+    pub jitter_us: u32,
+    pub timing: RoundTripTiming,
+}
+
+impl ReceiverReport {
+    /// fraction lost (1) + cumulative lost (4) + highest sequence (4) +
+    /// jitter (4), followed by the timing
+    pub const FIXED_SIZE: usize = 13;
+
+    pub fn parse(data: &[u8]) -> Result<Self, DecodeError> {
+        let too_short = DecodeError::PayloadTooShort {
+            expected: Self::FIXED_SIZE + 4,
+            actual: data.len(),
+        };
+        if data.len() < Self::FIXED_SIZE {
+            return Err(too_short);
+        }
+        let field = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let timing = RoundTripTiming::parse(&data[Self::FIXED_SIZE..])?.ok_or(too_short)?;
+        Ok(Self {
+            fraction_lost: data[0],
+            cumulative_lost: field(1),
+            highest_sequence: field(5),
+            jitter_us: field(9),
+            timing,
+        })
+    }
+
+    /// Share of the audio packets lost since the previous report.
+    pub fn loss_fraction(&self) -> f32 {
+        self.fraction_lost as f32 / 256.0
+    }
+
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_us as f32 / 1000.0
+    }
+}
+
+/// The payload of a [`NetworkPacketType::StartConnection`] packet.
+pub struct Offer {
+    /// `None` if the caller wants an unencrypted call.
+    pub key_exchange: Option<KeyExchange>,
+    pub capabilities: Capabilities,
+}
+
+impl Offer {
+    pub fn parse(data: &[u8]) -> Result<Self, DecodeError> {
+        let Some((&len, rest)) = data.split_first() else {
+            return Err(DecodeError::PayloadTooShort {
+                expected: 1,
+                actual: 0,
+            });
+        };
+        let len = len as usize;
+        let key_exchange = match len {
+            0 => None,
+            KeyExchange::SERIALIZED_SIZE if rest.len() >= len => {
+                Some(KeyExchange::deserialize(rest))
+            }
+            KeyExchange::SERIALIZED_SIZE => {
+                return Err(DecodeError::PayloadTooShort {
+                    expected: 1 + len,
+                    actual: data.len(),
+                })
+            }
+            _ => return Err(DecodeError::BadKeyShare { len }),
+        };
+        Ok(Self {
+            key_exchange,
+            capabilities: Capabilities::deserialize(&rest[len..])?,
+        })
+    }
+}
+
+/// The payload of a [`NetworkPacketType::Accept`] packet.
+pub struct Answer {
+    pub format: AudioFormat,
+    /// The callee's half of the key exchange and key confirmation, `None` if
+    /// the callee answered without encryption.
+    pub security: Option<(KeyExchange, [u8; CONFIRMATION_SIZE])>,
+}
+
+impl Answer {
+    pub fn parse(data: &[u8]) -> Result<Self, DecodeError> {
+        let format = AudioFormat::deserialize(data)?;
+        let rest = &data[AudioFormat::SERIALIZED_SIZE..];
+        let security = if rest.is_empty() {
+            None
+        } else if rest.len() < KeyExchange::SERIALIZED_SIZE + CONFIRMATION_SIZE {
+            return Err(DecodeError::PayloadTooShort {
+                expected: AudioFormat::SERIALIZED_SIZE
+                    + KeyExchange::SERIALIZED_SIZE
+                    + CONFIRMATION_SIZE,
+                actual: data.len(),
+            });
+        } else {
+            let (key_exchange, confirmation) = rest.split_at(KeyExchange::SERIALIZED_SIZE);
+            Some((
+                KeyExchange::deserialize(key_exchange),
+                confirmation[..CONFIRMATION_SIZE].try_into().unwrap(),
+            ))
+        };
+        Ok(Self { format, security })
+    }
+}
+
+/// The payload of a [`NetworkPacketType::PairRequest`] or
+/// [`NetworkPacketType::PairResponse`] packet.
+pub struct PairingHello {
+    pub identity: KeyShare,
+    /// The commitment in a request, the nonce in a response.
+    pub value: [u8; 32],
+}
+
+impl PairingHello {
+    pub fn parse(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < KEY_SHARE_SIZE + 32 {
+            return Err(DecodeError::PayloadTooShort {
+                expected: KEY_SHARE_SIZE + 32,
+                actual: data.len(),
+            });
+        }
+        Ok(Self {
+            identity: data[..KEY_SHARE_SIZE].try_into().unwrap(),
+            value: data[KEY_SHARE_SIZE..KEY_SHARE_SIZE + 32]
+                .try_into()
+                .unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Vec<u8> {
+        NetworkPacket::new_heartbeat()
+            .with_session(0x1234_5678)
+            .serialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn packets_survive_the_wire() {
+        let packets = [
+            NetworkPacket::new_audio(7, 960, CodecId::Pcm16, &[1, 2, 3, 4]),
+            NetworkPacket::new_stop_connection(StopReason::NotPaired),
+            NetworkPacket::new_reject(None),
+            NetworkPacket::new_pair_confirm(),
+        ];
+        for packet in packets {
+            let packet = packet.with_session(42);
+            let datagram = packet.serialize().unwrap();
+            assert_eq!(datagram.len(), HEADER_SIZE + packet.data.len());
+            assert_eq!(NetworkPacket::deserialize(&datagram), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn busy_and_reject_carry_their_reason() {
+        for reason in [
+            RejectReason::Declined,
+            RejectReason::IncompatibleFormat,
+            RejectReason::EncryptionRequired,
+            RejectReason::NotPaired,
+            RejectReason::PairingRefused,
+            RejectReason::KeyExchangeFailed,
+        ] {
+            for packet in [
+                NetworkPacket::new_busy(Some(reason)),
+                NetworkPacket::new_reject(Some(reason)),
+            ] {
+                let datagram = packet.serialize().unwrap();
+                let packet = NetworkPacket::deserialize(&datagram).unwrap();
+                assert_eq!(packet.reject_reason(), Some(reason));
+            }
+        }
+        // Older units send no reason, newer ones may send one we don't know
+        assert_eq!(NetworkPacket::new_busy(None).reject_reason(), None);
+        let mut packet = NetworkPacket::new_reject(None);
+        packet.data.push(200);
+        assert_eq!(packet.reject_reason(), Some(RejectReason::Declined));
+    }
+
+    #[test]
+    fn oversized_payloads_are_refused() {
+        let packet = NetworkPacket::new_audio(0, 0, CodecId::Pcm16, &[0; MAX_PAYLOAD_SIZE]);
+        assert_eq!(
+            packet.serialize(),
+            Err(EncodeError::PayloadTooLarge {
+                len: MAX_PAYLOAD_SIZE + AUDIO_HEADER_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_datagrams_are_rejected() {
+        let datagram = heartbeat();
+
+        assert_eq!(
+            NetworkPacket::deserialize(&datagram[..HEADER_SIZE - 1]),
+            Err(DecodeError::Truncated {
+                len: HEADER_SIZE - 1
+            })
+        );
+
+        let mut bad_magic = datagram.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            NetworkPacket::deserialize(&bad_magic),
+            Err(DecodeError::BadMagic)
+        );
+
+        let mut newer = datagram.clone();
+        newer[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            NetworkPacket::deserialize(&newer),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        let mut unknown = datagram;
+        unknown[3] = 200;
+        assert_eq!(
+            NetworkPacket::deserialize(&unknown),
+            Err(DecodeError::UnknownPacketType(200))
+        );
+    }
+
+    #[test]
+    fn receiver_reports_survive_the_wire() {
+        for echo in [None, Some((70_000, 12))] {
+            let report = ReceiverReport {
+                fraction_lost: 51,
+                cumulative_lost: 0x0102_0304,
+                highest_sequence: u32::MAX - 1,
+                jitter_us: 4200,
+                timing: RoundTripTiming { sent: 65_000, echo },
+            };
+            let datagram = NetworkPacket::new_receiver_report(&report)
+                .with_session(42)
+                .serialize()
+                .unwrap();
+            let packet = NetworkPacket::deserialize(&datagram).unwrap();
+            assert_eq!(packet.packet_type, NetworkPacketType::ReceiverReport);
+            assert_eq!(ReceiverReport::parse(&packet.data), Ok(report));
+            assert_eq!(report.loss_fraction(), 51.0 / 256.0);
+            assert_eq!(report.jitter_ms(), 4.2);
+        }
+
+        // The timing can't be left out or cut short
+        let report = ReceiverReport {
+            fraction_lost: 0,
+            cumulative_lost: 0,
+            highest_sequence: 0,
+            jitter_us: 0,
+            timing: RoundTripTiming {
+                sent: 1,
+                echo: Some((2, 3)),
+            },
+        };
+        let data = NetworkPacket::new_receiver_report(&report).data;
+        for len in [
+            ReceiverReport::FIXED_SIZE - 1,
+            ReceiverReport::FIXED_SIZE,
+            ReceiverReport::FIXED_SIZE + 6,
+        ] {
+            assert!(
+                matches!(
+                    ReceiverReport::parse(&data[..len]),
+                    Err(DecodeError::PayloadTooShort { .. })
+                ),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        let datagram = NetworkPacket::new_audio(1, 2, CodecId::Pcm16, &[0; 8])
+            .serialize()
+            .unwrap();
+        assert_eq!(
+            NetworkPacket::deserialize(&datagram[..datagram.len() - 3]),
+            Err(DecodeError::LengthMismatch {
+                declared: AUDIO_HEADER_SIZE + 8,
+                actual: AUDIO_HEADER_SIZE + 5,
+            })
+        );
+        assert!(matches!(
+            AudioPayload::parse(&[0; AUDIO_HEADER_SIZE - 1]),
+            Err(DecodeError::PayloadTooShort { .. })
+        ));
+    }
+}
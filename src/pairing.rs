@@ -0,0 +1,329 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::Identity,
+    packet::{
+        DecodeError, KeyShare, NetworkPacket, PairingHello, PairingNonce, PAIRING_NONCE_SIZE,
+    },
+};
+
+/// A unit we have paired with.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TrustedPeer {
+    pub identity: KeyShare,
+    /// Where it was when we paired.
+    pub address: IpAddr,
+}
+
+/// The units we have paired with, stored one per line as the hex identity
+/// key followed by the address.
+pub struct TrustedPeers {
+    path: PathBuf,
+    peers: Vec<TrustedPeer>,
+    /// Lines of the file we couldn't read.
+    skipped: usize,
+}
+
+impl TrustedPeers {
+    /// A missing file just means we haven't paired with anyone yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (peers, skipped) = crate::storage::read_lines(path, |line| {
+            let mut fields = line.split_whitespace();
+            Some(TrustedPeer {
+                identity: fields.next().and_then(from_hex)?,
+                address: fields.next()?.parse().ok()?,
+            })
+        })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            peers,
+            skipped,
+        })
+    }
+
+    /// How many lines of the file [`TrustedPeers::load`] couldn't read.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn is_trusted(&self, identity: &KeyShare) -> bool {
+        self.peers.iter().any(|peer| &peer.identity == identity)
+    }
+
+    /// Adds or updates a peer and saves the list.
+    /// Trusts `peer` from now on, once it is saved.
+    pub fn add(&mut self, peer: TrustedPeer) -> io::Result<()> {
+        let mut peers = self.peers.clone();
+        peers.retain(|known| known.identity != peer.identity);
+        peers.push(peer);
+        let contents = peers
+            .iter()
+            .map(|peer| format!("{} {}\n", to_hex(&peer.identity), peer.address))
+            .collect::<String>();
+        crate::storage::write_atomically(&self.path, contents.as_bytes())?;
+        self.peers = peers;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<KeyShare> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn commitment(identity: &KeyShare, nonce: &PairingNonce) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(b"phone pairing commitment");
+    hash.update(identity);
+    hash.update(nonce);
+    hash.finalize().into()
+}
+
+/// The six digit code both units show. It depends on both identities and
+/// both nonces, and since the initiator committed to its nonce before seeing
+/// the responder's, someone in the middle can't steer both units to the same
+/// code.
+fn short_code(
+    initiator: &KeyShare,
+    responder: &KeyShare,
+    initiator_nonce: &PairingNonce,
+    responder_nonce: &PairingNonce,
+) -> u32 {
+    let mut hash = Sha256::new();
+    hash.update(b"phone pairing code");
+    hash.update(initiator);
+    hash.update(responder);
+    hash.update(initiator_nonce);
+    hash.update(responder_nonce);
+    let digest = hash.finalize();
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000
+}
+
+/// Why pairing with a peer failed.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PairingError {
+    Malformed(DecodeError),
+    /// The nonce the initiator revealed isn't the one it committed to.
+    CommitmentMismatch,
+}
+
+impl fmt::Display for PairingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairingError::Malformed(error) => write!(f, "malformed pairing packet: {}", error),
+            PairingError::CommitmentMismatch => {
+                write!(f, "the revealed nonce doesn't match the commitment")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PairingError {}
+
+impl From<DecodeError> for PairingError {
+    fn from(error: DecodeError) -> Self {
+        PairingError::Malformed(error)
+    }
+}
+
+/// One side of pairing with another unit.
+///
+/// The initiator sends its identity and a commitment to a nonce, the
+/// responder answers with its identity and nonce, then the initiator reveals
+/// its nonce. Both sides show the [`short_code`] and the users confirm it
+/// matches on both screens.
+pub struct Pairing {
+    initiator: bool,
+    identity: KeyShare,
+    nonce: PairingNonce,
+    peer_identity: Option<KeyShare>,
+    /// What the initiator committed to, on the responder.
+    peer_commitment: [u8; 32],
+    /// Set once both nonces are known.
+    pub code: Option<u32>,
+    /// Our user confirmed the code.
+    pub confirmed: bool,
+    /// The peer's user confirmed the code.
+    pub peer_confirmed: bool,
+    /// The last packet we sent, repeated until pairing is over in case it got
+    /// lost.
+    pub last_sent: NetworkPacket,
+}
+
+fn new_nonce() -> io::Result<PairingNonce> {
+    let mut nonce = [0; PAIRING_NONCE_SIZE];
+    crate::random::fill(&mut nonce)?;
+    Ok(nonce)
+}
+
+impl Pairing {
+    pub fn initiate(identity: &Identity) -> io::Result<Self> {
+        let nonce = new_nonce()?;
+        Ok(Self {
+            initiator: true,
+            identity: identity.public,
+            nonce,
+            peer_identity: None,
+            peer_commitment: [0; 32],
+            code: None,
+            confirmed: false,
+            peer_confirmed: false,
+            last_sent: NetworkPacket::new_pair_request(
+                &identity.public,
+                &commitment(&identity.public, &nonce),
+            ),
+        })
+    }
+
+    pub fn respond(identity: &Identity, request: &PairingHello) -> io::Result<Self> {
+        let nonce = new_nonce()?;
+        Ok(Self {
+            initiator: false,
+            identity: identity.public,
+            nonce,
+            peer_identity: Some(request.identity),
+            peer_commitment: request.value,
+            code: None,
+            confirmed: false,
+            peer_confirmed: false,
+            last_sent: NetworkPacket::new_pair_response(&identity.public, &nonce),
+        })
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    pub fn peer_identity(&self) -> Option<KeyShare> {
+        self.peer_identity
+    }
+
+    /// On the initiator: takes the responder's identity and nonce, reveals
+    /// ours and works out the code.
+    pub fn handle_response(&mut self, response: &PairingHello) -> u32 {
+        let code = short_code(
+            &self.identity,
+            &response.identity,
+            &self.nonce,
+            &response.value,
+        );
+        self.peer_identity = Some(response.identity);
+        self.code = Some(code);
+        self.last_sent = NetworkPacket::new_pair_reveal(&self.nonce);
+        code
+    }
+
+    /// On the responder: checks the initiator's nonce against its commitment
+    /// and works out the code.
+    pub fn handle_reveal(&mut self, data: &[u8]) -> Result<u32, PairingError> {
+        let nonce: PairingNonce = data
+            .get(..PAIRING_NONCE_SIZE)
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or(DecodeError::PayloadTooShort {
+                expected: PAIRING_NONCE_SIZE,
+                actual: data.len(),
+            })?;
+        let initiator = self.peer_identity.unwrap_or_default();
+        if commitment(&initiator, &nonce) != self.peer_commitment {
+            return Err(PairingError::CommitmentMismatch);
+        }
+        let code = short_code(&initiator, &self.identity, &nonce, &self.nonce);
+        self.code = Some(code);
+        Ok(code)
+    }
+
+    /// Our user confirmed the code matches.
+    pub fn confirm(&mut self) {
+        self.confirmed = true;
+        self.last_sent = NetworkPacket::new_pair_confirm();
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.confirmed && self.peer_confirmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(packet: &NetworkPacket) -> PairingHello {
+        PairingHello::parse(&packet.data).unwrap()
+    }
+
+    #[test]
+    fn both_sides_show_the_same_code() {
+        let a = Identity::generate().unwrap();
+        let b = Identity::generate().unwrap();
+
+        let mut initiator = Pairing::initiate(&a).unwrap();
+        let mut responder = Pairing::respond(&b, &hello(&initiator.last_sent)).unwrap();
+        let code = initiator.handle_response(&hello(&responder.last_sent));
+        assert_eq!(responder.handle_reveal(&initiator.last_sent.data), Ok(code));
+        assert!(code < 1_000_000);
+
+        assert_eq!(initiator.peer_identity(), Some(b.public));
+        assert_eq!(responder.peer_identity(), Some(a.public));
+
+        initiator.confirm();
+        responder.peer_confirmed = true;
+        assert!(!responder.is_complete());
+        responder.confirm();
+        assert!(responder.is_complete());
+    }
+
+    #[test]
+    fn initiator_cant_change_its_nonce_after_the_response() {
+        let a = Identity::generate().unwrap();
+        let b = Identity::generate().unwrap();
+
+        let initiator = Pairing::initiate(&a).unwrap();
+        let mut responder = Pairing::respond(&b, &hello(&initiator.last_sent)).unwrap();
+        // Someone in the middle picking a nonce to get a particular code
+        let other = Pairing::initiate(&a).unwrap();
+        assert_eq!(
+            responder.handle_reveal(&other.nonce),
+            Err(PairingError::CommitmentMismatch)
+        );
+        assert_eq!(responder.code, None);
+    }
+
+    #[test]
+    fn trusted_peers_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trusted_peers");
+        let peer = TrustedPeer {
+            identity: Identity::generate().unwrap().public,
+            address: "192.168.1.20".parse().unwrap(),
+        };
+
+        let mut peers = TrustedPeers::load(&path).unwrap();
+        assert!(!peers.is_trusted(&peer.identity));
+        peers.add(peer.clone()).unwrap();
+        peers.add(peer.clone()).unwrap();
+
+        let peers = TrustedPeers::load(&path).unwrap();
+        assert!(peers.is_trusted(&peer.identity));
+        assert_eq!(peers.peers, vec![peer]);
+    }
+}
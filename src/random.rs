@@ -0,0 +1,21 @@
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+};
+
+/// Fills `buffer` with random bytes from the kernel.
+pub fn fill(buffer: &mut [u8]) -> std::io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buffer)
+}
+
+/// A random number for identifying things, not for keys.
+pub fn u32() -> u32 {
+    let mut bytes = [0; 4];
+    if fill(&mut bytes).is_ok() {
+        return u32::from_le_bytes(bytes);
+    }
+    // Not unpredictable, but good enough to tell things apart
+    RandomState::new().build_hasher().finish() as u32
+}
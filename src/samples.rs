@@ -0,0 +1,98 @@
+use crate::packet::DecodeError;
+
+/// Serializes interleaved samples as signed 16 bit little endian, whatever
+/// the byte order of the host.
+pub fn encode_le(samples: &[i16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+/// Reads signed 16 bit little endian samples. `bytes` doesn't need to be
+/// aligned. A trailing half sample means the data was cut short and is
+/// rejected rather than silently dropped.
+pub fn decode_le(bytes: &[u8]) -> Result<Vec<i16>, DecodeError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(DecodeError::PartialSample { len: bytes.len() });
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic generator so failures can be reproduced.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn samples(&mut self, len: usize) -> Vec<i16> {
+            (0..len)
+                .map(|_| match self.next() % 8 {
+                    // Make sure the extremes show up often
+                    0 => i16::MIN,
+                    1 => i16::MAX,
+                    2 => 0,
+                    3 => -1,
+                    _ => self.next() as i16,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn layout_is_little_endian() {
+        assert_eq!(encode_le(&[0x1234, -2]), [0x34, 0x12, 0xFE, 0xFF]);
+        assert_eq!(decode_le(&[0x34, 0x12, 0xFE, 0xFF]), Ok(vec![0x1234, -2]));
+    }
+
+    #[test]
+    fn round_trip_any_length() {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        for len in (0..=1024).chain([1599, 1600, 1601, 4801]) {
+            let samples = rng.samples(len);
+            let bytes = encode_le(&samples);
+            assert_eq!(bytes.len(), len * 2);
+            assert_eq!(decode_le(&bytes).unwrap(), samples, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn round_trip_from_unaligned_buffer() {
+        let mut rng = XorShift(0xDEAD_BEEF);
+        for _ in 0..256 {
+            let len = (rng.next() % 2048) as usize;
+            let samples = rng.samples(len);
+            // Start the samples at an odd address, like the payload of a
+            // received datagram
+            let mut buffer = vec![0xAA];
+            buffer.extend(encode_le(&samples));
+            assert_eq!(decode_le(&buffer[1..]).unwrap(), samples);
+        }
+    }
+
+    #[test]
+    fn odd_byte_counts_are_rejected() {
+        let mut rng = XorShift(42);
+        for _ in 0..256 {
+            let len = (rng.next() % 2048) as usize * 2 + 1;
+            let bytes = (0..len).map(|_| rng.next() as u8).collect::<Vec<_>>();
+            assert_eq!(decode_le(&bytes), Err(DecodeError::PartialSample { len }));
+            // Everything but the trailing byte is still valid audio
+            let samples = decode_le(&bytes[..len - 1]).unwrap();
+            assert_eq!(encode_le(&samples), bytes[..len - 1]);
+        }
+    }
+}
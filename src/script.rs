@@ -0,0 +1,618 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+
+use crate::{
+    input_audio_task::InputAudioCommand, network_thread::NetworkTaskCommand,
+    output_audio_task::OutputAudioTaskCommand, packet::new_session_id,
+    terminal_task::CallScreenCommand,
+};
+
+/// How long `expect` waits when the script doesn't say.
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we keep listening once the script is over.
+const LINGER_TIME: Duration = Duration::from_millis(200);
+
+/// What a script can wait for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// A unit is calling us.
+    Incoming,
+    /// A call, placed or taken, is up.
+    Connected,
+    /// The peer hung up, or gave up calling us.
+    HungUp,
+    /// The call couldn't be set up or was lost.
+    Failed,
+}
+
+impl Event {
+    const ALL: [(Event, &'static str); 4] = [
+        (Event::Incoming, "incoming"),
+        (Event::Connected, "connected"),
+        (Event::HungUp, "hungup"),
+        (Event::Failed, "failed"),
+    ];
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = Event::ALL.iter().find(|(event, _)| event == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+/// One line of a script.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Step {
+    Wait(Duration),
+    /// Calls the unit at the address, on our own port unless given.
+    Call(IpAddr, Option<u16>),
+    Accept,
+    Reject,
+    HangUp,
+    Mute(bool),
+    Volume(i64),
+    /// Waits for the event, failing the script if it doesn't come in time.
+    Expect(Event, Duration),
+    Quit,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// `500ms`, `2s` or `1.5s`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    if let Some(millis) = text.strip_suffix("ms") {
+        Some(Duration::from_millis(millis.parse().ok()?))
+    } else {
+        Duration::try_from_secs_f64(text.strip_suffix('s')?.parse().ok()?).ok()
+    }
+}
+
+fn parse_step(line: &str) -> Result<Step, String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let duration = |text: &str| parse_duration(text).ok_or(format!("bad duration {:?}", text));
+    Ok(match words[..] {
+        ["wait", time] => Step::Wait(duration(time)?),
+        ["call", address] => {
+            if let Ok(address) = address.parse::<SocketAddr>() {
+                Step::Call(address.ip(), Some(address.port()))
+            } else {
+                let ip = address
+                    .parse()
+                    .map_err(|_| format!("bad address {:?}", address))?;
+                Step::Call(ip, None)
+            }
+        }
+        ["accept"] => Step::Accept,
+        ["reject"] => Step::Reject,
+        ["hangup"] => Step::HangUp,
+        ["mute"] => Step::Mute(true),
+        ["unmute"] => Step::Mute(false),
+        ["volume", level] => match level.parse() {
+            Ok(level @ 0..=100) => Step::Volume(level),
+            _ => return Err(format!("volume goes from 0 to 100, not {:?}", level)),
+        },
+        ["expect", event, ref timeout @ ..] if timeout.len() <= 1 => {
+            let Some(&(event, _)) = Event::ALL.iter().find(|(_, name)| *name == event) else {
+                return Err(format!("unknown event {:?}", event));
+            };
+            let timeout = match timeout {
+                [time] => duration(time)?,
+                _ => DEFAULT_EXPECT_TIMEOUT,
+            };
+            Step::Expect(event, timeout)
+        }
+        ["quit"] => Step::Quit,
+        _ => return Err(format!("unknown command {:?}", line)),
+    })
+}
+
+/// Commands to drive the phone without a terminal, one per line, with `#`
+/// starting a comment:
+///
+/// ```text
+/// call 127.0.0.1:33446    # the port defaults to ours
+/// expect connected 5s     # incoming, connected, hungup or failed; 10s if no time is given
+/// wait 2s
+/// mute / unmute / volume 80
+/// accept / reject / hangup
+/// quit
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Script {
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut steps = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let step = parse_step(line).map_err(|reason| ScriptError {
+                line: index + 1,
+                reason,
+            })?;
+            steps.push(step);
+        }
+        Ok(Self { steps })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("in {}", path.display()))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CallState {
+    Idle,
+    Calling,
+    Ringing,
+    InCall,
+}
+
+/// Does what the terminal UI would for each step, and reports what happens
+/// on standard output.
+struct Driver {
+    output_audio: Sender<OutputAudioTaskCommand>,
+    input_audio: Sender<InputAudioCommand>,
+    network: Sender<NetworkTaskCommand>,
+    call_rx: Receiver<CallScreenCommand>,
+    call_port: u16,
+    state: CallState,
+    /// Events no `expect` has waited for yet, oldest first.
+    events: VecDeque<Event>,
+    started: Instant,
+    exit: bool,
+}
+
+impl Driver {
+    fn log(&self, message: impl fmt::Display) {
+        println!("[{:8.3}] {}", self.started.elapsed().as_secs_f64(), message);
+    }
+
+    /// Stops the audio of a call that is over.
+    fn end_call(&mut self) -> anyhow::Result<()> {
+        self.state = CallState::Idle;
+        self.input_audio.send(InputAudioCommand::Stop)?;
+        self.output_audio.send(OutputAudioTaskCommand::Stop)?;
+        Ok(())
+    }
+
+    fn accept(&mut self) -> anyhow::Result<()> {
+        if self.state != CallState::Ringing {
+            bail!("no call to accept");
+        }
+        self.network.send(NetworkTaskCommand::SendAccept)?;
+        self.input_audio.send(InputAudioCommand::Start)?;
+        self.output_audio.send(OutputAudioTaskCommand::Stop)?;
+        self.state = CallState::InCall;
+        self.log("accepted");
+        self.events.push_back(Event::Connected);
+        Ok(())
+    }
+
+    fn hang_up(&mut self) -> anyhow::Result<()> {
+        let command = match self.state {
+            CallState::Idle => bail!("no call to hang up"),
+            // Hanging up on a ringing call declines it
+            CallState::Ringing => NetworkTaskCommand::SendReject,
+            CallState::Calling | CallState::InCall => NetworkTaskCommand::StopConnection,
+        };
+        self.network.send(command)?;
+        self.log("hung up");
+        self.end_call()
+    }
+
+    fn handle(&mut self, command: CallScreenCommand) -> anyhow::Result<()> {
+        match command {
+            CallScreenCommand::StartCall(address, _, paired) => {
+                self.output_audio.send(OutputAudioTaskCommand::Stop)?;
+                self.input_audio.send(InputAudioCommand::Start)?;
+                self.state = CallState::InCall;
+                self.log(format_args!(
+                    "connected to {} (paired: {})",
+                    address, paired
+                ));
+                self.events.push_back(Event::Connected);
+            }
+            CallScreenCommand::IncomingCall(address, _, paired) => {
+                self.output_audio.send(OutputAudioTaskCommand::Stop)?;
+                self.state = CallState::Ringing;
+                self.log(format_args!(
+                    "incoming call from {} (paired: {})",
+                    address, paired
+                ));
+                self.events.push_back(Event::Incoming);
+            }
+            CallScreenCommand::StopCall if self.state != CallState::Idle => {
+                self.hang_up()?;
+            }
+            CallScreenCommand::PeerHungUp if self.state != CallState::Idle => {
+                self.log("peer hung up");
+                self.end_call()?;
+                self.events.push_back(Event::HungUp);
+            }
+            CallScreenCommand::CallFailed(reason) => {
+                self.log(format_args!("call failed: {}", reason));
+                self.end_call()?;
+                self.events.push_back(Event::Failed);
+            }
+            CallScreenCommand::AcceptCall => self.accept()?,
+            CallScreenCommand::MissedCall(address) => {
+                self.log(format_args!("turned away a call from {} as busy", address));
+            }
+            CallScreenCommand::Notice(message) => self.log(message),
+            // Volume keys, pairing and discovery are for people, and
+            // anything else is late for a call that is already over
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles what comes in until `deadline`, or only the next command if
+    /// `once`. Returns whether anything came.
+    fn pump(&mut self, deadline: Instant, once: bool) -> anyhow::Result<bool> {
+        let mut received = false;
+        while !self.exit {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.call_rx.recv_timeout(timeout) {
+                Ok(command) => {
+                    self.handle(command)?;
+                    received = true;
+                    if once {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => bail!("the phone went away"),
+            }
+        }
+        Ok(received)
+    }
+
+    fn run(&mut self, step: &Step) -> anyhow::Result<()> {
+        match *step {
+            Step::Wait(duration) => {
+                self.pump(Instant::now() + duration, false)?;
+            }
+            Step::Call(ip, port) => {
+                let address = SocketAddr::new(ip, port.unwrap_or(self.call_port));
+                self.network.send(NetworkTaskCommand::StartConnection(
+                    address,
+                    new_session_id(),
+                ))?;
+                self.state = CallState::Calling;
+                self.log(format_args!("calling {}", address));
+            }
+            Step::Accept => self.accept()?,
+            Step::Reject => {
+                if self.state != CallState::Ringing {
+                    bail!("no call to reject");
+                }
+                self.hang_up()?;
+            }
+            Step::HangUp => self.hang_up()?,
+            Step::Mute(mute) => self
+                .output_audio
+                .send(OutputAudioTaskCommand::SetMute(mute))?,
+            Step::Volume(level) => self
+                .output_audio
+                .send(OutputAudioTaskCommand::SetVolume(level))?,
+            Step::Expect(event, timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(position) = self.events.iter().position(|&e| e == event) {
+                        self.events.drain(..=position);
+                        break;
+                    }
+                    if !self.pump(deadline, true)? {
+                        bail!("no {} event within {:?}", event, timeout);
+                    }
+                }
+            }
+            Step::Quit => self.exit = true,
+        }
+        Ok(())
+    }
+}
+
+fn run_script(script: &Script, driver: &mut Driver) -> anyhow::Result<()> {
+    for (index, step) in script.steps.iter().enumerate() {
+        if driver.exit {
+            break;
+        }
+        driver
+            .run(step)
+            .with_context(|| format!("step {} ({:?}) failed", index + 1, step))?;
+    }
+    // Let the peer know rather than have it time out
+    if driver.state != CallState::Idle {
+        driver.hang_up()?;
+    }
+    // The network task still talks to us while it sends the last packets
+    driver.exit = false;
+    driver.pump(Instant::now() + LINGER_TIME, false)?;
+    Ok(())
+}
+
+/// Runs the script in place of the terminal UI, taking the same commands.
+/// The thread ends with the script, with an error if a step failed.
+pub fn create_script_task(
+    script: Script,
+    call_rx: Receiver<CallScreenCommand>,
+    output_audio: Sender<OutputAudioTaskCommand>,
+    input_audio: Sender<InputAudioCommand>,
+    network: Sender<NetworkTaskCommand>,
+    call_port: u16,
+) -> JoinHandle<anyhow::Result<()>> {
+    thread::spawn(move || {
+        let mut driver = Driver {
+            output_audio,
+            input_audio,
+            network,
+            call_rx,
+            call_port,
+            state: CallState::Idle,
+            events: VecDeque::new(),
+            started: Instant::now(),
+            exit: false,
+        };
+        run_script(&script, &mut driver)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam::channel::unbounded;
+
+    use crate::{
+        audio_format::{DEVICE_CHANNELS, DEVICE_SAMPLE_RATE},
+        call_stats::QualityMonitor,
+        crypto::Identity,
+        echo_canceller::EchoReference,
+        hardware::{MemoryCapture, NullVolume, SimulatedPlayback},
+        input_audio_task::{create_input_audio_task, ProcessingSettings},
+        network_thread::{create_network_task, NetworkTimeouts, SecuritySettings},
+        output_audio_task::create_output_audio_task,
+        pairing::TrustedPeers,
+        terminal_task::CallEndReason,
+        test_harness::next_port,
+    };
+
+    #[test]
+    fn scripts_parse() {
+        let script = Script::parse(
+            "# Call the other unit\n\
+             call 127.0.0.1:40001\n\
+             call ::1\n\
+             expect connected 2.5s  # slow network\n\
+             \n\
+             wait 300ms\n\
+             volume 80\n\
+             mute\n\
+             expect hungup\n\
+             quit\n",
+        )
+        .unwrap();
+        assert_eq!(
+            script.steps,
+            vec![
+                Step::Call("127.0.0.1".parse().unwrap(), Some(40001)),
+                Step::Call("::1".parse().unwrap(), None),
+                Step::Expect(Event::Connected, Duration::from_millis(2500)),
+                Step::Wait(Duration::from_millis(300)),
+                Step::Volume(80),
+                Step::Mute(true),
+                Step::Expect(Event::HungUp, DEFAULT_EXPECT_TIMEOUT),
+                Step::Quit,
+            ]
+        );
+
+        let error = Script::parse("wait 1s\n\nexpect ringing\n").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(error.to_string(), "line 3: unknown event \"ringing\"");
+        assert!(Script::parse("volume 101").is_err());
+        assert!(Script::parse("wait forever").is_err());
+    }
+
+    #[test]
+    fn driver_does_what_the_ui_would() {
+        let (output_tx, _output_rx) = unbounded();
+        let (input_tx, input_rx) = unbounded();
+        let (network_tx, network_rx) = unbounded();
+        let script =
+            Script::parse("expect incoming 1s\naccept\nexpect hungup 1s\nexpect failed 50ms\n")
+                .unwrap();
+        let (call_tx, call_rx) = unbounded();
+        let thread = create_script_task(script, call_rx, output_tx, input_tx, network_tx, 33445);
+
+        let peer = "127.0.0.1:40001".parse().unwrap();
+        call_tx
+            .send(CallScreenCommand::IncomingCall(peer, 1, false))
+            .unwrap();
+        assert!(matches!(
+            network_rx.recv_timeout(Duration::from_secs(1)),
+            Ok(NetworkTaskCommand::SendAccept)
+        ));
+        assert!(matches!(input_rx.recv(), Ok(InputAudioCommand::Start)));
+        call_tx.send(CallScreenCommand::PeerHungUp).unwrap();
+
+        let error = thread.join().unwrap().unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("no failed event within 50ms"),
+            "{:#}",
+            error
+        );
+        // The peer hung up, we don't hang up again
+        assert!(network_rx.try_recv().is_err());
+
+        let (output_tx, _output_rx) = unbounded();
+        let (input_tx, _input_rx) = unbounded();
+        let (network_tx, _network_rx) = unbounded();
+        let script = Script::parse("call 10.0.0.2\nexpect failed 1s\n").unwrap();
+        let (call_tx, call_rx) = unbounded();
+        let thread = create_script_task(script, call_rx, output_tx, input_tx, network_tx, 33445);
+        call_tx
+            .send(CallScreenCommand::CallFailed(CallEndReason::LineBusy))
+            .unwrap();
+        thread.join().unwrap().unwrap();
+    }
+
+    /// How much of each 10 ms block is a 500 Hz tone, from 0 to 1.
+    fn tone_blocks(samples: &[i16]) -> Vec<(f64, f64)> {
+        let left = samples
+            .iter()
+            .step_by(DEVICE_CHANNELS)
+            .map(|&sample| sample as f64)
+            .collect::<Vec<_>>();
+        left.chunks_exact(DEVICE_SAMPLE_RATE as usize / 100)
+            .map(|block| {
+                let energy = block.iter().map(|x| x * x).sum::<f64>();
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, x) in block.iter().enumerate() {
+                    let phase =
+                        2.0 * std::f64::consts::PI * 500.0 * i as f64 / DEVICE_SAMPLE_RATE as f64;
+                    re += x * phase.cos();
+                    im += x * phase.sin();
+                }
+                let tone = 2.0 * (re * re + im * im) / block.len() as f64;
+                (energy, tone / energy.max(1.0))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn audio_from_one_unit_arrives_at_the_other() {
+        let tone = (0..DEVICE_SAMPLE_RATE as usize * 5)
+            .flat_map(|i| {
+                let phase =
+                    2.0 * std::f64::consts::PI * 500.0 * i as f64 / DEVICE_SAMPLE_RATE as f64;
+                [(phase.sin() * 8000.0) as i16; DEVICE_CHANNELS]
+            })
+            .collect::<Vec<_>>();
+
+        let unit = |port: u16, capture: Vec<i16>, script: &str| {
+            let (playback, played) = SimulatedPlayback::memory();
+            let quality = QualityMonitor::default();
+            let echo_reference = EchoReference::default();
+            let (output_tx, output_thread) = create_output_audio_task(
+                Box::new(playback),
+                Box::new(NullVolume),
+                echo_reference.clone(),
+                quality.clone(),
+            );
+            let scratch = tempfile::tempdir().unwrap();
+            let trusted_peers = TrustedPeers::load(&scratch.path().join("trusted_peers")).unwrap();
+            let (network_thread, network_tx) = create_network_task(
+                port,
+                Identity::generate().unwrap(),
+                trusted_peers,
+                NetworkTimeouts::default(),
+                SecuritySettings::default(),
+                quality.clone(),
+            )
+            .unwrap();
+            let (input_thread, input_tx) = create_input_audio_task(
+                network_tx.clone(),
+                Box::new(MemoryCapture::new(capture)),
+                ProcessingSettings {
+                    echo_tail: Some(Duration::from_millis(128)),
+                    voice: Default::default(),
+                },
+                echo_reference,
+                quality.clone(),
+            )
+            .unwrap();
+            let (call_tx, call_rx) = unbounded();
+            network_tx
+                .send(NetworkTaskCommand::MainTaskQueue(call_tx))
+                .unwrap();
+            network_tx
+                .send(NetworkTaskCommand::OutputAudioQueue(output_tx.clone()))
+                .unwrap();
+            let script_thread = create_script_task(
+                Script::parse(script).unwrap(),
+                call_rx,
+                output_tx.clone(),
+                input_tx.clone(),
+                network_tx.clone(),
+                port,
+            );
+            move || {
+                let result = script_thread.join().unwrap();
+                output_tx.send(OutputAudioTaskCommand::Exit).unwrap();
+                input_tx.send(InputAudioCommand::Exit).unwrap();
+                network_tx.send(NetworkTaskCommand::Exit).unwrap();
+                output_thread.join().unwrap();
+                input_thread.join().unwrap();
+                network_thread.join().unwrap();
+                drop(scratch);
+                result.unwrap();
+                let played = played.lock().unwrap().clone();
+                (played, quality.get())
+            }
+        };
+
+        let (caller_port, callee_port) = (next_port(), next_port());
+        let caller = unit(
+            caller_port,
+            tone,
+            &format!(
+                "call 127.0.0.1:{}\nexpect connected\nwait 1s\nhangup\n",
+                callee_port
+            ),
+        );
+        let callee = unit(
+            callee_port,
+            Vec::new(),
+            "expect incoming\naccept\nexpect hungup\n",
+        );
+        let (_, sent) = caller();
+        let (played, received) = callee();
+
+        let blocks = tone_blocks(&played);
+        let tone_blocks = blocks
+            .iter()
+            .filter(|&&(energy, tone)| energy > 1e9 && tone > 0.9)
+            .count();
+        // Most of the second we talked, give or take the jitter buffer
+        assert!(
+            tone_blocks >= 50,
+            "{} of {} blocks",
+            tone_blocks,
+            blocks.len()
+        );
+        // Both ends counted the same call
+        assert!(sent.packets_sent >= 50, "{}", sent);
+        assert!(received.packets_received >= 50, "{}", received);
+        assert!(received.packets_received <= sent.packets_sent);
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides [`data_dir`], from the configuration. Only the first call
+/// counts.
+pub fn set_data_dir(dir: PathBuf) {
+    let _ = DATA_DIR.set(dir);
+}
+
+/// Where we keep what has to survive a restart: our identity, paired
+/// units and so on. The configured directory if any, `~/.local/share/phone`
+/// otherwise.
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = DATA_DIR.get() {
+        return dir.clone();
+    }
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/root".into());
+    Path::new(&home).join(".local/share/phone")
+}
+
+/// Reads a file of one record per line, leaving out blank lines, with
+/// `parse` turning a line into a record. A missing file has no records.
+/// Returns the records along with how many lines `parse` couldn't read, so
+/// a damaged file costs the lines it garbled rather than the whole file.
+pub fn read_lines<T>(
+    path: &Path,
+    mut parse: impl FnMut(&str) -> Option<T>,
+) -> io::Result<(Vec<T>, usize)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error),
+    };
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        match parse(line) {
+            Some(record) => records.push(record),
+            None => skipped += 1,
+        }
+    }
+    Ok((records, skipped))
+}
+
+/// What to tell the user when [`read_lines`] skipped lines of `path`.
+pub fn skipped_lines_notice(path: &Path, skipped: usize) -> Option<String> {
+    (skipped > 0).then(|| format!("Skipped {} unreadable lines of {}", skipped, path.display()))
+}
+
+/// Replaces the file at `path` with `contents`, so a crash or power cut in
+/// the middle leaves either the old or the new file, never half of one.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_with_mode(path, contents, 0o644)
+}
+
+/// Like [`write_atomically`], but only we can read the file.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_with_mode(path, contents, 0o600)
+}
+
+fn write_with_mode(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
@@ -1,8 +1,16 @@
 use crate::{
-    input_audio_task::InputAudioCommand, network_thread::NetworkTaskCommand,
+    call_history::{format_time, CallDirection, CallEnd, CallHistory, CallRecord},
+    call_stats::{CallQuality, QualityMonitor},
+    contacts::{Contact, Contacts},
+    discovery::NearbyDevice,
+    hardware::{LedColor, Leds, MemoryLeds},
+    input_audio_task::InputAudioCommand,
+    network_thread::NetworkTaskCommand,
     output_audio_task::OutputAudioTaskCommand,
+    packet::{new_session_id, SessionId},
+    voice_processing::{VoiceSettings, SUPPRESSION_RANGE_DB},
 };
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{Receiver, Sender};
 use crossterm::{
     event::{self, Event, KeyCode, KeyEvent},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
@@ -10,8 +18,8 @@ use crossterm::{
 };
 use ratatui::{prelude::*, widgets::*};
 use std::{
-    fs::File,
-    io::{stdout, Write},
+    fmt,
+    io::stdout,
     net::{IpAddr, SocketAddr},
     thread::{self, JoinHandle},
 };
@@ -64,11 +72,16 @@ struct HomeScreenState {
 
 impl HomeScreenState {
     pub fn new() -> HomeScreenState {
-        let mut menu_list_state = StatefulList::with_items(vec!["Call", "Contacts", "Exit"]);
+        let mut menu_list_state = StatefulList::with_items(vec![
+            "Call",
+            "Nearby devices",
+            "Contacts",
+            "Call history",
+            "Pair",
+            "Exit",
+        ]);
         menu_list_state.next();
-        HomeScreenState {
-            menu_list_state: menu_list_state,
-        }
+        HomeScreenState { menu_list_state }
     }
 }
 
@@ -79,23 +92,97 @@ enum CallScreenStatus {
     InCall {
         start_time: std::time::Instant,
     },
-    CallEnded {
-        start_time: std::time::Instant,
-        end_time: std::time::Instant,
+    Failed {
+        reason: CallEndReason,
+        at: std::time::Instant,
     },
 }
 
+/// How long a failed call stays on screen before we go back home.
+const FAILED_CALL_SCREEN_TIME: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How long a notice stays on the status line.
+const NOTICE_TIME: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Why a call could not be set up or ended on its own.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallEndReason {
+    IncompatibleFormat,
+    /// The peer answered but nobody picked up.
+    NoAnswer,
+    /// Nobody picked up the call ringing here.
+    MissedCall,
+    /// The peer never answered our call at all.
+    PeerUnreachable,
+    /// We stopped hearing from the peer.
+    ConnectionLost,
+    /// The peer is in another call.
+    LineBusy,
+    /// The peer refused the call.
+    Declined,
+    /// One side requires encryption and the other didn't offer it.
+    EncryptionRequired,
+    /// The peer's keys don't match ours, most likely a different pre-shared
+    /// key.
+    AuthenticationFailed,
+    /// One side only talks to units it has paired with.
+    NotPaired,
+    /// The peer isn't pairing, or its user cancelled.
+    PairingRefused,
+    /// The codes weren't confirmed on both units in time.
+    PairingTimedOut,
+    /// The codes matched but we couldn't save the peer.
+    PairingNotSaved,
+}
+
+impl fmt::Display for CallEndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallEndReason::IncompatibleFormat => write!(f, "No common audio format"),
+            CallEndReason::NoAnswer => write!(f, "No answer"),
+            CallEndReason::MissedCall => write!(f, "Missed call"),
+            CallEndReason::PeerUnreachable => write!(f, "Peer unreachable"),
+            CallEndReason::ConnectionLost => write!(f, "Connection lost"),
+            CallEndReason::LineBusy => write!(f, "Line busy"),
+            CallEndReason::Declined => write!(f, "Call declined"),
+            CallEndReason::EncryptionRequired => write!(f, "Encryption required"),
+            CallEndReason::AuthenticationFailed => write!(f, "Security check failed"),
+            CallEndReason::NotPaired => write!(f, "Not paired"),
+            CallEndReason::PairingRefused => write!(f, "Pairing refused"),
+            CallEndReason::PairingTimedOut => write!(f, "Pairing timed out"),
+            CallEndReason::PairingNotSaved => write!(f, "Couldn't save the pairing"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum CallScreenCommand {
-    StartCall(SocketAddr),
-    IncomingCall(SocketAddr),
+    /// The peer picked up. The flag says whether it is a unit we paired
+    /// with.
+    StartCall(SocketAddr, SessionId, bool),
+    IncomingCall(SocketAddr, SessionId, bool),
+    /// Hangs up, or declines a ringing call.
     StopCall,
-    EndCall,
+    /// The peer hung up, or the caller gave up before we answered.
+    PeerHungUp,
+    CallFailed(CallEndReason),
     AcceptCall,
-    RejectCall,
     IncreaseVolume,
     DecreaseVolume,
     ToggleMute,
-    Exit,
+    /// Opens the pairing screen, from the hardware pairing key.
+    StartPairing,
+    /// The code to compare with the one on the peer's screen.
+    PairingCode(u32),
+    PairingComplete,
+    PairingFailed(CallEndReason),
+    /// The units discovery currently hears on the local network.
+    NearbyDevices(Vec<NearbyDevice>),
+    /// A call from the given unit that we turned away as busy.
+    MissedCall(SocketAddr),
+    /// Something the user should know about that doesn't stop the phone,
+    /// shown on the status line for a while.
+    Notice(String),
 }
 
 struct CallScreenState {
@@ -104,35 +191,95 @@ struct CallScreenState {
     pub remote_ip: std::net::IpAddr,
     pub remote_name: Option<String>,
     pub call_status: CallScreenStatus,
+    /// Session ID of the call, as carried in its packets.
+    pub session: SessionId,
+    /// Whether the peer is a unit we paired with.
+    pub paired: bool,
+    pub direction: CallDirection,
+    /// When the call started ringing, for the call history.
+    pub started: std::time::SystemTime,
+    /// Whether the call quality panel is up.
+    pub show_stats: bool,
 }
 
 impl CallScreenState {
-    pub fn new(ip: IpAddr) -> CallScreenState {
+    pub fn new(ip: IpAddr, session: SessionId, paired: bool) -> CallScreenState {
         CallScreenState {
+            session,
+            paired,
+            direction: CallDirection::Outgoing,
+            started: std::time::SystemTime::now(),
             is_muted: false,
             volume: 100,
             call_status: CallScreenStatus::Calling,
             remote_ip: ip,
             remote_name: None,
+            show_stats: false,
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ContactField {
+    Name,
+    Ip,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ContactsMode {
+    Browse,
+    /// Typing into the search box.
+    Search,
+    /// Adding a contact, or editing the one at the given index.
+    Edit {
+        index: Option<usize>,
+        field: ContactField,
+    },
+}
+
 pub struct ContactsScreenState {
-    pub contacts: Vec<String>,
+    /// Indices into the address book of the contacts matching `search`.
+    pub contacts: Vec<usize>,
+    /// Position in `contacts`.
     pub selected_contact: Option<usize>,
+    pub search: String,
+    mode: ContactsMode,
     pub new_contact_name: String,
     pub new_contact_ip: String,
+    /// Why the last action didn't work.
+    pub error: Option<String>,
 }
 
 impl ContactsScreenState {
-    pub fn new() -> ContactsScreenState {
-        ContactsScreenState {
+    pub fn new(contacts: &Contacts) -> ContactsScreenState {
+        let mut state = ContactsScreenState {
             contacts: Vec::new(),
             selected_contact: None,
+            search: String::new(),
+            mode: ContactsMode::Browse,
             new_contact_name: String::new(),
             new_contact_ip: String::new(),
-        }
+            error: None,
+        };
+        state.refresh(contacts);
+        state
+    }
+
+    /// Reruns the search after the query or the address book changed.
+    fn refresh(&mut self, contacts: &Contacts) {
+        self.contacts = contacts.search(&self.search);
+        self.selected_contact = match self.selected_contact {
+            _ if self.contacts.is_empty() => None,
+            Some(i) => Some(i.min(self.contacts.len() - 1)),
+            None => Some(0),
+        };
+    }
+
+    /// Index into the address book of the selected contact.
+    fn selected(&self) -> Option<usize> {
+        self.selected_contact
+            .and_then(|i| self.contacts.get(i))
+            .copied()
     }
 }
 
@@ -140,17 +287,98 @@ struct CallInfoScreenState {
     pub ip: String,
 }
 
+#[derive(Clone, Copy)]
+enum PairingStatus {
+    /// Typing the address of the unit to pair with.
+    EnterAddress,
+    /// Waiting for the other unit to answer or start pairing.
+    Waiting,
+    ShowCode {
+        code: u32,
+        confirmed: bool,
+    },
+    Done {
+        at: std::time::Instant,
+    },
+    Failed {
+        reason: CallEndReason,
+        at: std::time::Instant,
+    },
+}
+
+struct HistoryScreenState {
+    pub list_state: ListState,
+}
+
+impl HistoryScreenState {
+    pub fn new(history: &CallHistory) -> HistoryScreenState {
+        let mut list_state = ListState::default();
+        if !history.records().is_empty() {
+            list_state.select(Some(0));
+        }
+        HistoryScreenState { list_state }
+    }
+}
+
+struct NearbyScreenState {
+    pub list_state: ListState,
+}
+
+impl NearbyScreenState {
+    pub fn new() -> NearbyScreenState {
+        NearbyScreenState {
+            list_state: ListState::default(),
+        }
+    }
+}
+
+struct PairingScreenState {
+    pub ip: String,
+    pub status: PairingStatus,
+}
+
+impl PairingScreenState {
+    pub fn new() -> PairingScreenState {
+        PairingScreenState {
+            ip: String::new(),
+            status: PairingStatus::EnterAddress,
+        }
+    }
+}
+
 enum ScreenState {
     Home(HomeScreenState),
     Contacts(ContactsScreenState),
     EnterCallInfo(CallInfoScreenState),
     Call(CallScreenState),
+    Pairing(PairingScreenState),
+    Nearby(NearbyScreenState),
+    History(HistoryScreenState),
 }
 
-struct LedInfo {
-    red: File,
-    green: File,
-    blue: File,
+const OFF: LedColor = [0, 0, 0];
+const WHITE: LedColor = [128, 128, 128];
+const RED: LedColor = [128, 0, 0];
+const GREEN: LedColor = [0, 128, 0];
+const BLUE: LedColor = [0, 0, 128];
+
+/// The LEDs while ringing, one step per frame drawn.
+const RING_ANIMATION: [[LedColor; 3]; 5] = [
+    [OFF, OFF, OFF],
+    [WHITE, WHITE, WHITE],
+    [RED, GREEN, BLUE],
+    [GREEN, BLUE, RED],
+    [BLUE, RED, GREEN],
+];
+
+/// What the UI starts from.
+pub struct TerminalSettings {
+    /// Port we call units on when we don't know theirs.
+    pub call_port: u16,
+    /// Noise suppression and gain control until changed on the call screen.
+    pub voice: VoiceSettings,
+    /// What we play while a call rings, decoded once up front.
+    pub ringtone: Vec<i16>,
 }
 
 struct AppState {
@@ -159,10 +387,24 @@ struct AppState {
     pub network_sender: Sender<NetworkTaskCommand>,
     pub call_rx: Receiver<CallScreenCommand>,
     pub screen_state: ScreenState,
-    pub led_0: LedInfo,
-    pub led_1: LedInfo,
-    pub led_2: LedInfo,
-    pub animation_state: u8,
+    /// Units discovery hears on the local network, kept while on other
+    /// screens.
+    pub nearby_devices: Vec<NearbyDevice>,
+    pub contacts: Contacts,
+    pub call_history: CallHistory,
+    /// How the current call is going, measured by the other tasks.
+    pub call_quality: QualityMonitor,
+    /// The three RGB LEDs.
+    pub leds: Box<dyn Leds>,
+    pub animation_state: usize,
+    /// Port we call units on when we don't know theirs.
+    pub call_port: u16,
+    /// Noise suppression and gain control of our microphone, changed from
+    /// the call screen for the rest of the run.
+    pub voice: VoiceSettings,
+    pub ringtone: Vec<i16>,
+    /// The notice on the status line and when it went up.
+    pub notice: Option<(String, std::time::Instant)>,
 }
 
 impl AppState {
@@ -171,181 +413,114 @@ impl AppState {
         input_audio_sender: Sender<InputAudioCommand>,
         network_sender: Sender<NetworkTaskCommand>,
         call_rx: Receiver<CallScreenCommand>,
-    ) -> AppState {
-        let led_0 = LedInfo {
-            blue: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:blue0/brightness")
-                .unwrap(),
-            green: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:green0/brightness")
-                .unwrap(),
-            red: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:red0/brightness")
-                .unwrap(),
-        };
-        let led_1 = LedInfo {
-            blue: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:blue1/brightness")
-                .unwrap(),
-            green: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:green1/brightness")
-                .unwrap(),
-            red: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:red1/brightness")
-                .unwrap(),
-        };
-        let led_2 = LedInfo {
-            blue: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:blue2/brightness")
-                .unwrap(),
-            green: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:green2/brightness")
-                .unwrap(),
-            red: std::fs::OpenOptions::new()
-                .write(true)
-                .open("/sys/class/leds/pca995x:red2/brightness")
-                .unwrap(),
-        };
-
-        AppState {
+        leds: Box<dyn Leds>,
+        settings: TerminalSettings,
+        call_quality: QualityMonitor,
+    ) -> anyhow::Result<AppState> {
+        let data_dir = crate::storage::data_dir();
+        let contacts_path = data_dir.join("contacts");
+        let history_path = data_dir.join("call_history");
+        let contacts = Contacts::load(&contacts_path)?;
+        let call_history = CallHistory::load(&history_path)?;
+        // Say so if either file was damaged, rather than quietly losing it
+        let notice = crate::storage::skipped_lines_notice(&contacts_path, contacts.skipped())
+            .or_else(|| crate::storage::skipped_lines_notice(&history_path, call_history.skipped()))
+            .map(|message| (message, std::time::Instant::now()));
+
+        Ok(AppState {
             output_audio_sender,
             input_audio_sender,
             network_sender,
             call_rx,
-            led_0,
-            led_1,
-            led_2,
+            leds,
             animation_state: 0,
+            call_port: settings.call_port,
             screen_state: ScreenState::Home(HomeScreenState::new()),
-        }
+            nearby_devices: Vec::new(),
+            contacts,
+            call_history,
+            call_quality,
+            voice: settings.voice,
+            ringtone: settings.ringtone,
+            notice,
+        })
     }
 
-    fn animation(&mut self) {
-        // Animation is:
-        // 0: All off
-        // 1: All on
-        // 2: led0.red on, led1.green on, led2.blue on
-        // 3: led0.green on, led1.blue on, led2.red on
-        // 4: led0.blue on, led1.red on, led2.green on
-
-        let led0 = &mut self.led_0;
-        let led1 = &mut self.led_1;
-        let led2 = &mut self.led_2;
-
-        match self.animation_state {
-            0 => {
-                led0.red.write_all(b"0").unwrap();
-                led0.green.write_all(b"0").unwrap();
-                led0.blue.write_all(b"0").unwrap();
-
-                led1.red.write_all(b"0").unwrap();
-                led1.green.write_all(b"0").unwrap();
-                led1.blue.write_all(b"0").unwrap();
-
-                led2.red.write_all(b"0").unwrap();
-                led2.green.write_all(b"0").unwrap();
-                led2.blue.write_all(b"0").unwrap();
-
-                self.animation_state = 1;
-            }
-            1 => {
-                led0.red.write_all(b"128").unwrap();
-                led0.green.write_all(b"128").unwrap();
-                led0.blue.write_all(b"128").unwrap();
-
-                led1.red.write_all(b"128").unwrap();
-                led1.green.write_all(b"128").unwrap();
-                led1.blue.write_all(b"128").unwrap();
-
-                led2.red.write_all(b"128").unwrap();
-                led2.green.write_all(b"128").unwrap();
-                led2.blue.write_all(b"128").unwrap();
-
-                self.animation_state = 2;
-            }
-            2 => {
-                led0.red.write_all(b"128").unwrap();
-                led0.green.write_all(b"0").unwrap();
-                led0.blue.write_all(b"0").unwrap();
-
-                led1.red.write_all(b"0").unwrap();
-                led1.green.write_all(b"128").unwrap();
-                led1.blue.write_all(b"0").unwrap();
-
-                led2.red.write_all(b"0").unwrap();
-                led2.green.write_all(b"0").unwrap();
-                led2.blue.write_all(b"128").unwrap();
-
-                self.animation_state = 3;
-            }
-            3 => {
-                led0.red.write_all(b"0").unwrap();
-                led0.green.write_all(b"128").unwrap();
-                led0.blue.write_all(b"0").unwrap();
-
-                led1.red.write_all(b"0").unwrap();
-                led1.green.write_all(b"0").unwrap();
-                led1.blue.write_all(b"128").unwrap();
-
-                led2.red.write_all(b"128").unwrap();
-                led2.green.write_all(b"0").unwrap();
-                led2.blue.write_all(b"0").unwrap();
+    /// Puts `message` on the status line.
+    fn notice(&mut self, message: String) {
+        self.notice = Some((message, std::time::Instant::now()));
+    }
 
-                self.animation_state = 4;
+    /// Adds the call on screen to the call history, unless it already ended.
+    fn log_call(&mut self, end: CallEnd) {
+        let ScreenState::Call(call_state) = &self.screen_state else {
+            return;
+        };
+        let (answered, duration) = match call_state.call_status {
+            CallScreenStatus::Calling | CallScreenStatus::IncomingCall => {
+                (false, std::time::Duration::ZERO)
             }
-            4 => {
-                led0.red.write_all(b"0").unwrap();
-                led0.green.write_all(b"0").unwrap();
-                led0.blue.write_all(b"128").unwrap();
-
-                led1.red.write_all(b"128").unwrap();
-                led1.green.write_all(b"0").unwrap();
-                led1.blue.write_all(b"0").unwrap();
+            CallScreenStatus::InCall { start_time } => (true, start_time.elapsed()),
+            CallScreenStatus::Failed { .. } => return,
+        };
+        let record = CallRecord {
+            direction: call_state.direction,
+            peer: call_state.remote_ip,
+            started: call_state.started,
+            duration,
+            end,
+            answered,
+            seen: false,
+            summary: answered.then(|| self.call_quality.get().into()),
+        };
+        self.add_to_history(record);
+    }
 
-                led2.red.write_all(b"0").unwrap();
-                led2.green.write_all(b"128").unwrap();
-                led2.blue.write_all(b"0").unwrap();
+    fn add_to_history(&mut self, record: CallRecord) {
+        if let Err(error) = self.call_history.add(record) {
+            self.notice(format!("Couldn't save the call history: {}", error));
+        }
+    }
 
-                self.animation_state = 0;
-            }
-            _ => unreachable!(),
+    fn show_leds(&mut self, colors: [LedColor; 3]) {
+        if let Err(error) = self.leds.show(colors) {
+            // Don't flood the status line with the same error every frame
+            self.notice(format!("Giving up on the LEDs: {}", error));
+            self.leds = Box::new(MemoryLeds::new().0);
         }
     }
 
+    fn animation(&mut self) {
+        self.show_leds(RING_ANIMATION[self.animation_state]);
+        self.animation_state = (self.animation_state + 1) % RING_ANIMATION.len();
+    }
+
     fn stop_animation(&mut self) {
         self.animation_state = 0;
-        let led0 = &mut self.led_0;
-        let led1 = &mut self.led_1;
-        let led2 = &mut self.led_2;
-
-        led0.red.write_all(b"0").unwrap();
-        led0.green.write_all(b"0").unwrap();
-        led0.blue.write_all(b"0").unwrap();
-
-        led1.red.write_all(b"0").unwrap();
-        led1.green.write_all(b"0").unwrap();
-        led1.blue.write_all(b"0").unwrap();
-
-        led2.red.write_all(b"0").unwrap();
-        led2.green.write_all(b"0").unwrap();
-        led2.blue.write_all(b"0").unwrap();
+        self.show_leds([OFF; 3]);
     }
 }
 
-fn main_screen<B: Backend>(f: &mut Frame<B>, state: &mut HomeScreenState) {
+fn main_screen<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    state: &mut HomeScreenState,
+    missed_calls: usize,
+) {
     let menu_items = state
         .menu_list_state
         .items
         .iter()
-        .map(|i| ListItem::new(Span::raw(*i)))
+        .map(|&i| match i {
+            "Call history" if missed_calls > 0 => ListItem::new(Line::from(vec![
+                Span::raw(i),
+                Span::styled(
+                    format!(" ({} missed)", missed_calls),
+                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ),
+            ])),
+            _ => ListItem::new(Span::raw(i)),
+        })
         .collect::<Vec<ListItem>>();
 
     let menu = List::new(menu_items)
@@ -354,10 +529,10 @@ fn main_screen<B: Backend>(f: &mut Frame<B>, state: &mut HomeScreenState) {
         .highlight_symbol(">> ");
 
     // Draw the menu
-    f.render_stateful_widget(menu, f.size(), &mut state.menu_list_state.state);
+    f.render_stateful_widget(menu, area, &mut state.menu_list_state.state);
 }
 
-fn enter_call_info<B: Backend>(f: &mut Frame<B>, state: &mut CallInfoScreenState) {
+fn enter_call_info<B: Backend>(f: &mut Frame<B>, area: Rect, state: &mut CallInfoScreenState) {
     // Render an input box for the IP in the center of the screen
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -366,7 +541,7 @@ fn enter_call_info<B: Backend>(f: &mut Frame<B>, state: &mut CallInfoScreenState
             Constraint::Percentage(40),
             Constraint::Percentage(30),
         ])
-        .split(f.size());
+        .split(area);
 
     let layout = Layout::default()
         .direction(Direction::Vertical)
@@ -423,13 +598,131 @@ fn call_screen_controls<B: Backend>(f: &mut Frame<B>, rect: Rect, state: &mut Ca
     f.render_widget(end_call, chunks[2]);
 }
 
-fn call_screen<B: Backend>(f: &mut Frame<B>, state: &mut CallScreenState) {
+/// What is done to our microphone, with the keys that change it. The gain
+/// control target and most gain have no keys, only the config file sets
+/// them.
+fn voice_status(voice: &VoiceSettings) -> String {
+    let noise = if voice.noise_suppression {
+        format!("{} dB", voice.suppression_db)
+    } else {
+        "off".to_string()
+    };
+    let gain = if voice.gain_control {
+        format!(
+            "{} dBFS, up to {} dB, set in config",
+            voice.target_dbfs, voice.max_gain_db
+        )
+    } else {
+        "off".to_string()
+    };
+    format!(
+        "Noise suppression: {} (n, -/+)   Gain control: {} (g)",
+        noise, gain
+    )
+}
+
+/// The voice settings after pressing `code` on the call screen, if it's one
+/// of their keys.
+fn adjust_voice(voice: VoiceSettings, code: KeyCode) -> Option<VoiceSettings> {
+    const SUPPRESSION_STEP_DB: u32 = 3;
+    let mut voice = voice;
+    match code {
+        KeyCode::Char('n') => voice.noise_suppression = !voice.noise_suppression,
+        KeyCode::Char('g') => voice.gain_control = !voice.gain_control,
+        KeyCode::Char('-') => {
+            voice.suppression_db = voice
+                .suppression_db
+                .saturating_sub(SUPPRESSION_STEP_DB)
+                .max(*SUPPRESSION_RANGE_DB.start())
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            voice.suppression_db =
+                (voice.suppression_db + SUPPRESSION_STEP_DB).min(*SUPPRESSION_RANGE_DB.end())
+        }
+        _ => return None,
+    }
+    Some(voice)
+}
+
+fn call_stats_panel<B: Backend>(f: &mut Frame<B>, rect: Rect, quality: &CallQuality) {
+    let rtt = quality
+        .rtt
+        .map_or_else(|| "-".to_string(), |rtt| format!("{} ms", rtt.as_millis()));
+    let lines = vec![
+        Line::from(format!(
+            "Packets: {} sent, {} received, {} lost ({:.1}%)",
+            quality.packets_sent,
+            quality.packets_received,
+            quality.packets_lost,
+            quality.loss_fraction * 100.0
+        )),
+        Line::from(format!(
+            "Jitter: {:.1} ms   Round trip: {}",
+            quality.jitter_ms, rtt
+        )),
+        Line::from(format!(
+            "Out of order: {} reordered, {} duplicates, {} too late",
+            quality.packets_reordered, quality.packets_duplicated, quality.packets_too_old
+        )),
+        Line::from(match quality.far_end {
+            Some(far_end) => format!(
+                "Peer hears: {} lost ({:.1}% lately), jitter {:.1} ms",
+                far_end.cumulative_lost,
+                far_end.loss_fraction() * 100.0,
+                far_end.jitter_ms()
+            ),
+            None => "Peer hears: no report yet".to_string(),
+        }),
+        Line::from(format!(
+            "Buffer: {}/{} frames   Underruns: {}   Concealed: {}",
+            quality.buffered_frames,
+            quality.target_frames,
+            quality.underruns,
+            quality.concealed_frames
+        )),
+        Line::from(format!(
+            "Xruns: {} playback, {} capture",
+            quality.playback_xruns, quality.capture_xruns
+        )),
+        Line::from(format!(
+            "Dropped: {} malformed, {} from earlier calls, {} too large to send",
+            quality.packets_malformed, quality.packets_stale, quality.packets_unsendable
+        )),
+        Line::from(format!(
+            "Security events: {} packets from other hosts, {} failed decryption",
+            quality.packets_foreign, quality.packets_rejected
+        )),
+    ];
+    let panel = Paragraph::new(lines).block(
+        Block::default()
+            .title("Call quality (s to hide)")
+            .borders(Borders::ALL),
+    );
+    f.render_widget(panel, rect);
+}
+
+fn call_screen<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    state: &mut CallScreenState,
+    quality: &CallQuality,
+    voice: &VoiceSettings,
+) {
     // Split screen in 2, top half is for the call info, bottom half is for the controls
 
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
-        .split(f.size());
+        .split(area);
+    let mut chunks = chunks.to_vec();
+    if state.show_stats {
+        let info = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(10)])
+            .split(chunks[0]);
+        call_stats_panel(f, info[1], quality);
+        chunks[0] = info[0];
+    }
 
     // Draw the call info
 
@@ -465,40 +758,274 @@ fn call_screen<B: Backend>(f: &mut Frame<B>, state: &mut CallScreenState) {
             let seconds = elapsed.as_secs() % 60;
             format!("In call with {} for {}:{:02}", name, minutes, seconds)
         }
-        CallScreenStatus::CallEnded {
-            start_time,
-            end_time,
-        } => {
-            let elapsed = end_time.duration_since(start_time);
-            format!(
-                "Call with {} ended after {}:{:02}",
-                name,
-                elapsed.as_secs(),
-                elapsed.subsec_millis() / 10
-            )
+        CallScreenStatus::Failed { reason, .. } => {
+            format!("Call with {}: {}", name, reason)
         }
     };
 
-    let call_info_block = Block::default().title("Call Info").borders(Borders::ALL);
+    // The session ID ties what's on screen to the logs
+    let call_info_block = Block::default()
+        .title(format!("Call Info ({:08x})", state.session))
+        .borders(Borders::ALL);
+    let elapsed_time = if state.paired {
+        elapsed_time
+    } else {
+        format!("{} (unpaired device)", elapsed_time)
+    };
     let call_info = Paragraph::new(elapsed_time).alignment(Alignment::Center);
 
     f.render_widget(call_info_block, chunks[0]);
     // Render the elapsed time
     f.render_widget(call_info, call_info_chunks[1]);
+    f.render_widget(
+        Paragraph::new(voice_status(voice)).alignment(Alignment::Center),
+        call_info_chunks[2],
+    );
     // Draw the controls
     call_screen_controls(f, chunks[1], state);
 }
 
+fn contacts_screen<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    state: &mut ContactsScreenState,
+    book: &Contacts,
+) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .split(area);
+
+    let search_style = if state.mode == ContactsMode::Search {
+        Style::default().add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+    };
+    let search = Paragraph::new(state.search.as_str())
+        .style(search_style)
+        .block(Block::default().title("Search").borders(Borders::ALL));
+    f.render_widget(search, chunks[0]);
+
+    if let ContactsMode::Edit { index, field } = state.mode {
+        let title = if index.is_some() {
+            "Edit contact"
+        } else {
+            "New contact"
+        };
+        let marker = |this: ContactField| if field == this { ">> " } else { "   " };
+        let form = Paragraph::new(vec![
+            Line::from(format!(
+                "{}Name: {}",
+                marker(ContactField::Name),
+                state.new_contact_name
+            )),
+            Line::from(format!(
+                "{}IP:   {}",
+                marker(ContactField::Ip),
+                state.new_contact_ip
+            )),
+        ])
+        .block(Block::default().title(title).borders(Borders::ALL));
+        f.render_widget(form, chunks[1]);
+    } else {
+        let items = state
+            .contacts
+            .iter()
+            .filter_map(|&i| book.get(i))
+            .map(|contact| ListItem::new(format!("{} ({})", contact.name, contact.address)))
+            .collect::<Vec<ListItem>>();
+        let list = List::new(items)
+            .block(Block::default().title("Contacts").borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+            .highlight_symbol(">> ");
+        let mut list_state = ListState::default();
+        list_state.select(state.selected_contact);
+        f.render_stateful_widget(list, chunks[1], &mut list_state);
+    }
+
+    let help = match (&state.error, state.mode) {
+        (Some(error), _) => error.as_str(),
+        (None, ContactsMode::Browse) => {
+            "enter: call  a: add  e: edit  d: delete  /: search  esc: back"
+        }
+        (None, ContactsMode::Search) => "Type to search, enter to keep, esc to clear",
+        (None, ContactsMode::Edit { .. }) => "tab: next field  enter: save  esc: cancel",
+    };
+    f.render_widget(Paragraph::new(help).alignment(Alignment::Center), chunks[2]);
+}
+
+fn history_screen<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    state: &mut HistoryScreenState,
+    history: &CallHistory,
+    contacts: &Contacts,
+) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)])
+        .split(area);
+
+    // Newest first
+    let items = history
+        .records()
+        .iter()
+        .rev()
+        .map(|record| {
+            let direction = match record.direction {
+                CallDirection::Incoming => "in ",
+                CallDirection::Outgoing => "out",
+            };
+            let peer = contacts
+                .name_for(record.peer)
+                .map_or_else(|| record.peer.to_string(), str::to_string);
+            let outcome = if record.is_missed() {
+                "Missed".to_string()
+            } else if record.answered {
+                let seconds = record.duration.as_secs();
+                let outcome = format!("{}:{:02}, {}", seconds / 60, seconds % 60, record.end);
+                match &record.summary {
+                    Some(summary) => format!("{} ({})", outcome, summary),
+                    None => outcome,
+                }
+            } else {
+                record.end.to_string()
+            };
+            let style = if record.is_missed() {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default()
+            };
+            ListItem::new(format!(
+                "{} {}  {}  {}",
+                direction,
+                format_time(record.started),
+                peer,
+                outcome
+            ))
+            .style(style)
+        })
+        .collect::<Vec<ListItem>>();
+    let list = List::new(items)
+        .block(Block::default().title("Call history").borders(Borders::ALL))
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol(">> ");
+    f.render_stateful_widget(list, chunks[0], &mut state.list_state);
+
+    let help =
+        Paragraph::new("Press enter to call back or esc to go back").alignment(Alignment::Center);
+    f.render_widget(help, chunks[1]);
+}
+
+fn nearby_screen<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    state: &mut NearbyScreenState,
+    devices: &[NearbyDevice],
+) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)])
+        .split(area);
+
+    let items = devices
+        .iter()
+        .map(|device| ListItem::new(format!("{} ({})", device.name, device.address)))
+        .collect::<Vec<ListItem>>();
+    let title = if devices.is_empty() {
+        "Nearby devices (searching...)"
+    } else {
+        "Nearby devices"
+    };
+    let list = List::new(items)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol(">> ");
+    f.render_stateful_widget(list, chunks[0], &mut state.list_state);
+
+    let help = Paragraph::new("Press enter to call or esc to go back").alignment(Alignment::Center);
+    f.render_widget(help, chunks[1]);
+}
+
+fn pairing_screen<B: Backend>(f: &mut Frame<B>, area: Rect, state: &mut PairingScreenState) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(30),
+            Constraint::Percentage(40),
+            Constraint::Percentage(30),
+        ])
+        .split(area);
+
+    let layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[1]);
+
+    let (status, help) = match state.status {
+        PairingStatus::EnterAddress => (
+            format!("Pair with IP: {}", state.ip),
+            "Press enter to pair, or enter with no IP to wait for the other unit",
+        ),
+        PairingStatus::Waiting => ("Waiting for the other unit...".to_string(), "Esc to cancel"),
+        PairingStatus::ShowCode {
+            code,
+            confirmed: false,
+        } => (
+            format!("Code: {:06}", code),
+            "Press enter if the other unit shows the same code, esc if not",
+        ),
+        PairingStatus::ShowCode {
+            code,
+            confirmed: true,
+        } => (
+            format!("Code: {:06}", code),
+            "Waiting for the other unit to confirm...",
+        ),
+        PairingStatus::Done { .. } => ("Paired".to_string(), ""),
+        PairingStatus::Failed { reason, .. } => (format!("Pairing failed: {}", reason), ""),
+    };
+
+    let block = Block::default().title("Pairing").borders(Borders::ALL);
+    f.render_widget(block, area);
+    f.render_widget(
+        Paragraph::new(status).alignment(Alignment::Center),
+        layout[0],
+    );
+    f.render_widget(Paragraph::new(help).alignment(Alignment::Center), layout[1]);
+}
+
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut AppState) {
+    // The bottom line is kept for notices
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)])
+        .split(f.size());
+    let area = chunks[0];
     match &mut app.screen_state {
         ScreenState::Home(home_state) => {
-            main_screen(f, home_state);
+            main_screen(f, area, home_state, app.call_history.unseen_missed());
         }
         ScreenState::EnterCallInfo(state) => {
-            enter_call_info(f, state);
+            enter_call_info(f, area, state);
         }
-        ScreenState::Contacts(_) => todo!(),
-        ScreenState::Call(call_state) => call_screen(f, call_state),
+        ScreenState::Contacts(state) => contacts_screen(f, area, state, &app.contacts),
+        ScreenState::Call(call_state) => {
+            call_screen(f, area, call_state, &app.call_quality.get(), &app.voice)
+        }
+        ScreenState::Pairing(state) => pairing_screen(f, area, state),
+        ScreenState::Nearby(state) => nearby_screen(f, area, state, &app.nearby_devices),
+        ScreenState::History(state) => {
+            history_screen(f, area, state, &app.call_history, &app.contacts)
+        }
+    }
+    if let Some((notice, _)) = &app.notice {
+        let status = Paragraph::new(notice.as_str()).style(Style::default().fg(Color::Yellow));
+        f.render_widget(status, chunks[1]);
     }
 }
 
@@ -507,7 +1034,20 @@ fn run_terminal_task(
     input_audio: Sender<InputAudioCommand>,
     network_queue: Sender<NetworkTaskCommand>,
     call_rx: Receiver<CallScreenCommand>,
+    leds: Box<dyn Leds>,
+    settings: TerminalSettings,
+    call_quality: QualityMonitor,
 ) -> anyhow::Result<()> {
+    let mut app = AppState::new(
+        output_audio,
+        input_audio,
+        network_queue,
+        call_rx,
+        leds,
+        settings,
+        call_quality,
+    )?;
+
     enable_raw_mode()?;
     stdout().execute(EnterAlternateScreen)?;
 
@@ -515,8 +1055,6 @@ fn run_terminal_task(
 
     let mut should_quit = false;
 
-    let mut app = AppState::new(output_audio, input_audio, network_queue, call_rx);
-
     while !should_quit {
         terminal.draw(|f| {
             ui(f, &mut app);
@@ -531,120 +1069,227 @@ fn run_terminal_task(
     Ok(())
 }
 
+/// Runs the UI on commands from `call_rx`, whose sender the other tasks
+/// should know before it starts.
 pub fn create_terminal_task(
+    call_rx: Receiver<CallScreenCommand>,
     output_audio: Sender<OutputAudioTaskCommand>,
     input_audio: Sender<InputAudioCommand>,
     network_queue: Sender<NetworkTaskCommand>,
-) -> (JoinHandle<()>, Sender<CallScreenCommand>) {
-    let (call_tx, call_rx) = unbounded();
-    let thread = thread::spawn(move || {
-        if let Err(e) = run_terminal_task(output_audio, input_audio, network_queue, call_rx) {
-            eprintln!("Error: {}", e);
-        }
-    });
-
-    (thread, call_tx)
+    leds: Box<dyn Leds>,
+    settings: TerminalSettings,
+    call_quality: QualityMonitor,
+) -> JoinHandle<anyhow::Result<()>> {
+    thread::spawn(move || {
+        run_terminal_task(
+            output_audio,
+            input_audio,
+            network_queue,
+            call_rx,
+            leds,
+            settings,
+            call_quality,
+        )
+    })
 }
 
-fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
-    // Check if we have a call request
-    if let Ok(cmd) = app.call_rx.try_recv() {
-        match cmd {
-            CallScreenCommand::StartCall(sock) => {
-                let call_screen_state = {
-                    let mut call_screen_state = CallScreenState::new(sock.ip());
-                    call_screen_state.call_status = CallScreenStatus::InCall {
-                        start_time: std::time::Instant::now(),
-                    };
-                    app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
-                    app.input_audio_sender.send(InputAudioCommand::Start)?;
-                    call_screen_state
+/// Reacts to a command from the other tasks.
+fn handle_call_command(app: &mut AppState, cmd: CallScreenCommand) -> anyhow::Result<()> {
+    match cmd {
+        CallScreenCommand::StartCall(sock, session, paired) => {
+            let call_screen_state = {
+                let mut call_screen_state = CallScreenState::new(sock.ip(), session, paired);
+                // Keep the name we were calling, e.g. from nearby devices
+                call_screen_state.remote_name = match &app.screen_state {
+                    ScreenState::Call(calling) if calling.remote_ip == sock.ip() => {
+                        call_screen_state.started = calling.started;
+                        calling.remote_name.clone()
+                    }
+                    _ => app.contacts.name_for(sock.ip()).map(str::to_string),
                 };
-                app.screen_state = ScreenState::Call(call_screen_state);
-            }
-            CallScreenCommand::IncomingCall(sock) => {
-                let call_screen_state = {
-                    let mut call_screen_state = CallScreenState::new(sock.ip());
-                    call_screen_state.call_status = CallScreenStatus::IncomingCall;
-                    app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
-                    call_screen_state
+                call_screen_state.call_status = CallScreenStatus::InCall {
+                    start_time: std::time::Instant::now(),
                 };
-                const INCOMING_CALL_SOUND: &[u8] = include_bytes!("assets/capitao_whatsapp.mp3");
-                let play_buffer = crate::utils::decode_bytes(INCOMING_CALL_SOUND);
-                app.output_audio_sender
-                    .send(OutputAudioTaskCommand::Play(play_buffer))?;
-                app.screen_state = ScreenState::Call(call_screen_state);
-            }
-            CallScreenCommand::StopCall => {
-                if let ScreenState::Call(_) = &mut app.screen_state {
-                    app.screen_state = ScreenState::Home(HomeScreenState::new());
-                    app.network_sender
-                        .send(NetworkTaskCommand::StopConnection)?;
-                    app.input_audio_sender.send(InputAudioCommand::Stop)?;
-                    app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
-                    app.stop_animation();
-                }
-            }
-            CallScreenCommand::AcceptCall => {
-                // Accept the call
-                if let ScreenState::Call(call_state) = &mut app.screen_state {
-                    app.network_sender.send(NetworkTaskCommand::SendAccept)?;
-                    app.input_audio_sender.send(InputAudioCommand::Start)?;
-                    call_state.call_status = CallScreenStatus::InCall {
-                        start_time: std::time::Instant::now(),
-                    };
-                    app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
-                    app.stop_animation();
-                }
-            }
-            CallScreenCommand::RejectCall => {
-                // Reject the call
+                app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
+                app.input_audio_sender.send(InputAudioCommand::Start)?;
+                call_screen_state
+            };
+            app.screen_state = ScreenState::Call(call_screen_state);
+        }
+        CallScreenCommand::IncomingCall(sock, session, paired) => {
+            let call_screen_state = {
+                let mut call_screen_state = CallScreenState::new(sock.ip(), session, paired);
+                call_screen_state.direction = CallDirection::Incoming;
+                call_screen_state.remote_name =
+                    app.contacts.name_for(sock.ip()).map(str::to_string);
+                call_screen_state.call_status = CallScreenStatus::IncomingCall;
+                app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
+                call_screen_state
+            };
+            app.output_audio_sender
+                .send(OutputAudioTaskCommand::Play(app.ringtone.clone()))?;
+            app.screen_state = ScreenState::Call(call_screen_state);
+        }
+        CallScreenCommand::StopCall => {
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                // Hanging up on a ringing call declines it
+                let (command, end) = if let CallScreenStatus::IncomingCall = call_state.call_status
+                {
+                    (NetworkTaskCommand::SendReject, CallEnd::Declined)
+                } else {
+                    (NetworkTaskCommand::StopConnection, CallEnd::LocalHangup)
+                };
+                app.log_call(end);
                 app.screen_state = ScreenState::Home(HomeScreenState::new());
-                app.network_sender
-                    .send(NetworkTaskCommand::StopConnection)?;
+                app.network_sender.send(command)?;
                 app.input_audio_sender.send(InputAudioCommand::Stop)?;
                 app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
                 app.stop_animation();
             }
-            CallScreenCommand::EndCall => {
-                // End the call
-                app.network_sender
-                    .send(NetworkTaskCommand::StopConnection)?;
+        }
+        CallScreenCommand::PeerHungUp => {
+            if let ScreenState::Call(_) = app.screen_state {
+                app.log_call(CallEnd::RemoteHangup);
                 app.screen_state = ScreenState::Home(HomeScreenState::new());
+                app.input_audio_sender.send(InputAudioCommand::Stop)?;
+                app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
+                app.stop_animation();
             }
-            CallScreenCommand::Exit => {
-                return Ok(true);
+        }
+        CallScreenCommand::CallFailed(reason) => {
+            app.log_call(CallEnd::Failed(reason));
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                call_state.call_status = CallScreenStatus::Failed {
+                    reason,
+                    at: std::time::Instant::now(),
+                };
+                app.input_audio_sender.send(InputAudioCommand::Stop)?;
+                app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
+                app.stop_animation();
             }
-            CallScreenCommand::IncreaseVolume => {
-                if let ScreenState::Call(call_state) = &mut app.screen_state {
-                    call_state.volume = (call_state.volume + 5).min(100);
-                    app.output_audio_sender
-                        .send(OutputAudioTaskCommand::SetVolume(call_state.volume))?;
-                }
+        }
+        CallScreenCommand::AcceptCall => {
+            // Accept the call
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                app.network_sender.send(NetworkTaskCommand::SendAccept)?;
+                app.input_audio_sender.send(InputAudioCommand::Start)?;
+                call_state.call_status = CallScreenStatus::InCall {
+                    start_time: std::time::Instant::now(),
+                };
+                app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
+                app.stop_animation();
             }
-            CallScreenCommand::DecreaseVolume => {
-                if let ScreenState::Call(call_state) = &mut app.screen_state {
-                    call_state.volume = (call_state.volume - 5).max(0);
-                    app.output_audio_sender
-                        .send(OutputAudioTaskCommand::SetVolume(call_state.volume))?;
-                }
+        }
+        CallScreenCommand::StartPairing => {
+            if let ScreenState::Home(_) = app.screen_state {
+                app.screen_state = ScreenState::Pairing(PairingScreenState::new());
             }
-            CallScreenCommand::ToggleMute => {
-                if let ScreenState::Call(call_state) = &mut app.screen_state {
-                    call_state.is_muted = !call_state.is_muted;
-                    app.output_audio_sender
-                        .send(OutputAudioTaskCommand::SetMute(call_state.is_muted))?;
+        }
+        CallScreenCommand::NearbyDevices(devices) => {
+            if let ScreenState::Nearby(state) = &mut app.screen_state {
+                // Keep the selection on screen as units come and go
+                match state.list_state.selected() {
+                    _ if devices.is_empty() => state.list_state.select(None),
+                    Some(i) if i >= devices.len() => {
+                        state.list_state.select(Some(devices.len() - 1))
+                    }
+                    None => state.list_state.select(Some(0)),
+                    _ => {}
                 }
             }
+            app.nearby_devices = devices;
+        }
+        CallScreenCommand::MissedCall(sock) => app.add_to_history(CallRecord {
+            direction: CallDirection::Incoming,
+            peer: sock.ip(),
+            started: std::time::SystemTime::now(),
+            duration: std::time::Duration::ZERO,
+            end: CallEnd::Failed(CallEndReason::LineBusy),
+            answered: false,
+            seen: false,
+            summary: None,
+        }),
+        CallScreenCommand::Notice(message) => app.notice(message),
+        CallScreenCommand::PairingCode(code) => {
+            if let ScreenState::Pairing(state) = &mut app.screen_state {
+                state.status = PairingStatus::ShowCode {
+                    code,
+                    confirmed: false,
+                };
+            }
+        }
+        CallScreenCommand::PairingComplete => {
+            if let ScreenState::Pairing(state) = &mut app.screen_state {
+                state.status = PairingStatus::Done {
+                    at: std::time::Instant::now(),
+                };
+            }
+        }
+        CallScreenCommand::PairingFailed(reason) => {
+            if let ScreenState::Pairing(state) = &mut app.screen_state {
+                state.status = PairingStatus::Failed {
+                    reason,
+                    at: std::time::Instant::now(),
+                };
+            }
+        }
+        CallScreenCommand::IncreaseVolume => {
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                call_state.volume = (call_state.volume + 5).min(100);
+                app.output_audio_sender
+                    .send(OutputAudioTaskCommand::SetVolume(call_state.volume))?;
+            }
+        }
+        CallScreenCommand::DecreaseVolume => {
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                call_state.volume = (call_state.volume - 5).max(0);
+                app.output_audio_sender
+                    .send(OutputAudioTaskCommand::SetVolume(call_state.volume))?;
+            }
         }
+        CallScreenCommand::ToggleMute => {
+            if let ScreenState::Call(call_state) = &mut app.screen_state {
+                call_state.is_muted = !call_state.is_muted;
+                app.output_audio_sender
+                    .send(OutputAudioTaskCommand::SetMute(call_state.is_muted))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves on from screens that have been up long enough.
+fn update_screens(app: &mut AppState) {
+    if matches!(&app.notice, Some((_, at)) if at.elapsed() > NOTICE_TIME) {
+        app.notice = None;
     }
 
     if let ScreenState::Call(call_state) = &mut app.screen_state {
-        if let CallScreenStatus::IncomingCall = call_state.call_status {
-            app.animation();
+        match call_state.call_status {
+            CallScreenStatus::IncomingCall => app.animation(),
+            CallScreenStatus::Failed { at, .. } if at.elapsed() > FAILED_CALL_SCREEN_TIME => {
+                app.screen_state = ScreenState::Home(HomeScreenState::new());
+            }
+            _ => {}
         }
     }
 
+    if let ScreenState::Pairing(state) = &app.screen_state {
+        if let PairingStatus::Done { at } | PairingStatus::Failed { at, .. } = state.status {
+            if at.elapsed() > FAILED_CALL_SCREEN_TIME {
+                app.screen_state = ScreenState::Home(HomeScreenState::new());
+            }
+        }
+    }
+}
+
+fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
+    // Check if we have a call request
+    if let Ok(cmd) = app.call_rx.try_recv() {
+        handle_call_command(app, cmd)?;
+    }
+    update_screens(app);
+
     match &mut app.screen_state {
         ScreenState::Home(home_state) => {
             if !event::poll(std::time::Duration::from_millis(100))? {
@@ -665,9 +1310,23 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
                             });
                         }
                         Some(1) => {
-                            app.screen_state = ScreenState::Contacts(ContactsScreenState::new());
+                            app.screen_state = ScreenState::Nearby(NearbyScreenState::new());
                         }
                         Some(2) => {
+                            app.screen_state =
+                                ScreenState::Contacts(ContactsScreenState::new(&app.contacts));
+                        }
+                        Some(3) => {
+                            if let Err(error) = app.call_history.mark_seen() {
+                                app.notice(format!("Couldn't save the call history: {}", error));
+                            }
+                            app.screen_state =
+                                ScreenState::History(HistoryScreenState::new(&app.call_history));
+                        }
+                        Some(4) => {
+                            app.screen_state = ScreenState::Pairing(PairingScreenState::new());
+                        }
+                        Some(5) => {
                             return Ok(true);
                         }
                         _ => {}
@@ -683,13 +1342,22 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
             if let Event::Key(KeyEvent { code, .. }) = event::read()? {
                 match code {
                     KeyCode::Enter => {
-                        if let Ok(ip) = state.ip.parse::<IpAddr>() {
-                            app.screen_state = ScreenState::Call(CallScreenState::new(ip));
+                        // Either just the IP, or the IP and port of a unit on
+                        // another port
+                        let address = state.ip.parse::<SocketAddr>().ok().or_else(|| {
+                            let ip = state.ip.parse::<IpAddr>().ok()?;
+                            Some(SocketAddr::new(ip, app.call_port))
+                        });
+                        if let Some(socket_addr) = address {
+                            let ip = socket_addr.ip();
+                            let session = new_session_id();
+                            // Paired or not, we find out when the peer answers
+                            let mut call_state = CallScreenState::new(ip, session, true);
+                            call_state.remote_name = app.contacts.name_for(ip).map(str::to_string);
+                            app.screen_state = ScreenState::Call(call_state);
                             //After we checked the IP, we can start the call
-                            //Create the socket IP address
-                            let socket_addr = SocketAddr::new(ip, 33445);
                             app.network_sender
-                                .send(NetworkTaskCommand::StartConnection(socket_addr))?;
+                                .send(NetworkTaskCommand::StartConnection(socket_addr, session))?;
                         }
                     }
                     KeyCode::Esc => {
@@ -705,7 +1373,272 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
                 }
             }
         }
-        ScreenState::Contacts(_) => todo!(),
+        ScreenState::Contacts(state) => {
+            if !event::poll(std::time::Duration::from_millis(100))? {
+                return Ok(false);
+            }
+            if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+                match state.mode {
+                    ContactsMode::Browse => {
+                        state.error = None;
+                        let count = state.contacts.len();
+                        match code {
+                            KeyCode::Up if count > 0 => {
+                                let i = state.selected_contact.unwrap_or(0);
+                                state.selected_contact = Some((i + count - 1) % count);
+                            }
+                            KeyCode::Down if count > 0 => {
+                                let i = state.selected_contact.map_or(0, |i| (i + 1) % count);
+                                state.selected_contact = Some(i);
+                            }
+                            KeyCode::Enter => {
+                                if let Some(contact) =
+                                    state.selected().and_then(|i| app.contacts.get(i))
+                                {
+                                    let session = new_session_id();
+                                    let mut call_state =
+                                        CallScreenState::new(contact.address, session, true);
+                                    call_state.remote_name = Some(contact.name.clone());
+                                    app.network_sender.send(
+                                        NetworkTaskCommand::StartConnection(
+                                            SocketAddr::new(contact.address, app.call_port),
+                                            session,
+                                        ),
+                                    )?;
+                                    app.screen_state = ScreenState::Call(call_state);
+                                }
+                            }
+                            KeyCode::Char('a') => {
+                                state.new_contact_name.clear();
+                                state.new_contact_ip.clear();
+                                state.mode = ContactsMode::Edit {
+                                    index: None,
+                                    field: ContactField::Name,
+                                };
+                            }
+                            KeyCode::Char('e') => {
+                                if let Some(index) = state.selected() {
+                                    if let Some(contact) = app.contacts.get(index) {
+                                        state.new_contact_name = contact.name.clone();
+                                        state.new_contact_ip = contact.address.to_string();
+                                        state.mode = ContactsMode::Edit {
+                                            index: Some(index),
+                                            field: ContactField::Name,
+                                        };
+                                    }
+                                }
+                            }
+                            KeyCode::Char('d') | KeyCode::Delete => {
+                                if let Some(index) = state.selected() {
+                                    if let Err(error) = app.contacts.remove(index) {
+                                        state.error = Some(format!("Couldn't save: {}", error));
+                                    }
+                                    state.refresh(&app.contacts);
+                                }
+                            }
+                            KeyCode::Char('/') => {
+                                state.mode = ContactsMode::Search;
+                            }
+                            KeyCode::Esc => {
+                                app.screen_state = ScreenState::Home(HomeScreenState::new());
+                            }
+                            _ => {}
+                        }
+                    }
+                    ContactsMode::Search => match code {
+                        KeyCode::Enter => state.mode = ContactsMode::Browse,
+                        KeyCode::Esc => {
+                            state.search.clear();
+                            state.mode = ContactsMode::Browse;
+                            state.refresh(&app.contacts);
+                        }
+                        KeyCode::Char(c) => {
+                            state.search.push(c);
+                            state.refresh(&app.contacts);
+                        }
+                        KeyCode::Backspace => {
+                            state.search.pop();
+                            state.refresh(&app.contacts);
+                        }
+                        _ => {}
+                    },
+                    ContactsMode::Edit { index, field } => {
+                        let input = match field {
+                            ContactField::Name => &mut state.new_contact_name,
+                            ContactField::Ip => &mut state.new_contact_ip,
+                        };
+                        match code {
+                            KeyCode::Tab | KeyCode::Down | KeyCode::Up => {
+                                let field = match field {
+                                    ContactField::Name => ContactField::Ip,
+                                    ContactField::Ip => ContactField::Name,
+                                };
+                                state.mode = ContactsMode::Edit { index, field };
+                            }
+                            KeyCode::Char(c) => input.push(c),
+                            KeyCode::Backspace => {
+                                input.pop();
+                            }
+                            KeyCode::Enter => {
+                                let name = state.new_contact_name.trim().to_string();
+                                match state.new_contact_ip.trim().parse::<IpAddr>() {
+                                    _ if name.is_empty() => {
+                                        state.error = Some("The name can't be empty".to_string());
+                                    }
+                                    Err(_) => {
+                                        state.error = Some(format!(
+                                            "{:?} is not an IP address",
+                                            state.new_contact_ip
+                                        ));
+                                    }
+                                    Ok(address) => {
+                                        let contact = Contact { name, address };
+                                        let saved = match index {
+                                            Some(index) => app.contacts.update(index, contact),
+                                            None => app.contacts.add(contact),
+                                        };
+                                        state.error = saved
+                                            .err()
+                                            .map(|error| format!("Couldn't save: {}", error));
+                                        state.mode = ContactsMode::Browse;
+                                        state.refresh(&app.contacts);
+                                    }
+                                }
+                            }
+                            KeyCode::Esc => {
+                                state.error = None;
+                                state.mode = ContactsMode::Browse;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        ScreenState::History(state) => {
+            if !event::poll(std::time::Duration::from_millis(100))? {
+                return Ok(false);
+            }
+            if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+                let count = app.call_history.records().len();
+                match code {
+                    KeyCode::Up if count > 0 => {
+                        let i = state.list_state.selected().unwrap_or(0);
+                        state.list_state.select(Some((i + count - 1) % count));
+                    }
+                    KeyCode::Down if count > 0 => {
+                        let i = state.list_state.selected().map_or(0, |i| (i + 1) % count);
+                        state.list_state.select(Some(i));
+                    }
+                    KeyCode::Enter => {
+                        // The list is shown newest first
+                        let record = state
+                            .list_state
+                            .selected()
+                            .and_then(|i| app.call_history.records().iter().rev().nth(i));
+                        if let Some(record) = record {
+                            let session = new_session_id();
+                            let mut call_state = CallScreenState::new(record.peer, session, true);
+                            call_state.remote_name =
+                                app.contacts.name_for(record.peer).map(str::to_string);
+                            app.network_sender
+                                .send(NetworkTaskCommand::StartConnection(
+                                    SocketAddr::new(record.peer, app.call_port),
+                                    session,
+                                ))?;
+                            app.screen_state = ScreenState::Call(call_state);
+                        }
+                    }
+                    KeyCode::Esc => {
+                        app.screen_state = ScreenState::Home(HomeScreenState::new());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        ScreenState::Nearby(state) => {
+            if !event::poll(std::time::Duration::from_millis(100))? {
+                return Ok(false);
+            }
+            if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+                let count = app.nearby_devices.len();
+                match code {
+                    KeyCode::Up if count > 0 => {
+                        let i = state.list_state.selected().unwrap_or(0);
+                        state.list_state.select(Some((i + count - 1) % count));
+                    }
+                    KeyCode::Down if count > 0 => {
+                        let i = state.list_state.selected().map_or(0, |i| (i + 1) % count);
+                        state.list_state.select(Some(i));
+                    }
+                    KeyCode::Enter => {
+                        let selected = state.list_state.selected().unwrap_or(0);
+                        if let Some(device) = app.nearby_devices.get(selected) {
+                            let session = new_session_id();
+                            let mut call_state =
+                                CallScreenState::new(device.address.ip(), session, true);
+                            call_state.remote_name = Some(device.name.clone());
+                            app.network_sender
+                                .send(NetworkTaskCommand::StartConnection(
+                                    device.address,
+                                    session,
+                                ))?;
+                            app.screen_state = ScreenState::Call(call_state);
+                        }
+                    }
+                    KeyCode::Esc => {
+                        app.screen_state = ScreenState::Home(HomeScreenState::new());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        ScreenState::Pairing(state) => {
+            if !event::poll(std::time::Duration::from_millis(100))? {
+                return Ok(false);
+            }
+            if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+                match (code, state.status) {
+                    (KeyCode::Enter, PairingStatus::EnterAddress) => {
+                        let peer = if state.ip.is_empty() {
+                            None
+                        } else if let Ok(ip) = state.ip.parse::<IpAddr>() {
+                            Some(SocketAddr::new(ip, app.call_port))
+                        } else {
+                            return Ok(false);
+                        };
+                        app.network_sender
+                            .send(NetworkTaskCommand::StartPairing(peer))?;
+                        state.status = PairingStatus::Waiting;
+                    }
+                    (KeyCode::Char(c), PairingStatus::EnterAddress) => {
+                        state.ip.push(c);
+                    }
+                    (KeyCode::Backspace, PairingStatus::EnterAddress) => {
+                        state.ip.pop();
+                    }
+                    (
+                        KeyCode::Enter,
+                        PairingStatus::ShowCode {
+                            code,
+                            confirmed: false,
+                        },
+                    ) => {
+                        app.network_sender
+                            .send(NetworkTaskCommand::ConfirmPairing)?;
+                        state.status = PairingStatus::ShowCode {
+                            code,
+                            confirmed: true,
+                        };
+                    }
+                    (KeyCode::Esc, _) => {
+                        app.network_sender.send(NetworkTaskCommand::CancelPairing)?;
+                        app.screen_state = ScreenState::Home(HomeScreenState::new());
+                    }
+                    _ => {}
+                }
+            }
+        }
         ScreenState::Call(state) => {
             if !event::poll(std::time::Duration::from_millis(100))? {
                 return Ok(false);
@@ -714,13 +1647,22 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
                 match code {
                     KeyCode::Esc => {
                         // TODO: End call should wait a few seconds before going back to the home screen
+                        let (command, end) =
+                            if let CallScreenStatus::IncomingCall = state.call_status {
+                                (NetworkTaskCommand::SendReject, CallEnd::Declined)
+                            } else {
+                                (NetworkTaskCommand::StopConnection, CallEnd::LocalHangup)
+                            };
+                        app.log_call(end);
                         app.screen_state = ScreenState::Home(HomeScreenState::new());
-                        app.network_sender
-                            .send(NetworkTaskCommand::StopConnection)?;
+                        app.network_sender.send(command)?;
                         app.input_audio_sender.send(InputAudioCommand::Stop)?;
                         app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
                         app.stop_animation();
                     }
+                    KeyCode::Char('s') => {
+                        state.show_stats = !state.show_stats;
+                    }
                     KeyCode::Char('m') => {
                         state.is_muted = !state.is_muted;
                         app.output_audio_sender
@@ -746,7 +1688,13 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
                             app.output_audio_sender.send(OutputAudioTaskCommand::Stop)?;
                         }
                     }
-                    _ => {}
+                    code => {
+                        if let Some(voice) = adjust_voice(app.voice, code) {
+                            app.voice = voice;
+                            app.input_audio_sender
+                                .send(InputAudioCommand::SetVoice(voice))?;
+                        }
+                    }
                 }
             }
         }
@@ -754,3 +1702,240 @@ fn handle_events(app: &mut AppState) -> anyhow::Result<bool> {
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        hardware::MemoryLeds,
+        test_harness::{Unit, PATIENCE},
+    };
+    use crossbeam::channel::unbounded;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    /// The terminal's state for `unit`, keeping contacts and call history in
+    /// a scratch directory that goes away with the returned handle.
+    fn app(
+        unit: &Unit,
+    ) -> (
+        AppState,
+        Receiver<InputAudioCommand>,
+        Receiver<OutputAudioTaskCommand>,
+        TempDir,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let (input_audio_sender, input_rx) = unbounded();
+        let (output_audio_sender, output_rx) = unbounded();
+        let app = AppState {
+            output_audio_sender,
+            input_audio_sender,
+            network_sender: unit.network.clone(),
+            call_rx: unbounded().1,
+            screen_state: ScreenState::Home(HomeScreenState::new()),
+            nearby_devices: Vec::new(),
+            contacts: Contacts::load(&dir.path().join("contacts")).unwrap(),
+            call_history: CallHistory::load(&dir.path().join("call_history")).unwrap(),
+            leds: Box::new(MemoryLeds::new().0),
+            animation_state: 0,
+            call_port: unit.address.port(),
+            call_quality: unit.quality.clone(),
+            voice: VoiceSettings::default(),
+            ringtone: vec![0; 960],
+            notice: None,
+        };
+        (app, input_rx, output_rx, dir)
+    }
+
+    fn call_status(app: &AppState) -> Option<CallScreenStatus> {
+        match &app.screen_state {
+            ScreenState::Call(state) => Some(state.call_status),
+            _ => None,
+        }
+    }
+
+    fn next_input_command(input_rx: &Receiver<InputAudioCommand>) -> InputAudioCommand {
+        input_rx.recv_timeout(PATIENCE).unwrap()
+    }
+
+    #[test]
+    fn outgoing_call_screen_follows_the_network() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let (mut app, input_rx, _output_rx, _dir) = app(&alice);
+
+        // What picking a contact does
+        let session = new_session_id();
+        app.screen_state =
+            ScreenState::Call(CallScreenState::new(bob.address.ip(), session, false));
+        app.network_sender
+            .send(NetworkTaskCommand::StartConnection(bob.address, session))
+            .unwrap();
+        assert!(matches!(bob.next_ui(), CallScreenCommand::IncomingCall(..)));
+        bob.send(NetworkTaskCommand::SendAccept);
+
+        handle_call_command(&mut app, alice.next_ui()).unwrap();
+        assert!(matches!(
+            call_status(&app),
+            Some(CallScreenStatus::InCall { .. })
+        ));
+        assert!(matches!(
+            next_input_command(&input_rx),
+            InputAudioCommand::Start
+        ));
+
+        bob.send(NetworkTaskCommand::StopConnection);
+        handle_call_command(&mut app, alice.next_ui()).unwrap();
+        assert!(matches!(app.screen_state, ScreenState::Home(_)));
+        assert!(matches!(
+            next_input_command(&input_rx),
+            InputAudioCommand::Stop
+        ));
+        let record = &app.call_history.records()[0];
+        assert_eq!(
+            (record.direction, record.answered, record.end),
+            (CallDirection::Outgoing, true, CallEnd::RemoteHangup)
+        );
+    }
+
+    #[test]
+    fn declining_from_the_keys_turns_the_caller_away() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let (mut app, _input_rx, output_rx, _dir) = app(&alice);
+
+        bob.call(&alice);
+        handle_call_command(&mut app, alice.next_ui()).unwrap();
+        assert!(matches!(
+            call_status(&app),
+            Some(CallScreenStatus::IncomingCall)
+        ));
+        // Ringing
+        assert!(output_rx
+            .try_iter()
+            .any(|command| matches!(command, OutputAudioTaskCommand::Play(_))));
+
+        // The hang up key on a ringing call
+        handle_call_command(&mut app, CallScreenCommand::StopCall).unwrap();
+        assert!(matches!(app.screen_state, ScreenState::Home(_)));
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::Declined)
+        );
+        let record = &app.call_history.records()[0];
+        assert_eq!(
+            (record.direction, record.end),
+            (CallDirection::Incoming, CallEnd::Declined)
+        );
+        assert!(!record.is_missed());
+    }
+
+    #[test]
+    fn failed_call_stays_on_screen_for_a_while() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let (mut app, _input_rx, _output_rx, _dir) = app(&alice);
+
+        let session = alice.call(&bob);
+        app.screen_state =
+            ScreenState::Call(CallScreenState::new(bob.address.ip(), session, false));
+        assert!(matches!(bob.next_ui(), CallScreenCommand::IncomingCall(..)));
+        bob.send(NetworkTaskCommand::SendReject);
+        handle_call_command(&mut app, alice.next_ui()).unwrap();
+        assert!(matches!(
+            call_status(&app),
+            Some(CallScreenStatus::Failed {
+                reason: CallEndReason::Declined,
+                ..
+            })
+        ));
+
+        update_screens(&mut app);
+        assert!(call_status(&app).is_some());
+        if let ScreenState::Call(state) = &mut app.screen_state {
+            state.call_status = CallScreenStatus::Failed {
+                reason: CallEndReason::Declined,
+                at: Instant::now() - FAILED_CALL_SCREEN_TIME * 2,
+            };
+        }
+        update_screens(&mut app);
+        assert!(matches!(app.screen_state, ScreenState::Home(_)));
+    }
+
+    #[test]
+    fn calls_turned_away_as_busy_are_missed() {
+        let unit = Unit::new();
+        let (mut app, _input_rx, _output_rx, _dir) = app(&unit);
+
+        let caller = "192.168.1.30:5000".parse().unwrap();
+        handle_call_command(&mut app, CallScreenCommand::MissedCall(caller)).unwrap();
+        assert_eq!(app.call_history.unseen_missed(), 1);
+        assert_eq!(app.call_history.records()[0].peer, caller.ip());
+    }
+
+    struct BrokenLeds;
+
+    impl Leds for BrokenLeds {
+        fn show(&mut self, _colors: [LedColor; 3]) -> anyhow::Result<()> {
+            anyhow::bail!("No such device")
+        }
+    }
+
+    #[test]
+    fn broken_leds_are_given_up_on_the_status_line() {
+        let unit = Unit::new();
+        let (mut app, _input_rx, _output_rx, _dir) = app(&unit);
+        app.leds = Box::new(BrokenLeds);
+
+        app.animation();
+        assert_eq!(
+            app.notice.as_ref().unwrap().0,
+            "Giving up on the LEDs: No such device"
+        );
+        // Once
+        app.notice = None;
+        app.animation();
+        assert!(app.notice.is_none());
+    }
+
+    #[test]
+    fn notices_leave_the_status_line_after_a_while() {
+        let unit = Unit::new();
+        let (mut app, _input_rx, _output_rx, _dir) = app(&unit);
+
+        handle_call_command(&mut app, CallScreenCommand::Notice("Disk full".to_string())).unwrap();
+        update_screens(&mut app);
+        assert_eq!(app.notice.as_ref().unwrap().0, "Disk full");
+
+        app.notice = Some(("Disk full".to_string(), Instant::now() - NOTICE_TIME * 2));
+        update_screens(&mut app);
+        assert!(app.notice.is_none());
+    }
+
+    #[test]
+    fn call_screen_keys_change_the_voice_settings() {
+        let voice = VoiceSettings::default();
+        let toggled = adjust_voice(voice, KeyCode::Char('n')).unwrap();
+        assert!(!toggled.noise_suppression);
+        assert!(
+            !adjust_voice(voice, KeyCode::Char('g'))
+                .unwrap()
+                .gain_control
+        );
+        assert_eq!(
+            adjust_voice(voice, KeyCode::Char('+'))
+                .unwrap()
+                .suppression_db,
+            voice.suppression_db + 3
+        );
+        assert!(adjust_voice(voice, KeyCode::Char('m')).is_none());
+
+        let mut weakest = voice;
+        for _ in 0..20 {
+            weakest = adjust_voice(weakest, KeyCode::Char('-')).unwrap();
+        }
+        assert_eq!(weakest.suppression_db, *SUPPRESSION_RANGE_DB.start());
+        assert_eq!(
+            voice_status(&toggled),
+            "Noise suppression: off (n, -/+)   Gain control: -18 dBFS, up to 20 dB, set in config (g)"
+        );
+    }
+}
@@ -0,0 +1,680 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU16, Ordering},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::{
+    call_stats::QualityMonitor,
+    crypto::Identity,
+    network_thread::{create_network_task, NetworkTaskCommand, NetworkTimeouts, SecuritySettings},
+    output_audio_task::OutputAudioTaskCommand,
+    packet::{new_session_id, SessionId},
+    pairing::TrustedPeers,
+    terminal_task::CallScreenCommand,
+};
+
+/// How long we wait for something that should happen.
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Every unit gets its own port, so tests can run in parallel.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(41000);
+
+/// A port no other test uses.
+pub fn next_port() -> u16 {
+    NEXT_PORT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Short enough for tests to watch calls time out.
+pub fn fast_timeouts() -> NetworkTimeouts {
+    NetworkTimeouts {
+        heartbeat_interval: Duration::from_millis(50),
+        report_interval: Duration::from_millis(200),
+        unreachable_timeout: Duration::from_millis(500),
+        ring_timeout: Duration::from_millis(800),
+        connection_lost_timeout: Duration::from_millis(500),
+        pairing_timeout: Duration::from_secs(2),
+    }
+}
+
+/// A network task on a loopback port.
+pub struct Unit {
+    pub address: SocketAddr,
+    pub network: Sender<NetworkTaskCommand>,
+    /// What the network task tells the UI.
+    pub ui: Receiver<CallScreenCommand>,
+    /// What the network task gives the output audio task.
+    pub audio: Receiver<OutputAudioTaskCommand>,
+    /// What the network task measures of its calls.
+    pub quality: QualityMonitor,
+    thread: Option<JoinHandle<()>>,
+    /// Where the unit keeps its paired units unless told otherwise, removed
+    /// with it.
+    _scratch: tempfile::TempDir,
+}
+
+impl Unit {
+    pub fn start(timeouts: NetworkTimeouts, security: SecuritySettings) -> Self {
+        let scratch = tempfile::tempdir().unwrap();
+        let trusted_peers = TrustedPeers::load(&scratch.path().join("trusted_peers")).unwrap();
+        Self::launch(timeouts, security, trusted_peers, scratch)
+    }
+
+    /// A unit keeping its paired units in `trusted_peers`.
+    pub fn with_trusted_peers(trusted_peers: TrustedPeers) -> Self {
+        let scratch = tempfile::tempdir().unwrap();
+        Self::launch(
+            fast_timeouts(),
+            SecuritySettings::default(),
+            trusted_peers,
+            scratch,
+        )
+    }
+
+    fn launch(
+        timeouts: NetworkTimeouts,
+        security: SecuritySettings,
+        trusted_peers: TrustedPeers,
+        scratch: tempfile::TempDir,
+    ) -> Self {
+        let port = next_port();
+        let quality = QualityMonitor::default();
+        let (thread, network) = create_network_task(
+            port,
+            Identity::generate().unwrap(),
+            trusted_peers,
+            timeouts,
+            security,
+            quality.clone(),
+        )
+        .unwrap();
+        let (ui_tx, ui) = unbounded();
+        let (audio_tx, audio) = unbounded();
+        network
+            .send(NetworkTaskCommand::MainTaskQueue(ui_tx))
+            .unwrap();
+        network
+            .send(NetworkTaskCommand::OutputAudioQueue(audio_tx))
+            .unwrap();
+        Self {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            network,
+            ui,
+            audio,
+            quality,
+            thread: Some(thread),
+            _scratch: scratch,
+        }
+    }
+
+    pub fn new() -> Self {
+        Self::start(fast_timeouts(), SecuritySettings::default())
+    }
+
+    pub fn call(&self, other: &Unit) -> SessionId {
+        let session = new_session_id();
+        self.network
+            .send(NetworkTaskCommand::StartConnection(other.address, session))
+            .unwrap();
+        session
+    }
+
+    pub fn send(&self, command: NetworkTaskCommand) {
+        self.network.send(command).unwrap();
+    }
+
+    /// The next command for the UI.
+    pub fn next_ui(&self) -> CallScreenCommand {
+        match self.ui.recv_timeout(PATIENCE) {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => panic!("{} told the UI nothing", self.address),
+            Err(RecvTimeoutError::Disconnected) => panic!("{} stopped", self.address),
+        }
+    }
+
+    /// Fails if the UI hears anything for `time`.
+    pub fn expect_quiet_ui(&self, time: Duration) {
+        if let Ok(command) = self.ui.recv_timeout(time) {
+            panic!("{} unexpectedly told the UI {:?}", self.address, command);
+        }
+    }
+
+    /// Waits for a call frame to reach the output audio task.
+    pub fn next_audio_frame(&self) -> Vec<i16> {
+        loop {
+            match self.audio.recv_timeout(PATIENCE) {
+                Ok(OutputAudioTaskCommand::QueueFrame(frame)) => return frame.samples,
+                Ok(_) => {}
+                Err(_) => panic!("{} played no audio", self.address),
+            }
+        }
+    }
+
+    /// Sets up a call from `self` to `other`, who picks up.
+    pub fn connect(&self, other: &Unit) -> SessionId {
+        let session = self.call(other);
+        assert_eq!(
+            other.next_ui(),
+            CallScreenCommand::IncomingCall(self.address, session, false)
+        );
+        other.send(NetworkTaskCommand::SendAccept);
+        assert_eq!(
+            self.next_ui(),
+            CallScreenCommand::StartCall(other.address, session, false)
+        );
+        session
+    }
+}
+
+impl Drop for Unit {
+    fn drop(&mut self) {
+        let _ = self.network.send(NetworkTaskCommand::Exit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        audio_format::{Capabilities, DEVICE_CHANNELS},
+        impairment::{Direction, ImpairmentProxy, Impairments},
+        packet::{
+            KeyExchange, NetworkPacket, NetworkPacketType, RejectReason, StopReason, KEY_SHARE_SIZE,
+        },
+        terminal_task::CallEndReason,
+    };
+
+    /// 10 ms of a ramp, as the input audio task would send it.
+    fn audio() -> Vec<i16> {
+        (0..480 * DEVICE_CHANNELS as i16).map(|i| i * 10).collect()
+    }
+
+    #[test]
+    fn accepted_call_carries_audio_both_ways() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+
+        for _ in 0..3 {
+            alice.send(NetworkTaskCommand::SendAudio(audio()));
+            bob.send(NetworkTaskCommand::SendAudio(audio()));
+        }
+        assert!(bob.next_audio_frame().iter().any(|&sample| sample != 0));
+        assert!(alice.next_audio_frame().iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn heartbeats_measure_the_round_trip() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        let deadline = std::time::Instant::now() + PATIENCE;
+        while alice.quality.get().rtt.is_none() || bob.quality.get().rtt.is_none() {
+            assert!(std::time::Instant::now() < deadline, "no round trip time");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        // Loopback, with the network tasks polling every millisecond or so
+        assert!(alice.quality.get().rtt.unwrap() < Duration::from_millis(200));
+
+        alice.send(NetworkTaskCommand::SendAudio(audio()));
+        alice.send(NetworkTaskCommand::SendAudio(audio()));
+        bob.next_audio_frame();
+        assert!(alice.quality.get().packets_sent > 0);
+        assert!(bob.quality.get().packets_received > 0);
+    }
+
+    #[test]
+    fn caller_hangs_up() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        alice.send(NetworkTaskCommand::StopConnection);
+        assert_eq!(bob.next_ui(), CallScreenCommand::PeerHungUp);
+        alice.expect_quiet_ui(Duration::from_millis(700));
+    }
+
+    #[test]
+    fn callee_hangs_up() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        bob.send(NetworkTaskCommand::StopConnection);
+        assert_eq!(alice.next_ui(), CallScreenCommand::PeerHungUp);
+        bob.expect_quiet_ui(Duration::from_millis(700));
+    }
+
+    #[test]
+    fn declined_call() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let session = alice.call(&bob);
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::IncomingCall(alice.address, session, false)
+        );
+        bob.send(NetworkTaskCommand::SendReject);
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::Declined)
+        );
+    }
+
+    #[test]
+    fn caller_gives_up_before_an_answer() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.call(&bob);
+        assert!(matches!(bob.next_ui(), CallScreenCommand::IncomingCall(..)));
+        alice.send(NetworkTaskCommand::StopConnection);
+        assert_eq!(bob.next_ui(), CallScreenCommand::PeerHungUp);
+    }
+
+    /// A unit that rings for longer than the others.
+    fn patient_unit() -> Unit {
+        let mut timeouts = fast_timeouts();
+        timeouts.ring_timeout *= 3;
+        Unit::start(timeouts, SecuritySettings::default())
+    }
+
+    #[test]
+    fn nobody_answers() {
+        let (alice, bob) = (Unit::new(), patient_unit());
+        alice.call(&bob);
+        assert!(matches!(bob.next_ui(), CallScreenCommand::IncomingCall(..)));
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::NoAnswer)
+        );
+        assert_eq!(bob.next_ui(), CallScreenCommand::PeerHungUp);
+    }
+
+    #[test]
+    fn callee_stops_ringing() {
+        let (alice, bob) = (patient_unit(), Unit::new());
+        alice.call(&bob);
+        assert!(matches!(bob.next_ui(), CallScreenCommand::IncomingCall(..)));
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::MissedCall)
+        );
+        assert_eq!(alice.next_ui(), CallScreenCommand::PeerHungUp);
+    }
+
+    #[test]
+    fn nobody_listening() {
+        let alice = Unit::new();
+        let nobody = Unit::new();
+        let address = nobody.address;
+        drop(nobody);
+        alice.send(NetworkTaskCommand::StartConnection(
+            address,
+            new_session_id(),
+        ));
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::PeerUnreachable)
+        );
+    }
+
+    #[test]
+    fn busy_unit_turns_other_callers_away() {
+        let (alice, bob, carol) = (Unit::new(), Unit::new(), Unit::new());
+        alice.connect(&bob);
+        carol.call(&bob);
+        assert_eq!(
+            carol.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::LineBusy)
+        );
+        assert_eq!(bob.next_ui(), CallScreenCommand::MissedCall(carol.address));
+        bob.expect_quiet_ui(Duration::from_millis(300));
+
+        // The call goes on
+        alice.send(NetworkTaskCommand::SendAudio(audio()));
+        alice.send(NetworkTaskCommand::SendAudio(audio()));
+        bob.next_audio_frame();
+    }
+
+    #[test]
+    fn strangers_cant_hang_up_a_call() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let session = alice.connect(&bob);
+        let hangup = NetworkPacket::new_stop_connection(StopReason::Hangup).with_session(session);
+        RawPeer::new().send(hangup, &bob);
+
+        let deadline = std::time::Instant::now() + PATIENCE;
+        while bob.quality.get().packets_foreign == 0 {
+            assert!(std::time::Instant::now() < deadline, "not counted");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        bob.expect_quiet_ui(Duration::from_millis(200));
+    }
+
+    /// A bare socket standing in for a unit, to send what a network task
+    /// wouldn't and to leave out what it would.
+    struct RawPeer(std::net::UdpSocket);
+
+    impl RawPeer {
+        fn new() -> Self {
+            // Off the same ports as the units, so it can't take one of theirs
+            let port = next_port();
+            let socket = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+            socket.set_read_timeout(Some(PATIENCE)).unwrap();
+            Self(socket)
+        }
+
+        fn address(&self) -> SocketAddr {
+            self.0.local_addr().unwrap()
+        }
+
+        fn send(&self, packet: NetworkPacket, to: &Unit) {
+            self.0
+                .send_to(&packet.serialize().unwrap(), to.address)
+                .unwrap();
+        }
+
+        /// Waits for a packet of the given type, skipping heartbeats and
+        /// the like.
+        fn expect(&self, packet_type: NetworkPacketType) -> NetworkPacket {
+            let mut buffer = [0; 2048];
+            loop {
+                let (len, _) = self.0.recv_from(&mut buffer).unwrap();
+                let packet = NetworkPacket::deserialize(&buffer[..len]).unwrap();
+                if packet.packet_type == packet_type {
+                    return packet;
+                }
+            }
+        }
+
+        /// Calls `unit`, which takes unencrypted calls, and has it pick up.
+        fn connect(&self, unit: &Unit) -> SessionId {
+            let session = new_session_id();
+            let offer = NetworkPacket::new_start_connection(&Capabilities::local(), None);
+            self.send(offer.with_session(session), unit);
+            assert_eq!(
+                unit.next_ui(),
+                CallScreenCommand::IncomingCall(self.address(), session, false)
+            );
+            unit.send(NetworkTaskCommand::SendAccept);
+            self.expect(NetworkPacketType::Accept);
+            session
+        }
+    }
+
+    #[test]
+    fn repeated_offers_to_a_busy_unit_are_one_missed_call() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        let caller = RawPeer::new();
+        let offer = NetworkPacket::new_start_connection(&Capabilities::local(), None)
+            .with_session(new_session_id());
+        caller.send(offer.clone(), &bob);
+        caller.expect(NetworkPacketType::Busy);
+        caller.send(offer, &bob);
+        caller.expect(NetworkPacketType::Busy);
+
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::MissedCall(caller.address())
+        );
+        bob.expect_quiet_ui(Duration::from_millis(300));
+    }
+
+    fn unencrypted_unit() -> Unit {
+        Unit::start(
+            fast_timeouts(),
+            SecuritySettings {
+                allow_unencrypted: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn packets_from_an_earlier_call_are_ignored() {
+        let (alice, bob) = (RawPeer::new(), unencrypted_unit());
+        let session = alice.connect(&bob);
+
+        let hangup = NetworkPacket::new_stop_connection(StopReason::Hangup);
+        alice.send(hangup.clone().with_session(session.wrapping_add(1)), &bob);
+        let deadline = std::time::Instant::now() + PATIENCE;
+        while bob.quality.get().packets_stale == 0 {
+            assert!(std::time::Instant::now() < deadline, "not counted");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        bob.expect_quiet_ui(Duration::from_millis(200));
+
+        alice.send(hangup.with_session(session), &bob);
+        assert_eq!(bob.next_ui(), CallScreenCommand::PeerHungUp);
+    }
+
+    #[test]
+    fn lost_answer_is_sent_again() {
+        let (alice, bob) = (RawPeer::new(), unencrypted_unit());
+        let session = alice.connect(&bob);
+        // Alice acts as if the Accept never arrived and repeats her offer
+        let offer = NetworkPacket::new_start_connection(&Capabilities::local(), None);
+        alice.send(offer.with_session(session), &bob);
+        let accept = alice.expect(NetworkPacketType::Accept);
+        assert_eq!(accept.session, session);
+    }
+
+    #[test]
+    fn caller_repeats_its_offer_until_answered() {
+        let (alice, bob) = (Unit::new(), RawPeer::new());
+        let session = new_session_id();
+        alice.send(NetworkTaskCommand::StartConnection(bob.address(), session));
+        bob.expect(NetworkPacketType::StartConnection);
+        bob.send(NetworkPacket::new_heartbeat().with_session(session), &alice);
+        // Ringing, but the answer could still get lost
+        for _ in 0..2 {
+            bob.expect(NetworkPacketType::StartConnection);
+        }
+    }
+
+    #[test]
+    fn malformed_key_share_is_refused() {
+        let (alice, bob) = (RawPeer::new(), Unit::new());
+        let mut offer = NetworkPacket::new_start_connection(&Capabilities::local(), None);
+        offer.data[0] = 5;
+        offer.data.splice(1..1, [0; 5]);
+        alice.send(offer.with_session(new_session_id()), &bob);
+        let reject = alice.expect(NetworkPacketType::Reject);
+        assert_eq!(
+            reject.reject_reason(),
+            Some(RejectReason::KeyExchangeFailed)
+        );
+        bob.expect_quiet_ui(Duration::from_millis(200));
+    }
+
+    #[test]
+    fn unusable_key_share_is_refused() {
+        let (alice, bob) = (RawPeer::new(), Unit::new());
+        // An all-zero public key makes for an all-zero shared secret
+        let exchange = KeyExchange {
+            key_share: [0; KEY_SHARE_SIZE],
+            identity: [0; KEY_SHARE_SIZE],
+        };
+        let offer = NetworkPacket::new_start_connection(&Capabilities::local(), Some(&exchange));
+        alice.send(offer.with_session(new_session_id()), &bob);
+        let reject = alice.expect(NetworkPacketType::Reject);
+        assert_eq!(
+            reject.reject_reason(),
+            Some(RejectReason::KeyExchangeFailed)
+        );
+        assert_eq!(bob.quality.get().packets_rejected, 1);
+    }
+
+    #[test]
+    fn vanished_peer_drops_the_call() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        drop(bob);
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::ConnectionLost)
+        );
+    }
+
+    #[test]
+    fn units_call_again_after_hanging_up() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        alice.connect(&bob);
+        alice.send(NetworkTaskCommand::StopConnection);
+        assert_eq!(bob.next_ui(), CallScreenCommand::PeerHungUp);
+        bob.connect(&alice);
+    }
+
+    #[test]
+    fn pairing_that_cant_be_saved_leaves_calls_working() {
+        let dir = tempfile::tempdir().unwrap();
+        let trusted_peers = TrustedPeers::load(&dir.path().join("data/trusted_peers")).unwrap();
+        // A file where the data directory should be
+        std::fs::write(dir.path().join("data"), "").unwrap();
+        let (alice, bob) = (Unit::new(), Unit::with_trusted_peers(trusted_peers));
+
+        alice.send(NetworkTaskCommand::StartPairing(None));
+        // Alice refuses to pair until she has taken that in
+        std::thread::sleep(Duration::from_millis(50));
+        bob.send(NetworkTaskCommand::StartPairing(Some(alice.address)));
+        assert!(matches!(alice.next_ui(), CallScreenCommand::PairingCode(_)));
+        assert!(matches!(bob.next_ui(), CallScreenCommand::PairingCode(_)));
+        // Bob finishes last, once Alice has saved him
+        bob.send(NetworkTaskCommand::ConfirmPairing);
+        alice.send(NetworkTaskCommand::ConfirmPairing);
+        assert_eq!(alice.next_ui(), CallScreenCommand::PairingComplete);
+        match bob.next_ui() {
+            CallScreenCommand::Notice(notice) => {
+                assert!(notice.contains("data/trusted_peers"), "{}", notice)
+            }
+            other => panic!("expected a notice, got {:?}", other),
+        }
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::PairingFailed(CallEndReason::PairingNotSaved)
+        );
+
+        // Bob never trusted Alice, but still calls her
+        let session = bob.call(&alice);
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::IncomingCall(bob.address, session, true)
+        );
+        alice.send(NetworkTaskCommand::SendAccept);
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::StartCall(alice.address, session, false)
+        );
+    }
+
+    /// A proxy in front of `unit`, impairing both ways.
+    fn impaired_link(unit: &Unit, impairments: Impairments) -> ImpairmentProxy {
+        ImpairmentProxy::start(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            unit.address,
+            impairments,
+            impairments,
+            0x5EED,
+        )
+        .unwrap()
+    }
+
+    /// Calls `other` through `proxy` and has it pick up.
+    fn connect_through(caller: &Unit, proxy: &ImpairmentProxy, other: &Unit) {
+        let session = new_session_id();
+        caller.send(NetworkTaskCommand::StartConnection(proxy.address, session));
+        assert!(matches!(
+            other.next_ui(),
+            CallScreenCommand::IncomingCall(_, incoming, false) if incoming == session
+        ));
+        other.send(NetworkTaskCommand::SendAccept);
+        assert_eq!(
+            caller.next_ui(),
+            CallScreenCommand::StartCall(proxy.address, session, false)
+        );
+    }
+
+    #[test]
+    fn call_survives_a_bad_link() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let proxy = impaired_link(
+            &bob,
+            Impairments {
+                loss: 0.1,
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(10),
+                duplicate: 0.05,
+                reorder: 0.05,
+                reorder_delay: Duration::from_millis(15),
+                bandwidth: None,
+            },
+        );
+        connect_through(&alice, &proxy, &bob);
+
+        for _ in 0..50 {
+            alice.send(NetworkTaskCommand::SendAudio(audio()));
+            bob.send(NetworkTaskCommand::SendAudio(audio()));
+        }
+        assert!(bob.next_audio_frame().iter().any(|&sample| sample != 0));
+        assert!(alice.next_audio_frame().iter().any(|&sample| sample != 0));
+        alice.expect_quiet_ui(Duration::from_millis(300));
+        bob.expect_quiet_ui(Duration::from_millis(0));
+    }
+
+    #[test]
+    fn receiver_reports_show_how_our_audio_arrives() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let proxy = impaired_link(&bob, Impairments::default());
+        proxy.set_impairments(
+            Direction::ToTarget,
+            Impairments {
+                loss: 0.3,
+                ..Default::default()
+            },
+        );
+        connect_through(&alice, &proxy, &bob);
+
+        let deadline = std::time::Instant::now() + PATIENCE;
+        loop {
+            let far_end = alice.quality.get().far_end;
+            if far_end.is_some_and(|report| report.cumulative_lost > 0) {
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "no loss reported");
+            alice.send(NetworkTaskCommand::SendAudio(audio()));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Bob sends no audio, so Alice has nothing to report losing
+        let deadline = std::time::Instant::now() + PATIENCE;
+        while bob.quality.get().far_end.is_none() {
+            assert!(std::time::Instant::now() < deadline, "no report from alice");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(bob.quality.get().far_end.unwrap().cumulative_lost, 0);
+        assert!(bob.quality.get().rtt.is_some());
+    }
+
+    #[test]
+    fn link_going_dead_drops_the_call() {
+        let (alice, bob) = (Unit::new(), Unit::new());
+        let proxy = impaired_link(&bob, Impairments::default());
+        connect_through(&alice, &proxy, &bob);
+
+        let dead = Impairments {
+            loss: 1.0,
+            ..Default::default()
+        };
+        proxy.set_impairments(Direction::ToTarget, dead);
+        proxy.set_impairments(Direction::FromTarget, dead);
+        assert_eq!(
+            alice.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::ConnectionLost)
+        );
+        assert_eq!(
+            bob.next_ui(),
+            CallScreenCommand::CallFailed(CallEndReason::ConnectionLost)
+        );
+        let (to_bob, from_bob) = proxy.stats();
+        assert!(to_bob.lost > 0 && from_bob.lost > 0);
+    }
+}
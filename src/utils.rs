@@ -1,15 +1,60 @@
+use minimp3::{ffi, MAX_SAMPLES_PER_FRAME};
+
+/// Decodes a whole MP3 file to interleaved samples.
+///
+/// Feeds minimp3's C decoder straight from `bytes`: its `Decoder` buffers
+/// the stream in a slice-deque, which writes past the end of its slice and
+/// aborts debug builds.
 pub fn decode_bytes(bytes: &[u8]) -> Vec<i16> {
-    let mut decoder = minimp3::Decoder::new(bytes);
+    // SAFETY: the decoder state is plain numbers, for which zero is valid,
+    // and mp3dec_init sets it up before use
+    let mut decoder: ffi::mp3dec_t = unsafe { std::mem::zeroed() };
+    unsafe { ffi::mp3dec_init(&mut decoder) };
+    let mut pcm = [0; MAX_SAMPLES_PER_FRAME];
     let mut samples = Vec::new();
-    loop {
-        match decoder.next_frame() {
-            Ok(frame) => {
-                samples.extend_from_slice(&frame.data);
-            }
-            Err(minimp3::Error::Eof) => break,
-            Err(e) => panic!("Error decoding mp3: {:?}", e),
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let mut info = ffi::mp3dec_frame_info_t {
+            frame_bytes: 0,
+            frame_offset: 0,
+            channels: 0,
+            hz: 0,
+            layer: 0,
+            bitrate_kbps: 0,
+        };
+        // SAFETY: minimp3 reads at most the given length of `rest` and
+        // writes at most MAX_SAMPLES_PER_FRAME samples to `pcm`
+        let decoded = unsafe {
+            ffi::mp3dec_decode_frame(
+                &mut decoder,
+                rest.as_ptr(),
+                rest.len().min(i32::MAX as usize) as i32,
+                pcm.as_mut_ptr(),
+                &mut info,
+            )
+        };
+        // Nothing left that looks like a frame
+        if info.frame_bytes <= 0 {
+            break;
         }
+        // Frames without samples are tags or garbage it skipped
+        samples.extend_from_slice(&pcm[..decoded as usize * info.channels as usize]);
+        rest = &rest[info.frame_bytes as usize..];
     }
-
     samples
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ringtone_decodes() {
+        let samples = decode_bytes(include_bytes!("assets/capitao_whatsapp.mp3"));
+        // Several seconds of stereo
+        assert!(samples.len() > 48000 * 2 * 5, "{}", samples.len());
+        assert_eq!(samples.len() % 2, 0);
+        assert!(samples.iter().any(|&sample| sample.abs() > 1000));
+        assert!(decode_bytes(b"not an mp3").is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+use std::{collections::VecDeque, ops::RangeInclusive};
+
+use crate::{
+    audio_format::DEVICE_SAMPLE_RATE,
+    fft::{Complex, Fft},
+};
+
+/// Strengths of noise suppression we offer, in dB.
+pub const SUPPRESSION_RANGE_DB: RangeInclusive<u32> = 3..=40;
+/// Speech levels gain control can aim for, in dB relative to full scale.
+pub const TARGET_RANGE_DBFS: RangeInclusive<i32> = -40..=-3;
+/// Most gain control may amplify, in dB.
+pub const MAX_GAIN_RANGE_DB: RangeInclusive<u32> = 0..=40;
+
+/// Frames the noise suppressor analyses at a time (10.7 ms at 48 kHz).
+const FRAME_FRAMES: usize = 512;
+/// Frames between analyses, half a frame so the windows add up to one.
+const HOP_FRAMES: usize = FRAME_FRAMES / 2;
+const BINS: usize = FRAME_FRAMES / 2 + 1;
+/// Weight of the previous frame's speech estimate in the a priori SNR
+/// (Ephraim and Malah's decision directed approach), which keeps the
+/// suppression from warbling.
+const DECISION_DIRECTED: f32 = 0.98;
+/// Weight of the previous frames in the smoothed power spectrum.
+const POWER_SMOOTHING: f32 = 0.7;
+/// How fast the noise estimate rises each hop while the power stays above
+/// it, about 4 dB a second. It drops to the power right away.
+const NOISE_RISE: f32 = 1.005;
+/// How fast it rises during the first half second, to learn the noise at
+/// the start of a call.
+const NOISE_RISE_AT_START: f32 = 1.05;
+const START_HOPS: usize = DEVICE_SAMPLE_RATE as usize / 2 / HOP_FRAMES;
+/// Lowest noise estimate, so it can still rise after digital silence.
+const MIN_NOISE: f32 = 1e-12;
+/// The recent minimum sits well below the noise's average power, this
+/// makes up for it.
+const NOISE_BIAS: f32 = 2.0;
+
+/// Below this level a block is taken as a pause in speech, and gain control
+/// leaves its gain alone rather than bring up the background (-45 dBFS).
+const SPEECH_GATE: f32 = 0.0056;
+/// Weight of the previous blocks in the speech level.
+const LEVEL_SMOOTHING: f32 = 0.8;
+/// Gain control turns loud speech down to at most this.
+const MIN_GAIN: f32 = 0.25;
+/// How fast gain control may raise and lower its gain, in dB a second.
+const GAIN_RISE_DB_PER_SEC: f32 = 6.0;
+const GAIN_FALL_DB_PER_SEC: f32 = 20.0;
+/// Highest peak gain control lets through, a little below full scale.
+const PEAK_LIMIT: f32 = 0.9;
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// What is done to the captured speech after echo cancellation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VoiceSettings {
+    pub noise_suppression: bool,
+    /// Most the noise is turned down, in dB.
+    pub suppression_db: u32,
+    pub gain_control: bool,
+    /// Level speech is brought to, in dB relative to full scale.
+    pub target_dbfs: i32,
+    /// Most gain control amplifies, in dB.
+    pub max_gain_db: u32,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            noise_suppression: true,
+            suppression_db: 15,
+            gain_control: true,
+            target_dbfs: -18,
+            max_gain_db: 20,
+        }
+    }
+}
+
+/// Turns down stationary background noise, such as fans, with a short time
+/// spectral gain. The noise spectrum is the recent minimum of the signal's,
+/// so speech pauses teach it what the noise sounds like.
+pub struct NoiseSuppressor {
+    channels: usize,
+    fft: Fft,
+    /// Square root of a Hann window, applied before analysis and again
+    /// after synthesis.
+    window: Vec<f32>,
+    /// Lowest gain of any bin.
+    floor: f32,
+    /// Passes the audio through at unity gain, still learning the noise and
+    /// with the same delay, so switching back on is seamless.
+    bypass: bool,
+    /// The last frame of input of each channel.
+    history: Vec<Vec<f32>>,
+    /// Second half of the last synthesized frame of each channel, to add to
+    /// the next.
+    overlap: Vec<Vec<f32>>,
+    /// Mono power spectrum, smoothed over a few frames.
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    hops: usize,
+    /// Speech power estimate of the previous frame.
+    speech: Vec<f32>,
+    /// Interleaved input not yet making up a hop.
+    pending: Vec<i16>,
+    /// Processed interleaved audio.
+    processed: VecDeque<i16>,
+    spectra: Vec<Vec<Complex>>,
+}
+
+impl NoiseSuppressor {
+    pub fn new(channels: usize, suppression_db: u32) -> Self {
+        let window = (0..FRAME_FRAMES)
+            .map(|n| {
+                let phase = 2.0 * std::f32::consts::PI * n as f32 / FRAME_FRAMES as f32;
+                (0.5 - 0.5 * phase.cos()).sqrt()
+            })
+            .collect();
+        Self {
+            channels,
+            fft: Fft::new(FRAME_FRAMES),
+            window,
+            floor: db_to_gain(-(suppression_db as f32)),
+            bypass: false,
+            history: vec![vec![0.0; FRAME_FRAMES]; channels],
+            overlap: vec![vec![0.0; HOP_FRAMES]; channels],
+            power: vec![0.0; BINS],
+            noise: Vec::new(),
+            hops: 0,
+            speech: vec![0.0; BINS],
+            pending: Vec::new(),
+            // Enough for any length of input to be answered with as much
+            // output
+            processed: VecDeque::from(vec![0; HOP_FRAMES * channels]),
+            spectra: vec![vec![Complex::ZERO; FRAME_FRAMES]; channels],
+        }
+    }
+
+    pub fn set_suppression(&mut self, suppression_db: u32) {
+        self.floor = db_to_gain(-(suppression_db as f32));
+    }
+
+    pub fn set_bypass(&mut self, bypass: bool) {
+        self.bypass = bypass;
+    }
+
+    /// Takes interleaved audio and returns as much with the noise turned
+    /// down, [`FRAME_FRAMES`] later.
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        self.pending.extend_from_slice(samples);
+        let hop_len = HOP_FRAMES * self.channels;
+        while self.pending.len() >= hop_len {
+            let hop = self.pending.drain(..hop_len).collect::<Vec<_>>();
+            self.process_hop(&hop);
+        }
+        self.processed.drain(..samples.len()).collect()
+    }
+
+    fn process_hop(&mut self, hop: &[i16]) {
+        for (channel, history) in self.history.iter_mut().enumerate() {
+            history.drain(..HOP_FRAMES);
+            history.extend(
+                hop.iter()
+                    .skip(channel)
+                    .step_by(self.channels)
+                    .map(|&sample| sample as f32 / 32768.0),
+            );
+            let spectrum = &mut self.spectra[channel];
+            for ((value, &sample), &weight) in spectrum.iter_mut().zip(&*history).zip(&self.window)
+            {
+                *value = Complex::new(sample * weight, 0.0);
+            }
+            self.fft.forward(spectrum);
+        }
+
+        for (bin, power) in self.power.iter_mut().enumerate() {
+            let frame_power = self
+                .spectra
+                .iter()
+                .map(|spectrum| spectrum[bin].norm_sqr())
+                .sum::<f32>()
+                / self.channels as f32;
+            *power = *power * POWER_SMOOTHING + frame_power * (1.0 - POWER_SMOOTHING);
+        }
+        if self.noise.is_empty() {
+            self.noise = self
+                .power
+                .iter()
+                .map(|&power| power.max(MIN_NOISE))
+                .collect();
+        }
+        let rise = if self.hops < START_HOPS {
+            NOISE_RISE_AT_START
+        } else {
+            NOISE_RISE
+        };
+        self.hops += 1;
+        let mut gains = [0.0; BINS];
+        for (bin, gain) in gains.iter_mut().enumerate() {
+            let power = self.power[bin];
+            let noise = &mut self.noise[bin];
+            *noise = if power < *noise {
+                power.max(MIN_NOISE)
+            } else {
+                *noise * rise
+            };
+            let noise = *noise * NOISE_BIAS;
+            let posterior = power / noise;
+            let prior = DECISION_DIRECTED * self.speech[bin] / noise
+                + (1.0 - DECISION_DIRECTED) * (posterior - 1.0).max(0.0);
+            *gain = (prior / (1.0 + prior)).max(self.floor);
+            self.speech[bin] = *gain * *gain * power;
+            if self.bypass {
+                *gain = 1.0;
+            }
+        }
+
+        let mut output = vec![0; HOP_FRAMES * self.channels];
+        for channel in 0..self.channels {
+            let spectrum = &mut self.spectra[channel];
+            for bin in 0..BINS {
+                spectrum[bin] = spectrum[bin].scale(gains[bin]);
+                if bin > 0 && bin < FRAME_FRAMES / 2 {
+                    spectrum[FRAME_FRAMES - bin] = spectrum[bin].conj();
+                }
+            }
+            self.fft.inverse(spectrum);
+            let overlap = &mut self.overlap[channel];
+            for frame in 0..HOP_FRAMES {
+                let sample = overlap[frame] + spectrum[frame].re * self.window[frame];
+                overlap[frame] = spectrum[frame + HOP_FRAMES].re * self.window[frame + HOP_FRAMES];
+                output[frame * self.channels + channel] =
+                    (sample * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+        self.processed.extend(output);
+    }
+}
+
+/// Brings speech to a steady level: slowly up when it's quiet, quickly down
+/// when it's loud, never past full scale, and not at all during pauses.
+pub struct GainControl {
+    channels: usize,
+    /// Speech level to aim for, RMS relative to full scale.
+    target: f32,
+    max_gain: f32,
+    gain: f32,
+    /// Recent speech level, RMS relative to full scale.
+    level: Option<f32>,
+}
+
+impl GainControl {
+    pub fn new(channels: usize, target_dbfs: i32, max_gain_db: u32) -> Self {
+        Self {
+            channels,
+            target: db_to_gain(target_dbfs as f32),
+            max_gain: db_to_gain(max_gain_db as f32),
+            gain: 1.0,
+            level: None,
+        }
+    }
+
+    pub fn set_target(&mut self, target_dbfs: i32, max_gain_db: u32) {
+        self.target = db_to_gain(target_dbfs as f32);
+        self.max_gain = db_to_gain(max_gain_db as f32);
+    }
+
+    /// Amplifies interleaved audio in place.
+    pub fn process(&mut self, samples: &mut [i16]) {
+        if samples.is_empty() {
+            return;
+        }
+        let scaled = |sample: i16| sample as f32 / 32768.0;
+        let rms =
+            (samples.iter().map(|&s| scaled(s).powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+        let peak = samples.iter().map(|&s| scaled(s).abs()).fold(0.0, f32::max);
+
+        let mut gain = self.gain;
+        if rms > SPEECH_GATE {
+            let level = match self.level {
+                Some(level) => level * LEVEL_SMOOTHING + rms * (1.0 - LEVEL_SMOOTHING),
+                None => rms,
+            };
+            self.level = Some(level);
+            let wanted = (self.target / level).clamp(MIN_GAIN, self.max_gain);
+            let seconds = (samples.len() / self.channels) as f32 / DEVICE_SAMPLE_RATE as f32;
+            gain = wanted.clamp(
+                self.gain / db_to_gain(GAIN_FALL_DB_PER_SEC * seconds),
+                self.gain * db_to_gain(GAIN_RISE_DB_PER_SEC * seconds),
+            );
+        }
+        // Ramp over the block so the gain doesn't step audibly, unless the
+        // block would clip
+        let mut start = self.gain;
+        if peak * gain > PEAK_LIMIT {
+            gain = PEAK_LIMIT / peak;
+        }
+        if peak * start > PEAK_LIMIT {
+            start = gain;
+        }
+        let frames = samples.len() / self.channels;
+        for (frame, samples) in samples.chunks_exact_mut(self.channels).enumerate() {
+            let ramped = start + (gain - start) * (frame + 1) as f32 / frames as f32;
+            for sample in samples {
+                *sample = (*sample as f32 * ramped).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+        self.gain = gain;
+    }
+}
+
+/// The noise suppression and gain control stage of the capture path, each
+/// switched on and off by the settings. Both stages always run, so the
+/// delay doesn't change and nothing learned is lost when one is switched
+/// off.
+pub struct VoiceProcessor {
+    settings: VoiceSettings,
+    noise_suppressor: NoiseSuppressor,
+    gain_control: GainControl,
+}
+
+impl VoiceProcessor {
+    pub fn new(channels: usize, settings: VoiceSettings) -> Self {
+        let mut processor = Self {
+            settings,
+            noise_suppressor: NoiseSuppressor::new(channels, settings.suppression_db),
+            gain_control: GainControl::new(channels, settings.target_dbfs, settings.max_gain_db),
+        };
+        processor.configure();
+        processor
+    }
+
+    /// Changes the settings in the middle of a call. What is already
+    /// learned about the noise and the speech level is kept.
+    pub fn set_settings(&mut self, settings: VoiceSettings) {
+        self.settings = settings;
+        self.configure();
+    }
+
+    fn configure(&mut self) {
+        let settings = self.settings;
+        self.noise_suppressor
+            .set_suppression(settings.suppression_db);
+        self.noise_suppressor
+            .set_bypass(!settings.noise_suppression);
+        self.gain_control
+            .set_target(settings.target_dbfs, settings.max_gain_db);
+    }
+
+    pub fn process(&mut self, samples: Vec<i16>) -> Vec<i16> {
+        let mut samples = self.noise_suppressor.process(&samples);
+        // Gain control adds no delay, leaving it out keeps its gain for
+        // when it's back on
+        if self.settings.gain_control {
+            self.gain_control.process(&mut samples);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = DEVICE_SAMPLE_RATE as usize;
+
+    /// Deterministic white noise with the given RMS, relative to full scale.
+    fn noise(frames: usize, rms: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..frames)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                // Uniform noise has an RMS of 1/sqrt(3) of its peak
+                (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * rms * 3f32.sqrt()
+            })
+            .collect()
+    }
+
+    fn tone(frames: usize, rms: f32) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                let phase = 2.0 * std::f32::consts::PI * 440.0 * frame as f32 / RATE as f32;
+                phase.sin() * rms * 2f32.sqrt()
+            })
+            .collect()
+    }
+
+    fn stereo(mono: &[f32]) -> Vec<i16> {
+        mono.iter()
+            .flat_map(|&sample| [(sample * 32768.0) as i16; 2])
+            .collect()
+    }
+
+    fn rms_dbfs(samples: &[i16]) -> f32 {
+        let power = samples
+            .iter()
+            .map(|&sample| (sample as f32 / 32768.0).powi(2))
+            .sum::<f32>()
+            / samples.len() as f32;
+        10.0 * power.log10()
+    }
+
+    /// Feeds `samples` through `process` in reads the size the input audio
+    /// task makes.
+    fn in_reads(samples: &[i16], process: impl FnMut(&[i16]) -> Vec<i16>) -> Vec<i16> {
+        samples.chunks(400 * 2).flat_map(process).collect()
+    }
+
+    #[test]
+    fn fan_noise_is_turned_down_and_speech_kept() {
+        // Two seconds of noise, then a tone over the same noise
+        let background = noise(RATE * 4, 0.01, 3);
+        let mut input = background.clone();
+        for (sample, tone) in input[RATE * 2..].iter_mut().zip(tone(RATE * 2, 0.1)) {
+            *sample += tone;
+        }
+        let input = stereo(&input);
+        let mut suppressor = NoiseSuppressor::new(2, 15);
+        let output = in_reads(&input, |read| suppressor.process(read));
+        assert_eq!(output.len(), input.len());
+
+        // Half a second from the start of the given second, lined up
+        // for the suppressor's delay
+        let half_second = |samples: &[i16], second: usize| {
+            let start = (second * RATE + FRAME_FRAMES) * 2;
+            samples[start..start + RATE].to_vec()
+        };
+        let reduction = rms_dbfs(&half_second(&input, 1)) - rms_dbfs(&half_second(&output, 1));
+        assert!(reduction > 12.0, "noise only {:.1} dB down", reduction);
+        let speech_loss = rms_dbfs(&half_second(&input, 3)) - rms_dbfs(&half_second(&output, 3));
+        assert!(speech_loss.abs() < 1.0, "speech {:.1} dB down", speech_loss);
+    }
+
+    #[test]
+    fn suppressor_passes_audio_unchanged_with_no_suppression() {
+        let input = stereo(&tone(RATE / 2, 0.2));
+        let mut suppressor = NoiseSuppressor::new(2, 0);
+        let output = in_reads(&input, |read| suppressor.process(read));
+        let delay = FRAME_FRAMES * 2;
+        for (output, input) in output[delay..].iter().zip(&input) {
+            assert!((output - input).abs() <= 2, "{} vs {}", output, input);
+        }
+    }
+
+    #[test]
+    fn quiet_speech_is_brought_up_to_the_target() {
+        let input = stereo(&tone(RATE * 6, db_to_gain(-35.0)));
+        let mut control = GainControl::new(2, -18, 20);
+        let output = in_reads(&input, |read| {
+            let mut read = read.to_vec();
+            control.process(&mut read);
+            read
+        });
+        let level = rms_dbfs(&output[output.len() - RATE * 2..]);
+        assert!((level - -18.0).abs() < 2.0, "ended up at {:.1} dBFS", level);
+    }
+
+    #[test]
+    fn gain_control_neither_clips_nor_brings_up_pauses() {
+        let mut control = GainControl::new(2, -18, 20);
+        let mut loud = stereo(&tone(RATE, 0.7));
+        for read in loud.chunks_mut(800) {
+            control.process(read);
+        }
+        let peak = loud
+            .iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap();
+        assert!(peak as f32 <= PEAK_LIMIT * 32768.0 + 1.0, "peak {}", peak);
+
+        let mut control = GainControl::new(2, -18, 20);
+        let pause = stereo(&noise(RATE * 2, db_to_gain(-60.0), 5));
+        let mut output = pause.clone();
+        for read in output.chunks_mut(800) {
+            control.process(read);
+        }
+        assert_eq!(output, pause);
+    }
+
+    #[test]
+    fn stages_switch_on_and_off() {
+        let settings = VoiceSettings {
+            noise_suppression: false,
+            gain_control: false,
+            ..Default::default()
+        };
+        let mut processor = VoiceProcessor::new(2, settings);
+        let input = stereo(&tone(RATE / 10, 0.01));
+        // Off, the audio only comes out later
+        let output = processor.process(input.clone());
+        let delay = FRAME_FRAMES * 2;
+        assert!(output[..delay].iter().all(|&sample| sample == 0));
+        assert!(output[delay..]
+            .iter()
+            .zip(&input)
+            .all(|(&output, &input)| (output as i32 - input as i32).abs() <= 1));
+
+        // What the suppressor learned carries on across switching
+        let hops = processor.noise_suppressor.hops;
+        processor.set_settings(VoiceSettings::default());
+        assert_eq!(processor.process(input.clone()).len(), input.len());
+        processor.set_settings(settings);
+        processor.process(input.clone());
+        assert!(processor.noise_suppressor.hops > hops);
+    }
+}
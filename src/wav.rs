@@ -0,0 +1,152 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::audio_format::{DEVICE_CHANNELS, DEVICE_SAMPLE_RATE};
+
+const HEADER_SIZE: u32 = 44;
+
+/// Reads a 16-bit PCM WAV file recorded at [`DEVICE_SAMPLE_RATE`], as
+/// interleaved samples with [`DEVICE_CHANNELS`]. Mono files are copied to
+/// every channel.
+pub fn read(path: &Path) -> anyhow::Result<Vec<i16>> {
+    let bytes = std::fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    parse(&bytes).with_context(|| format!("{} isn't a usable WAV file", path.display()))
+}
+
+fn parse(bytes: &[u8]) -> anyhow::Result<Vec<i16>> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        bail!("no RIFF/WAVE header");
+    }
+    let mut format = None;
+    let mut chunks = &bytes[12..];
+    while chunks.len() >= 8 {
+        let id = &chunks[0..4];
+        let size = u32::from_le_bytes(chunks[4..8].try_into().unwrap()) as usize;
+        let body = &chunks[8..(8 + size).min(chunks.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let field = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+                let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                format = Some((field(0), field(2), rate, field(14)));
+            }
+            b"data" => {
+                let Some((encoding, channels, rate, bits)) = format else {
+                    bail!("data before the format");
+                };
+                if encoding != 1 || bits != 16 {
+                    bail!("only 16-bit PCM is supported");
+                }
+                if rate != DEVICE_SAMPLE_RATE {
+                    bail!("recorded at {} Hz, expected {}", rate, DEVICE_SAMPLE_RATE);
+                }
+                let samples = body
+                    .chunks_exact(2)
+                    .map(|sample| i16::from_le_bytes([sample[0], sample[1]]));
+                return match channels as usize {
+                    DEVICE_CHANNELS => Ok(samples.collect()),
+                    1 => Ok(samples
+                        .flat_map(|sample| [sample; DEVICE_CHANNELS])
+                        .collect()),
+                    _ => bail!("{} channels, expected 1 or {}", channels, DEVICE_CHANNELS),
+                };
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        chunks = &chunks[(8 + size + size % 2).min(chunks.len())..];
+    }
+    bail!("no audio data")
+}
+
+/// Writes a 16-bit PCM WAV file at the device format. The header is kept
+/// up to date after every write, so the file is usable even if we never get
+/// to close it.
+pub struct Writer {
+    file: File,
+    data_size: u32,
+}
+
+impl Writer {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+        let mut writer = Self { file, data_size: 0 };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = DEVICE_CHANNELS as u16 * 2;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&(DEVICE_CHANNELS as u16).to_le_bytes());
+        header.extend_from_slice(&DEVICE_SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(DEVICE_SAMPLE_RATE * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_size.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.file
+            .seek(SeekFrom::Start((HEADER_SIZE + self.data_size) as u64))?;
+        self.file.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        self.write_header()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_files_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("written.wav");
+        let samples = (0..960)
+            .map(|i| (i * 31 - 15000) as i16)
+            .collect::<Vec<_>>();
+
+        let mut writer = Writer::create(&path).unwrap();
+        writer.write(&samples[..100]).unwrap();
+        assert_eq!(read(&path).unwrap(), samples[..100]);
+        writer.write(&samples[100..]).unwrap();
+        assert_eq!(read(&path).unwrap(), samples);
+    }
+
+    #[test]
+    fn mono_files_play_on_both_channels() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF\0\0\0\0WAVE");
+        // A chunk we don't know about, with an odd size
+        bytes.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        bytes.extend_from_slice(b"fmt \x10\0\0\0\x01\0\x01\0");
+        bytes.extend_from_slice(&DEVICE_SAMPLE_RATE.to_le_bytes());
+        bytes.extend_from_slice(&(DEVICE_SAMPLE_RATE * 2).to_le_bytes());
+        bytes.extend_from_slice(b"\x02\0\x10\0data\x04\0\0\0");
+        bytes.extend_from_slice(&[1, 0, 0xff, 0xff]);
+        assert_eq!(parse(&bytes).unwrap(), vec![1, 1, -1, -1]);
+
+        let rate = bytes.len() - 24;
+        bytes[rate..rate + 4].copy_from_slice(&8000u32.to_le_bytes());
+        let error = parse(&bytes).unwrap_err().to_string();
+        assert!(error.contains("8000 Hz"), "{}", error);
+    }
+}